@@ -1,25 +1,28 @@
 use near_crypto::InMemorySigner;
+use near_crypto::PublicKey;
 use near_jsonrpc_client::{
     methods::{self, tx::RpcTransactionResponse},
     JsonRpcClient,
 };
-use near_jsonrpc_primitives::types::query::QueryResponseKind;
+use near_jsonrpc_primitives::types::query::{QueryResponseKind, RpcQueryError};
 use near_primitives::{
     types::{AccountId, BlockReference, Finality, FunctionArgs},
-    views::QueryRequest,
+    views::{AccessKeyPermissionView, FinalExecutionStatus, QueryRequest},
 };
 use near_sdk::{Gas, NearToken};
 use serde::de::DeserializeOwned;
 use serde_json::from_slice;
 
+use crate::config::{VERBOSE_DEBUG, VERBOSE_INFO};
+
 #[derive(Clone)]
 pub struct Client {
     client: JsonRpcClient,
-    verbose: bool,
+    verbose: u8,
 }
 
 impl Client {
-    pub fn new(server_addr: &str, verbose: bool) -> Self {
+    pub fn new(server_addr: &str, verbose: u8) -> Self {
         Self {
             client: JsonRpcClient::connect(server_addr),
             verbose,
@@ -32,6 +35,15 @@ impl Client {
         method_name: impl ToString,
         args: impl ToString,
     ) -> R {
+        if self.verbose >= VERBOSE_DEBUG {
+            eprintln!(
+                "\nView call {}.{}({})",
+                account_id,
+                method_name.to_string(),
+                args.to_string()
+            );
+        }
+
         let request = methods::query::RpcQueryRequest {
             block_reference: BlockReference::Finality(Finality::Final),
             request: QueryRequest::CallFunction {
@@ -49,6 +61,36 @@ impl Client {
         }
     }
 
+    // Permission of `public_key` on `account_id`, None if it isn't an access key of the account
+    pub async fn access_key(
+        &self,
+        account_id: AccountId,
+        public_key: PublicKey,
+    ) -> Result<Option<AccessKeyPermissionView>, String> {
+        if self.verbose >= VERBOSE_DEBUG {
+            eprintln!("\nView access key {} of {}", public_key, account_id);
+        }
+
+        let request = methods::query::RpcQueryRequest {
+            block_reference: BlockReference::Finality(Finality::Final),
+            request: QueryRequest::ViewAccessKey {
+                account_id,
+                public_key,
+            },
+        };
+
+        match self.client.call(request).await {
+            Ok(result) => match result.kind {
+                QueryResponseKind::AccessKey(access_key) => Ok(Some(access_key.permission)),
+                _ => unreachable!(),
+            },
+            Err(e) => match e.handler_error() {
+                Some(RpcQueryError::UnknownAccessKey { .. }) => Ok(None),
+                _ => Err(e.to_string()),
+            },
+        }
+    }
+
     pub async fn change_call(
         &self,
         signer: &InMemorySigner,
@@ -58,6 +100,18 @@ impl Client {
         gas: Gas,
         deposit: NearToken,
     ) -> RpcTransactionResponse {
+        if self.verbose >= VERBOSE_DEBUG {
+            eprintln!(
+                "\nChange call {}.{}({}) by {} with {} and {}",
+                contract,
+                method_name.to_string(),
+                args.to_string(),
+                signer.account_id,
+                gas,
+                deposit
+            );
+        }
+
         let access_key_query_response = self
             .client
             .call(near_jsonrpc_client::methods::query::RpcQueryRequest {
@@ -100,7 +154,7 @@ impl Client {
         let sent_at = tokio::time::Instant::now();
         let tx_hash = self.client.call(request).await.unwrap();
 
-        if self.verbose {
+        if self.verbose >= VERBOSE_INFO {
             eprintln!(
                 "\nSubmitted transaction.\nhttps://nearblocks.io/txns/{:?}\n",
                 tx_hash
@@ -139,3 +193,12 @@ impl Client {
         }
     }
 }
+
+// Extract the failure reason of an executed transaction, if it failed
+pub fn transaction_failure(response: &RpcTransactionResponse) -> Option<String> {
+    let outcome = response.final_execution_outcome.clone()?.into_outcome();
+    match outcome.status {
+        FinalExecutionStatus::Failure(err) => Some(err.to_string()),
+        _ => None,
+    }
+}
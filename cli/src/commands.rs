@@ -1,10 +1,27 @@
 use crate::{
-    config::{Channel, Config, ConfigUpdate, SignedState},
-    provider::{Details, Provider},
-    utils::{find_only_channel_id, find_signer},
+    client::Client,
+    config::{
+        archive_closed_channel, channel_file, Channel, ChannelIndex, ChannelLock, Config,
+        ConfigUpdate, SignedState, CLOSE_NONCE, VERBOSE_DETAILS, VERBOSE_INFO,
+    },
+    contract::{
+        Contract, ContractChannel, ContractInfo, CLOSED_CHANNEL_REUSED_ERROR,
+        FORCE_CLOSE_NOT_READY_ERROR, HARD_CLOSE_TIMEOUT,
+    },
+    provider::{Details, Provider, ProviderError},
+    utils::{confirm, find_only_channel_id, find_signer},
 };
 use base64::{prelude::BASE64_STANDARD, Engine};
-use near_sdk::{AccountId, NearToken};
+use near_crypto::PublicKey;
+use near_primitives::views::AccessKeyPermissionView;
+use near_sdk::{json_types::U128, AccountId, NearToken};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CLOSE_CONFIRMATION_ATTEMPTS: u32 = 5;
+const CLOSE_CONFIRMATION_INTERVAL: Duration = Duration::from_secs(2);
+const BENCHMARK_PROMPT: &str = "Hello, my name is";
+// Contract methods providers sign transactions for with their receiver key
+const PROVIDER_METHODS: [&str; 3] = ["withdraw", "close", "withdraw_and_close"];
 
 pub async fn open_payment_channel_command(
     config: &Config,
@@ -28,9 +45,29 @@ pub async fn open_payment_channel_command(
     let channel_id = uuid::Uuid::new_v4().to_string();
 
     let near_contract = config.near_contract();
-    near_contract
-        .open_payment_channel(&channel_id, &details, &sender, amount)
-        .await;
+    let listing_cost = near_contract
+        .contract_info()
+        .await
+        .listing_cost(&details.account_id, &sender.account_id);
+    if let Err(failure) = near_contract
+        .open_payment_channel(
+            &channel_id,
+            &details,
+            &sender,
+            amount.saturating_add(listing_cost),
+        )
+        .await
+    {
+        if failure.contains(CLOSED_CHANNEL_REUSED_ERROR) {
+            eprintln!(
+                "Channel id {} was already used by a closed channel. Run `open` again to generate a new channel id.",
+                channel_id
+            );
+        } else {
+            eprintln!("Failed to open channel: {}", failure);
+        }
+        std::process::exit(1);
+    }
 
     let channel = Channel {
         channel_id,
@@ -41,11 +78,20 @@ pub async fn open_payment_channel_command(
         added_balance: amount,
         withdrawn_balance: NearToken::from_yoctonear(0),
         force_close_started: None,
+        nonce: 0,
+        label: None,
     };
 
     // Save channel information to local storage
     config.update_channel(&channel);
 
+    // Best effort, let the provider cache the channel before the first payment
+    if let Err(e) = provider.announce_channel(&channel.channel_id).await {
+        if config.verbose >= VERBOSE_DETAILS {
+            println!("Provider was not notified of the new channel: {}", e);
+        }
+    }
+
     Ok(())
 }
 
@@ -63,7 +109,7 @@ pub fn config_command(mut config: Config, update: &ConfigUpdate) {
 
 pub async fn info_command(config: &Config, channel_id: Option<String>, update: bool) {
     let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
-    let mut channel = Channel::load(&channel_id, true);
+    let mut channel = Channel::load(&channel_id, config.verbose.max(VERBOSE_DETAILS));
 
     if update {
         // ensure current spent balance is synced with the provider
@@ -72,10 +118,18 @@ pub async fn info_command(config: &Config, channel_id: Option<String>, update: b
         channel.spent_balance = NearToken::from_yoctonear(spent_balance.spent_balance.into());
         channel.save(config.verbose);
 
+        let details = provider.receiver_details().await;
+        if channel.receiver.key_rotated_to(&details) {
+            eprintln!(
+                "\nProvider {} rotated its receiver key, channel {} can only be closed with the previous one. Run `rotate-key {}` to move its balance to a channel with the new key.",
+                details.account_id, channel_id, channel_id
+            );
+        }
+
         let contract = config.near_contract();
         let updated_channel = contract.channel(&channel_id).await;
         if let Some(updated_channel) = updated_channel {
-            if config.verbose {
+            if config.verbose >= VERBOSE_DETAILS {
                 println!(
                     "\nChannel details from the contract:\n{}\n",
                     near_sdk::serde_json::to_string_pretty(&updated_channel).unwrap()
@@ -84,21 +138,13 @@ pub async fn info_command(config: &Config, channel_id: Option<String>, update: b
 
             if updated_channel.is_closed() {
                 eprintln!("Channel {} is closed. Removing it.", channel_id);
-                let source = crate::config::channel_file(&channel_id);
-                let target = crate::config::closed_channel_file(&channel_id);
-                let folder = target.parent().unwrap();
-                if !folder.exists() {
-                    std::fs::create_dir_all(folder).unwrap();
-                }
-
                 // Remove channel from local
-                std::fs::copy(&source, &target).unwrap();
-                std::fs::remove_file(&source).unwrap();
+                archive_closed_channel(&channel_id);
                 std::process::exit(1);
             }
 
             if channel.update_if_newer(updated_channel, config.verbose) {
-                if config.verbose {
+                if config.verbose >= VERBOSE_DETAILS {
                     println!(
                         "\nChannel details:\n{}\n",
                         near_sdk::serde_json::to_string_pretty(&channel.redacted()).unwrap()
@@ -109,64 +155,406 @@ pub async fn info_command(config: &Config, channel_id: Option<String>, update: b
             eprintln!("Channel {} not found", channel_id);
         }
     }
+
+    println!("\nChannel {}:", channel_id);
+    println!("  Added balance:           {}", channel.added_balance);
+    println!("  Spent balance:           {}", channel.spent_balance);
+    println!("  Withdrawn by provider:   {}", channel.withdrawn_balance);
+    println!(
+        "  Outstanding to provider: {}",
+        channel.outstanding_balance()
+    );
+    println!(
+        "  Spendable remaining:     {}",
+        channel.spendable_remaining()
+    );
+    println!(
+        "  Refundable on close:     {}",
+        channel.refundable_on_close()
+    );
 }
 
-pub async fn send_command(config: &Config, amount: NearToken, channel_id: Option<String>) {
+// Show the signed states the provider accepted for the channel with the amount each one
+// charged, and compare the latest one with the spent balance known locally
+pub async fn history_command(config: &Config, channel_id: Option<String>, json: bool) {
     let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
-    let mut channel = Channel::load(&channel_id, config.verbose);
+    let channel = Channel::load(&channel_id, config.verbose);
+    let provider = Provider::new(config.provider_url.clone());
+    let history = match provider.history(&channel_id).await {
+        Ok(history) => history,
+        Err(e) => {
+            eprintln!("Failed to fetch the channel history: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let entries = history
+        .signed_states
+        .iter()
+        .zip(history.charges())
+        .collect::<Vec<_>>();
+    let provider_spent_balance = history.spent_balance();
+    let discrepancy = history.discrepancy(channel.spent_balance);
+
+    if json {
+        let output = serde_json::json!({
+            "channel_id": channel_id,
+            "pruned": history.pruned,
+            "signed_states": entries
+                .iter()
+                .map(|(entry, delta)| serde_json::json!({
+                    "created_at": entry.created_at,
+                    "spent_balance": entry.spent_balance,
+                    "delta": delta.to_string(),
+                }))
+                .collect::<Vec<_>>(),
+            "provider_spent_balance": U128(provider_spent_balance.as_yoctonear()),
+            "local_spent_balance": U128(channel.spent_balance.as_yoctonear()),
+            "discrepancy": discrepancy,
+        });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+        return;
+    }
+
+    println!("\nHistory of channel {}:", channel_id);
+    if entries.is_empty() {
+        println!("  No payments recorded by the provider");
+    }
+    for (entry, delta) in &entries {
+        let charged = if *delta < 0 {
+            format!("-{}", NearToken::from_yoctonear(delta.unsigned_abs()))
+        } else {
+            format!("+{}", NearToken::from_yoctonear(*delta as u128))
+        };
+        println!(
+            "  {}  {:>16}  spent {}",
+            entry.created_at,
+            charged,
+            NearToken::from_yoctonear(entry.spent_balance.0)
+        );
+    }
+    if history.pruned {
+        println!(
+            "  The provider only keeps the latest signed state, earlier payments are not listed"
+        );
+    }
+
+    println!("\n  Spent balance (provider): {}", provider_spent_balance);
+    println!("  Spent balance (local):    {}", channel.spent_balance);
+    match discrepancy {
+        Some(discrepancy) => println!("  Discrepancy: {}", discrepancy),
+        None => println!("  Local and provider spent balances match"),
+    }
+}
+
+pub async fn send_command(
+    config: &Config,
+    amount: NearToken,
+    channel_id: Option<String>,
+    force: bool,
+) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let channel = sign_payment(config, &channel_id, amount, force).await;
+
+    println!("{}", authorization_summary(&channel, amount));
+    println!("\nPayload:\n{}\n", channel.payload_b64());
+}
 
-    // ensure current spent balance is synced with the provider
+// What the payload lets the receiver take, `channel` is already updated with the payment
+pub fn authorization_summary(channel: &Channel, amount: NearToken) -> String {
+    format!(
+        "\nAuthorizing {} to take {} more from channel {}:\n  \
+         Total authorized: {}\n  \
+         Left in channel:  {}",
+        channel.receiver.account_id,
+        amount,
+        channel.channel_id,
+        channel.spent_balance,
+        channel.spendable_remaining()
+    )
+}
+
+// Increase the spent balance of the channel by `amount` and save it,
+// the returned channel signs the payment with `payload`
+async fn sign_payment(
+    config: &Config,
+    channel_id: &str,
+    amount: NearToken,
+    force: bool,
+) -> Channel {
+    // Held until the new balance is saved, concurrent sends on the same channel wait here
+    let _lock = ChannelLock::acquire(channel_id);
+    let mut channel = Channel::load(channel_id, config.verbose);
+    if let Err(e) = reconcile_channel(config, &mut channel, force).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    // ensure current spent balance and nonce are synced with the provider
+    // payloads signed locally but not submitted yet are not known by the provider,
+    // never go below them so two payloads are never signed with the same balance or nonce
     let provider = Provider::new(config.provider_url.clone());
     let spent_balance = provider.spent_balance(&channel_id).await;
-    println!(
-        "Spent balance: {}",
-        NearToken::from_yoctonear(spent_balance.spent_balance.into())
-    );
-    channel.spent_balance = NearToken::from_yoctonear(spent_balance.spent_balance.into());
+    let provider_spent_balance = NearToken::from_yoctonear(spent_balance.spent_balance.into());
+    println!("Spent balance: {}", provider_spent_balance);
+    channel.spent_balance = channel.spent_balance.max(provider_spent_balance);
+    channel.nonce = channel.nonce.max(spent_balance.nonce);
     channel.save(config.verbose);
 
     let new_balance = channel.spent_balance.saturating_add(amount);
     if new_balance > channel.added_balance {
         eprintln!(
             "Amount exceeds the available balance. Current balance: {}, Sending: {}",
-            channel.available_balance(),
+            channel.spendable_remaining(),
             amount
         );
         std::process::exit(1);
     }
 
     channel.spent_balance = new_balance;
+    channel.nonce += 1;
 
-    if config.verbose {
+    if config.verbose >= VERBOSE_DETAILS {
         println!(
             "\nState of the channel signed:\n{}\n",
             serde_json::to_string_pretty(&channel.payload()).unwrap()
         );
     }
 
-    println!("\nPayload:\n{}\n", channel.payload_b64());
+    channel.save(config.verbose);
+    channel
 }
 
-pub async fn withdraw_command(config: &Config, payload: String) {
+// Measure what completions cost on a provider: send sample completions paying `amount`
+// each, and compare what the provider recorded with the usage it reported
+pub async fn benchmark_command(
+    config: &Config,
+    channel_id: Option<String>,
+    model: String,
+    amount: NearToken,
+    requests: u32,
+    max_tokens: u64,
+) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let provider = Provider::new(config.provider_url.clone());
+    let request = serde_json::json!({
+        "model": model,
+        "prompt": BENCHMARK_PROMPT,
+        "max_tokens": max_tokens,
+    });
+
+    let mut charged: u128 = 0;
+    let mut tokens: u64 = 0;
+    for i in 1..=requests {
+        let before = provider.spent_balance(&channel_id).await.spent_balance.0;
+        let channel = sign_payment(config, &channel_id, amount, false).await;
+        let response = match provider.completion(&channel.payload_b64(), &request).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("\nSample request {} failed: {}", i, e);
+                std::process::exit(1);
+            }
+        };
+        let after = provider.spent_balance(&channel_id).await.spent_balance.0;
+
+        let request_charged = after.saturating_sub(before);
+        let request_tokens = response["usage"]["total_tokens"].as_u64().unwrap_or(0);
+        println!(
+            "  Request {}: charged {}, {} tokens",
+            i,
+            NearToken::from_yoctonear(request_charged),
+            request_tokens
+        );
+        charged += request_charged;
+        tokens += request_tokens;
+    }
+
+    println!("\nBenchmark of {} ({} requests):", model, requests);
+    println!(
+        "  Cost per request:    {}",
+        NearToken::from_yoctonear(charged / requests as u128)
+    );
+    if tokens > 0 {
+        println!(
+            "  Cost per 1K tokens:  {}",
+            NearToken::from_yoctonear(charged * 1000 / tokens as u128)
+        );
+    } else {
+        println!("  Cost per 1K tokens:  unknown, the provider didn't report usage");
+    }
+}
+
+// Sync the local channel with the contract before acting on its balances.
+// The contract being ahead is expected (the provider withdrew, a top up landed)
+// and is synced. The local copy being ahead means it diverged in ways a refresh
+// can't explain, the contract balances are only taken with `force`.
+async fn reconcile_channel(
+    config: &Config,
+    channel: &mut Channel,
+    force: bool,
+) -> Result<(), String> {
     let contract = config.near_contract();
-    let raw = BASE64_STANDARD.decode(payload).unwrap();
-    let state: SignedState = near_sdk::borsh::from_slice(&raw).unwrap();
+    let contract_channel = match contract.channel(&channel.channel_id).await {
+        Some(contract_channel) => contract_channel,
+        None => return Err(format!("Channel {} not found", channel.channel_id)),
+    };
 
-    let channel = Channel::load(&state.state.channel_id, config.verbose);
+    reconcile_with_contract(channel, contract_channel, force, config.verbose)
+}
 
-    if config.verbose {
+// Apply what the contract knows about the channel to the local copy, see `reconcile_channel`
+pub fn reconcile_with_contract(
+    channel: &mut Channel,
+    contract_channel: ContractChannel,
+    force: bool,
+    verbose: u8,
+) -> Result<(), String> {
+    if contract_channel.is_closed() {
+        archive_closed_channel(&channel.channel_id);
+        return Err(format!(
+            "Channel {} is closed. Removing it.",
+            channel.channel_id
+        ));
+    }
+
+    if channel.added_balance > contract_channel.added_balance
+        || channel.withdrawn_balance > contract_channel.withdrawn_balance
+    {
+        eprintln!(
+            "Channel {} diverged from the contract. Local added/withdrawn balance: {}/{}, contract: {}/{}",
+            channel.channel_id,
+            channel.added_balance,
+            channel.withdrawn_balance,
+            contract_channel.added_balance,
+            contract_channel.withdrawn_balance
+        );
+        if !force {
+            return Err(
+                "Run again with --force to use the balances from the contract.".to_string(),
+            );
+        }
+        channel.added_balance = contract_channel.added_balance;
+        channel.withdrawn_balance = contract_channel.withdrawn_balance;
+        channel.force_close_started = contract_channel.force_close_started;
+        channel.save(verbose);
+        return Ok(());
+    }
+
+    if channel.update_if_newer(contract_channel, verbose) && verbose >= VERBOSE_INFO {
+        println!("Channel {} synced with the contract.", channel.channel_id);
+    }
+    Ok(())
+}
+
+// Show what a withdraw with the payload would pay out and submit it once confirmed.
+// Only the receiver can submit the withdraw, the contract rejects it from anyone else
+pub async fn withdraw_command(config: &Config, payload: String, force: bool, yes: bool) {
+    let contract = config.near_contract();
+    let state = match decode_payload(&payload) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let channel_id = state.state.channel_id.clone();
+
+    // Only the sender keeps a local copy of the channel
+    if channel_file(&channel_id).exists() {
+        let mut channel = Channel::load(&channel_id, config.verbose);
+        if let Err(e) = reconcile_channel(config, &mut channel, force).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let contract_channel = match contract.channel(&channel_id).await {
+        Some(contract_channel) if contract_channel.is_closed() => {
+            eprintln!("Channel {} is closed", channel_id);
+            std::process::exit(1);
+        }
+        Some(contract_channel) => contract_channel,
+        None => {
+            eprintln!("Channel {} not found", channel_id);
+            std::process::exit(1);
+        }
+    };
+
+    if config.verbose >= VERBOSE_DETAILS {
         println!(
             "\nWithdrawing from the channel:\n{}\n",
             serde_json::to_string_pretty(&state).unwrap()
         );
     }
 
-    if state.state.spent_balance <= channel.withdrawn_balance {
-        eprintln!("Nothing to withdraw");
+    let signed_by_sender = signed_by_sender(&state, &contract_channel.sender.public_key);
+
+    let withdrawable = state
+        .state
+        .spent_balance
+        .saturating_sub(contract_channel.withdrawn_balance);
+
+    println!("\nWithdraw from channel {}:", channel_id);
+    println!(
+        "  Receiver:             {}",
+        contract_channel.receiver.account_id
+    );
+    println!("  Signed spent balance: {}", state.state.spent_balance);
+    println!(
+        "  Already withdrawn:    {}",
+        contract_channel.withdrawn_balance
+    );
+    println!("  Withdrawable:         {}", withdrawable);
+
+    if !signed_by_sender {
+        eprintln!(
+            "\nThe payload is not signed by the sender of channel {}, the contract would reject it.",
+            channel_id
+        );
         std::process::exit(1);
     }
 
-    contract.withdraw(state).await;
+    if state.state.spent_balance > contract_channel.added_balance {
+        eprintln!(
+            "\nThe signed spent balance is above the deposit of {}, the contract would reject it. It can be withdrawn in full after a topup.",
+            contract_channel.added_balance
+        );
+        std::process::exit(1);
+    }
+
+    let state_nonce = contract.state_nonce(&channel_id).await;
+    if !newer_than_accepted(state.state.nonce, state_nonce) {
+        eprintln!(
+            "\nThe payload is older than the last state accepted on channel {} (nonce {} <= {}), the contract would reject it.",
+            channel_id, state.state.nonce, state_nonce
+        );
+        std::process::exit(1);
+    }
+
+    if withdrawable.is_zero() {
+        println!("\nNothing to withdraw.");
+        return;
+    }
+
+    if let Some(account_id) = &config.account_id {
+        if *account_id != contract_channel.receiver.account_id {
+            eprintln!(
+                "\n{} is not the receiver of the channel, only {} can withdraw.",
+                account_id, contract_channel.receiver.account_id
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if !yes && !confirm("Submit the withdraw?") {
+        println!("\nWithdraw cancelled.");
+        return;
+    }
+
+    if let Err(failure) = contract.withdraw(state).await {
+        eprintln!("\nWithdraw failed: {}", failure);
+        std::process::exit(1);
+    }
 }
 
 pub fn close_payload_command(config: &Config, channel_id: Option<String>) {
@@ -179,6 +567,7 @@ pub fn close_payload_command(config: &Config, channel_id: Option<String>) {
     let state = crate::config::State {
         channel_id: channel_id.clone(),
         spent_balance: NearToken::from_near(0),
+        nonce: CLOSE_NONCE,
     };
 
     let raw_state = near_sdk::borsh::to_vec(&state).unwrap();
@@ -187,7 +576,7 @@ pub fn close_payload_command(config: &Config, channel_id: Option<String>) {
         signature: signer.sign(&raw_state),
     };
 
-    if config.verbose {
+    if config.verbose >= VERBOSE_DETAILS {
         println!(
             "\nState of the channel signed:\n{}\n",
             serde_json::to_string_pretty(&signed_state).unwrap()
@@ -205,36 +594,368 @@ pub async fn close_command(config: &Config, channel_id: Option<String>, payload:
     let channel = Channel::load(&channel_id, config.verbose);
 
     let signed_state = if let Some(payload) = payload {
-        let raw = BASE64_STANDARD.decode(payload);
-        near_sdk::borsh::from_slice(&raw.unwrap()).unwrap()
+        decode_signed_state(&payload)
     } else {
-        let send_signer = near_crypto::InMemorySigner::from_secret_key(
-            channel.sender.account_id.clone(),
-            channel.sender_secret_key.clone(),
+        request_close_payload(config, &channel).await
+    };
+    // Same checks as the contract, a payload it would reject isn't submitted
+    let raw_state = near_sdk::borsh::to_vec(&signed_state.state).unwrap();
+    if signed_state.state.channel_id != channel_id
+        || signed_state.state.spent_balance.as_yoctonear() != 0
+        || !signed_state
+            .signature
+            .verify(&raw_state, &channel.receiver.public_key)
+    {
+        eprintln!(
+            "\nThe close payload is not a close state of channel {} signed by the receiver, the contract would reject it.",
+            channel_id
         );
+        std::process::exit(1);
+    }
 
-        let state = crate::config::State {
-            channel_id: channel_id.clone(),
-            spent_balance: NearToken::from_near(0),
-        };
+    let contract = config.near_contract();
+    if let Err(failure) = contract.close(signed_state).await {
+        eprintln!("\nClose failed: {}", failure);
+        std::process::exit(1);
+    }
+    archive_when_closed(&contract, &channel_id).await;
+}
 
-        let raw_state = near_sdk::borsh::to_vec(&state).unwrap();
-        let signed_state = crate::config::SignedState {
-            state,
-            signature: send_signer.sign(&raw_state),
-        };
-        let signed_state_payload =
-            BASE64_STANDARD.encode(&near_sdk::borsh::to_vec(&signed_state).unwrap());
-        let provider = Provider::new(config.provider_url.clone());
-        provider
-            .close_payload(&channel_id, &signed_state_payload)
-            .await
+fn decode_signed_state(payload: &str) -> SignedState {
+    let raw = BASE64_STANDARD.decode(payload);
+    near_sdk::borsh::from_slice(&raw.unwrap()).unwrap()
+}
+
+// Ask the provider for the close payload of a channel, exits if the provider refuses.
+// The provider withdraws what it earned before signing the close payload.
+async fn request_close_payload(config: &Config, channel: &Channel) -> SignedState {
+    let channel_id = channel.channel_id.clone();
+    let send_signer = near_crypto::InMemorySigner::from_secret_key(
+        channel.sender.account_id.clone(),
+        channel.sender_secret_key.clone(),
+    );
+
+    let state = crate::config::State {
+        channel_id: channel_id.clone(),
+        spent_balance: NearToken::from_near(0),
+        nonce: CLOSE_NONCE,
     };
 
+    let raw_state = near_sdk::borsh::to_vec(&state).unwrap();
+    let signed_state = crate::config::SignedState {
+        state,
+        signature: send_signer.sign(&raw_state),
+    };
+    let signed_state_payload =
+        BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&signed_state).unwrap());
+    let provider = Provider::new(config.provider_url.clone());
+    match provider
+        .close_payload(&channel_id, &signed_state_payload)
+        .await
+    {
+        Ok(signed_state) => signed_state,
+        Err(e) => {
+            // Providers close dust channels themselves instead of handing out a payload
+            let contract = config.near_contract();
+            if let Some(contract_channel) = contract.channel(&channel_id).await {
+                if contract_channel.is_closed() {
+                    archive_closed_channel(&channel_id);
+                    println!(
+                        "\nChannel {} was already closed on-chain by the provider.",
+                        channel_id
+                    );
+                    std::process::exit(0);
+                }
+            }
+            eprintln!("\nFailed to get the close payload from the provider: {}", e);
+            if let ProviderError::Unreachable(_) = e {
+                eprintln!("If the provider is offline, use `close --force` instead.");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// Confirm the channel was tombstoned on-chain before removing it locally.
+// The view call reads from final blocks, which may lag the executed transaction.
+async fn archive_when_closed(contract: &Contract, channel_id: &str) {
+    for _ in 0..CLOSE_CONFIRMATION_ATTEMPTS {
+        match contract.channel(channel_id).await {
+            Some(contract_channel) if contract_channel.is_closed() => {
+                archive_closed_channel(channel_id);
+                println!("\nChannel {} closed.", channel_id);
+                return;
+            }
+            _ => tokio::time::sleep(CLOSE_CONFIRMATION_INTERVAL).await,
+        }
+    }
+
+    eprintln!(
+        "\nChannel {} is still open on-chain. The contract may have rejected the close.",
+        channel_id
+    );
+    std::process::exit(1);
+}
+
+// Close a channel cooperatively and open a new one with another provider,
+// funded with the balance refunded by the close. Stops at the first step that
+// fails, the new channel is only opened once the old one is closed on-chain.
+pub async fn move_command(config: &Config, channel_id: Option<String>, provider_url: String) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let channel = Channel::load(&channel_id, config.verbose);
     let contract = config.near_contract();
-    contract.close(signed_state).await;
 
-    println!("\nChannel closed. Use `info` to check the channel was closed locally.")
+    println!(
+        "\n[1/3] Requesting the close payload for channel {}.",
+        channel_id
+    );
+    let signed_state = request_close_payload(config, &channel).await;
+
+    let refund = refund_to_move(&contract, &channel_id).await;
+
+    println!(
+        "\n[2/3] Closing channel {}, {} will be refunded.",
+        channel_id, refund
+    );
+    if let Err(failure) = contract.close(signed_state).await {
+        eprintln!("\nClose failed: {}", failure);
+        std::process::exit(1);
+    }
+    archive_when_closed(&contract, &channel_id).await;
+
+    println!(
+        "\n[3/3] Opening a channel with {} for {}.",
+        provider_url, refund
+    );
+    let mut new_config = config.clone();
+    new_config.provider_url = provider_url;
+    if let Err(e) = open_payment_channel_command(&new_config, refund).await {
+        eprintln!(
+            "\nFailed to open the new channel: {}. The refund of {} is in your account.",
+            e, refund
+        );
+        std::process::exit(1);
+    }
+    println!("\nMoved {} from channel {}.", refund, channel_id);
+}
+
+// Whatever the provider didn't withdraw is refunded to the sender on close.
+// Exits if the channel isn't open or there is nothing to move to a new channel
+async fn refund_to_move(contract: &Contract, channel_id: &str) -> NearToken {
+    let refund = match contract.channel(channel_id).await {
+        Some(contract_channel) if !contract_channel.is_closed() => contract_channel
+            .added_balance
+            .saturating_sub(contract_channel.withdrawn_balance),
+        _ => {
+            eprintln!("\nChannel {} is not open on-chain.", channel_id);
+            std::process::exit(1);
+        }
+    };
+    if refund.is_zero() {
+        eprintln!(
+            "\nChannel {} has no balance left to move. Use `close` instead.",
+            channel_id
+        );
+        std::process::exit(1);
+    }
+    refund
+}
+
+// Whether `signed_state` is a state of `channel` signed with the receiver key the channel
+// was opened with, the only key the contract accepts to close it
+pub fn signed_by_channel_receiver(signed_state: &SignedState, channel: &Channel) -> bool {
+    let raw_state = near_sdk::borsh::to_vec(&signed_state.state).unwrap();
+    signed_state.state.channel_id == channel.channel_id
+        && signed_state
+            .signature
+            .verify(&raw_state, &channel.receiver.public_key)
+}
+
+// Close a channel opened with a receiver key the provider has since rotated, and open
+// a new one with the new key funded with the refund. The close payload has to be signed
+// with the key of the channel, it's checked before it is submitted so a provider signing
+// with its new key doesn't get the channel stuck halfway
+pub async fn rotate_key_command(
+    config: &Config,
+    channel_id: Option<String>,
+    payload: Option<String>,
+) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let channel = Channel::load(&channel_id, config.verbose);
+    let provider = Provider::new(config.provider_url.clone());
+    let details = provider.receiver_details().await;
+
+    if details.account_id != channel.receiver.account_id {
+        eprintln!(
+            "\nProvider at {} is {}, channel {} was opened with {}. Use `move` to change provider.",
+            config.provider_url, details.account_id, channel_id, channel.receiver.account_id
+        );
+        std::process::exit(1);
+    }
+    if !channel.receiver.key_rotated_to(&details) {
+        println!(
+            "\nChannel {} already uses the receiver key of {}, nothing to rotate.",
+            channel_id, details.account_id
+        );
+        return;
+    }
+    println!(
+        "\nProvider {} rotated its receiver key:\n  Key of channel {}: {}\n  New key: {}",
+        details.account_id, channel_id, channel.receiver.public_key, details.public_key
+    );
+
+    println!(
+        "\n[1/3] Getting the close payload of channel {} signed with the previous key.",
+        channel_id
+    );
+    let signed_state = match payload {
+        Some(payload) => decode_signed_state(&payload),
+        None => request_close_payload(config, &channel).await,
+    };
+    if !signed_by_channel_receiver(&signed_state, &channel) {
+        eprintln!(
+            "\nThe close payload is not signed with {}, the contract would reject it.\nAsk the provider for a close payload signed with that key and pass it with `--payload`, or use `close --force` to recover the balance without the provider.",
+            channel.receiver.public_key
+        );
+        std::process::exit(1);
+    }
+
+    let contract = config.near_contract();
+    let refund = refund_to_move(&contract, &channel_id).await;
+
+    println!(
+        "\n[2/3] Closing channel {}, {} will be refunded.",
+        channel_id, refund
+    );
+    if let Err(failure) = contract.close(signed_state).await {
+        eprintln!("\nClose failed: {}", failure);
+        std::process::exit(1);
+    }
+    archive_when_closed(&contract, &channel_id).await;
+
+    println!(
+        "\n[3/3] Opening a channel with the new key of {} for {}.",
+        details.account_id, refund
+    );
+    config.replace_provider(&details);
+    if let Err(e) = open_payment_channel_command(config, refund).await {
+        eprintln!(
+            "\nFailed to open the new channel: {}. The refund of {} is in your account.",
+            e, refund
+        );
+        std::process::exit(1);
+    }
+    println!(
+        "\nMoved {} from channel {} to the new receiver key.",
+        refund, channel_id
+    );
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+fn format_nanos(nanos: u64) -> String {
+    let minutes = nanos / (60 * 1_000_000_000);
+    format!(
+        "{}d {}h {}m",
+        minutes / (24 * 60),
+        (minutes / 60) % 24,
+        minutes % 60
+    )
+}
+
+// Start a force close, doesn't need the provider. The balance can be recovered
+// with `close --force-finish` once the hard close timeout has passed.
+pub async fn force_close_start_command(config: &Config, channel_id: Option<String>) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let mut channel = Channel::load(&channel_id, config.verbose);
+    let contract = config.near_contract();
+
+    if channel.force_close_started.is_none() {
+        if let Err(failure) = contract.force_close_start(&channel_id).await {
+            eprintln!("Failed to start force close: {}", failure);
+            std::process::exit(1);
+        }
+
+        // Track the start time reported by the contract, so finishing knows when it's ready
+        match contract.channel(&channel_id).await {
+            Some(contract_channel) if contract_channel.force_close_started.is_some() => {
+                channel.update_if_newer(contract_channel, config.verbose);
+            }
+            _ => {
+                eprintln!("\nForce close of channel {} was not confirmed on-chain. Run `info` to check its state.", channel_id);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // The timeout of the channel on the contract, the default if it can't be read
+    let unlock_at = force_close_unlock_at(&contract, &channel_id)
+        .await
+        .unwrap_or(channel.force_close_started.unwrap() + HARD_CLOSE_TIMEOUT);
+    println!("\nForce close of channel {} started.", channel_id);
+    println!(
+        "It can be finished in {} with `close --force-finish {}`.\n",
+        format_nanos(unlock_at.saturating_sub(now_nanos())),
+        channel_id
+    );
+}
+
+pub async fn force_close_finish_command(config: &Config, channel_id: Option<String>) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let channel = Channel::load(&channel_id, config.verbose);
+
+    let force_close_started = match channel.force_close_started {
+        Some(force_close_started) => force_close_started,
+        None => {
+            eprintln!(
+                "\nForce close of channel {} was not started. Run `close --force` first.",
+                channel_id
+            );
+            std::process::exit(1);
+        }
+    };
+
+    // Each channel has its own timeout on the contract, the default if it can't be read
+    let contract = config.near_contract();
+    let unlock_at = force_close_unlock_at(&contract, &channel_id)
+        .await
+        .unwrap_or(force_close_started + HARD_CLOSE_TIMEOUT);
+    let now = now_nanos();
+    if now < unlock_at {
+        eprintln!(
+            "\nChannel {} can't be closed yet. Try again in {}.",
+            channel_id,
+            format_nanos(unlock_at - now)
+        );
+        std::process::exit(1);
+    }
+
+    if let Err(failure) = contract.force_close_finish(&channel_id).await {
+        if failure.contains(FORCE_CLOSE_NOT_READY_ERROR) {
+            // The local clock disagrees with the contract
+            eprintln!(
+                "\nChannel {} can't be closed yet. Try again in {}.",
+                channel_id,
+                format_nanos(unlock_at.saturating_sub(now_nanos()))
+            );
+        } else {
+            eprintln!("Failed to finish force close: {}", failure);
+        }
+        std::process::exit(1);
+    }
+    archive_when_closed(&contract, &channel_id).await;
+}
+
+// When the force close started on-chain can be finished, None if it wasn't started
+async fn force_close_unlock_at(contract: &Contract, channel_id: &str) -> Option<u64> {
+    let force_close_started = contract.channel(channel_id).await?.force_close_started?;
+    let timeout = contract.force_close_timeout(channel_id).await;
+    Some(force_close_started + timeout.0)
 }
 
 pub async fn topup_command(config: &Config, channel_id: Option<String>, amount: NearToken) {
@@ -254,3 +975,308 @@ pub async fn topup_command(config: &Config, channel_id: Option<String>, amount:
 
     println!("\nChannel topped up\n");
 }
+
+// See `simulate::simulate`, exits if a step of the flow fails
+#[cfg(feature = "simulate")]
+pub fn simulate_command(deposit: NearToken, price: NearToken, prompts: u32) {
+    match crate::simulate::simulate(deposit, price, prompts) {
+        Ok(simulation) => println!(
+            "\nSimulation completed. Deposited {}, paid {} to the provider, refunded {}.",
+            simulation.deposit, simulation.paid, simulation.refunded
+        ),
+        Err(e) => {
+            eprintln!("\nSimulation failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Rebuild the channel index from the channel files, e.g. after editing or restoring
+// channel files by hand
+pub fn reindex_command() {
+    let (index, skipped) = ChannelIndex::rebuild();
+
+    println!("\nIndexed {} channels", index.channels.len());
+    for (channel_id, summary) in &index.channels {
+        match &summary.label {
+            Some(label) => println!("  {} ({}) with {}", channel_id, label, summary.provider),
+            None => println!("  {} with {}", channel_id, summary.provider),
+        }
+    }
+
+    if !skipped.is_empty() {
+        eprintln!("\nSkipped {} unreadable channel files:", skipped.len());
+        for path in skipped {
+            eprintln!("  {:?}", path);
+        }
+    }
+}
+
+pub fn decode_command(payload: String) {
+    let signed_state = match decode_payload(&payload) {
+        Ok(signed_state) => signed_state,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    println!("\nChannel id:    {}", signed_state.state.channel_id);
+    println!("Spent balance: {}", signed_state.state.spent_balance);
+    println!("Nonce:         {}", signed_state.state.nonce);
+    println!("Signature:     {}", signed_state.signature);
+
+    if signed_state.state.nonce == CLOSE_NONCE {
+        println!("\nThis is a close payload.");
+    }
+}
+
+// Decode a payment or close payload, a base64 borsh serialized `SignedState`. Payloads
+// signed before the nonce existed decode with nonce 0
+pub fn decode_payload(payload: &str) -> Result<SignedState, String> {
+    let raw = BASE64_STANDARD
+        .decode(payload.trim())
+        .map_err(|e| format!("Payload is not valid base64: {}", e))?;
+    near_sdk::borsh::from_slice::<SignedState>(&raw)
+        .or_else(|e| {
+            near_sdk::borsh::from_slice::<ppp_core::LegacySignedState<_>>(&raw)
+                .map(SignedState::from)
+                .map_err(|_| e)
+        })
+        .map_err(|e| format!("Payload is not a borsh serialized SignedState: {}", e))
+}
+
+// Same as the contract: a state with nonce 0 may be signed over the layout without the
+// nonce, see `ppp_core::LegacyState`
+pub fn signed_by_sender(signed_state: &SignedState, sender_public_key: &PublicKey) -> bool {
+    let verify = |message: Vec<u8>| signed_state.signature.verify(&message, sender_public_key);
+    verify(near_sdk::borsh::to_vec(&signed_state.state).unwrap())
+        || (signed_state.state.nonce == 0
+            && verify(
+                near_sdk::borsh::to_vec(&ppp_core::LegacyState::from(&signed_state.state)).unwrap(),
+            ))
+}
+
+// Same as the contract: a state must be newer than the last one accepted on the channel,
+// states with nonce 0 are accepted until the channel accepts one with a nonce
+pub fn newer_than_accepted(nonce: u64, state_nonce: u64) -> bool {
+    nonce > state_nonce || (nonce == 0 && state_nonce == 0)
+}
+
+// Bytes an external signer (HSM, remote service) has to sign to pay `spent_balance`
+// in total on the channel. See `assemble_payload`
+pub fn signable_state(channel_id: String, spent_balance: NearToken, nonce: u64) -> Vec<u8> {
+    let state = crate::config::State {
+        channel_id,
+        spent_balance,
+        nonce,
+    };
+    near_sdk::borsh::to_vec(&state).unwrap()
+}
+
+pub fn signable_state_command(channel_id: Option<String>, spent_balance: NearToken, nonce: u64) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let raw_state = signable_state(channel_id, spent_balance, nonce);
+
+    println!("\nBorsh serialized state to sign:");
+    println!("  base64: {}", BASE64_STANDARD.encode(&raw_state));
+    println!(
+        "  hex:    {}",
+        raw_state
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    );
+}
+
+// Signed state of a state signed externally, after checking the signature against the
+// sender key of the channel
+pub fn assemble_payload(
+    channel: &Channel,
+    spent_balance: NearToken,
+    nonce: u64,
+    signature: &str,
+) -> Result<SignedState, String> {
+    let signature = signature
+        .trim()
+        .parse::<near_crypto::Signature>()
+        .map_err(|e| format!("Invalid signature: {}", e))?;
+    let signed_state = SignedState {
+        state: crate::config::State {
+            channel_id: channel.channel_id.clone(),
+            spent_balance,
+            nonce,
+        },
+        signature,
+    };
+
+    let raw_state = near_sdk::borsh::to_vec(&signed_state.state).unwrap();
+    if !signed_state
+        .signature
+        .verify(&raw_state, &channel.sender.public_key)
+    {
+        return Err(format!(
+            "Signature doesn't match the sender key of the channel {}",
+            channel.sender.public_key
+        ));
+    }
+    Ok(signed_state)
+}
+
+// Print the payload of a state signed externally, see `assemble_payload`
+pub fn assemble_payload_command(
+    config: &Config,
+    channel_id: Option<String>,
+    spent_balance: NearToken,
+    nonce: u64,
+    signature: String,
+) {
+    let channel_id = channel_id.unwrap_or_else(find_only_channel_id);
+    let channel = Channel::load(&channel_id, config.verbose);
+
+    let signed_state = match assemble_payload(&channel, spent_balance, nonce, &signature) {
+        Ok(signed_state) => signed_state,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if config.verbose >= VERBOSE_DETAILS {
+        println!(
+            "\nState of the channel signed:\n{}\n",
+            serde_json::to_string_pretty(&signed_state).unwrap()
+        );
+    }
+
+    println!(
+        "\nPayload:\n{}\n",
+        BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&signed_state).unwrap())
+    );
+}
+
+// Check that the key the provider advertises in `/info` is an access key of its account,
+// otherwise it can't sign the withdraw and close transactions of its channels
+pub async fn verify_provider_command(config: &Config) {
+    let provider = Provider::new(config.provider_url.clone());
+    let details = provider.receiver_details().await;
+    println!(
+        "\nProvider {} receives payments on {} with key {}",
+        config.provider_url, details.account_id, details.public_key
+    );
+
+    let client = Client::new(&config.near_rpc_url, config.verbose);
+    let permission = match client
+        .access_key(details.account_id.clone(), details.public_key.clone())
+        .await
+    {
+        Ok(permission) => permission,
+        Err(e) => {
+            eprintln!("Failed to fetch the access key: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match check_provider_key(
+        permission,
+        &details.account_id,
+        &details.public_key,
+        &config.contract,
+    ) {
+        Ok(message) => println!("  {}", message),
+        Err(warning) => {
+            eprintln!("Warning: {}", warning);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Whether a provider can sign for its channels on `contract` with the key `public_key` of
+// `account_id`, given the key's permission (None if it isn't an access key of the account)
+pub fn check_provider_key(
+    permission: Option<AccessKeyPermissionView>,
+    account_id: &AccountId,
+    public_key: &PublicKey,
+    contract: &AccountId,
+) -> Result<String, String> {
+    match permission {
+        Some(AccessKeyPermissionView::FullAccess) => {
+            Ok(format!("Full access key of {}", account_id))
+        }
+        Some(AccessKeyPermissionView::FunctionCall {
+            receiver_id,
+            method_names,
+            ..
+        }) => {
+            if receiver_id != contract.as_str() {
+                return Err(format!(
+                    "the key can't call the payment channel contract {}, only {}",
+                    contract, receiver_id
+                ));
+            }
+            // An empty list allows every method of the receiver
+            let missing = PROVIDER_METHODS
+                .into_iter()
+                .filter(|method| {
+                    !method_names.is_empty()
+                        && !method_names.iter().any(|name| name.as_str() == *method)
+                })
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                return Err(format!(
+                    "the key can't call {:?} on the payment channel contract",
+                    missing
+                ));
+            }
+            Ok(format!(
+                "Function call key of {} for {}",
+                account_id, receiver_id
+            ))
+        }
+        None => Err(format!(
+            "{} is not an access key of {}, the provider can't sign transactions with it",
+            public_key, account_id
+        )),
+    }
+}
+
+pub async fn requirements_command(config: &Config) {
+    let contract = config.near_contract();
+    let ContractInfo {
+        hard_close_timeout,
+        storage_byte_cost,
+        channel_storage_cost,
+        account_channel_storage_cost,
+        recommended_gas,
+    } = contract.contract_info().await;
+
+    println!("\nContract: {}", config.contract);
+    println!("Storage cost per byte:    {}", storage_byte_cost);
+    println!("Storage cost per channel: {}", channel_storage_cost);
+    println!(
+        "Listing cost per account: {} (paid on open for the sender and the receiver, refunded on close)",
+        account_channel_storage_cost
+    );
+    println!(
+        "Force close timeout:      {} hours",
+        hard_close_timeout.0 / (60 * 60 * 1_000_000_000)
+    );
+
+    println!("\nRecommended gas per operation:");
+    println!("  open_channel:       {}", recommended_gas.open_channel);
+    println!("  topup:              {}", recommended_gas.topup);
+    println!("  withdraw:           {}", recommended_gas.withdraw);
+    println!("  close:              {}", recommended_gas.close);
+    println!(
+        "  withdraw_and_close: {}",
+        recommended_gas.withdraw_and_close
+    );
+    println!(
+        "  force_close_start:  {}",
+        recommended_gas.force_close_start
+    );
+    println!(
+        "  force_close_finish: {}\n",
+        recommended_gas.force_close_finish
+    );
+}
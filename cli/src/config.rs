@@ -1,8 +1,10 @@
 use base64::{prelude::BASE64_STANDARD, Engine};
 use clap::Parser;
-use near_sdk::{near, AccountId, NearToken};
+use near_sdk::{AccountId, NearToken};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use crate::{
     contract::{Contract, ContractChannel},
@@ -16,6 +18,14 @@ pub fn data_storage() -> PathBuf {
         .join("near_payment_channel")
 }
 
+// Verbosity levels of `-v`, each level includes the output of the previous ones
+// Saved files and submitted transactions
+pub const VERBOSE_INFO: u8 = 1;
+// Channel details and signed states
+pub const VERBOSE_DETAILS: u8 = 2;
+// Config file and RPC requests
+pub const VERBOSE_DEBUG: u8 = 3;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
     // Account id of the payment channel contract
@@ -26,9 +36,9 @@ pub struct Config {
     pub near_rpc_url: String,
     // Account id of the user
     pub account_id: Option<AccountId>,
-    // Verbose mode
+    // Verbosity level, 0 is quiet
     #[serde(default, skip)]
-    pub verbose: bool,
+    pub verbose: u8,
     // Path to the config file
     #[serde(skip)]
     pub config_file: PathBuf,
@@ -46,7 +56,7 @@ impl Default for Config {
             contract: "paymentchannel.near".to_string().parse().unwrap(),
             provider_url: "https://payperprompt.near.ai".to_string(),
             near_rpc_url: "https://archival-rpc.mainnet.near.org/".to_string(),
-            verbose: true,
+            verbose: VERBOSE_INFO,
             account_id: None,
             config_file: PathBuf::new(),
         }
@@ -54,9 +64,9 @@ impl Default for Config {
 }
 
 impl Config {
-    pub fn load(config_file: PathBuf, verbose: bool) -> Self {
+    pub fn load(config_file: PathBuf, verbose: u8) -> Self {
         if !config_file.exists() {
-            if verbose {
+            if verbose >= VERBOSE_INFO {
                 println!(
                     "Config file not found, creating a new one at {:?}\n",
                     config_file
@@ -76,7 +86,7 @@ impl Config {
 
         // Read config from file
         let config = std::fs::read_to_string(&config_file).unwrap();
-        if verbose {
+        if verbose >= VERBOSE_DEBUG {
             println!("\nConfig file:\n{}\n", config);
         }
 
@@ -102,15 +112,18 @@ impl Config {
     }
 
     pub fn update_provider(&self, details: &Details) {
-        let providers = data_storage().join("providers");
-        if !providers.exists() {
-            std::fs::create_dir_all(&providers).unwrap();
-        }
-        let provider_file = providers.join(format!("{}.json", &details.account_id));
+        let provider_file = provider_file(&details.account_id);
 
         if provider_file.exists() {
             let prev_details = std::fs::read_to_string(&provider_file).unwrap();
             let prev_details = serde_json::from_str::<Details>(&prev_details).unwrap();
+            if prev_details.key_rotated_to(details) {
+                eprintln!(
+                    "Provider {} rotated its receiver key from {} to {}.\nRun `rotate-key` to close the channels opened with the previous key and reopen them with the new one.",
+                    details.account_id, prev_details.public_key, details.public_key
+                );
+                std::process::exit(1);
+            }
             if prev_details != *details {
                 eprintln!(
                     "Provider details already exist and are different. {:?}.\nRemove the provider and make sure no active open channels exist with this provider.",
@@ -119,12 +132,22 @@ impl Config {
                 std::process::exit(1);
             }
         } else {
-            let details = serde_json::to_string_pretty(&details).unwrap();
-            std::fs::write(&provider_file, details).unwrap();
+            self.save_provider(&provider_file, details);
+        }
+    }
 
-            if self.verbose {
-                println!("Provider information saved to {:?}", provider_file);
-            }
+    // Overwrite the saved details of a provider that rotated its receiver key. Channels
+    // keep the receiver details they were opened with
+    pub fn replace_provider(&self, details: &Details) {
+        self.save_provider(&provider_file(&details.account_id), details);
+    }
+
+    fn save_provider(&self, provider_file: &Path, details: &Details) {
+        let details = serde_json::to_string_pretty(&details).unwrap();
+        std::fs::write(provider_file, details).unwrap();
+
+        if self.verbose >= VERBOSE_INFO {
+            println!("Provider information saved to {:?}", provider_file);
         }
     }
 
@@ -137,19 +160,9 @@ impl Config {
     }
 }
 
-#[near(serializers = [borsh, json])]
-#[derive(Debug)]
-pub struct State {
-    pub channel_id: String,
-    pub spent_balance: NearToken,
-}
-
-#[near(serializers = [borsh, json])]
-#[derive(Debug)]
-pub struct SignedState {
-    pub state: State,
-    pub signature: near_crypto::Signature,
-}
+// Shared with the contract, so the states signed here verify on-chain
+pub use ppp_core::{State, CLOSE_NONCE};
+pub type SignedState = ppp_core::SignedState<near_crypto::Signature>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Channel {
@@ -161,6 +174,20 @@ pub struct Channel {
     pub added_balance: NearToken,
     pub withdrawn_balance: NearToken,
     pub force_close_started: Option<near_sdk::Timestamp>,
+    // Nonce of the last state signed, the next state must have a higher one
+    #[serde(default)]
+    pub nonce: u64,
+    // Name shown when listing channels, set by editing the channel file
+    #[serde(default)]
+    pub label: Option<String>,
+}
+
+pub fn provider_file(account_id: &AccountId) -> PathBuf {
+    let providers = data_storage().join("providers");
+    if !providers.exists() {
+        std::fs::create_dir_all(&providers).unwrap();
+    }
+    providers.join(format!("{}.json", account_id))
 }
 
 pub fn channel_file(channel_id: &str) -> PathBuf {
@@ -175,13 +202,210 @@ pub fn closed_channel_file(channel_id: &str) -> PathBuf {
         .join(format!("{}.json", channel_id))
 }
 
+// Kept in the channels folder next to the channel files, it isn't a channel
+const CHANNEL_INDEX_NAME: &str = "index";
+
+pub fn channel_index_file() -> PathBuf {
+    channel_file(CHANNEL_INDEX_NAME)
+}
+
+pub fn channel_lock_file(channel_id: &str) -> PathBuf {
+    data_storage()
+        .join("channels")
+        .join(format!("{}.lock", channel_id))
+}
+
+// How long to wait for another command holding the channel lock
+const CHANNEL_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const CHANNEL_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+// Exclusive lock on a channel held while reading, updating and saving it,
+// so concurrent commands can't overwrite each other's balance.
+// An advisory lock of the OS on the lock file, released when dropped or when the
+// command exits, even if it's killed. The lock file is left in place.
+pub struct ChannelLock {
+    _file: std::fs::File,
+}
+
+impl ChannelLock {
+    pub fn acquire(channel_id: &str) -> Self {
+        let path = channel_lock_file(channel_id);
+        let folder = path.parent().unwrap();
+        if !folder.exists() {
+            std::fs::create_dir_all(folder).unwrap();
+        }
+
+        let file = match std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+        {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Unable to lock channel {}: {}", channel_id, e);
+                std::process::exit(1);
+            }
+        };
+
+        let started = Instant::now();
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Self { _file: file },
+                Err(std::fs::TryLockError::WouldBlock) => (),
+                Err(std::fs::TryLockError::Error(e)) => {
+                    eprintln!("Unable to lock channel {}: {}", channel_id, e);
+                    std::process::exit(1);
+                }
+            }
+
+            if started.elapsed() > CHANNEL_LOCK_TIMEOUT {
+                eprintln!(
+                    "Channel {} is locked by another command, try again once it's done",
+                    channel_id
+                );
+                std::process::exit(1);
+            }
+            std::thread::sleep(CHANNEL_LOCK_RETRY_INTERVAL);
+        }
+    }
+}
+
+// Move a channel that was closed on-chain from the channels folder to the closed channels folder
+pub fn archive_closed_channel(channel_id: &str) {
+    let source = channel_file(channel_id);
+    let target = closed_channel_file(channel_id);
+    let folder = target.parent().unwrap();
+    if !folder.exists() {
+        std::fs::create_dir_all(folder).unwrap();
+    }
+
+    std::fs::copy(&source, &target).unwrap();
+    std::fs::remove_file(&source).unwrap();
+
+    ChannelIndex::update(|index| {
+        index.channels.remove(channel_id);
+    });
+}
+
+// What the index keeps of each channel, enough to list channels without reading
+// their files. The channel files stay the source of truth (and the only place the
+// sender keys are stored)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelSummary {
+    pub provider: AccountId,
+    pub added_balance: NearToken,
+    pub spent_balance: NearToken,
+    pub withdrawn_balance: NearToken,
+    pub label: Option<String>,
+}
+
+impl From<&Channel> for ChannelSummary {
+    fn from(channel: &Channel) -> Self {
+        Self {
+            provider: channel.receiver.account_id.clone(),
+            added_balance: channel.added_balance,
+            spent_balance: channel.spent_balance,
+            withdrawn_balance: channel.withdrawn_balance,
+            label: channel.label.clone(),
+        }
+    }
+}
+
+// Summary of every open channel, kept in `channels/index.json` and updated each time
+// a channel is saved or archived
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelIndex {
+    pub channels: BTreeMap<String, ChannelSummary>,
+}
+
+impl ChannelIndex {
+    // Read the index, it is rebuilt from the channel files if it is missing or corrupt
+    pub fn load() -> Self {
+        match Self::read() {
+            Some(index) => index,
+            None => Self::rebuild().0,
+        }
+    }
+
+    // Build the index from the channel files and save it. Returns the files that
+    // couldn't be read, they are left out of the index
+    pub fn rebuild() -> (Self, Vec<PathBuf>) {
+        let _lock = ChannelLock::acquire(CHANNEL_INDEX_NAME);
+        let (index, skipped) = Self::scan();
+        index.save();
+        (index, skipped)
+    }
+
+    // Apply `change` to the index on disk, holding the index lock so concurrent
+    // commands (on different channels) don't drop each other's updates
+    fn update(change: impl FnOnce(&mut Self)) {
+        let _lock = ChannelLock::acquire(CHANNEL_INDEX_NAME);
+        let mut index = Self::read().unwrap_or_else(|| Self::scan().0);
+        change(&mut index);
+        index.save();
+    }
+
+    fn read() -> Option<Self> {
+        let index = std::fs::read_to_string(channel_index_file()).ok()?;
+        serde_json::from_str(&index).ok()
+    }
+
+    fn scan() -> (Self, Vec<PathBuf>) {
+        let mut index = Self::default();
+        let mut skipped = vec![];
+
+        let channels = data_storage().join("channels");
+        if !channels.exists() {
+            return (index, skipped);
+        }
+
+        for entry in std::fs::read_dir(&channels).unwrap() {
+            let path = entry.unwrap().path();
+            if !path.is_file()
+                || path.extension() != Some("json".as_ref())
+                || path == channel_index_file()
+            {
+                continue;
+            }
+
+            let channel = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|channel| serde_json::from_str::<Channel>(&channel).ok());
+            match channel {
+                Some(channel) => {
+                    index
+                        .channels
+                        .insert(channel.channel_id.clone(), (&channel).into());
+                }
+                None => skipped.push(path),
+            }
+        }
+
+        (index, skipped)
+    }
+
+    fn save(&self) {
+        let index_file = channel_index_file();
+        let folder = index_file.parent().unwrap();
+        if !folder.exists() {
+            std::fs::create_dir_all(folder).unwrap();
+        }
+
+        // Written to a temporary file first, a reader never sees a partial index
+        let tmp_file = index_file.with_extension("json.tmp");
+        std::fs::write(&tmp_file, serde_json::to_string_pretty(self).unwrap()).unwrap();
+        std::fs::rename(&tmp_file, &index_file).unwrap();
+    }
+}
+
 impl Channel {
-    pub fn load(channel_id: &str, verbose: bool) -> Self {
+    pub fn load(channel_id: &str, verbose: u8) -> Self {
         let channel_file = channel_file(&channel_id);
         let channel = std::fs::read_to_string(&channel_file).unwrap();
 
         let channel: Channel = serde_json::from_str(&channel).unwrap();
-        if verbose {
+        if verbose >= VERBOSE_DETAILS {
             println!(
                 "\nChannel details:\n{}\n",
                 near_sdk::serde_json::to_string_pretty(&channel.redacted()).unwrap()
@@ -190,7 +414,7 @@ impl Channel {
         channel
     }
 
-    pub fn save(&self, verbose: bool) {
+    pub fn save(&self, verbose: u8) {
         let channels = data_storage().join("channels");
         if !channels.exists() {
             std::fs::create_dir_all(&channels).unwrap();
@@ -201,19 +425,38 @@ impl Channel {
         let channel = serde_json::to_string_pretty(self).unwrap();
         std::fs::write(&channel_file, channel).unwrap();
 
-        if verbose {
+        let summary = ChannelSummary::from(self);
+        ChannelIndex::update(|index| {
+            index.channels.insert(self.channel_id.clone(), summary);
+        });
+
+        if verbose >= VERBOSE_INFO {
             println!("\nChannel information saved to:\n{:?}\n", channel_file);
         }
     }
 
-    pub fn available_balance(&self) -> NearToken {
+    // How much more can be spent on the channel
+    pub fn spendable_remaining(&self) -> NearToken {
         self.added_balance.saturating_sub(self.spent_balance)
     }
 
+    // Refunded to the sender if the channel is closed now, the contract only keeps what the
+    // provider already withdrew. Same as `spendable_remaining` once the provider withdrew
+    // everything it's owed
+    pub fn refundable_on_close(&self) -> NearToken {
+        self.added_balance.saturating_sub(self.withdrawn_balance)
+    }
+
+    // Amount the receiver is owed but hasn't withdrawn from the contract yet
+    pub fn outstanding_balance(&self) -> NearToken {
+        self.spent_balance.saturating_sub(self.withdrawn_balance)
+    }
+
     pub fn info(&self) -> State {
         State {
             channel_id: self.channel_id.clone(),
             spent_balance: self.spent_balance,
+            nonce: self.nonce,
         }
     }
 
@@ -267,7 +510,7 @@ impl Channel {
         false
     }
 
-    pub fn update_if_newer(&mut self, contract_channel: ContractChannel, verbose: bool) -> bool {
+    pub fn update_if_newer(&mut self, contract_channel: ContractChannel, verbose: u8) -> bool {
         if self.newer(&contract_channel) {
             self.added_balance = contract_channel.added_balance;
             self.withdrawn_balance = contract_channel.withdrawn_balance;
@@ -1,14 +1,22 @@
 use crate::{
-    client::Client,
+    client::{transaction_failure, Client},
     config::{Config, SignedState},
     provider::Details,
     utils::find_signer,
 };
 use near_crypto::{InMemorySigner, PublicKey};
 use near_primitives::types::AccountId;
-use near_sdk::{near, Gas, NearToken, Timestamp};
+use near_sdk::{json_types::U64, near, Gas, NearToken, Timestamp};
 use serde_json::json;
 
+// Copied from the contract code
+pub const CLOSED_CHANNEL_REUSED_ERROR: &str = "Channel id belongs to a closed channel";
+pub const FORCE_CLOSE_NOT_READY_ERROR: &str = "Not enough time has passed";
+pub const HARD_CLOSE_TIMEOUT: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+pub const MAX_CHANNELS_PER_VIEW: usize = 100;
+pub const CLOSED_CHANNEL_ACCOUNT_ID: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
 #[near(serializers = [json])]
 #[derive(Clone, Debug)]
 pub struct ContractAccount {
@@ -17,7 +25,7 @@ pub struct ContractAccount {
 }
 
 #[near(serializers = [json])]
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ContractChannel {
     pub receiver: ContractAccount,
     pub sender: ContractAccount,
@@ -26,13 +34,45 @@ pub struct ContractChannel {
     pub force_close_started: Option<Timestamp>,
 }
 
+#[near(serializers = [json])]
+#[derive(Debug)]
+pub struct ContractOperationGas {
+    pub open_channel: Gas,
+    pub withdraw: Gas,
+    pub topup: Gas,
+    pub close: Gas,
+    pub withdraw_and_close: Gas,
+    pub force_close_start: Gas,
+    pub force_close_finish: Gas,
+}
+
+#[near(serializers = [json])]
+#[derive(Debug)]
+pub struct ContractInfo {
+    pub hard_close_timeout: U64,
+    pub storage_byte_cost: NearToken,
+    pub channel_storage_cost: NearToken,
+    // Contracts deployed before `channels_for_account` don't report it, listing is free there
+    #[serde(default)]
+    pub account_channel_storage_cost: NearToken,
+    pub recommended_gas: ContractOperationGas,
+}
+
+impl ContractInfo {
+    // Paid on top of the balance when opening a channel, for listing it under the sender
+    // and the receiver by `channels_for_account`
+    pub fn listing_cost(&self, receiver: &AccountId, sender: &AccountId) -> NearToken {
+        let listed_accounts = if receiver == sender { 1 } else { 2 };
+        self.account_channel_storage_cost
+            .saturating_mul(listed_accounts)
+    }
+}
+
 impl ContractChannel {
+    // Same definition as the contract: closed channels are replaced by a tombstone
+    // with the closed account id as sender
     pub fn is_closed(&self) -> bool {
-        self.added_balance.is_zero()
-            && self.sender.account_id
-                == "0000000000000000000000000000000000000000000000000000000000000000"
-                    .parse::<AccountId>()
-                    .unwrap()
+        self.sender.account_id.as_str() == CLOSED_CHANNEL_ACCOUNT_ID
     }
 }
 
@@ -66,8 +106,9 @@ impl Contract {
         receiver: &Details,
         sender: &Details,
         amount: NearToken,
-    ) {
-        self.client
+    ) -> Result<(), String> {
+        let response = self
+            .client
             .change_call(
                 &self.signer,
                 self.contract.clone(),
@@ -82,10 +123,16 @@ impl Contract {
                 amount,
             )
             .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
     }
 
-    pub async fn withdraw(&self, state: SignedState) {
-        self.client
+    pub async fn withdraw(&self, state: SignedState) -> Result<(), String> {
+        let response = self
+            .client
             .change_call(
                 &self.signer,
                 self.contract.clone(),
@@ -96,6 +143,11 @@ impl Contract {
                 NearToken::from_yoctonear(0),
             )
             .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
     }
 
     pub async fn channel(&self, channel_id: &str) -> Option<ContractChannel> {
@@ -108,8 +160,48 @@ impl Contract {
             .await
     }
 
-    pub async fn close(&self, state: SignedState) {
+    // Read several channels in one view call, at most `MAX_CHANNELS_PER_VIEW`
+    pub async fn channels(&self, channel_ids: &[String]) -> Vec<Option<ContractChannel>> {
+        self.client
+            .view_call(
+                self.contract.clone(),
+                "channels",
+                json!({"channel_ids": channel_ids}),
+            )
+            .await
+    }
+
+    // Nonce of the last signed state the contract accepted on the channel
+    pub async fn state_nonce(&self, channel_id: &str) -> u64 {
+        self.client
+            .view_call(
+                self.contract.clone(),
+                "state_nonce",
+                json!({"channel_id": channel_id}),
+            )
+            .await
+    }
+
+    // How long a force close of the channel takes to finish, in nanoseconds
+    pub async fn force_close_timeout(&self, channel_id: &str) -> U64 {
+        self.client
+            .view_call(
+                self.contract.clone(),
+                "force_close_timeout",
+                json!({"channel_id": channel_id}),
+            )
+            .await
+    }
+
+    pub async fn contract_info(&self) -> ContractInfo {
         self.client
+            .view_call(self.contract.clone(), "contract_info", json!({}))
+            .await
+    }
+
+    pub async fn close(&self, state: SignedState) -> Result<(), String> {
+        let response = self
+            .client
             .change_call(
                 &self.signer,
                 self.contract.clone(),
@@ -120,10 +212,20 @@ impl Contract {
                 NearToken::from_yoctonear(0),
             )
             .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
     }
 
-    pub async fn withdraw_and_close(&self, state: SignedState, close: SignedState) {
-        self.client
+    pub async fn withdraw_and_close(
+        &self,
+        state: SignedState,
+        close: SignedState,
+    ) -> Result<(), String> {
+        let response = self
+            .client
             .change_call(
                 &self.signer,
                 self.contract.clone(),
@@ -133,6 +235,49 @@ impl Contract {
                 NearToken::from_yoctonear(0),
             )
             .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn force_close_start(&self, channel_id: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .change_call(
+                &self.signer,
+                self.contract.clone(),
+                "force_close_start",
+                json!({"channel_id": channel_id}),
+                Gas::from_tgas(15),
+                NearToken::from_yoctonear(0),
+            )
+            .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn force_close_finish(&self, channel_id: &str) -> Result<(), String> {
+        let response = self
+            .client
+            .change_call(
+                &self.signer,
+                self.contract.clone(),
+                "force_close_finish",
+                json!({"channel_id": channel_id}),
+                Gas::from_tgas(15),
+                NearToken::from_yoctonear(0),
+            )
+            .await;
+
+        match transaction_failure(&response) {
+            Some(failure) => Err(failure),
+            None => Ok(()),
+        }
     }
 
     pub async fn topup(&self, channel_id: &str, amount: NearToken) {
@@ -3,4 +3,6 @@ pub mod commands;
 pub mod config;
 pub mod contract;
 pub mod provider;
+#[cfg(feature = "simulate")]
+pub mod simulate;
 pub mod utils;
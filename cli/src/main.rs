@@ -1,7 +1,10 @@
 use clap::Parser;
 use cli::commands::{
-    close_command, close_payload_command, config_command, info_command,
-    open_payment_channel_command, send_command, topup_command, withdraw_command,
+    assemble_payload_command, benchmark_command, close_command, close_payload_command,
+    config_command, decode_command, force_close_finish_command, force_close_start_command,
+    history_command, info_command, move_command, open_payment_channel_command, reindex_command,
+    requirements_command, rotate_key_command, send_command, signable_state_command, topup_command,
+    verify_provider_command, withdraw_command,
 };
 use cli::config::{data_storage, Config, ConfigUpdate};
 use near_sdk::NearToken;
@@ -25,6 +28,30 @@ enum Commands {
         channel_id: Option<String>,
         /// Manual payload to close the channel, if not specified we
         /// ask the provider to generate it.
+        #[arg(short, long, conflicts_with_all = ["force", "force_finish"])]
+        payload: Option<String>,
+        /// Start a force close without the provider. The balance can be
+        /// recovered with `--force-finish` after the close timeout (7 days).
+        #[arg(long, conflicts_with = "force_finish")]
+        force: bool,
+        /// Finish a force close started with `--force`.
+        #[arg(long)]
+        force_finish: bool,
+    },
+    /// Close a payment channel and open a new one with another provider,
+    /// using the refunded balance.
+    Move {
+        channel_id: Option<String>,
+        /// Url of the provider to open the new channel with.
+        #[arg(long)]
+        provider_url: String,
+    },
+    /// Close a channel opened with a receiver key the provider has rotated,
+    /// and open a new one with its new key using the refunded balance.
+    RotateKey {
+        channel_id: Option<String>,
+        /// Close payload signed with the previous key, if not specified we
+        /// ask the provider to generate it.
         #[arg(short, long)]
         payload: Option<String>,
     },
@@ -34,6 +61,51 @@ enum Commands {
         #[arg(short, long)]
         no_update: bool,
     },
+    /// Show the payments the provider recorded for a channel.
+    History {
+        channel_id: Option<String>,
+        /// Print the history as json.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Measure the cost of completions by sending sample requests to the provider.
+    Benchmark {
+        channel_id: Option<String>,
+        /// Model to request, as `provider::model`.
+        #[arg(short, long)]
+        model: String,
+        /// Amount paid for each request.
+        #[arg(short, long)]
+        amount: NearToken,
+        /// Number of sample requests to average over.
+        #[arg(short, long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        requests: u32,
+        /// `max_tokens` of each sample request.
+        #[arg(long, default_value_t = 16)]
+        max_tokens: u64,
+    },
+    /// Show the contract requirements (storage cost, recommended gas).
+    Requirements,
+    /// Check the key advertised by the provider is an access key of its account.
+    VerifyProvider,
+    /// Rebuild the channel index from the channel files. (Off-chain)
+    Reindex,
+    /// Decode a base64 payload (signed state or close payload). (Off-chain)
+    Decode { payload: String },
+    /// Run open, send, prompt and close against the contract and a mock provider
+    /// in-process, printing every payload. Nothing is sent to the network. (Off-chain)
+    #[cfg(feature = "simulate")]
+    Simulate {
+        /// Amount deposited in the simulated channel.
+        #[arg(short, long, default_value = "1 NEAR")]
+        deposit: NearToken,
+        /// Amount paid for each prompt.
+        #[arg(short, long, default_value = "0.01 NEAR")]
+        price: NearToken,
+        /// Number of prompts sent before closing the channel.
+        #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+        prompts: u32,
+    },
     /// Show and update configuration.
     #[command(subcommand)]
     Config(ConfigUpdate),
@@ -45,30 +117,63 @@ enum Commands {
 #[derive(Parser, Clone)]
 enum AdvancedCommands {
     /// Withdraw balance, run this command from the point of view of the receiver.
+    /// Shows the amount to withdraw and asks for confirmation first.
     Withdraw {
         /// Signed state created by the sender encoded in base64
         payload: String,
+        /// Use the contract balances even if the local channel is ahead of them.
+        #[arg(long)]
+        force: bool,
+        /// Submit the withdraw without asking for confirmation.
+        #[arg(short, long)]
+        yes: bool,
     },
     /// Receiver generates the closing payload.
     ClosePayload { channel_id: Option<String> },
-    /// Start a force close of a payment channel.
-    StartForceClose,
-    /// Finish a force close of a payment channel.
-    FinishForceClose,
+    /// Start a force close of a payment channel. Same as `close --force`.
+    StartForceClose { channel_id: Option<String> },
+    /// Finish a force close of a payment channel. Same as `close --force-finish`.
+    FinishForceClose { channel_id: Option<String> },
+    /// Print the borsh serialized state to sign with an external signer. (Off-chain)
+    SignableState {
+        channel_id: Option<String>,
+        /// Total spent balance of the state, not the amount of the payment.
+        #[arg(short, long)]
+        spent_balance: NearToken,
+        /// Nonce of the state, above the nonce of every state signed before on the channel.
+        #[arg(short, long)]
+        nonce: u64,
+    },
+    /// Build a payload from a state signed with an external signer. (Off-chain)
+    AssemblePayload {
+        channel_id: Option<String>,
+        /// Total spent balance of the signed state.
+        #[arg(short, long)]
+        spent_balance: NearToken,
+        /// Nonce of the signed state.
+        #[arg(short, long)]
+        nonce: u64,
+        /// Signature of the state, e.g. `ed25519:...`.
+        #[arg(long)]
+        signature: String,
+    },
     /// Sign transaction to send money to the receiver. (Off-chain)
     Send {
         /// How much money to send.
         amount: NearToken,
         /// Id of the channel. If it is not specified we look if there is only one channel and use it.
         channel_id: Option<String>,
+        /// Use the contract balances even if the local channel is ahead of them.
+        #[arg(long)]
+        force: bool,
     },
 }
 
 #[derive(Parser)]
 struct CLI {
-    /// Verbose mode.
-    #[arg(short, long)]
-    verbose: bool,
+    /// Verbose mode, repeat for more output (-v, -vv, -vvv).
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
     /// Path to the config file. Default is <CONFIG_DIR>/.near_payment_channel/config.json
     #[arg(short, long)]
     config_file: Option<PathBuf>,
@@ -97,25 +202,86 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         Commands::Close {
             channel_id,
             payload,
-        } => close_command(&config, channel_id, payload).await,
+            force,
+            force_finish,
+        } => {
+            if force {
+                force_close_start_command(&config, channel_id).await
+            } else if force_finish {
+                force_close_finish_command(&config, channel_id).await
+            } else {
+                close_command(&config, channel_id, payload).await
+            }
+        }
+        Commands::Move {
+            channel_id,
+            provider_url,
+        } => move_command(&config, channel_id, provider_url).await,
+        Commands::RotateKey {
+            channel_id,
+            payload,
+        } => rotate_key_command(&config, channel_id, payload).await,
         Commands::Info {
             channel_id,
             no_update,
         } => {
             info_command(&config, channel_id, !no_update).await;
         }
+        Commands::History { channel_id, json } => {
+            history_command(&config, channel_id, json).await;
+        }
+        Commands::Benchmark {
+            channel_id,
+            model,
+            amount,
+            requests,
+            max_tokens,
+        } => benchmark_command(&config, channel_id, model, amount, requests, max_tokens).await,
+        Commands::Requirements => requirements_command(&config).await,
+        Commands::VerifyProvider => verify_provider_command(&config).await,
+        Commands::Reindex => reindex_command(),
+        Commands::Decode { payload } => decode_command(payload),
+        #[cfg(feature = "simulate")]
+        Commands::Simulate {
+            deposit,
+            price,
+            prompts,
+        } => cli::commands::simulate_command(deposit, price, prompts),
         Commands::Config(update) => {
             config_command(config, &update);
         }
         Commands::Advanced(advanced_commands) => match advanced_commands {
-            AdvancedCommands::Withdraw { payload } => withdraw_command(&config, payload).await,
+            AdvancedCommands::Withdraw {
+                payload,
+                force,
+                yes,
+            } => withdraw_command(&config, payload, force, yes).await,
             AdvancedCommands::ClosePayload { channel_id } => {
                 close_payload_command(&config, channel_id)
             }
-            AdvancedCommands::StartForceClose => println!("StartForceClose"),
-            AdvancedCommands::FinishForceClose => println!("FinishForceClose"),
-            AdvancedCommands::Send { amount, channel_id } => {
-                send_command(&config, amount, channel_id).await;
+            AdvancedCommands::StartForceClose { channel_id } => {
+                force_close_start_command(&config, channel_id).await
+            }
+            AdvancedCommands::FinishForceClose { channel_id } => {
+                force_close_finish_command(&config, channel_id).await
+            }
+            AdvancedCommands::SignableState {
+                channel_id,
+                spent_balance,
+                nonce,
+            } => signable_state_command(channel_id, spent_balance, nonce),
+            AdvancedCommands::AssemblePayload {
+                channel_id,
+                spent_balance,
+                nonce,
+                signature,
+            } => assemble_payload_command(&config, channel_id, spent_balance, nonce, signature),
+            AdvancedCommands::Send {
+                amount,
+                channel_id,
+                force,
+            } => {
+                send_command(&config, amount, channel_id, force).await;
             }
         },
     }
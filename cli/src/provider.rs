@@ -1,9 +1,17 @@
 use near_crypto::PublicKey;
-use near_sdk::{json_types::U128, AccountId};
-use serde::{Deserialize, Serialize};
+use near_sdk::{json_types::U128, AccountId, NearToken};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use crate::config::SignedState;
 
+// Version of the close payload sent to `/pc/close/:channel_name`, a base64 borsh
+// serialized `SignedState` of the channel with a zero spent balance, signed by the sender.
+// Sent in the close version header so providers can reject payloads they don't understand
+pub const CLOSE_PAYLOAD_VERSION: u32 = 1;
+pub const CLOSE_VERSION_HEADER_NAME: &str = "X-PPP-Close-Version";
+// Header carrying the base64 borsh serialized `SignedState` paying for a completion
+pub const PAYMENTS_HEADER_NAME: &str = "X-Payments-Signature";
+
 pub struct Provider {
     provider_url: String,
 }
@@ -14,11 +22,114 @@ pub struct Details {
     pub public_key: PublicKey,
 }
 
+impl Details {
+    // True if the same receiver now advertises another key. Channels opened with the
+    // previous key still need a signature from it to be closed
+    pub fn key_rotated_to(&self, current: &Details) -> bool {
+        self.account_id == current.account_id && self.public_key != current.public_key
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct SpentBalance {
     pub spent_balance: U128,
+    // Nonce of the latest signed state, 0 from providers that don't report it
+    #[serde(default)]
+    pub nonce: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct HistoryEntry {
+    pub created_at: String,
+    pub spent_balance: U128,
+}
+
+// Signed states the provider accepted for a channel, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct History {
+    pub channel_name: String,
+    // True if the provider only keeps the latest signed state
+    pub pruned: bool,
+    pub signed_states: Vec<HistoryEntry>,
+}
+
+impl History {
+    // Amount charged by each signed state, the first one is charged from zero. Balances
+    // never go down for valid states, a negative charge would be a provider bug
+    pub fn charges(&self) -> Vec<i128> {
+        let mut previous: u128 = 0;
+        self.signed_states
+            .iter()
+            .map(|entry| {
+                let charge = entry.spent_balance.0 as i128 - previous as i128;
+                previous = entry.spent_balance.0;
+                charge
+            })
+            .collect()
+    }
+
+    // Spent balance of the latest signed state, zero without any
+    pub fn spent_balance(&self) -> NearToken {
+        let spent_balance = self.signed_states.last().map(|entry| entry.spent_balance.0);
+        NearToken::from_yoctonear(spent_balance.unwrap_or(0))
+    }
+
+    // How the spent balance signed locally differs from the one the provider recorded,
+    // None if they match
+    pub fn discrepancy(&self, local_spent_balance: NearToken) -> Option<String> {
+        let provider_spent_balance = self.spent_balance();
+        if local_spent_balance > provider_spent_balance {
+            Some(format!(
+                "{} signed locally has not been recorded by the provider",
+                local_spent_balance.saturating_sub(provider_spent_balance)
+            ))
+        } else if local_spent_balance < provider_spent_balance {
+            Some(format!(
+                "provider recorded {} more than the local spent balance",
+                provider_spent_balance.saturating_sub(local_spent_balance)
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+// Error body returned by the provider endpoints
+#[derive(Deserialize)]
+struct ErrorBody {
+    message: String,
+}
+
+#[derive(Debug)]
+pub enum ProviderError {
+    // The provider could not be reached
+    Unreachable(String),
+    // The provider rejected the request with an error message
+    Rejected { status: u16, message: String },
+    // The provider answered with a body we don't understand
+    Malformed { status: u16, body: String },
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProviderError::Unreachable(e) => write!(f, "Provider unreachable: {}", e),
+            ProviderError::Rejected { status, message } => {
+                write!(f, "{} (status {})", message, status)
+            }
+            ProviderError::Malformed { status, body } => {
+                write!(
+                    f,
+                    "Unexpected provider response (status {}): {}",
+                    status, body
+                )
+            }
+        }
+    }
 }
 
+impl std::error::Error for ProviderError {}
+
 impl Provider {
     pub fn new(provider_url: String) -> Self {
         Self { provider_url }
@@ -42,21 +153,81 @@ impl Provider {
             .unwrap()
     }
 
-    pub async fn close_payload(&self, channel_id: &str, signed_state_payload: &str) -> SignedState {
-        let client = reqwest::Client::new();
-        let response = client
+    // Tell the provider a channel was opened, so it reads it from the contract ahead of
+    // the first payment. Providers that don't warm up channels answer with an error
+    pub async fn announce_channel(&self, channel_id: &str) -> Result<(), ProviderError> {
+        let response = reqwest::Client::new()
+            .post(format!("{}/pc/open/{}", self.provider_url, channel_id))
+            .send()
+            .await
+            .map_err(|e| ProviderError::Unreachable(e.to_string()))?;
+
+        parse_response::<serde_json::Value>(response)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn history(&self, channel_id: &str) -> Result<History, ProviderError> {
+        let response = reqwest::get(format!("{}/pc/history/{}", self.provider_url, channel_id))
+            .await
+            .map_err(|e| ProviderError::Unreachable(e.to_string()))?;
+
+        parse_response(response).await
+    }
+
+    pub async fn close_payload(
+        &self,
+        channel_id: &str,
+        signed_state_payload: &str,
+    ) -> Result<SignedState, ProviderError> {
+        let response = reqwest::Client::new()
             .post(format!("{}/pc/close/{}", self.provider_url, channel_id))
+            .header(CLOSE_VERSION_HEADER_NAME, CLOSE_PAYLOAD_VERSION)
             .body(signed_state_payload.to_string())
             .send()
             .await
-            .unwrap();
-        if response.status().is_success() {
-            return response.json::<SignedState>().await.unwrap();
-        } else {
-            panic!(
-                "Failed to close channel: {}",
-                response.text().await.unwrap()
-            );
+            .map_err(|e| ProviderError::Unreachable(e.to_string()))?;
+
+        parse_response(response).await
+    }
+
+    // Request a completion from the OpenAI compatible api, paid with the given signed state
+    pub async fn completion(
+        &self,
+        payment: &str,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, ProviderError> {
+        let response = reqwest::Client::new()
+            .post(format!("{}/oai/completions", self.provider_url))
+            .header(PAYMENTS_HEADER_NAME, payment)
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| ProviderError::Unreachable(e.to_string()))?;
+
+        parse_response(response).await
+    }
+}
+
+async fn parse_response<T: DeserializeOwned>(
+    response: reqwest::Response,
+) -> Result<T, ProviderError> {
+    let status = response.status().as_u16();
+    let success = response.status().is_success();
+    let body = response
+        .text()
+        .await
+        .map_err(|e| ProviderError::Unreachable(e.to_string()))?;
+
+    if success {
+        serde_json::from_str::<T>(&body).map_err(|_| ProviderError::Malformed { status, body })
+    } else {
+        match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(error) => Err(ProviderError::Rejected {
+                status,
+                message: error.message,
+            }),
+            Err(_) => Err(ProviderError::Malformed { status, body }),
         }
     }
 }
@@ -0,0 +1,345 @@
+// Runs the whole pay flow (open, send, prompt, close) against the contract code and a mock
+// provider, both in-process. Nothing is sent to the network and no funds are spent, the
+// contract runs in the near-sdk mocked blockchain
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::test_utils::{get_logs, VMContextBuilder};
+use near_sdk::{testing_env, AccountId, NearToken};
+use payment_channel::Contract as ContractCode;
+use serde_json::json;
+
+use crate::config::{Channel, SignedState, State, CLOSE_NONCE};
+use crate::contract::{ContractChannel, ContractInfo};
+use crate::provider::Details;
+
+const CONTRACT_ACCOUNT_ID: &str = "contract.simulated";
+const SENDER_ACCOUNT_ID: &str = "sender.simulated";
+const RECEIVER_ACCOUNT_ID: &str = "receiver.simulated";
+const CHANNEL_ID: &str = "simulated-channel";
+
+// Balances at the end of a simulation
+#[derive(Debug)]
+pub struct Simulation {
+    pub deposit: NearToken,
+    // Withdrawn by the provider before the close
+    pub paid: NearToken,
+    // Refunded to the sender by the close, besides the listing cost
+    pub refunded: NearToken,
+}
+
+// Open a channel with `deposit`, pay `price` for each of `prompts` completions and close
+// the channel. Every step is printed, fails at the first step the contract or the provider
+// rejects
+pub fn simulate(deposit: NearToken, price: NearToken, prompts: u32) -> Result<Simulation, String> {
+    let sender = party(SENDER_ACCOUNT_ID);
+    let mut provider = MockProvider::new(party(RECEIVER_ACCOUNT_ID), price);
+
+    step("Fetch the provider details (GET /info)");
+    print_json(&provider.details);
+
+    step("Open the channel (open_channel)");
+    let mut channel = Channel {
+        channel_id: CHANNEL_ID.to_string(),
+        receiver: provider.details.clone(),
+        sender: Details {
+            account_id: sender.account_id.clone(),
+            public_key: sender.secret_key.public_key(),
+        },
+        sender_secret_key: sender.secret_key.clone(),
+        spent_balance: NearToken::from_yoctonear(0),
+        added_balance: deposit,
+        withdrawn_balance: NearToken::from_yoctonear(0),
+        force_close_started: None,
+        nonce: 0,
+        label: None,
+    };
+    let open_args = json!({
+        "channel_id": CHANNEL_ID,
+        "receiver": channel.receiver,
+        "sender": channel.sender,
+    });
+    print_json(&open_args);
+    set_context(&contract_account_id(), NearToken::from_yoctonear(0));
+    let mut contract = ContractCode::init();
+    // The full deposit goes to the channel, listing it is paid on top
+    let info: ContractInfo =
+        serde_json::from_value(serde_json::to_value(contract.contract_info()).unwrap()).unwrap();
+    let listing_cost = info.listing_cost(&channel.receiver.account_id, &channel.sender.account_id);
+    set_context(&sender.account_id, deposit.saturating_add(listing_cost));
+    call("open_channel", || {
+        contract.open_channel(
+            CHANNEL_ID.to_string(),
+            serde_json::from_value(open_args["receiver"].clone()).unwrap(),
+            serde_json::from_value(open_args["sender"].clone()).unwrap(),
+            None,
+            None,
+        )
+    })?;
+    print_events();
+
+    for prompt in 1..=prompts {
+        step(&format!("Sign the payment of prompt {} (send)", prompt));
+        let spent_balance = channel.spent_balance.saturating_add(price);
+        if spent_balance > channel.added_balance {
+            return Err(format!(
+                "The deposit of {} doesn't pay for {} prompts at {}",
+                deposit, prompts, price
+            ));
+        }
+        channel.spent_balance = spent_balance;
+        channel.nonce += 1;
+        print_json(&channel.payload());
+        let payment = channel.payload_b64();
+        println!("Payment header: {}", payment);
+
+        step(&format!("Send prompt {} (POST /oai/completions)", prompt));
+        let request = json!({
+            "model": "simulated::model",
+            "prompt": format!("Simulated prompt {}", prompt),
+        });
+        print_json(&request);
+        let response = provider.completion(&contract, &payment, &request)?;
+        print_json(&response);
+    }
+
+    step("Request the close payload (POST /pc/close)");
+    let close_request = sender_close_request(&channel);
+    print_json(&close_request);
+    let close_request = BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&close_request).unwrap());
+    let close = provider.close(&mut contract, &close_request)?;
+    print_events();
+    print_json(&close);
+
+    step("Close the channel (close)");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0));
+    call("close", || contract.close(to_contract_state(&close)))?;
+    let events = print_events();
+
+    let refund: NearToken = events
+        .iter()
+        .find(|event| event["event"] == "close")
+        .and_then(|event| serde_json::from_value(event["data"][0]["refund"].clone()).ok())
+        .ok_or_else(|| "The close didn't refund the sender".to_string())?;
+    // The listing cost paid on top of the deposit is refunded with it
+    let refunded = refund.saturating_sub(listing_cost);
+    let simulation = Simulation {
+        deposit,
+        paid: provider.withdrawn,
+        refunded,
+    };
+    if simulation.paid.saturating_add(simulation.refunded) != deposit {
+        return Err(format!(
+            "Paid {} and refunded {}, but {} was deposited",
+            simulation.paid, simulation.refunded, deposit
+        ));
+    }
+    Ok(simulation)
+}
+
+// Stands in for the provider: checks payments like the completion endpoints and signs
+// the close like `/pc/close`, against the channel stored in the contract
+struct MockProvider {
+    details: Details,
+    secret_key: SecretKey,
+    price: NearToken,
+    // Latest payment accepted
+    latest: Option<SignedState>,
+    withdrawn: NearToken,
+}
+
+impl MockProvider {
+    fn new(receiver: Party, price: NearToken) -> Self {
+        Self {
+            details: Details {
+                account_id: receiver.account_id,
+                public_key: receiver.secret_key.public_key(),
+            },
+            secret_key: receiver.secret_key,
+            price,
+            latest: None,
+            withdrawn: NearToken::from_yoctonear(0),
+        }
+    }
+
+    fn completion(
+        &mut self,
+        contract: &ContractCode,
+        payment: &str,
+        request: &serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let signed_state = self.verify_sender_state(contract, payment)?;
+        let (spent_before, nonce_before) = self
+            .latest
+            .as_ref()
+            .map(|latest| (latest.state.spent_balance, latest.state.nonce))
+            .unwrap_or((NearToken::from_yoctonear(0), 0));
+        if signed_state.state.nonce <= nonce_before {
+            return Err("Provider rejected the payment: nonce is not above the last one".into());
+        }
+        let paid = signed_state
+            .state
+            .spent_balance
+            .saturating_sub(spent_before);
+        if paid < self.price {
+            return Err(format!(
+                "Provider rejected the payment: {} paid, the price is {}",
+                paid, self.price
+            ));
+        }
+        self.latest = Some(signed_state);
+
+        let prompt = request["prompt"].as_str().unwrap_or_default();
+        Ok(json!({
+            "object": "text_completion",
+            "model": request["model"],
+            "choices": [{
+                "index": 0,
+                "text": format!("Simulated completion of: {}", prompt),
+                "finish_reason": "stop",
+            }],
+        }))
+    }
+
+    // Withdraw the latest payment, then sign the close state
+    fn close(
+        &mut self,
+        contract: &mut ContractCode,
+        close_request: &str,
+    ) -> Result<SignedState, String> {
+        let close_request = self.verify_sender_state(contract, close_request)?;
+        if close_request.state.spent_balance.as_yoctonear() != 0
+            || close_request.state.nonce != CLOSE_NONCE
+        {
+            return Err("Provider rejected the close request: invalid state".to_string());
+        }
+
+        if let Some(latest) = &self.latest {
+            println!("Provider withdraws the latest payment (withdraw):");
+            print_json(latest);
+            set_context(&self.details.account_id, NearToken::from_yoctonear(0));
+            call("withdraw", || contract.withdraw(to_contract_state(latest)))?;
+            self.withdrawn = latest.state.spent_balance;
+        }
+
+        let state = State {
+            channel_id: close_request.state.channel_id,
+            spent_balance: NearToken::from_yoctonear(0),
+            nonce: CLOSE_NONCE,
+        };
+        let signature = self
+            .secret_key
+            .sign(&near_sdk::borsh::to_vec(&state).unwrap());
+        Ok(SignedState { state, signature })
+    }
+
+    fn verify_sender_state(
+        &self,
+        contract: &ContractCode,
+        payload: &str,
+    ) -> Result<SignedState, String> {
+        let signed_state: SignedState = BASE64_STANDARD
+            .decode(payload)
+            .ok()
+            .and_then(|bytes| near_sdk::borsh::from_slice(&bytes).ok())
+            .ok_or_else(|| "Provider rejected the payload: not a signed state".to_string())?;
+        let channel: ContractChannel = contract
+            .channel(signed_state.state.channel_id.clone())
+            .map(|channel| serde_json::from_value(serde_json::to_value(channel).unwrap()).unwrap())
+            .ok_or_else(|| "Provider rejected the payload: unknown channel".to_string())?;
+
+        let message = near_sdk::borsh::to_vec(&signed_state.state).unwrap();
+        if !signed_state
+            .signature
+            .verify(&message, &channel.sender.public_key)
+        {
+            return Err("Provider rejected the payload: invalid signature".to_string());
+        }
+        if signed_state.state.spent_balance > channel.added_balance {
+            return Err("Provider rejected the payload: above the deposit".to_string());
+        }
+        Ok(signed_state)
+    }
+}
+
+struct Party {
+    account_id: AccountId,
+    secret_key: SecretKey,
+}
+
+// Keys derived from the account id, the simulation is the same on every run
+fn party(account_id: &str) -> Party {
+    Party {
+        account_id: account_id.parse().unwrap(),
+        secret_key: SecretKey::from_seed(KeyType::ED25519, account_id),
+    }
+}
+
+// Same as `request_close_payload`, the sender asks for the close with a zero state
+fn sender_close_request(channel: &Channel) -> SignedState {
+    let state = State {
+        channel_id: channel.channel_id.clone(),
+        spent_balance: NearToken::from_yoctonear(0),
+        nonce: CLOSE_NONCE,
+    };
+    let signature = channel
+        .sender_secret_key
+        .sign(&near_sdk::borsh::to_vec(&state).unwrap());
+    SignedState { state, signature }
+}
+
+fn contract_account_id() -> AccountId {
+    CONTRACT_ACCOUNT_ID.parse().unwrap()
+}
+
+// The next contract call is a transaction from `predecessor` attaching `deposit`
+fn set_context(predecessor: &AccountId, deposit: NearToken) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(contract_account_id())
+        .predecessor_account_id(predecessor.clone())
+        .attached_deposit(deposit)
+        .build());
+}
+
+// The contract and the cli sign with different types sharing the json format
+fn to_contract_state(signed_state: &SignedState) -> payment_channel::SignedState {
+    serde_json::from_value(serde_json::to_value(signed_state).unwrap()).unwrap()
+}
+
+// A contract panic is the failure of the simulated transaction
+fn call<T>(method: &str, f: impl FnOnce() -> T) -> Result<T, String> {
+    catch_unwind(AssertUnwindSafe(f)).map_err(|panic| {
+        let message = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| {
+                panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+            })
+            .unwrap_or_default();
+        format!("Contract rejected {}: {}", method, message)
+    })
+}
+
+// Events logged by the last contract call
+fn print_events() -> Vec<serde_json::Value> {
+    let events = get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .filter_map(|event| serde_json::from_str::<serde_json::Value>(event).ok())
+        .collect::<Vec<_>>();
+    for event in &events {
+        println!("Event: {}", event);
+    }
+    events
+}
+
+fn step(description: &str) {
+    println!("\n== {}", description);
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
@@ -1,8 +1,9 @@
 use near_crypto::{InMemorySigner, SecretKey};
 use near_sdk::AccountId;
+use std::io::Write;
 use std::{path::PathBuf, str::FromStr};
 
-use crate::config::{data_storage, Channel};
+use crate::config::ChannelIndex;
 
 fn find_on_path(path: PathBuf, target: &str) -> Option<PathBuf> {
     for entry in std::fs::read_dir(path).unwrap() {
@@ -38,11 +39,8 @@ fn load_memory_signer(account_id: AccountId, path: PathBuf) -> InMemorySigner {
 }
 
 pub fn find_only_channel_id() -> String {
-    let mut channels = std::fs::read_dir(data_storage().join("channels"))
-        .unwrap()
-        .map(|e| e.unwrap().path())
-        .filter(|e| e.is_file() && e.extension() == Some("json".as_ref()))
-        .map(|e| serde_json::from_str::<Channel>(&std::fs::read_to_string(&e).unwrap()).unwrap());
+    let index = ChannelIndex::load();
+    let mut channels = index.channels.keys();
 
     let first = channels.next().expect("No channels found");
 
@@ -51,5 +49,17 @@ pub fn find_only_channel_id() -> String {
         std::process::exit(1);
     }
 
-    first.channel_id
+    first.clone()
+}
+
+// Ask a yes/no question on the terminal, anything but `y` or `yes` is a no
+pub fn confirm(question: &str) -> bool {
+    print!("\n{} [y/N] ", question);
+    std::io::stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
@@ -0,0 +1,49 @@
+// Shared by the integration test binaries, not every binary uses every helper
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use cli::config::{data_storage, Channel};
+use cli::provider::Details;
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::NearToken;
+
+pub const PROVIDER: &str = "provider.testnet";
+pub const SENDER: &str = "sender.testnet";
+
+// The channels are saved under the home directory, every test binary gets its own.
+// Tests of a binary run in parallel, they use distinct channel ids
+pub fn init() -> PathBuf {
+    static HOME: OnceLock<PathBuf> = OnceLock::new();
+    HOME.get_or_init(|| {
+        let home = std::env::temp_dir().join(format!("ppp-cli-test-{}", std::process::id()));
+        std::fs::create_dir_all(&home).unwrap();
+        std::env::set_var("HOME", &home);
+        home
+    });
+    data_storage()
+}
+
+pub fn details(account_id: &str) -> Details {
+    Details {
+        account_id: account_id.parse().unwrap(),
+        public_key: SecretKey::from_seed(KeyType::ED25519, account_id).public_key(),
+    }
+}
+
+// A channel of `SENDER` with `PROVIDER`, not saved
+pub fn channel(channel_id: &str, added: u128, spent: u128, withdrawn: u128) -> Channel {
+    Channel {
+        channel_id: channel_id.to_string(),
+        receiver: details(PROVIDER),
+        sender: details(SENDER),
+        sender_secret_key: SecretKey::from_seed(KeyType::ED25519, SENDER),
+        spent_balance: NearToken::from_yoctonear(spent),
+        added_balance: NearToken::from_yoctonear(added),
+        withdrawn_balance: NearToken::from_yoctonear(withdrawn),
+        force_close_started: None,
+        nonce: 0,
+        label: None,
+    }
+}
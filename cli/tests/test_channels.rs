@@ -0,0 +1,105 @@
+mod common;
+
+use cli::config::{
+    archive_closed_channel, channel_file, closed_channel_file, Channel, ChannelIndex, ChannelLock,
+};
+use common::{channel, init};
+use near_sdk::NearToken;
+
+#[test]
+fn test_archive_closed_channel() {
+    init();
+    let channel = channel("archived", 1_000, 100, 0);
+    channel.save(0);
+    assert!(ChannelIndex::load().channels.contains_key("archived"));
+
+    archive_closed_channel("archived");
+
+    assert!(!channel_file("archived").exists());
+    let archived = std::fs::read_to_string(closed_channel_file("archived")).unwrap();
+    let archived: cli::config::Channel = serde_json::from_str(&archived).unwrap();
+    assert_eq!(archived.spent_balance, channel.spent_balance);
+    assert!(!ChannelIndex::load().channels.contains_key("archived"));
+}
+
+#[test]
+fn test_channel_balances() {
+    let channel = channel("balances", 1_000, 300, 100);
+
+    assert_eq!(
+        channel.spendable_remaining(),
+        NearToken::from_yoctonear(700)
+    );
+    assert_eq!(
+        channel.outstanding_balance(),
+        NearToken::from_yoctonear(200)
+    );
+    assert_eq!(
+        channel.refundable_on_close(),
+        NearToken::from_yoctonear(900)
+    );
+}
+
+#[test]
+fn test_spendable_and_refundable_diverge_until_withdrawn() {
+    // The provider hasn't withdrawn what was spent, closing now refunds that too
+    let mut channel = channel("diverging", 1_000, 400, 150);
+    assert_eq!(
+        channel.spendable_remaining(),
+        NearToken::from_yoctonear(600)
+    );
+    assert_eq!(
+        channel.refundable_on_close(),
+        NearToken::from_yoctonear(850)
+    );
+
+    // Once everything spent is withdrawn, both are the same
+    channel.withdrawn_balance = channel.spent_balance;
+    assert_eq!(channel.spendable_remaining(), channel.refundable_on_close());
+    assert_eq!(
+        channel.refundable_on_close(),
+        NearToken::from_yoctonear(600)
+    );
+}
+
+#[test]
+fn test_channel_balances_saturate() {
+    // Withdrawn ahead of the local spent balance, e.g. a payment signed elsewhere
+    let channel = channel("saturated", 1_000, 100, 300);
+
+    assert_eq!(channel.outstanding_balance(), NearToken::from_yoctonear(0));
+    assert_eq!(
+        channel.refundable_on_close(),
+        NearToken::from_yoctonear(700)
+    );
+}
+
+#[test]
+fn test_parallel_sends_are_serialized() {
+    init();
+    channel("parallel", 1_000, 0, 0).save(0);
+
+    let sends = (0..8)
+        .map(|_| {
+            std::thread::spawn(|| {
+                for _ in 0..10 {
+                    // What `send` does with the channel while holding its lock
+                    let _lock = ChannelLock::acquire("parallel");
+                    let mut channel = Channel::load("parallel", 0);
+                    channel.spent_balance = channel
+                        .spent_balance
+                        .saturating_add(NearToken::from_yoctonear(1));
+                    channel.nonce += 1;
+                    channel.save(0);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for send in sends {
+        send.join().unwrap();
+    }
+
+    let channel = Channel::load("parallel", 0);
+    assert_eq!(channel.spent_balance, NearToken::from_yoctonear(80));
+    assert_eq!(channel.nonce, 80);
+}
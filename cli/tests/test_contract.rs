@@ -0,0 +1,37 @@
+// The contract code is only a dependency of the `simulate` feature
+#![cfg(feature = "simulate")]
+
+use cli::contract::{ContractInfo, HARD_CLOSE_TIMEOUT};
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::{testing_env, AccountId, Gas};
+use payment_channel::Contract as ContractCode;
+
+#[test]
+fn test_contract_info_matches_the_contract() {
+    let contract_account_id: AccountId = "contract.testnet".parse().unwrap();
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(contract_account_id.clone())
+        .predecessor_account_id(contract_account_id)
+        .build());
+    let contract = ContractCode::init();
+
+    let info = serde_json::to_value(contract.contract_info()).unwrap();
+    let info: ContractInfo = serde_json::from_value(info).unwrap();
+
+    assert_eq!(info.hard_close_timeout.0, HARD_CLOSE_TIMEOUT);
+    assert!(!info.storage_byte_cost.is_zero());
+    assert!(info.channel_storage_cost > info.storage_byte_cost);
+    assert!(info.account_channel_storage_cost > info.storage_byte_cost);
+    let gas = info.recommended_gas;
+    for operation_gas in [
+        gas.open_channel,
+        gas.withdraw,
+        gas.topup,
+        gas.close,
+        gas.withdraw_and_close,
+        gas.force_close_start,
+        gas.force_close_finish,
+    ] {
+        assert!(operation_gas > Gas::from_gas(0));
+    }
+}
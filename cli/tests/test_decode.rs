@@ -0,0 +1,136 @@
+mod common;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cli::commands::{
+    assemble_payload, decode_payload, newer_than_accepted, signable_state, signed_by_sender,
+};
+use cli::config::CLOSE_NONCE;
+use common::{channel, PROVIDER, SENDER};
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::NearToken;
+
+#[test]
+fn test_decode_payment_payload() {
+    let mut channel = channel("decoded", 1_000, 300, 0);
+    channel.nonce = 7;
+
+    // Pasted payloads often end with a newline
+    let signed_state = decode_payload(&format!("{}\n", channel.payload_b64())).unwrap();
+
+    assert_eq!(signed_state.state.channel_id, "decoded");
+    assert_eq!(
+        signed_state.state.spent_balance,
+        NearToken::from_yoctonear(300)
+    );
+    assert_eq!(signed_state.state.nonce, 7);
+    let raw_state = near_sdk::borsh::to_vec(&signed_state.state).unwrap();
+    assert!(signed_state
+        .signature
+        .verify(&raw_state, &channel.sender.public_key));
+}
+
+#[test]
+fn test_decode_close_payload() {
+    let mut channel = channel("decoded-close", 1_000, 0, 0);
+    channel.nonce = CLOSE_NONCE;
+
+    let signed_state = decode_payload(&channel.payload_b64()).unwrap();
+
+    assert_eq!(signed_state.state.nonce, CLOSE_NONCE);
+}
+
+#[test]
+fn test_decode_legacy_payload() {
+    let channel = channel("legacy", 1_000, 300, 0);
+    // Signed before the nonce existed
+    let state = ppp_core::LegacyState {
+        channel_id: "legacy".to_string(),
+        spent_balance: NearToken::from_yoctonear(300),
+    };
+    let signature = SecretKey::from_seed(KeyType::ED25519, SENDER)
+        .sign(&near_sdk::borsh::to_vec(&state).unwrap());
+    let payload = BASE64_STANDARD.encode(
+        near_sdk::borsh::to_vec(&ppp_core::LegacySignedState { state, signature }).unwrap(),
+    );
+
+    let signed_state = decode_payload(&payload).unwrap();
+    assert_eq!(signed_state.state.channel_id, "legacy");
+    assert_eq!(
+        signed_state.state.spent_balance,
+        NearToken::from_yoctonear(300)
+    );
+    assert_eq!(signed_state.state.nonce, 0);
+
+    // The contract accepts it until the channel accepts a state with a nonce
+    assert!(signed_by_sender(&signed_state, &channel.sender.public_key));
+    assert!(newer_than_accepted(signed_state.state.nonce, 0));
+    assert!(!newer_than_accepted(signed_state.state.nonce, 3));
+
+    // Only with nonce 0
+    let mut with_nonce = signed_state;
+    with_nonce.state.nonce = 1;
+    assert!(!signed_by_sender(&with_nonce, &channel.sender.public_key));
+}
+
+#[test]
+fn test_decode_invalid_payload() {
+    let error = decode_payload("not base64!").unwrap_err();
+    assert!(error.starts_with("Payload is not valid base64"));
+
+    let truncated = &channel("truncated", 1_000, 300, 0).payload_b64()[..20];
+    let truncated = BASE64_STANDARD.encode(&BASE64_STANDARD.decode(truncated).unwrap());
+    let error = decode_payload(&truncated).unwrap_err();
+    assert!(error.starts_with("Payload is not a borsh serialized SignedState"));
+}
+
+#[test]
+fn test_externally_signed_payload_verifies() {
+    let mut channel = channel("external", 1_000, 300, 0);
+    channel.nonce = 4;
+
+    // What the external signer does with the bytes of `signable-state`
+    let raw_state = signable_state("external".to_string(), NearToken::from_yoctonear(300), 4);
+    let signature = SecretKey::from_seed(KeyType::ED25519, SENDER).sign(&raw_state);
+
+    let signed_state = assemble_payload(
+        &channel,
+        NearToken::from_yoctonear(300),
+        4,
+        &format!("{}\n", signature),
+    )
+    .unwrap();
+    let payload = BASE64_STANDARD.encode(near_sdk::borsh::to_vec(&signed_state).unwrap());
+
+    // Same payload as the one signed with the channel key, and it verifies once decoded
+    assert_eq!(payload, channel.payload_b64());
+    let decoded = decode_payload(&payload).unwrap();
+    assert_eq!(decoded.state, channel.info());
+    let raw_state = near_sdk::borsh::to_vec(&decoded.state).unwrap();
+    assert!(decoded
+        .signature
+        .verify(&raw_state, &channel.sender.public_key));
+}
+
+#[test]
+fn test_externally_signed_payload_with_another_key() {
+    let channel = channel("external-other", 1_000, 300, 0);
+    let raw_state = signable_state(
+        "external-other".to_string(),
+        NearToken::from_yoctonear(300),
+        1,
+    );
+    let signature = SecretKey::from_seed(KeyType::ED25519, PROVIDER).sign(&raw_state);
+
+    let error = assemble_payload(
+        &channel,
+        NearToken::from_yoctonear(300),
+        1,
+        &signature.to_string(),
+    )
+    .unwrap_err();
+    assert!(error.starts_with("Signature doesn't match the sender key"));
+
+    let error =
+        assemble_payload(&channel, NearToken::from_yoctonear(300), 1, "signature").unwrap_err();
+    assert!(error.starts_with("Invalid signature"));
+}
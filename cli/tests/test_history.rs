@@ -0,0 +1,55 @@
+use cli::provider::{History, HistoryEntry};
+use near_sdk::json_types::U128;
+use near_sdk::NearToken;
+
+fn history(spent_balances: &[u128]) -> History {
+    History {
+        channel_name: "channel".to_string(),
+        pruned: false,
+        signed_states: spent_balances
+            .iter()
+            .map(|spent_balance| HistoryEntry {
+                created_at: "2026-01-01T00:00:00".to_string(),
+                spent_balance: U128(*spent_balance),
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn test_history_charges() {
+    let history = history(&[100, 250, 300]);
+
+    assert_eq!(history.charges(), vec![100, 150, 50]);
+    assert_eq!(history.spent_balance(), NearToken::from_yoctonear(300));
+}
+
+#[test]
+fn test_empty_history() {
+    let history = history(&[]);
+
+    assert!(history.charges().is_empty());
+    assert_eq!(history.spent_balance(), NearToken::from_yoctonear(0));
+    assert_eq!(history.discrepancy(NearToken::from_yoctonear(0)), None);
+}
+
+#[test]
+fn test_history_discrepancy() {
+    let history = history(&[100, 250]);
+
+    assert_eq!(history.discrepancy(NearToken::from_yoctonear(250)), None);
+    assert_eq!(
+        history.discrepancy(NearToken::from_yoctonear(300)),
+        Some(format!(
+            "{} signed locally has not been recorded by the provider",
+            NearToken::from_yoctonear(50)
+        ))
+    );
+    assert_eq!(
+        history.discrepancy(NearToken::from_yoctonear(200)),
+        Some(format!(
+            "provider recorded {} more than the local spent balance",
+            NearToken::from_yoctonear(50)
+        ))
+    );
+}
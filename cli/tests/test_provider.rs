@@ -0,0 +1,115 @@
+use cli::provider::{Provider, ProviderError, CLOSE_PAYLOAD_VERSION, CLOSE_VERSION_HEADER_NAME};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+// Provider answering every request with `status` and `body`, after checking it carries
+// the close version header. Returns its url
+async fn mock_provider(status: &'static str, body: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Headers, then as much body as the content length says
+            let headers = loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break headers.to_string();
+                    }
+                }
+            };
+            assert!(headers.lines().any(|line| {
+                line.split_once(':').is_some_and(|(name, value)| {
+                    name.eq_ignore_ascii_case(CLOSE_VERSION_HEADER_NAME)
+                        && value.trim() == CLOSE_PAYLOAD_VERSION.to_string()
+                })
+            }));
+
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    url
+}
+
+#[tokio::test]
+async fn test_close_payload_rejected() {
+    let url = mock_provider(
+        "400 Bad Request",
+        r#"{"message": "Unsupported close payload version"}"#,
+    )
+    .await;
+
+    let error = Provider::new(url)
+        .close_payload("channel", "payload")
+        .await
+        .unwrap_err();
+
+    match error {
+        ProviderError::Rejected { status, message } => {
+            assert_eq!(status, 400);
+            assert_eq!(message, "Unsupported close payload version");
+        }
+        error => panic!("Expected a rejection, got {:?}", error),
+    }
+}
+
+#[tokio::test]
+async fn test_close_payload_malformed() {
+    let url = mock_provider("502 Bad Gateway", "upstream unavailable").await;
+
+    let error = Provider::new(url)
+        .close_payload("channel", "payload")
+        .await
+        .unwrap_err();
+
+    match error {
+        ProviderError::Malformed { status, body } => {
+            assert_eq!(status, 502);
+            assert_eq!(body, "upstream unavailable");
+        }
+        error => panic!("Expected a malformed response, got {:?}", error),
+    }
+}
+
+#[tokio::test]
+async fn test_close_payload_malformed_success() {
+    // A success without a signed state is as unexpected as an unstructured error
+    let url = mock_provider("200 OK", r#"{"spent_balance": "0"}"#).await;
+
+    let error = Provider::new(url)
+        .close_payload("channel", "payload")
+        .await
+        .unwrap_err();
+
+    match error {
+        ProviderError::Malformed { status, body } => {
+            assert_eq!(status, 200);
+            assert_eq!(body, r#"{"spent_balance": "0"}"#);
+        }
+        error => panic!("Expected a malformed response, got {:?}", error),
+    }
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use cli::commands::reconcile_with_contract;
+use cli::config::{channel_file, closed_channel_file, Channel};
+use cli::contract::{ContractAccount, ContractChannel, CLOSED_CHANNEL_ACCOUNT_ID};
+use common::{channel, init};
+use near_sdk::NearToken;
+
+fn contract_channel(channel: &Channel, added: u128, withdrawn: u128) -> ContractChannel {
+    ContractChannel {
+        receiver: ContractAccount {
+            account_id: channel.receiver.account_id.clone(),
+            public_key: channel.receiver.public_key.clone(),
+        },
+        sender: ContractAccount {
+            account_id: channel.sender.account_id.clone(),
+            public_key: channel.sender.public_key.clone(),
+        },
+        added_balance: NearToken::from_yoctonear(added),
+        withdrawn_balance: NearToken::from_yoctonear(withdrawn),
+        force_close_started: None,
+    }
+}
+
+#[test]
+fn test_reconcile_contract_ahead() {
+    init();
+    let mut channel = channel("ahead", 1_000, 300, 0);
+    channel.save(0);
+
+    // Topped up and withdrawn since the last sync
+    let contract_channel = contract_channel(&channel, 1_500, 200);
+    reconcile_with_contract(&mut channel, contract_channel, false, 0).unwrap();
+
+    assert_eq!(channel.added_balance, NearToken::from_yoctonear(1_500));
+    assert_eq!(channel.withdrawn_balance, NearToken::from_yoctonear(200));
+    assert_eq!(channel.spent_balance, NearToken::from_yoctonear(300));
+    let saved = Channel::load("ahead", 0);
+    assert_eq!(saved.added_balance, NearToken::from_yoctonear(1_500));
+}
+
+#[test]
+fn test_reconcile_local_ahead() {
+    init();
+    let mut channel = channel("diverged", 1_000, 300, 200);
+    channel.save(0);
+
+    let contract_channel = contract_channel(&channel, 1_000, 100);
+    let error =
+        reconcile_with_contract(&mut channel, contract_channel.clone(), false, 0).unwrap_err();
+    assert!(error.contains("--force"));
+    assert_eq!(channel.withdrawn_balance, NearToken::from_yoctonear(200));
+
+    reconcile_with_contract(&mut channel, contract_channel, true, 0).unwrap();
+    assert_eq!(channel.withdrawn_balance, NearToken::from_yoctonear(100));
+    let saved = Channel::load("diverged", 0);
+    assert_eq!(saved.withdrawn_balance, NearToken::from_yoctonear(100));
+}
+
+#[test]
+fn test_reconcile_closed() {
+    init();
+    let mut channel = channel("reconcile-closed", 1_000, 300, 0);
+    channel.save(0);
+
+    let mut contract_channel = contract_channel(&channel, 1_000, 300);
+    contract_channel.sender.account_id = CLOSED_CHANNEL_ACCOUNT_ID.parse().unwrap();
+    let error = reconcile_with_contract(&mut channel, contract_channel, false, 0).unwrap_err();
+
+    assert!(error.contains("closed"));
+    assert!(!channel_file("reconcile-closed").exists());
+    assert!(closed_channel_file("reconcile-closed").exists());
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use cli::commands::signed_by_channel_receiver;
+use cli::config::{provider_file, Config, SignedState, State, CLOSE_NONCE};
+use cli::provider::Details;
+use common::{channel, details, init, PROVIDER};
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::NearToken;
+
+// Details of `PROVIDER` after rotating its receiver key
+fn rotated_details() -> Details {
+    Details {
+        account_id: PROVIDER.parse().unwrap(),
+        public_key: rotated_key().public_key(),
+    }
+}
+
+fn rotated_key() -> SecretKey {
+    SecretKey::from_seed(KeyType::ED25519, "rotated")
+}
+
+fn close_payload(channel_id: &str, secret_key: &SecretKey) -> SignedState {
+    let state = State {
+        channel_id: channel_id.to_string(),
+        spent_balance: NearToken::from_yoctonear(0),
+        nonce: CLOSE_NONCE,
+    };
+    let signature = secret_key.sign(&near_sdk::borsh::to_vec(&state).unwrap());
+    SignedState { state, signature }
+}
+
+#[test]
+fn test_key_rotated_to() {
+    let previous = details(PROVIDER);
+
+    assert!(previous.key_rotated_to(&rotated_details()));
+    assert!(!previous.key_rotated_to(&details(PROVIDER)));
+    // Another provider, not a rotation
+    assert!(!previous.key_rotated_to(&details("other.testnet")));
+}
+
+#[test]
+fn test_close_payload_must_be_signed_with_the_channel_key() {
+    let channel = channel("rotated", 1_000, 100, 0);
+    let channel_key = SecretKey::from_seed(KeyType::ED25519, PROVIDER);
+
+    assert!(signed_by_channel_receiver(
+        &close_payload("rotated", &channel_key),
+        &channel
+    ));
+    // The contract only knows the key the channel was opened with
+    assert!(!signed_by_channel_receiver(
+        &close_payload("rotated", &rotated_key()),
+        &channel
+    ));
+    assert!(!signed_by_channel_receiver(
+        &close_payload("other", &channel_key),
+        &channel
+    ));
+}
+
+#[test]
+fn test_replace_provider_details() {
+    init();
+    let config = Config::default();
+    config.update_provider(&details(PROVIDER));
+
+    config.replace_provider(&rotated_details());
+
+    let saved = std::fs::read_to_string(provider_file(&PROVIDER.parse().unwrap())).unwrap();
+    let saved: Details = serde_json::from_str(&saved).unwrap();
+    assert_eq!(saved, rotated_details());
+    // Channels opened from now on use the new key
+    config.update_provider(&rotated_details());
+}
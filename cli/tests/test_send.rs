@@ -0,0 +1,27 @@
+mod common;
+
+use cli::commands::authorization_summary;
+use common::{channel, PROVIDER};
+use near_sdk::NearToken;
+
+#[test]
+fn test_authorization_summary() {
+    // Already updated with a payment of 200
+    let channel = channel("authorized", 1_000, 300, 0);
+
+    let summary = authorization_summary(&channel, NearToken::from_yoctonear(200));
+
+    assert!(summary.contains(&format!(
+        "Authorizing {} to take {} more from channel authorized",
+        PROVIDER,
+        NearToken::from_yoctonear(200)
+    )));
+    assert!(summary.contains(&format!(
+        "Total authorized: {}",
+        NearToken::from_yoctonear(300)
+    )));
+    assert!(summary.contains(&format!(
+        "Left in channel:  {}",
+        NearToken::from_yoctonear(700)
+    )));
+}
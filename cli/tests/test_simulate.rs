@@ -0,0 +1,28 @@
+// The simulation runs the contract code in-process, only built with the `simulate` feature
+
+#[cfg(feature = "simulate")]
+#[test]
+fn test_simulated_flow_refunds_the_unspent_deposit() {
+    use near_sdk::NearToken;
+
+    let deposit = NearToken::from_near(1);
+    let simulation = cli::simulate::simulate(deposit, NearToken::from_millinear(10), 3).unwrap();
+
+    assert_eq!(simulation.deposit, deposit);
+    assert_eq!(simulation.paid, NearToken::from_millinear(30));
+    assert_eq!(simulation.refunded, NearToken::from_millinear(970));
+}
+
+#[cfg(feature = "simulate")]
+#[test]
+fn test_simulated_deposit_too_small() {
+    use near_sdk::NearToken;
+
+    let error = cli::simulate::simulate(
+        NearToken::from_millinear(25),
+        NearToken::from_millinear(10),
+        3,
+    )
+    .unwrap_err();
+    assert!(error.contains("doesn't pay for 3 prompts"));
+}
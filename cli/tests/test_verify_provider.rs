@@ -0,0 +1,159 @@
+mod common;
+
+use cli::client::Client;
+use cli::commands::check_provider_key;
+use common::{details, PROVIDER};
+use near_primitives::views::AccessKeyPermissionView;
+use near_sdk::AccountId;
+use serde_json::{json, Value};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+const CONTRACT: &str = "contract.testnet";
+
+// JSON-RPC server answering every request with `answer`, the result (or error) of
+// `view_access_key`. Returns its url
+async fn mock_rpc(answer: Value) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 4096];
+            // Headers, then as much body as the content length says
+            let body = loop {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                    let length = headers
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            name.eq_ignore_ascii_case("content-length")
+                                .then(|| value.trim().parse::<usize>().unwrap())
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break body.to_string();
+                    }
+                }
+            };
+            let request: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(request["params"]["request_type"], "view_access_key");
+
+            let mut response = json!({ "jsonrpc": "2.0", "id": request["id"] });
+            match answer.get("error") {
+                Some(error) => response["error"] = error.clone(),
+                None => response["result"] = answer.clone(),
+            }
+            let response = response.to_string();
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+        }
+    });
+    url
+}
+
+fn access_key(permission: Value) -> Value {
+    json!({
+        "nonce": 1,
+        "permission": permission,
+        "block_height": 1,
+        "block_hash": "11111111111111111111111111111111",
+    })
+}
+
+async fn permission(answer: Value) -> Option<AccessKeyPermissionView> {
+    let provider = details(PROVIDER);
+    Client::new(&mock_rpc(answer).await, 0)
+        .access_key(provider.account_id, provider.public_key)
+        .await
+        .unwrap()
+}
+
+fn check(permission: Option<AccessKeyPermissionView>) -> Result<String, String> {
+    let provider = details(PROVIDER);
+    let contract: AccountId = CONTRACT.parse().unwrap();
+    check_provider_key(
+        permission,
+        &provider.account_id,
+        &provider.public_key,
+        &contract,
+    )
+}
+
+#[tokio::test]
+async fn test_full_access_key() {
+    let permission = permission(access_key(json!("FullAccess"))).await;
+
+    assert_eq!(permission, Some(AccessKeyPermissionView::FullAccess));
+    assert!(check(permission).is_ok());
+}
+
+#[tokio::test]
+async fn test_function_call_key() {
+    let key = |receiver_id: &str, method_names: Value| {
+        access_key(json!({
+            "FunctionCall": {
+                "allowance": null,
+                "receiver_id": receiver_id,
+                "method_names": method_names,
+            }
+        }))
+    };
+
+    // Every method of the contract
+    assert!(check(permission(key(CONTRACT, json!([]))).await).is_ok());
+    assert!(check(
+        permission(key(
+            CONTRACT,
+            json!(["withdraw", "close", "withdraw_and_close"])
+        ))
+        .await
+    )
+    .is_ok());
+
+    let error = check(permission(key(CONTRACT, json!(["withdraw"]))).await).unwrap_err();
+    assert!(error.contains("\"close\""));
+    assert!(error.contains("\"withdraw_and_close\""));
+
+    let error = check(permission(key("other.testnet", json!([]))).await).unwrap_err();
+    assert!(error.contains(CONTRACT));
+}
+
+#[tokio::test]
+async fn test_unknown_access_key() {
+    let provider = details(PROVIDER);
+    let permission = permission(json!({
+        "error": {
+            "name": "HANDLER_ERROR",
+            "cause": {
+                "name": "UNKNOWN_ACCESS_KEY",
+                "info": {
+                    "public_key": provider.public_key.to_string(),
+                    "block_height": 1,
+                    "block_hash": "11111111111111111111111111111111",
+                },
+            },
+            "code": -32000,
+            "message": "Server error",
+            "data": "access key does not exist while viewing",
+        }
+    }))
+    .await;
+
+    assert_eq!(permission, None);
+    let error = check(permission).unwrap_err();
+    assert!(error.contains(&provider.public_key.to_string()));
+}
@@ -0,0 +1,60 @@
+use near_sdk::{near, AccountId, NearToken, Timestamp};
+
+/// NEP-297 events logged on every channel lifecycle transition, so indexers and
+/// providers can follow channels without polling `channel`.
+#[near(event_json(standard = "payment_channel"))]
+pub enum Event<'a> {
+    #[event_version("1.0.0")]
+    OpenChannel {
+        channel_id: &'a str,
+        receiver: &'a AccountId,
+        sender: &'a AccountId,
+        added_balance: NearToken,
+        sponsor: Option<&'a AccountId>,
+    },
+    #[event_version("1.0.0")]
+    Topup {
+        channel_id: &'a str,
+        amount: NearToken,
+        added_balance: NearToken,
+    },
+    /// `amount` is transferred to the receiver, before the owner fee
+    #[event_version("1.0.0")]
+    Withdraw {
+        channel_id: &'a str,
+        receiver: &'a AccountId,
+        amount: NearToken,
+        withdrawn_balance: NearToken,
+    },
+    #[event_version("1.0.0")]
+    ForceCloseStart {
+        channel_id: &'a str,
+        sender: &'a AccountId,
+        started_at: Timestamp,
+    },
+    #[event_version("1.0.0")]
+    ForceCloseCancel {
+        channel_id: &'a str,
+        sender: &'a AccountId,
+    },
+    /// `refund_to` is None once the sender is refunded again
+    #[event_version("1.0.0")]
+    RefundToUpdate {
+        channel_id: &'a str,
+        refund_to: Option<&'a AccountId>,
+    },
+    /// `refund` is transferred to `refund_to`: the refund recipient set by the sender, the
+    /// sponsor or the sender of the channel. It includes the listing cost paid on open
+    #[event_version("1.0.0")]
+    Close {
+        channel_id: &'a str,
+        refund_to: &'a AccountId,
+        refund: NearToken,
+    },
+    #[event_version("1.0.0")]
+    ForceCloseFinish {
+        channel_id: &'a str,
+        refund_to: &'a AccountId,
+        refund: NearToken,
+    },
+}
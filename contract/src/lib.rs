@@ -1,13 +1,18 @@
 use fraction::Fraction;
 use near_sdk::borsh::to_vec;
-use near_sdk::store::{LazyOption, LookupMap};
+use near_sdk::json_types::U64;
+use near_sdk::store::{IterableSet, LazyOption, LookupMap};
 use near_sdk::{
-    env, near, near_bindgen, require, AccountId, NearToken, PanicOnDefault, Promise, PublicKey,
-    Timestamp,
+    env, near, near_bindgen, require, AccountId, Gas, NearToken, PanicOnDefault, Promise,
+    PublicKey, Timestamp,
 };
-use signature::Signature;
 use std::str::FromStr;
 
+pub use signature::Signature;
+
+use events::Event;
+
+mod events;
 mod fraction;
 mod signature;
 
@@ -16,6 +21,35 @@ type ChannelId = String;
 const SECOND: u64 = 1_000_000_000;
 const DAY: u64 = 24 * 60 * 60 * SECOND;
 const HARD_CLOSE_TIMEOUT: u64 = 7 * DAY;
+// Shortest force close timeout a channel can be opened with, leaves the receiver
+// time to withdraw before the sender takes the balance back
+const MIN_FORCE_CLOSE_TIMEOUT: u64 = 60 * 60 * SECOND;
+
+// Upper bound of the storage used by a channel: 40 bytes of record overhead,
+// the key (prefix + uuid channel id) and the value (two accounts with 64 char ids).
+// Closed channels keep using storage as tombstones
+const MAX_CHANNEL_STORAGE_BYTES: u64 = 40 + 41 + 243;
+
+// Upper bound of the storage used by listing a channel under one of its accounts in
+// `account_channels`, 40 bytes of record overhead per record: the element of the account's
+// set (key 38, uuid channel id 40), its position (key 74, value 4), and the record of the
+// set itself (key 69 for a 64 char account id, value 80) in case it's new
+const MAX_ACCOUNT_CHANNEL_STORAGE_BYTES: u64 = 40 + 38 + 40 + 40 + 74 + 4 + 40 + 69 + 80;
+
+// Maximum number of channels read by a single `channels` view call
+const MAX_CHANNELS_PER_VIEW: usize = 100;
+
+// Gas recommended for each operation, clients should attach at least this much
+const OPEN_CHANNEL_GAS: Gas = Gas::from_tgas(40);
+const WITHDRAW_GAS: Gas = Gas::from_tgas(40);
+const TOPUP_GAS: Gas = Gas::from_tgas(15);
+const CLOSE_GAS: Gas = Gas::from_tgas(15);
+const WITHDRAW_AND_CLOSE_GAS: Gas = Gas::from_tgas(15);
+const FORCE_CLOSE_GAS: Gas = Gas::from_tgas(15);
+
+// Closed channels are kept in the state with this account id as sender and receiver
+const CLOSED_CHANNEL_ACCOUNT_ID: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
 
 #[near(serializers = [borsh, json])]
 #[derive(Clone)]
@@ -27,10 +61,7 @@ pub struct Account {
 impl Default for Account {
     fn default() -> Self {
         Self {
-            account_id: "0000000000000000000000000000000000000000000000000000000000000000"
-                .to_string()
-                .try_into()
-                .unwrap(),
+            account_id: CLOSED_CHANNEL_ACCOUNT_ID.to_string().try_into().unwrap(),
             public_key: PublicKey::from_str("ed25519:11111111111111111111111111111111").unwrap(),
         }
     }
@@ -46,6 +77,39 @@ pub struct Channel {
     force_close_started: Option<Timestamp>,
 }
 
+impl Channel {
+    /// Closed channels are replaced by a `Channel::default()` tombstone.
+    /// Clients only look at the sender (`ContractChannel::is_closed` in the cli,
+    /// `ChannelRow::is_closed` in the provider), keep them in sync.
+    fn is_closed(&self) -> bool {
+        self.sender.account_id.as_str() == CLOSED_CHANNEL_ACCOUNT_ID
+    }
+}
+
+#[near(serializers = [json])]
+pub struct OperationGas {
+    pub open_channel: Gas,
+    pub withdraw: Gas,
+    pub topup: Gas,
+    pub close: Gas,
+    pub withdraw_and_close: Gas,
+    pub force_close_start: Gas,
+    pub force_close_finish: Gas,
+}
+
+/// Requirements of the contract, so clients can fund and call it correctly
+#[near(serializers = [json])]
+pub struct ContractInfo {
+    pub hard_close_timeout: U64,
+    pub storage_byte_cost: NearToken,
+    pub channel_storage_cost: NearToken,
+    /// Paid out of the deposit of a new channel for each account it's listed under by
+    /// `channels_for_account`: the sender and the receiver, once if they are the same.
+    /// Refunded with the remaining balance on close
+    pub account_channel_storage_cost: NearToken,
+    pub recommended_gas: OperationGas,
+}
+
 #[near(serializers = [borsh, json])]
 #[derive(Clone)]
 pub struct Ownership {
@@ -59,30 +123,103 @@ pub struct Ownership {
 pub struct Contract {
     channels: LookupMap<ChannelId, Channel>,
     ownership: LazyOption<Ownership>,
+    /// Accounts that funded a channel on behalf of its sender. The remaining
+    /// balance of a sponsored channel is refunded to the sponsor on close.
+    sponsors: LookupMap<ChannelId, AccountId>,
+    /// Number of open channels opened by each account, used to enforce
+    /// `max_channels_per_sender`. A sponsored channel counts for its sponsor
+    open_channels: LookupMap<AccountId, u32>,
+    /// Limits the storage a single sender can take with channels. No limit if unset.
+    max_channels_per_sender: Option<u32>,
+    /// Minimum time between two withdrawals from the same channel. No limit if unset.
+    withdraw_cooldown: Option<Timestamp>,
+    /// Timestamp of the last withdraw of each channel, only recorded while a cooldown is set
+    last_withdrawals: LookupMap<ChannelId, Timestamp>,
+    /// Nonce expected in the next topup authorization of each channel
+    topup_nonces: LookupMap<ChannelId, u64>,
+    /// Force close timeout of the channels opened with one, other channels use
+    /// `HARD_CLOSE_TIMEOUT`
+    force_close_timeouts: LookupMap<ChannelId, Timestamp>,
+    /// Nonce of the last signed state accepted by `withdraw` or `close` on each channel
+    state_nonces: LookupMap<ChannelId, u64>,
+    /// Open channels of each account, as sender or receiver. One storage record per
+    /// channel, paid by the opener (see `MAX_ACCOUNT_CHANNEL_STORAGE_BYTES`)
+    account_channels: LookupMap<AccountId, IterableSet<ChannelId>>,
+    /// Accounts designated by the sender to receive the remaining balance on close,
+    /// instead of the sender itself
+    refund_recipients: LookupMap<ChannelId, AccountId>,
+    /// Nonce expected in the next refund recipient update of each channel
+    refund_to_nonces: LookupMap<ChannelId, u64>,
+}
+
+/// Shared with the clients (see `ppp_core`), so the signed bytes always match
+pub type SignedState = ppp_core::SignedState<Signature>;
+
+fn verify_signed_state(signed_state: &SignedState, pk: &PublicKey) -> bool {
+    let verify = |message: Vec<u8>| verify_signature(&message, &signed_state.signature, pk);
+    verify(to_vec(&signed_state.state).unwrap())
+        // Signed before the nonce existed, `accept_state_nonce` decides if it's still valid
+        || (signed_state.state.nonce == 0
+            && verify(to_vec(&ppp_core::LegacyState::from(&signed_state.state)).unwrap()))
 }
 
+/// Lets anyone add `amount` to a channel on behalf of its sender.
+/// `contract_id` and `channel_id` bind it to a single channel of this contract,
+/// `nonce` must be the channel's next topup nonce so it can be used only once
 #[near(serializers = [borsh, json])]
-struct State {
+pub struct TopupAuthorization {
+    contract_id: AccountId,
     channel_id: ChannelId,
-    spent_balance: NearToken,
+    amount: NearToken,
+    nonce: u64,
 }
 
 #[near(serializers = [borsh, json])]
-pub struct SignedState {
-    state: State,
+pub struct SignedTopupAuthorization {
+    authorization: TopupAuthorization,
     signature: Signature,
 }
 
-impl SignedState {
+impl SignedTopupAuthorization {
     fn verify(&self, pk: &PublicKey) -> bool {
-        let message = to_vec(&self.state).unwrap();
-        let pk_raw = pk.as_bytes();
-        assert!(pk_raw[0] == 0, "Invalid public key");
-        let pk_raw_32: [u8; 32] = pk_raw[1..].try_into().unwrap();
-        env::ed25519_verify(self.signature.as_ref(), message.as_ref(), &pk_raw_32)
+        verify_signature(&to_vec(&self.authorization).unwrap(), &self.signature, pk)
     }
 }
 
+/// Lets the sender change where the remaining balance of a channel is refunded on close,
+/// `None` refunds the sender again. Bound to a single channel like `TopupAuthorization`,
+/// `nonce` must be the channel's next refund nonce so it can be used only once
+#[near(serializers = [borsh, json])]
+pub struct RefundToUpdate {
+    contract_id: AccountId,
+    channel_id: ChannelId,
+    refund_to: Option<AccountId>,
+    nonce: u64,
+}
+
+#[near(serializers = [borsh, json])]
+pub struct SignedRefundToUpdate {
+    update: RefundToUpdate,
+    signature: Signature,
+}
+
+impl SignedRefundToUpdate {
+    fn verify(&self, pk: &PublicKey) -> bool {
+        verify_signature(&to_vec(&self.update).unwrap(), &self.signature, pk)
+    }
+}
+
+fn account_channel_storage_cost() -> NearToken {
+    env::storage_byte_cost().saturating_mul(MAX_ACCOUNT_CHANNEL_STORAGE_BYTES as u128)
+}
+
+fn verify_signature(message: &[u8], signature: &Signature, pk: &PublicKey) -> bool {
+    let pk_raw = pk.as_bytes();
+    assert!(pk_raw[0] == 0, "Invalid public key");
+    let pk_raw_32: [u8; 32] = pk_raw[1..].try_into().unwrap();
+    env::ed25519_verify(signature.as_ref(), message, &pk_raw_32)
+}
+
 #[near_bindgen]
 impl Contract {
     #[init]
@@ -91,50 +228,204 @@ impl Contract {
         Contract {
             channels: LookupMap::new(b"c".to_vec()),
             ownership: LazyOption::new(b"o", None),
+            sponsors: LookupMap::new(b"s".to_vec()),
+            open_channels: LookupMap::new(b"n".to_vec()),
+            max_channels_per_sender: None,
+            withdraw_cooldown: None,
+            last_withdrawals: LookupMap::new(b"w".to_vec()),
+            topup_nonces: LookupMap::new(b"t".to_vec()),
+            force_close_timeouts: LookupMap::new(b"f".to_vec()),
+            state_nonces: LookupMap::new(b"e".to_vec()),
+            account_channels: LookupMap::new(b"a".to_vec()),
+            refund_recipients: LookupMap::new(b"r".to_vec()),
+            refund_to_nonces: LookupMap::new(b"u".to_vec()),
         }
     }
 
+    /// Only the sender can open its channel, `open_sponsored_channel` opens one for
+    /// another account. `force_close_timeout` is how long a force close takes to finish
+    /// on this channel, in nanoseconds. Defaults to `HARD_CLOSE_TIMEOUT`. `refund_to`
+    /// receives the remaining balance on close instead of the sender, see `update_refund_to`
     #[payable]
-    pub fn open_channel(&mut self, channel_id: ChannelId, receiver: Account, sender: Account) {
+    pub fn open_channel(
+        &mut self,
+        channel_id: ChannelId,
+        receiver: Account,
+        sender: Account,
+        force_close_timeout: Option<U64>,
+        refund_to: Option<AccountId>,
+    ) {
+        // Otherwise anyone could use up the open channels allowed to the sender
         require!(
-            !self.channels.contains_key(&channel_id),
-            "Channel already exists"
+            env::predecessor_account_id() == sender.account_id,
+            "Only the sender can open its channel, use open_sponsored_channel instead"
+        );
+        self.insert_new_channel(channel_id.clone(), receiver, sender, None);
+
+        if let Some(refund_to) = refund_to {
+            self.refund_recipients.insert(channel_id.clone(), refund_to);
+        }
+
+        if let Some(force_close_timeout) = force_close_timeout {
+            require!(
+                force_close_timeout.0 >= MIN_FORCE_CLOSE_TIMEOUT,
+                format!(
+                    "Force close timeout must be at least {} nanoseconds",
+                    MIN_FORCE_CLOSE_TIMEOUT
+                )
+            );
+            self.force_close_timeouts
+                .insert(channel_id, force_close_timeout.0);
+        }
+    }
+
+    /// Open a channel funded by the caller (the sponsor) whose payments are
+    /// authorized by `sender`. The sender can force close the channel, but the
+    /// remaining balance is always refunded to the sponsor. Topups by anyone
+    /// are refunded to the sponsor as well.
+    #[payable]
+    pub fn open_sponsored_channel(
+        &mut self,
+        channel_id: ChannelId,
+        receiver: Account,
+        sender: Account,
+    ) {
+        self.insert_new_channel(
+            channel_id,
+            receiver,
+            sender,
+            Some(env::predecessor_account_id()),
+        );
+    }
+
+    fn insert_new_channel(
+        &mut self,
+        channel_id: ChannelId,
+        receiver: Account,
+        sender: Account,
+        sponsor: Option<AccountId>,
+    ) {
+        if let Some(channel) = self.channels.get(&channel_id) {
+            if channel.is_closed() {
+                // Distinct from an open channel, clients match it. Nothing is logged, the
+                // panic would revert it
+                env::panic_str("Channel id belongs to a closed channel. Use a new channel id.");
+            }
+            env::panic_str("Channel already exists");
+        }
+
+        // Counted for whoever opens the channel, the sponsor of a sponsored channel
+        let opener = sponsor.clone().unwrap_or_else(|| sender.account_id.clone());
+        let open_channels = self.sender_open_channels(opener.clone());
+        if let Some(max_channels) = self.max_channels_per_sender {
+            require!(
+                open_channels < max_channels,
+                format!(
+                    "{} has reached the maximum of {} open channels",
+                    opener, max_channels
+                )
+            );
+        }
+        self.open_channels.insert(opener, open_channels + 1);
+
+        // The opener pays for listing the channel under its accounts, the rest is the balance
+        let listed_accounts = if receiver.account_id == sender.account_id {
+            1
+        } else {
+            2
+        };
+        let listing_cost = account_channel_storage_cost().saturating_mul(listed_accounts);
+        require!(
+            env::attached_deposit() >= listing_cost,
+            format!(
+                "Attached deposit must cover the storage cost of {} to list the channel",
+                listing_cost.exact_amount_display()
+            )
         );
 
         let channel = Channel {
             receiver,
             sender,
-            added_balance: env::attached_deposit(),
+            added_balance: env::attached_deposit().saturating_sub(listing_cost),
             withdrawn_balance: NearToken::from_yoctonear(0),
             force_close_started: None,
         };
 
+        Event::OpenChannel {
+            channel_id: &channel_id,
+            receiver: &channel.receiver.account_id,
+            sender: &channel.sender.account_id,
+            added_balance: channel.added_balance,
+            sponsor: sponsor.as_ref(),
+        }
+        .emit();
+
+        if let Some(sponsor) = sponsor {
+            self.sponsors.insert(channel_id.clone(), sponsor);
+        }
+        self.index_account_channel(&channel.sender.account_id, &channel_id);
+        if channel.receiver.account_id != channel.sender.account_id {
+            self.index_account_channel(&channel.receiver.account_id, &channel_id);
+        }
         self.channels.insert(channel_id, channel);
     }
 
+    /// Only the receiver can withdraw, otherwise the sender could submit a state with a
+    /// high nonce and a low spent balance first, and make the receiver's states stale
     pub fn withdraw(&mut self, state: SignedState) -> Promise {
         let channel_id = state.state.channel_id.clone();
 
+        if let Some(cooldown) = self.withdraw_cooldown {
+            let now = env::block_timestamp();
+            if let Some(last_withdraw) = self.last_withdrawals.get(&channel_id) {
+                require!(
+                    now.saturating_sub(*last_withdraw) >= cooldown,
+                    "Withdraw cooldown has not elapsed yet"
+                );
+            }
+            self.last_withdrawals.insert(channel_id.clone(), now);
+        }
+
+        self.accept_state_nonce(&state);
         let channel = self.channels.get_mut(&channel_id).unwrap();
 
         require!(
-            state.verify(&channel.sender.public_key),
+            env::predecessor_account_id() == channel.receiver.account_id,
+            "Only receiver can withdraw"
+        );
+
+        require!(
+            verify_signed_state(&state, &channel.sender.public_key),
             "Invalid signature from sender"
         );
 
+        // A state above the deposit (e.g. signed expecting a topup) is rejected rather than
+        // capped, a capped withdraw would use up its nonce and the rest couldn't be
+        // withdrawn anymore. It can be withdrawn in full once the topup lands
+        require!(
+            state.state.spent_balance <= channel.added_balance,
+            "Spent exceeds deposit"
+        );
+        let withdrawable = state.state.spent_balance;
+
         require!(
-            channel.withdrawn_balance < state.state.spent_balance,
+            channel.withdrawn_balance < withdrawable,
             "No balance to withdraw"
         );
 
-        let difference = state
-            .state
-            .spent_balance
-            .saturating_sub(channel.withdrawn_balance);
+        let difference = withdrawable.saturating_sub(channel.withdrawn_balance);
 
         let receiver = channel.receiver.account_id.clone();
 
-        channel.withdrawn_balance = state.state.spent_balance;
+        channel.withdrawn_balance = withdrawable;
+
+        Event::Withdraw {
+            channel_id: &channel_id,
+            receiver: &receiver,
+            amount: difference,
+            withdrawn_balance: withdrawable,
+        }
+        .emit();
 
         let after_fee = self.owner_collect_fee(difference);
 
@@ -144,19 +435,111 @@ impl Contract {
     #[payable]
     pub fn topup(&mut self, channel_id: ChannelId) {
         let channel = self.channels.get_mut(&channel_id).unwrap();
+        require!(!channel.is_closed(), "Channel is closed.");
         require!(channel.force_close_started.is_none(), "Channel is closing.");
         let amount = env::attached_deposit();
         channel.added_balance = channel.added_balance.saturating_add(amount);
+
+        Event::Topup {
+            channel_id: &channel_id,
+            amount,
+            added_balance: channel.added_balance,
+        }
+        .emit();
+    }
+
+    /// Topup authorized by the sender, submitted with the deposit by anyone (e.g. a
+    /// sponsor). The funds belong to the channel like any other topup, refunded on close
+    /// like the rest of the balance (see `refund_to`)
+    #[payable]
+    pub fn topup_with_authorization(&mut self, authorization: SignedTopupAuthorization) {
+        let topup = &authorization.authorization;
+        require!(
+            topup.contract_id == env::current_account_id(),
+            "Authorization is for another contract"
+        );
+
+        let channel_id = topup.channel_id.clone();
+        let nonce = self.topup_nonce(channel_id.clone());
+        require!(topup.nonce == nonce, "Invalid topup nonce");
+
+        let channel = self.channels.get_mut(&channel_id).unwrap();
+        require!(!channel.is_closed(), "Channel is closed.");
+        require!(channel.force_close_started.is_none(), "Channel is closing.");
+        require!(
+            authorization.verify(&channel.sender.public_key),
+            "Invalid signature from sender"
+        );
+        require!(
+            env::attached_deposit() == topup.amount,
+            "Attached deposit must match the authorized amount"
+        );
+
+        channel.added_balance = channel.added_balance.saturating_add(topup.amount);
+
+        Event::Topup {
+            channel_id: &channel_id,
+            amount: topup.amount,
+            added_balance: channel.added_balance,
+        }
+        .emit();
+
+        self.topup_nonces.insert(channel_id, nonce + 1);
+    }
+
+    /// Change the refund recipient of a channel with an update signed by its sender, so
+    /// the refund can be moved away from a compromised sender account. Anyone can submit
+    /// it. The remaining balance of a sponsored channel always goes back to the sponsor
+    pub fn update_refund_to(&mut self, update: SignedRefundToUpdate) {
+        let refund_to_update = &update.update;
+        require!(
+            refund_to_update.contract_id == env::current_account_id(),
+            "Update is for another contract"
+        );
+
+        let channel_id = refund_to_update.channel_id.clone();
+        let nonce = self.refund_to_nonce(channel_id.clone());
+        require!(refund_to_update.nonce == nonce, "Invalid refund nonce");
+
+        let channel = self.channels.get(&channel_id).unwrap();
+        require!(!channel.is_closed(), "Channel is closed.");
+        require!(
+            update.verify(&channel.sender.public_key),
+            "Invalid signature from sender"
+        );
+        require!(
+            !self.sponsors.contains_key(&channel_id),
+            "Sponsored channels are refunded to the sponsor"
+        );
+
+        match &refund_to_update.refund_to {
+            Some(refund_to) => {
+                self.refund_recipients
+                    .insert(channel_id.clone(), refund_to.clone());
+            }
+            None => {
+                self.refund_recipients.remove(&channel_id);
+            }
+        }
+
+        Event::RefundToUpdate {
+            channel_id: &channel_id,
+            refund_to: refund_to_update.refund_to.as_ref(),
+        }
+        .emit();
+
+        self.refund_to_nonces.insert(channel_id, nonce + 1);
     }
 
     pub fn close(&mut self, state: SignedState) -> Promise {
         let channel_id = state.state.channel_id.clone();
 
+        self.accept_state_nonce(&state);
         let channel = self.channels.get_mut(&channel_id).unwrap();
 
         // Anyone can close the channel, as long as it has a signature from the receiver
         require!(
-            state.verify(&channel.receiver.public_key),
+            verify_signed_state(&state, &channel.receiver.public_key),
             "Invalid signature from receiver"
         );
 
@@ -170,16 +553,33 @@ impl Contract {
             .saturating_sub(channel.withdrawn_balance);
 
         let sender = channel.sender.account_id.clone();
+        let receiver = channel.receiver.account_id.clone();
+        self.release_open_channel(&channel_id, &sender);
+        let listing_cost = self.unindex_channel(&sender, &receiver, &channel_id);
+        let remaining_balance = remaining_balance.saturating_add(listing_cost);
+        let refund_to = self.remove_refund_to(&channel_id, sender);
+        self.last_withdrawals.remove(&channel_id);
+        self.topup_nonces.remove(&channel_id);
+        self.force_close_timeouts.remove(&channel_id);
+        self.state_nonces.remove(&channel_id);
 
         // Remove channel from the state
         //
         // This is equivalent to remove the channel, though we keep it in the state
         // so no new channel with the same id is created in the future. If the same
         // channel is reused (either provider or user could trick each other) by
-        // reusing an old channel id and replaying old messages.
-        self.channels.insert(channel_id, Default::default());
+        // reusing an old channel id and replaying old messages. The state nonces
+        // start over with the channel, so they don't prevent this on their own.
+        self.channels.insert(channel_id.clone(), Default::default());
+
+        Event::Close {
+            channel_id: &channel_id,
+            refund_to: &refund_to,
+            refund: remaining_balance,
+        }
+        .emit();
 
-        Promise::new(sender).transfer(remaining_balance)
+        Promise::new(refund_to).transfer(remaining_balance)
     }
 
     pub fn withdraw_and_close(&mut self, state: SignedState, close: SignedState) -> Promise {
@@ -199,26 +599,75 @@ impl Contract {
             "Only sender can start a force close action"
         );
 
-        channel.force_close_started = Some(env::block_timestamp());
+        let started_at = env::block_timestamp();
+        channel.force_close_started = Some(started_at);
+
+        Event::ForceCloseStart {
+            channel_id: &channel_id,
+            sender: &channel.sender.account_id,
+            started_at,
+        }
+        .emit();
+    }
+
+    /// Abort a force close started by mistake, the channel can be used (and topped
+    /// up) again as if the force close was never started
+    pub fn cancel_force_close(&mut self, channel_id: ChannelId) {
+        let channel = self.channels.get_mut(&channel_id).unwrap();
+
+        require!(
+            env::predecessor_account_id() == channel.sender.account_id,
+            "Only sender can cancel a force close action"
+        );
+
+        require!(
+            channel.force_close_started.is_some(),
+            "Channel is not closing."
+        );
+
+        channel.force_close_started = None;
+
+        Event::ForceCloseCancel {
+            channel_id: &channel_id,
+            sender: &channel.sender.account_id,
+        }
+        .emit();
     }
 
     pub fn force_close_finish(&mut self, channel_id: ChannelId) -> Promise {
+        let force_close_timeout = self.force_close_timeout(channel_id.clone()).0;
         let channel = self.channels.get_mut(&channel_id).unwrap();
 
         match channel.force_close_started {
             Some(start_event) => {
                 let difference = env::block_timestamp() - start_event;
-                if difference >= HARD_CLOSE_TIMEOUT {
+                if difference >= force_close_timeout {
                     let remaining_balance = channel
                         .added_balance
                         .saturating_sub(channel.withdrawn_balance);
 
                     let sender = channel.sender.account_id.clone();
+                    let receiver = channel.receiver.account_id.clone();
+                    self.release_open_channel(&channel_id, &sender);
+                    let listing_cost = self.unindex_channel(&sender, &receiver, &channel_id);
+                    let remaining_balance = remaining_balance.saturating_add(listing_cost);
+                    let refund_to = self.remove_refund_to(&channel_id, sender);
+                    self.last_withdrawals.remove(&channel_id);
+                    self.topup_nonces.remove(&channel_id);
+                    self.force_close_timeouts.remove(&channel_id);
+                    self.state_nonces.remove(&channel_id);
 
                     // Remove channel from the state [See message above]
-                    self.channels.insert(channel_id, Default::default());
+                    self.channels.insert(channel_id.clone(), Default::default());
 
-                    Promise::new(sender).transfer(remaining_balance)
+                    Event::ForceCloseFinish {
+                        channel_id: &channel_id,
+                        refund_to: &refund_to,
+                        refund: remaining_balance,
+                    }
+                    .emit();
+
+                    Promise::new(refund_to).transfer(remaining_balance)
                 } else {
                     env::panic_str("Channel can't be closed yet. Not enough time has passed.");
                 }
@@ -232,6 +681,204 @@ impl Contract {
     pub fn channel(&self, channel_id: ChannelId) -> Option<Channel> {
         self.channels.get(&channel_id).cloned()
     }
+
+    /// Read several channels at once, in the same order as `channel_ids`
+    pub fn channels(&self, channel_ids: Vec<ChannelId>) -> Vec<Option<Channel>> {
+        require!(
+            channel_ids.len() <= MAX_CHANNELS_PER_VIEW,
+            format!("At most {} channels per call", MAX_CHANNELS_PER_VIEW)
+        );
+        channel_ids
+            .iter()
+            .map(|channel_id| self.channels.get(channel_id).cloned())
+            .collect()
+    }
+
+    /// Open channels where `account_id` is the sender or the receiver, at most `limit`
+    /// (up to `MAX_CHANNELS_PER_VIEW`) starting at `from_index`, oldest first. Closing a
+    /// channel moves the newest one of the account to its place
+    pub fn channels_for_account(
+        &self,
+        account_id: AccountId,
+        from_index: u64,
+        limit: u64,
+    ) -> Vec<(ChannelId, Channel)> {
+        let limit = limit.min(MAX_CHANNELS_PER_VIEW as u64) as usize;
+        self.account_channels
+            .get(&account_id)
+            .map(|channel_ids| {
+                channel_ids
+                    .iter()
+                    .skip(usize::try_from(from_index).unwrap_or(usize::MAX))
+                    .take(limit)
+                    .filter_map(|channel_id| {
+                        let channel = self.channels.get(channel_id)?;
+                        Some((channel_id.clone(), channel.clone()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn contract_info(&self) -> ContractInfo {
+        let storage_byte_cost = env::storage_byte_cost();
+        ContractInfo {
+            hard_close_timeout: U64(HARD_CLOSE_TIMEOUT),
+            storage_byte_cost,
+            channel_storage_cost: storage_byte_cost
+                .saturating_mul(MAX_CHANNEL_STORAGE_BYTES as u128),
+            account_channel_storage_cost: account_channel_storage_cost(),
+            recommended_gas: OperationGas {
+                open_channel: OPEN_CHANNEL_GAS,
+                withdraw: WITHDRAW_GAS,
+                topup: TOPUP_GAS,
+                close: CLOSE_GAS,
+                withdraw_and_close: WITHDRAW_AND_CLOSE_GAS,
+                force_close_start: FORCE_CLOSE_GAS,
+                force_close_finish: FORCE_CLOSE_GAS,
+            },
+        }
+    }
+
+    pub fn sender_open_channels(&self, account_id: AccountId) -> u32 {
+        self.open_channels.get(&account_id).copied().unwrap_or(0)
+    }
+
+    pub fn max_channels_per_sender(&self) -> Option<u32> {
+        self.max_channels_per_sender
+    }
+
+    pub fn withdraw_cooldown(&self) -> Option<U64> {
+        self.withdraw_cooldown.map(U64)
+    }
+
+    /// Nonce the next topup authorization of the channel must carry
+    pub fn topup_nonce(&self, channel_id: ChannelId) -> u64 {
+        self.topup_nonces.get(&channel_id).copied().unwrap_or(0)
+    }
+
+    /// How long a force close of the channel takes to finish
+    pub fn force_close_timeout(&self, channel_id: ChannelId) -> U64 {
+        U64(self
+            .force_close_timeouts
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(HARD_CLOSE_TIMEOUT))
+    }
+
+    /// Nonce of the last signed state accepted on the channel, the next one must be higher
+    pub fn state_nonce(&self, channel_id: ChannelId) -> u64 {
+        self.state_nonces.get(&channel_id).copied().unwrap_or(0)
+    }
+
+    // Rejects replays of a state, or of any state signed before it. States signed before
+    // the nonce existed (nonce 0) are accepted until the channel accepts one with a nonce:
+    // replaying them pays nothing twice, the withdrawn balance only grows
+    fn accept_state_nonce(&mut self, state: &SignedState) {
+        let channel_id = &state.state.channel_id;
+        if state.state.nonce == 0 && !self.state_nonces.contains_key(channel_id) {
+            return;
+        }
+        require!(
+            state.state.nonce > self.state_nonce(channel_id.clone()),
+            "Signed state is older than the last accepted one"
+        );
+        self.state_nonces
+            .insert(channel_id.clone(), state.state.nonce);
+    }
+
+    /// When the channel was last withdrawn from, if a withdraw cooldown applies to it
+    pub fn last_withdraw(&self, channel_id: ChannelId) -> Option<U64> {
+        self.last_withdrawals.get(&channel_id).copied().map(U64)
+    }
+
+    fn index_account_channel(&mut self, account_id: &AccountId, channel_id: &ChannelId) {
+        self.account_channels
+            .entry(account_id.clone())
+            .or_insert_with(|| {
+                // Hashed, so the prefix of an account is never the start of another's
+                let prefix = [b"A".as_slice(), &env::sha256_array(account_id.as_bytes())];
+                IterableSet::new(prefix.concat())
+            })
+            .insert(channel_id.clone());
+    }
+
+    // Returns whether the channel was listed under the account
+    fn unindex_account_channel(&mut self, account_id: &AccountId, channel_id: &ChannelId) -> bool {
+        let Some(channel_ids) = self.account_channels.get_mut(account_id) else {
+            return false;
+        };
+        let listed = channel_ids.remove(channel_id);
+        if channel_ids.is_empty() {
+            self.account_channels.remove(account_id);
+        }
+        listed
+    }
+
+    // Unlist a closing channel from its accounts, returns the listing cost paid on open to
+    // refund. Channels opened before the index existed were neither listed nor charged
+    fn unindex_channel(
+        &mut self,
+        sender: &AccountId,
+        receiver: &AccountId,
+        channel_id: &ChannelId,
+    ) -> NearToken {
+        let listed_accounts = [sender, receiver]
+            .into_iter()
+            .filter(|account_id| self.unindex_account_channel(account_id, channel_id))
+            .count();
+        account_channel_storage_cost().saturating_mul(listed_accounts as u128)
+    }
+
+    // Called before the sponsor of the closing channel is forgotten, it counts for them
+    fn release_open_channel(&mut self, channel_id: &ChannelId, sender: &AccountId) {
+        let opener = self
+            .sponsors
+            .get(channel_id)
+            .cloned()
+            .unwrap_or_else(|| sender.clone());
+        // Channels opened before the counter existed aren't counted, hence the saturation
+        let open_channels = self.sender_open_channels(opener.clone()).saturating_sub(1);
+        if open_channels == 0 {
+            self.open_channels.remove(&opener);
+        } else {
+            self.open_channels.insert(opener, open_channels);
+        }
+    }
+
+    /// The account funding the channel, if it was opened as a sponsored channel
+    pub fn sponsor(&self, channel_id: ChannelId) -> Option<AccountId> {
+        self.sponsors.get(&channel_id).cloned()
+    }
+
+    /// Account the remaining balance of an open channel is refunded to on close: the
+    /// refund recipient set by the sender, the sponsor or the sender
+    pub fn refund_to(&self, channel_id: ChannelId) -> Option<AccountId> {
+        let channel = self.channels.get(&channel_id)?;
+        if channel.is_closed() {
+            return None;
+        }
+        self.refund_recipients
+            .get(&channel_id)
+            .or_else(|| self.sponsors.get(&channel_id))
+            .cloned()
+            .or_else(|| Some(channel.sender.account_id.clone()))
+    }
+
+    /// Nonce the next refund recipient update of the channel must carry
+    pub fn refund_to_nonce(&self, channel_id: ChannelId) -> u64 {
+        self.refund_to_nonces.get(&channel_id).copied().unwrap_or(0)
+    }
+
+    // Forget where a closing channel is refunded, returns the account receiving the refund
+    fn remove_refund_to(&mut self, channel_id: &ChannelId, sender: AccountId) -> AccountId {
+        let sponsor = self.sponsors.remove(channel_id);
+        self.refund_to_nonces.remove(channel_id);
+        self.refund_recipients
+            .remove(channel_id)
+            .or(sponsor)
+            .unwrap_or(sender)
+    }
 }
 
 // Owner methods
@@ -273,6 +920,17 @@ impl Contract {
         }
     }
 
+    #[private]
+    pub fn set_max_channels_per_sender(&mut self, max_channels_per_sender: Option<u32>) {
+        self.max_channels_per_sender = max_channels_per_sender;
+    }
+
+    /// Bound how often a receiver can withdraw from a channel, in nanoseconds
+    #[private]
+    pub fn set_withdraw_cooldown(&mut self, withdraw_cooldown: Option<U64>) {
+        self.withdraw_cooldown = withdraw_cooldown.map(|withdraw_cooldown| withdraw_cooldown.0);
+    }
+
     pub fn owner_withdraw(&mut self) -> Promise {
         let Ownership {
             owner,
@@ -310,16 +968,63 @@ impl Contract {
     #[private]
     #[init(ignore_state)]
     pub fn migrate() -> Self {
+        // Layouts deployed before this version, the second one only appends `ownership`
+        #[derive(borsh::BorshDeserialize)]
+        struct StateV0 {
+            channels: LookupMap<ChannelId, Channel>,
+        }
+
         #[derive(borsh::BorshDeserialize)]
-        struct OldContract {
+        struct StateV1 {
             channels: LookupMap<ChannelId, Channel>,
+            ownership: LazyOption<Ownership>,
         }
 
-        let contract = env::state_read::<OldContract>().unwrap();
+        let state = env::storage_read(b"STATE")
+            .unwrap_or_else(|| env::panic_str("No contract state to migrate"));
+
+        // `from_slice` fails unless every byte is read, so a layout only matches its own state
+        if let Ok(contract) = borsh::from_slice::<Self>(&state) {
+            // Already the current layout, nothing to migrate
+            return contract;
+        }
+        let StateV1 {
+            channels,
+            ownership,
+        } = borsh::from_slice::<StateV1>(&state)
+            .ok()
+            .or_else(|| {
+                borsh::from_slice::<StateV0>(&state)
+                    .ok()
+                    .map(|StateV0 { channels }| StateV1 {
+                        channels,
+                        ownership: LazyOption::new(b"o", None),
+                    })
+            })
+            .unwrap_or_else(|| env::panic_str("Unknown contract state layout"));
 
         Self {
-            channels: contract.channels,
-            ownership: LazyOption::new(b"o", None),
+            channels,
+            ownership,
+            sponsors: LookupMap::new(b"s".to_vec()),
+            // Senders of existing channels start counting from 0, closing one of them
+            // saturates at 0
+            open_channels: LookupMap::new(b"n".to_vec()),
+            max_channels_per_sender: None,
+            withdraw_cooldown: None,
+            last_withdrawals: LookupMap::new(b"w".to_vec()),
+            topup_nonces: LookupMap::new(b"t".to_vec()),
+            // Existing channels have no entry, they keep the `HARD_CLOSE_TIMEOUT` default
+            force_close_timeouts: LookupMap::new(b"f".to_vec()),
+            // States signed before the nonce existed keep verifying with nonce 0, until
+            // the channel accepts a state with a nonce
+            state_nonces: LookupMap::new(b"e".to_vec()),
+            // Channels opened before the index existed aren't listed by
+            // `channels_for_account`, nor charged for it
+            account_channels: LookupMap::new(b"a".to_vec()),
+            // Existing channels keep refunding their sender
+            refund_recipients: LookupMap::new(b"r".to_vec()),
+            refund_to_nonces: LookupMap::new(b"u".to_vec()),
         }
     }
 }
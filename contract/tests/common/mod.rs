@@ -0,0 +1,207 @@
+// Shared by the integration test binaries, not every binary uses every helper
+#![allow(dead_code)]
+
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::{testing_env, AccountId, NearToken};
+use payment_channel::{
+    Account, Contract, SignedRefundToUpdate, SignedState, SignedTopupAuthorization,
+};
+use serde_json::json;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every state signed by `Party::sign` is newer than the ones signed before it
+static NEXT_NONCE: AtomicU64 = AtomicU64::new(1);
+
+#[derive(borsh::BorshSerialize)]
+pub struct TopupAuthorization {
+    pub contract_id: String,
+    pub channel_id: String,
+    pub amount: u128,
+    pub nonce: u64,
+}
+
+#[derive(borsh::BorshSerialize)]
+pub struct RefundToUpdate {
+    pub contract_id: String,
+    pub channel_id: String,
+    pub refund_to: Option<String>,
+    pub nonce: u64,
+}
+
+pub struct Party {
+    pub account_id: AccountId,
+    pub secret_key: SecretKey,
+}
+
+impl Party {
+    pub fn new(account_id: &str) -> Self {
+        Self {
+            account_id: account_id.parse().unwrap(),
+            secret_key: SecretKey::from_seed(KeyType::ED25519, account_id),
+        }
+    }
+
+    pub fn account(&self) -> Account {
+        serde_json::from_value(json!({
+            "account_id": self.account_id,
+            "public_key": self.secret_key.public_key().to_string(),
+        }))
+        .unwrap()
+    }
+
+    pub fn sign(&self, channel_id: &str, spent_balance: NearToken) -> SignedState {
+        let nonce = NEXT_NONCE.fetch_add(1, Ordering::Relaxed);
+        self.sign_with_nonce(channel_id, spent_balance, nonce)
+    }
+
+    pub fn sign_with_nonce(
+        &self,
+        channel_id: &str,
+        spent_balance: NearToken,
+        nonce: u64,
+    ) -> SignedState {
+        let state = ppp_core::State {
+            channel_id: channel_id.to_string(),
+            spent_balance,
+            nonce,
+        };
+        let signature = self.secret_key.sign(&borsh::to_vec(&state).unwrap());
+        serde_json::from_value(json!({
+            "state": {
+                "channel_id": channel_id,
+                "spent_balance": spent_balance,
+                "nonce": nonce,
+            },
+            "signature": signature.to_string(),
+        }))
+        .unwrap()
+    }
+
+    // A state signed before the nonce existed, it decodes with nonce 0
+    pub fn sign_legacy(&self, channel_id: &str, spent_balance: NearToken) -> SignedState {
+        let state = ppp_core::LegacyState {
+            channel_id: channel_id.to_string(),
+            spent_balance,
+        };
+        let signature = self.secret_key.sign(&borsh::to_vec(&state).unwrap());
+        serde_json::from_value(json!({
+            "state": {
+                "channel_id": channel_id,
+                "spent_balance": spent_balance,
+                "nonce": 0,
+            },
+            "signature": signature.to_string(),
+        }))
+        .unwrap()
+    }
+
+    pub fn sign_topup(
+        &self,
+        channel_id: &str,
+        amount: NearToken,
+        nonce: u64,
+    ) -> SignedTopupAuthorization {
+        let authorization = TopupAuthorization {
+            contract_id: near_sdk::env::current_account_id().to_string(),
+            channel_id: channel_id.to_string(),
+            amount: amount.as_yoctonear(),
+            nonce,
+        };
+        let signature = self
+            .secret_key
+            .sign(&borsh::to_vec(&authorization).unwrap());
+        serde_json::from_value(json!({
+            "authorization": {
+                "contract_id": authorization.contract_id,
+                "channel_id": channel_id,
+                "amount": amount,
+                "nonce": nonce,
+            },
+            "signature": signature.to_string(),
+        }))
+        .unwrap()
+    }
+
+    pub fn sign_refund_to(
+        &self,
+        channel_id: &str,
+        refund_to: Option<&AccountId>,
+        nonce: u64,
+    ) -> SignedRefundToUpdate {
+        let update = RefundToUpdate {
+            contract_id: near_sdk::env::current_account_id().to_string(),
+            channel_id: channel_id.to_string(),
+            refund_to: refund_to.map(|account_id| account_id.to_string()),
+            nonce,
+        };
+        let signature = self.secret_key.sign(&borsh::to_vec(&update).unwrap());
+        serde_json::from_value(json!({
+            "update": {
+                "contract_id": update.contract_id,
+                "channel_id": channel_id,
+                "refund_to": refund_to,
+                "nonce": nonce,
+            },
+            "signature": signature.to_string(),
+        }))
+        .unwrap()
+    }
+}
+
+pub fn set_context(predecessor: &AccountId, deposit: NearToken, block_timestamp: u64) {
+    testing_env!(VMContextBuilder::new()
+        .predecessor_account_id(predecessor.clone())
+        .attached_deposit(deposit)
+        .block_timestamp(block_timestamp)
+        .build());
+}
+
+// Copied from the contract code
+pub const MAX_ACCOUNT_CHANNEL_STORAGE_BYTES: u128 = 425;
+
+// Paid by the opener for listing a channel under two accounts, refunded on close
+pub fn listing_cost() -> NearToken {
+    near_sdk::env::storage_byte_cost().saturating_mul(2 * MAX_ACCOUNT_CHANNEL_STORAGE_BYTES)
+}
+
+// Deposit opening a channel between two accounts with `added_balance`, the opener also
+// pays for listing the channel under both accounts
+pub fn opening_deposit(added_balance: NearToken) -> NearToken {
+    added_balance.saturating_add(listing_cost())
+}
+
+// Contract with one open channel of `deposit` from `sender.near` to `receiver.near`
+pub fn setup(channel_id: &str, deposit: NearToken) -> (Contract, Party, Party) {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+
+    set_context(&sender.account_id, opening_deposit(deposit), 0);
+    let mut contract = Contract::init();
+    contract.open_channel(
+        channel_id.to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+
+    (contract, receiver, sender)
+}
+
+pub fn channel_json(contract: &Contract, channel_id: &str) -> serde_json::Value {
+    serde_json::to_value(contract.channel(channel_id.to_string()).unwrap()).unwrap()
+}
+
+// Total amount transferred by the receipts created so far, per receiver
+pub fn transferred_to(account_id: &AccountId) -> NearToken {
+    near_sdk::test_utils::get_created_receipts()
+        .iter()
+        .filter(|receipt| &receipt.receiver_id == account_id)
+        .flat_map(|receipt| receipt.actions.iter())
+        .filter_map(|action| match action {
+            near_sdk::mock::MockAction::Transfer { deposit, .. } => Some(*deposit),
+            _ => None,
+        })
+        .fold(NearToken::from_yoctonear(0), NearToken::saturating_add)
+}
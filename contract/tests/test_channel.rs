@@ -0,0 +1,625 @@
+mod common;
+
+use common::{
+    channel_json, opening_deposit, set_context, setup, transferred_to, Party,
+    MAX_ACCOUNT_CHANNEL_STORAGE_BYTES,
+};
+use near_sdk::test_utils::get_created_receipts;
+use near_sdk::{AccountId, NearToken};
+use payment_channel::Contract;
+use serde_json::json;
+
+#[test]
+fn test_open_channel() {
+    let (contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let channel = channel_json(&contract, "channel");
+
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(1)));
+    assert_eq!(channel["sender"]["account_id"], json!(sender.account_id));
+}
+
+#[test]
+#[should_panic(expected = "Channel already exists")]
+fn test_open_existing_channel() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+}
+
+#[test]
+#[should_panic(expected = "Channel id belongs to a closed channel. Use a new channel id.")]
+fn test_reopen_closed_channel() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn test_sponsored_channel_refunds_sponsor() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(
+        &sponsor.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_sponsored_channel("channel".to_string(), receiver.account(), sender.account());
+    assert_eq!(
+        contract.sponsor("channel".to_string()),
+        Some(sponsor.account_id.clone())
+    );
+
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receiver_id, sponsor.account_id);
+    assert_eq!(contract.sponsor("channel".to_string()), None);
+}
+
+#[test]
+fn test_sponsored_channel_force_close_refunds_sponsor() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(
+        &sponsor.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_sponsored_channel("channel".to_string(), receiver.account(), sender.account());
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.force_close_start("channel".to_string());
+
+    set_context(
+        &sender.account_id,
+        NearToken::from_yoctonear(0),
+        7 * 24 * 60 * 60 * 1_000_000_000,
+    );
+    contract.force_close_finish("channel".to_string());
+
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receiver_id, sponsor.account_id);
+}
+
+#[test]
+fn test_unsponsored_channel_refunds_sender() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    assert_eq!(contract.sponsor("channel".to_string()), None);
+
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receiver_id, sender.account_id);
+}
+
+#[test]
+fn test_contract_info() {
+    let (contract, _, _) = setup("channel", NearToken::from_near(1));
+    let info = serde_json::to_value(contract.contract_info()).unwrap();
+
+    assert_eq!(info["hard_close_timeout"], json!("604800000000000"));
+    assert_eq!(
+        info["channel_storage_cost"],
+        json!(near_sdk::env::storage_byte_cost().saturating_mul(324))
+    );
+    assert_eq!(
+        info["account_channel_storage_cost"],
+        json!(near_sdk::env::storage_byte_cost().saturating_mul(MAX_ACCOUNT_CHANNEL_STORAGE_BYTES))
+    );
+    assert_eq!(
+        info["recommended_gas"]["open_channel"],
+        json!(near_sdk::Gas::from_tgas(40))
+    );
+    assert_eq!(
+        info["recommended_gas"]["close"],
+        json!(near_sdk::Gas::from_tgas(15))
+    );
+}
+
+#[test]
+fn test_channels_batch() {
+    let (mut contract, receiver, sender) = setup("first", NearToken::from_near(1));
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(2)),
+        0,
+    );
+    contract.open_channel(
+        "second".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+
+    let channels = contract.channels(vec![
+        "second".to_string(),
+        "missing".to_string(),
+        "first".to_string(),
+    ]);
+
+    assert_eq!(channels.len(), 3);
+    assert_eq!(
+        serde_json::to_value(&channels[0]).unwrap()["added_balance"],
+        json!(NearToken::from_near(2))
+    );
+    assert!(channels[1].is_none());
+    assert_eq!(
+        serde_json::to_value(&channels[2]).unwrap()["added_balance"],
+        json!(NearToken::from_near(1))
+    );
+}
+
+#[test]
+#[should_panic(expected = "At most 100 channels per call")]
+fn test_channels_batch_too_large() {
+    let (contract, _, _) = setup("channel", NearToken::from_near(1));
+    contract.channels((0..101).map(|i| i.to_string()).collect());
+}
+
+fn account_channel_ids(
+    contract: &Contract,
+    account_id: &AccountId,
+    from_index: u64,
+    limit: u64,
+) -> Vec<String> {
+    contract
+        .channels_for_account(account_id.clone(), from_index, limit)
+        .into_iter()
+        .map(|(channel_id, _)| channel_id)
+        .collect()
+}
+
+#[test]
+fn test_channels_for_account() {
+    let (mut contract, receiver, sender) = setup("first", NearToken::from_near(1));
+    let other = Party::new("other.near");
+
+    // `sender` is the receiver of this one
+    set_context(
+        &other.account_id,
+        opening_deposit(NearToken::from_near(2)),
+        0,
+    );
+    contract.open_channel(
+        "second".to_string(),
+        sender.account(),
+        other.account(),
+        None,
+        None,
+    );
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(3)),
+        0,
+    );
+    contract.open_channel(
+        "third".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+
+    let channels = contract.channels_for_account(sender.account_id.clone(), 0, 10);
+    assert_eq!(channels.len(), 3);
+    assert_eq!(channels[1].0, "second");
+    assert_eq!(
+        serde_json::to_value(&channels[1].1).unwrap()["added_balance"],
+        json!(NearToken::from_near(2))
+    );
+    assert_eq!(
+        account_channel_ids(&contract, &receiver.account_id, 0, 10),
+        vec!["first", "third"]
+    );
+    assert_eq!(
+        account_channel_ids(&contract, &other.account_id, 0, 10),
+        vec!["second"]
+    );
+
+    // Pages
+    assert_eq!(
+        account_channel_ids(&contract, &sender.account_id, 1, 1),
+        vec!["second"]
+    );
+    assert_eq!(
+        account_channel_ids(&contract, &sender.account_id, 2, 10),
+        vec!["third"]
+    );
+    assert!(account_channel_ids(&contract, &sender.account_id, 3, 10).is_empty());
+    assert!(
+        account_channel_ids(&contract, &Party::new("nobody.near").account_id, 0, 10).is_empty()
+    );
+}
+
+#[test]
+fn test_channels_for_account_after_close() {
+    let (mut contract, receiver, sender) = setup("first", NearToken::from_near(1));
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "second".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+
+    contract.close(receiver.sign("first", NearToken::from_yoctonear(0)));
+    assert_eq!(
+        account_channel_ids(&contract, &sender.account_id, 0, 10),
+        vec!["second"]
+    );
+    assert_eq!(
+        account_channel_ids(&contract, &receiver.account_id, 0, 10),
+        vec!["second"]
+    );
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.force_close_start("second".to_string());
+    set_context(
+        &sender.account_id,
+        NearToken::from_yoctonear(0),
+        HARD_CLOSE_TIMEOUT,
+    );
+    contract.force_close_finish("second".to_string());
+    assert!(account_channel_ids(&contract, &sender.account_id, 0, 10).is_empty());
+    assert!(account_channel_ids(&contract, &receiver.account_id, 0, 10).is_empty());
+}
+
+#[test]
+fn test_opener_pays_for_listing_the_channel() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let listing_cost =
+        near_sdk::env::storage_byte_cost().saturating_mul(MAX_ACCOUNT_CHANNEL_STORAGE_BYTES);
+
+    set_context(&sender.account_id, NearToken::from_near(1), 0);
+    let mut contract = Contract::init();
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    assert_eq!(
+        channel_json(&contract, "channel")["added_balance"],
+        json!(NearToken::from_near(1).saturating_sub(listing_cost.saturating_mul(2)))
+    );
+
+    // Listed once when the sender is the receiver
+    contract.open_channel(
+        "self".to_string(),
+        sender.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    assert_eq!(
+        channel_json(&contract, "self")["added_balance"],
+        json!(NearToken::from_near(1).saturating_sub(listing_cost))
+    );
+}
+
+// Open a channel to `receiver` and a channel to the sender itself, depositing 1 NEAR in each
+fn open_listed_channels(receiver: &Party, sender: &Party) -> Contract {
+    set_context(&sender.account_id, NearToken::from_near(1), 0);
+    let mut contract = Contract::init();
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    contract.open_channel(
+        "self".to_string(),
+        sender.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    contract
+}
+
+#[test]
+fn test_close_refunds_listing_cost() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let mut contract = open_listed_channels(&receiver, &sender);
+
+    // The whole deposit comes back, listing cost included
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+    assert_eq!(transferred_to(&sender.account_id), NearToken::from_near(1));
+
+    contract.close(sender.sign("self", NearToken::from_yoctonear(0)));
+    assert_eq!(transferred_to(&sender.account_id), NearToken::from_near(2));
+}
+
+#[test]
+fn test_force_close_finish_refunds_listing_cost() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let mut contract = open_listed_channels(&receiver, &sender);
+
+    contract.force_close_start("channel".to_string());
+    contract.force_close_start("self".to_string());
+    set_context(
+        &sender.account_id,
+        NearToken::from_yoctonear(0),
+        7 * 24 * 60 * 60 * 1_000_000_000,
+    );
+    contract.force_close_finish("channel".to_string());
+    contract.force_close_finish("self".to_string());
+
+    assert_eq!(transferred_to(&sender.account_id), NearToken::from_near(2));
+}
+
+#[test]
+#[should_panic(expected = "Attached deposit must cover the storage cost")]
+fn test_open_channel_below_listing_cost() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(1), 0);
+    let mut contract = Contract::init();
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn test_channels_for_account_limit_is_capped() {
+    let (mut contract, receiver, sender) = setup("channel-0", NearToken::from_near(1));
+    for i in 1..=100 {
+        set_context(
+            &sender.account_id,
+            opening_deposit(NearToken::from_near(1)),
+            0,
+        );
+        contract.open_channel(
+            format!("channel-{}", i),
+            receiver.account(),
+            sender.account(),
+            None,
+            None,
+        );
+    }
+
+    assert_eq!(
+        account_channel_ids(&contract, &sender.account_id, 0, 1000).len(),
+        100
+    );
+}
+
+fn set_max_channels_per_sender(contract: &mut Contract, max_channels: u32) {
+    // Private methods must be called by the contract account itself
+    let contract_account: AccountId = "alice.near".parse().unwrap();
+    set_context(&contract_account, NearToken::from_yoctonear(0), 0);
+    contract.set_max_channels_per_sender(Some(max_channels));
+}
+
+#[test]
+fn test_open_channels_up_to_cap() {
+    let (mut contract, receiver, sender) = setup("channel-0", NearToken::from_near(1));
+    set_max_channels_per_sender(&mut contract, 3);
+
+    for i in 1..3 {
+        set_context(
+            &sender.account_id,
+            opening_deposit(NearToken::from_near(1)),
+            0,
+        );
+        contract.open_channel(
+            format!("channel-{}", i),
+            receiver.account(),
+            sender.account(),
+            None,
+            None,
+        );
+    }
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 3);
+
+    // Closing a channel frees a slot
+    contract.close(receiver.sign("channel-0", NearToken::from_yoctonear(0)));
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 2);
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "channel-3".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 3);
+}
+
+#[test]
+#[should_panic(expected = "sender.near has reached the maximum of 2 open channels")]
+fn test_open_channels_beyond_cap() {
+    let (mut contract, receiver, sender) = setup("channel-0", NearToken::from_near(1));
+    set_max_channels_per_sender(&mut contract, 2);
+
+    for i in 1..3 {
+        set_context(
+            &sender.account_id,
+            opening_deposit(NearToken::from_near(1)),
+            0,
+        );
+        contract.open_channel(
+            format!("channel-{}", i),
+            receiver.account(),
+            sender.account(),
+            None,
+            None,
+        );
+    }
+}
+
+#[test]
+#[should_panic(expected = "Only the sender can open its channel")]
+fn test_open_channel_for_another_sender() {
+    let (mut contract, receiver, sender) = setup("channel-0", NearToken::from_near(1));
+    let other = Party::new("other.near");
+
+    set_context(
+        &other.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "channel-1".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+}
+
+#[test]
+fn test_sponsored_channels_count_for_the_sponsor() {
+    let (mut contract, receiver, sender) = setup("channel-0", NearToken::from_near(1));
+    let sponsor = Party::new("sponsor.near");
+    set_max_channels_per_sender(&mut contract, 2);
+
+    // A third party using up the cap only uses up its own
+    for i in 1..3 {
+        set_context(
+            &sponsor.account_id,
+            opening_deposit(NearToken::from_near(1)),
+            0,
+        );
+        contract.open_sponsored_channel(
+            format!("sponsored-{}", i),
+            receiver.account(),
+            sender.account(),
+        );
+    }
+    assert_eq!(contract.sender_open_channels(sponsor.account_id.clone()), 2);
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 1);
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "channel-1".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 2);
+
+    // Closing a sponsored channel frees a slot of the sponsor
+    contract.close(receiver.sign("sponsored-1", NearToken::from_yoctonear(0)));
+    assert_eq!(contract.sender_open_channels(sponsor.account_id.clone()), 1);
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 2);
+}
+
+// Copied from the contract code
+const CLOSED_CHANNEL_ACCOUNT_ID: &str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+const HARD_CLOSE_TIMEOUT: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// The cli and the provider consider a channel closed when its sender is the closed account id
+fn assert_tombstone(contract: &Contract, channel_id: &str) {
+    let channel = channel_json(contract, channel_id);
+    assert_eq!(
+        channel["sender"]["account_id"],
+        json!(CLOSED_CHANNEL_ACCOUNT_ID)
+    );
+    assert_eq!(
+        channel["receiver"]["account_id"],
+        json!(CLOSED_CHANNEL_ACCOUNT_ID)
+    );
+    assert_eq!(
+        channel["added_balance"],
+        json!(NearToken::from_yoctonear(0))
+    );
+    assert_eq!(channel["force_close_started"], json!(null));
+}
+
+#[test]
+fn test_close_leaves_tombstone() {
+    let (mut contract, receiver, _) = setup("channel", NearToken::from_near(1));
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+    assert_tombstone(&contract, "channel");
+}
+
+#[test]
+fn test_withdraw_and_close_leaves_tombstone() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw_and_close(
+        sender.sign("channel", NearToken::from_millinear(100)),
+        receiver.sign("channel", NearToken::from_yoctonear(0)),
+    );
+    assert_tombstone(&contract, "channel");
+}
+
+#[test]
+fn test_force_close_leaves_tombstone() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.force_close_start("channel".to_string());
+
+    set_context(
+        &sender.account_id,
+        NearToken::from_yoctonear(0),
+        HARD_CLOSE_TIMEOUT,
+    );
+    contract.force_close_finish("channel".to_string());
+    assert_tombstone(&contract, "channel");
+}
+
+#[test]
+#[should_panic(expected = "Channel is closed.")]
+fn test_topup_tombstone() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    // The deposit would be stuck, a tombstone is never refunded
+    set_context(&sender.account_id, NearToken::from_near(1), 0);
+    contract.topup("channel".to_string());
+}
@@ -0,0 +1,207 @@
+mod common;
+
+use common::{listing_cost, opening_deposit, set_context, setup, Party};
+use near_sdk::test_utils::get_logs;
+use near_sdk::NearToken;
+use payment_channel::Contract;
+use serde_json::{json, Value};
+
+const WEEK: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Events logged since the last `set_context`
+fn events() -> Vec<Value> {
+    get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|event| serde_json::from_str(event).unwrap())
+        .collect()
+}
+
+fn assert_single_event(event: &str, data: Value) {
+    let events = events();
+    assert_eq!(events.len(), 1);
+    assert_eq!(
+        events[0],
+        json!({
+            "standard": "payment_channel",
+            "version": "1.0.0",
+            "event": event,
+            "data": [data],
+        })
+    );
+}
+
+#[test]
+fn test_open_channel_event() {
+    setup("channel", NearToken::from_near(1));
+
+    assert_single_event(
+        "open_channel",
+        json!({
+            "channel_id": "channel",
+            "receiver": "receiver.near",
+            "sender": "sender.near",
+            "added_balance": NearToken::from_near(1),
+            "sponsor": null,
+        }),
+    );
+}
+
+#[test]
+fn test_open_sponsored_channel_event() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(
+        &sponsor.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_sponsored_channel("channel".to_string(), receiver.account(), sender.account());
+
+    assert_single_event(
+        "open_channel",
+        json!({
+            "channel_id": "channel",
+            "receiver": "receiver.near",
+            "sender": "sender.near",
+            "added_balance": NearToken::from_near(1),
+            "sponsor": "sponsor.near",
+        }),
+    );
+}
+
+#[test]
+fn test_topup_event() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, NearToken::from_near(2), 0);
+    contract.topup("channel".to_string());
+
+    assert_single_event(
+        "topup",
+        json!({
+            "channel_id": "channel",
+            "amount": NearToken::from_near(2),
+            "added_balance": NearToken::from_near(3),
+        }),
+    );
+}
+
+#[test]
+fn test_withdraw_event() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(3));
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(1)));
+    assert_single_event(
+        "withdraw",
+        json!({
+            "channel_id": "channel",
+            "receiver": "receiver.near",
+            "amount": NearToken::from_near(1),
+            "withdrawn_balance": NearToken::from_near(1),
+        }),
+    );
+
+    // Only the difference to the previous withdraw is transferred
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(3)));
+    assert_single_event(
+        "withdraw",
+        json!({
+            "channel_id": "channel",
+            "receiver": "receiver.near",
+            "amount": NearToken::from_near(2),
+            "withdrawn_balance": NearToken::from_near(3),
+        }),
+    );
+}
+
+#[test]
+fn test_close_event() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(3));
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(1)));
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    assert_single_event(
+        "close",
+        json!({
+            "channel_id": "channel",
+            "refund_to": "sender.near",
+            "refund": NearToken::from_near(2).saturating_add(listing_cost()),
+        }),
+    );
+}
+
+#[test]
+fn test_force_close_events() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 5);
+    contract.force_close_start("channel".to_string());
+    assert_single_event(
+        "force_close_start",
+        json!({
+            "channel_id": "channel",
+            "sender": "sender.near",
+            "started_at": 5,
+        }),
+    );
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 6);
+    contract.cancel_force_close("channel".to_string());
+    assert_single_event(
+        "force_close_cancel",
+        json!({
+            "channel_id": "channel",
+            "sender": "sender.near",
+        }),
+    );
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 7);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), WEEK + 7);
+    contract.force_close_finish("channel".to_string());
+    assert_single_event(
+        "force_close_finish",
+        json!({
+            "channel_id": "channel",
+            "refund_to": "sender.near",
+            "refund": NearToken::from_near(1).saturating_add(listing_cost()),
+        }),
+    );
+}
+
+#[test]
+fn test_refund_to_update_event() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let safe = Party::new("safe.near");
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&safe.account_id), 0));
+    assert_single_event(
+        "refund_to_update",
+        json!({
+            "channel_id": "channel",
+            "refund_to": "safe.near",
+        }),
+    );
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.update_refund_to(sender.sign_refund_to("channel", None, 1));
+    assert_single_event(
+        "refund_to_update",
+        json!({
+            "channel_id": "channel",
+            "refund_to": null,
+        }),
+    );
+}
@@ -0,0 +1,254 @@
+mod common;
+
+use common::{channel_json, opening_deposit, set_context, setup, Party};
+use near_sdk::json_types::U64;
+use near_sdk::test_utils::get_created_receipts;
+use near_sdk::NearToken;
+use payment_channel::Contract;
+use serde_json::json;
+
+// Copied from the contract code
+const HARD_CLOSE_TIMEOUT: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+const START: u64 = 1_000_000_000;
+const HOUR: u64 = 60 * 60 * 1_000_000_000;
+
+fn no_deposit() -> NearToken {
+    NearToken::from_yoctonear(0)
+}
+
+#[test]
+fn test_force_close_start() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["force_close_started"], json!(START));
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(1)));
+}
+
+#[test]
+#[should_panic(expected = "Only sender can start a force close action")]
+fn test_force_close_start_by_non_sender() {
+    let (mut contract, receiver, _) = setup("channel", NearToken::from_near(1));
+
+    set_context(&receiver.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Channel is already closing.")]
+fn test_force_close_start_twice() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+    contract.force_close_start("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Channel is closing.")]
+fn test_topup_while_closing() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, NearToken::from_near(1), START);
+    contract.topup("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Channel is not closing.")]
+fn test_force_close_finish_without_start() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_finish("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Channel can't be closed yet. Not enough time has passed.")]
+fn test_force_close_finish_before_timeout() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(
+        &sender.account_id,
+        no_deposit(),
+        START + HARD_CLOSE_TIMEOUT - 1,
+    );
+    contract.force_close_finish("channel".to_string());
+}
+
+#[test]
+fn test_force_close_finish_after_timeout() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, no_deposit(), START + HARD_CLOSE_TIMEOUT);
+    contract.force_close_finish("channel".to_string());
+
+    // The remaining balance is refunded to the sender and the channel is tombstoned
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receiver_id, sender.account_id);
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(no_deposit()));
+    assert_eq!(channel["force_close_started"], json!(null));
+}
+
+#[test]
+fn test_withdraw_while_closing() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    // The receiver can still withdraw what was spent before the channel closes
+    set_context(&receiver.account_id, no_deposit(), START + 1);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(400)));
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(
+        channel["withdrawn_balance"],
+        json!(NearToken::from_millinear(400))
+    );
+
+    set_context(&sender.account_id, no_deposit(), START + HARD_CLOSE_TIMEOUT);
+    contract.force_close_finish("channel".to_string());
+
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.last().unwrap().receiver_id, sender.account_id);
+}
+
+#[test]
+#[should_panic(expected = "Channel is not closing.")]
+fn test_force_close_finish_twice() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, no_deposit(), START + HARD_CLOSE_TIMEOUT);
+    contract.force_close_finish("channel".to_string());
+    contract.force_close_finish("channel".to_string());
+}
+
+#[test]
+fn test_cancel_force_close() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+    contract.cancel_force_close("channel".to_string());
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["force_close_started"], json!(null));
+
+    // The channel accepts topups again
+    set_context(&sender.account_id, NearToken::from_near(1), START + 1);
+    contract.topup("channel".to_string());
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(2)));
+}
+
+#[test]
+#[should_panic(expected = "Only sender can cancel a force close action")]
+fn test_cancel_force_close_by_non_sender() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&receiver.account_id, no_deposit(), START);
+    contract.cancel_force_close("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Channel is not closing.")]
+fn test_cancel_force_close_without_start() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.cancel_force_close("channel".to_string());
+}
+
+fn setup_with_force_close_timeout(force_close_timeout: u64) -> (Contract, Party, Party) {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        Some(U64(force_close_timeout)),
+        None,
+    );
+
+    (contract, receiver, sender)
+}
+
+#[test]
+fn test_default_force_close_timeout() {
+    let (contract, _, _) = setup("channel", NearToken::from_near(1));
+    assert_eq!(
+        contract.force_close_timeout("channel".to_string()),
+        U64(HARD_CLOSE_TIMEOUT)
+    );
+}
+
+#[test]
+fn test_force_close_finish_after_channel_timeout() {
+    let (mut contract, _, sender) = setup_with_force_close_timeout(HOUR);
+    assert_eq!(
+        contract.force_close_timeout("channel".to_string()),
+        U64(HOUR)
+    );
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, no_deposit(), START + HOUR);
+    contract.force_close_finish("channel".to_string());
+
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    assert_eq!(receipts[0].receiver_id, sender.account_id);
+
+    // The timeout goes away with the channel
+    assert_eq!(
+        contract.force_close_timeout("channel".to_string()),
+        U64(HARD_CLOSE_TIMEOUT)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Channel can't be closed yet. Not enough time has passed.")]
+fn test_force_close_finish_before_channel_timeout() {
+    let (mut contract, _, sender) = setup_with_force_close_timeout(HOUR);
+
+    set_context(&sender.account_id, no_deposit(), START);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, no_deposit(), START + HOUR - 1);
+    contract.force_close_finish("channel".to_string());
+}
+
+#[test]
+#[should_panic(expected = "Force close timeout must be at least 3600000000000 nanoseconds")]
+fn test_open_channel_with_too_short_force_close_timeout() {
+    setup_with_force_close_timeout(HOUR - 1);
+}
@@ -0,0 +1,204 @@
+mod common;
+
+use common::{channel_json, opening_deposit, set_context, setup, transferred_to, Party};
+use near_sdk::store::{LazyOption, LookupMap};
+use near_sdk::{env, NearToken};
+use payment_channel::{Channel, Contract, Ownership};
+use serde_json::json;
+
+// State of the first deployed contract, before sponsors and the later fields
+#[derive(borsh::BorshSerialize)]
+struct BaselineContract {
+    channels: LookupMap<String, Channel>,
+    ownership: LazyOption<Ownership>,
+}
+
+// Deploy the baseline contract with one open channel
+fn baseline(channel_id: &str, receiver: &Party, sender: &Party) {
+    let channel: Channel = serde_json::from_value(json!({
+        "receiver": receiver.account(),
+        "sender": sender.account(),
+        "added_balance": NearToken::from_near(1),
+        "withdrawn_balance": NearToken::from_yoctonear(0),
+        "force_close_started": null,
+    }))
+    .unwrap();
+
+    let mut channels = LookupMap::new(b"c".to_vec());
+    channels.insert(channel_id.to_string(), channel);
+    channels.flush();
+    env::state_write(&BaselineContract {
+        channels,
+        ownership: LazyOption::new(b"o", None),
+    });
+}
+
+#[test]
+fn test_migrate_baseline_state() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    baseline("channel", &receiver, &sender);
+
+    let mut contract = Contract::migrate();
+
+    // The existing channel is kept, with the defaults of the later fields
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(1)));
+    assert_eq!(channel["sender"]["account_id"], json!(sender.account_id));
+    assert!(contract.owner().is_none());
+    assert_eq!(contract.sponsor("channel".to_string()), None);
+    assert_eq!(contract.max_channels_per_sender(), None);
+    assert_eq!(contract.state_nonce("channel".to_string()), 0);
+
+    // And still works
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    assert_eq!(
+        channel_json(&contract, "channel")["withdrawn_balance"],
+        json!(NearToken::from_millinear(100))
+    );
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    contract.open_channel(
+        "other".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        None,
+    );
+    assert_eq!(contract.sender_open_channels(sender.account_id.clone()), 1);
+}
+
+#[test]
+fn test_migrate_state_before_ownership() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    // Only the channels, serialized as the prefix of the map
+    let channel: Channel = serde_json::from_value(json!({
+        "receiver": receiver.account(),
+        "sender": sender.account(),
+        "added_balance": NearToken::from_near(1),
+        "withdrawn_balance": NearToken::from_yoctonear(0),
+        "force_close_started": null,
+    }))
+    .unwrap();
+    let mut channels = LookupMap::new(b"c".to_vec());
+    channels.insert("channel".to_string(), channel);
+    channels.flush();
+    env::state_write(&channels);
+
+    let mut contract = Contract::migrate();
+
+    assert_eq!(
+        channel_json(&contract, "channel")["added_balance"],
+        json!(NearToken::from_near(1))
+    );
+    assert!(contract.owner().is_none());
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    assert_eq!(
+        channel_json(&contract, "channel")["withdrawn_balance"],
+        json!(NearToken::from_millinear(100))
+    );
+}
+
+#[test]
+fn test_migrate_current_state() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    let safe = Party::new("safe.near");
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&safe.account_id), 0));
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(100), 3));
+    env::state_write(&contract);
+    drop(contract);
+
+    // Nothing to migrate, the state is kept as is
+    let contract = Contract::migrate();
+
+    assert_eq!(
+        channel_json(&contract, "channel")["withdrawn_balance"],
+        json!(NearToken::from_millinear(100))
+    );
+    assert_eq!(contract.state_nonce("channel".to_string()), 3);
+    assert_eq!(
+        contract.refund_to("channel".to_string()),
+        Some(safe.account_id)
+    );
+    assert_eq!(
+        contract
+            .channels_for_account(sender.account_id.clone(), 0, 10)
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn test_migrate_withdraw_legacy_state() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    baseline("channel", &receiver, &sender);
+
+    let mut contract = Contract::migrate();
+
+    // States signed before the upgrade still withdraw, without using up a nonce
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign_legacy("channel", NearToken::from_millinear(100)));
+    contract.withdraw(sender.sign_legacy("channel", NearToken::from_millinear(200)));
+    assert_eq!(
+        channel_json(&contract, "channel")["withdrawn_balance"],
+        json!(NearToken::from_millinear(200))
+    );
+    assert_eq!(contract.state_nonce("channel".to_string()), 0);
+
+    // Until the channel accepts a state with a nonce
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(300), 1));
+    assert_eq!(contract.state_nonce("channel".to_string()), 1);
+}
+
+#[test]
+#[should_panic(expected = "Signed state is older than the last accepted one")]
+fn test_migrate_legacy_state_after_nonce() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    baseline("channel", &receiver, &sender);
+
+    let mut contract = Contract::migrate();
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(100), 1));
+    contract.withdraw(sender.sign_legacy("channel", NearToken::from_millinear(200)));
+}
+
+#[test]
+fn test_migrate_close_refunds_no_listing_cost() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    baseline("channel", &receiver, &sender);
+
+    let mut contract = Contract::migrate();
+
+    // Opened before channels were listed, its deposit paid for no listing
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+    assert_eq!(transferred_to(&sender.account_id), NearToken::from_near(1));
+}
+
+#[test]
+#[should_panic(expected = "Unknown contract state layout")]
+fn test_migrate_unknown_state() {
+    let sender = Party::new("sender.near");
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    env::state_write(&(String::from("unknown"), 0u8));
+
+    Contract::migrate();
+}
@@ -0,0 +1,41 @@
+mod common;
+
+use common::{set_context, setup, transferred_to};
+use near_sdk::NearToken;
+
+// A `ppp_core` state signed and encoded with the off-chain signature type, as the cli
+// does, decodes and verifies with that type, and is accepted by the contract on withdraw.
+// Only the contract is built here, the provider checks its own encoding in its tests
+#[test]
+fn test_state_signed_off_chain_is_withdrawn() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    let spent = NearToken::from_millinear(100);
+
+    // Signed off chain
+    let state = ppp_core::State {
+        channel_id: "channel".to_string(),
+        spent_balance: spent,
+        nonce: 1,
+    };
+    let signed_state = ppp_core::SignedState {
+        signature: sender.secret_key.sign(&borsh::to_vec(&state).unwrap()),
+        state,
+    };
+    let payload = borsh::to_vec(&signed_state).unwrap();
+
+    // Received off chain
+    let received: ppp_core::SignedState<near_crypto::Signature> =
+        borsh::from_slice(&payload).unwrap();
+    assert!(received.signature.verify(
+        &borsh::to_vec(&received.state).unwrap(),
+        &sender.secret_key.public_key()
+    ));
+
+    // contract, states reach it as json
+    let on_chain: payment_channel::SignedState =
+        serde_json::from_value(serde_json::to_value(&received).unwrap()).unwrap();
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(on_chain);
+
+    assert_eq!(transferred_to(&receiver.account_id), spent);
+}
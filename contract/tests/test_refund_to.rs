@@ -0,0 +1,187 @@
+mod common;
+
+use common::{listing_cost, opening_deposit, set_context, setup, Party};
+use near_sdk::test_utils::get_created_receipts;
+use near_sdk::{AccountId, NearToken};
+use payment_channel::Contract;
+
+const WEEK: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+fn setup_with_refund_to(refund_to: &AccountId) -> (Contract, Party, Party) {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+
+    set_context(
+        &sender.account_id,
+        opening_deposit(NearToken::from_near(3)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_channel(
+        "channel".to_string(),
+        receiver.account(),
+        sender.account(),
+        None,
+        Some(refund_to.clone()),
+    );
+
+    (contract, receiver, sender)
+}
+
+// The only transfer of the last call, as (receiver, amount)
+fn single_transfer() -> (AccountId, NearToken) {
+    let receipts = get_created_receipts();
+    assert_eq!(receipts.len(), 1);
+    let amount = match &receipts[0].actions[..] {
+        [near_sdk::mock::MockAction::Transfer { deposit, .. }] => *deposit,
+        _ => panic!("Expected a single transfer"),
+    };
+    (receipts[0].receiver_id.clone(), amount)
+}
+
+#[test]
+fn test_close_refunds_sender_by_default() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(3));
+    assert_eq!(
+        contract.refund_to("channel".to_string()),
+        Some(sender.account_id.clone())
+    );
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(1)));
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    assert_eq!(
+        single_transfer(),
+        (
+            sender.account_id,
+            NearToken::from_near(2).saturating_add(listing_cost())
+        )
+    );
+    assert_eq!(contract.refund_to("channel".to_string()), None);
+}
+
+#[test]
+fn test_close_refunds_refund_to() {
+    let safe = Party::new("safe.near");
+    let (mut contract, receiver, sender) = setup_with_refund_to(&safe.account_id);
+    assert_eq!(
+        contract.refund_to("channel".to_string()),
+        Some(safe.account_id.clone())
+    );
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(1)));
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    assert_eq!(
+        single_transfer(),
+        (
+            safe.account_id,
+            NearToken::from_near(2).saturating_add(listing_cost())
+        )
+    );
+}
+
+#[test]
+fn test_force_close_finish_refunds_refund_to() {
+    let safe = Party::new("safe.near");
+    let (mut contract, _, sender) = setup_with_refund_to(&safe.account_id);
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), 0);
+    contract.force_close_start("channel".to_string());
+
+    set_context(&sender.account_id, NearToken::from_yoctonear(0), WEEK);
+    contract.force_close_finish("channel".to_string());
+
+    assert_eq!(
+        single_transfer(),
+        (
+            safe.account_id,
+            NearToken::from_near(3).saturating_add(listing_cost())
+        )
+    );
+}
+
+#[test]
+fn test_update_refund_to() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(3));
+    let safe = Party::new("safe.near");
+    let relayer = Party::new("relayer.near");
+    assert_eq!(contract.refund_to_nonce("channel".to_string()), 0);
+
+    // Anyone can submit the update signed by the sender
+    set_context(&relayer.account_id, NearToken::from_yoctonear(0), 0);
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&safe.account_id), 0));
+    assert_eq!(
+        contract.refund_to("channel".to_string()),
+        Some(safe.account_id.clone())
+    );
+    assert_eq!(contract.refund_to_nonce("channel".to_string()), 1);
+
+    set_context(&receiver.account_id, NearToken::from_yoctonear(0), 0);
+    contract.close(receiver.sign("channel", NearToken::from_yoctonear(0)));
+
+    assert_eq!(
+        single_transfer(),
+        (
+            safe.account_id,
+            NearToken::from_near(3).saturating_add(listing_cost())
+        )
+    );
+}
+
+#[test]
+fn test_update_refund_to_back_to_sender() {
+    let safe = Party::new("safe.near");
+    let (mut contract, _, sender) = setup_with_refund_to(&safe.account_id);
+
+    contract.update_refund_to(sender.sign_refund_to("channel", None, 0));
+
+    assert_eq!(
+        contract.refund_to("channel".to_string()),
+        Some(sender.account_id)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Invalid refund nonce")]
+fn test_update_refund_to_replayed() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let safe = Party::new("safe.near");
+    let attacker = Party::new("attacker.near");
+
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&attacker.account_id), 0));
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&safe.account_id), 1));
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&attacker.account_id), 0));
+}
+
+#[test]
+#[should_panic(expected = "Invalid signature from sender")]
+fn test_update_refund_to_not_from_sender() {
+    let (mut contract, receiver, _) = setup("channel", NearToken::from_near(1));
+
+    contract.update_refund_to(receiver.sign_refund_to("channel", Some(&receiver.account_id), 0));
+}
+
+#[test]
+#[should_panic(expected = "Sponsored channels are refunded to the sponsor")]
+fn test_update_refund_to_sponsored_channel() {
+    let receiver = Party::new("receiver.near");
+    let sender = Party::new("sender.near");
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(
+        &sponsor.account_id,
+        opening_deposit(NearToken::from_near(1)),
+        0,
+    );
+    let mut contract = Contract::init();
+    contract.open_sponsored_channel("channel".to_string(), receiver.account(), sender.account());
+
+    contract.update_refund_to(sender.sign_refund_to("channel", Some(&sender.account_id), 0));
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{channel_json, set_context, setup, Party};
+use near_sdk::NearToken;
+use serde_json::json;
+
+#[test]
+fn test_topup_with_authorization() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let sponsor = Party::new("sponsor.near");
+    assert_eq!(contract.topup_nonce("channel".to_string()), 0);
+
+    set_context(&sponsor.account_id, NearToken::from_near(2), 0);
+    contract.topup_with_authorization(sender.sign_topup("channel", NearToken::from_near(2), 0));
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(3)));
+    assert_eq!(contract.topup_nonce("channel".to_string()), 1);
+
+    // The next authorization carries the next nonce
+    set_context(&sponsor.account_id, NearToken::from_near(1), 0);
+    contract.topup_with_authorization(sender.sign_topup("channel", NearToken::from_near(1), 1));
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(4)));
+    assert_eq!(contract.topup_nonce("channel".to_string()), 2);
+}
+
+#[test]
+#[should_panic(expected = "Invalid topup nonce")]
+fn test_topup_with_replayed_authorization() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(&sponsor.account_id, NearToken::from_near(2), 0);
+    contract.topup_with_authorization(sender.sign_topup("channel", NearToken::from_near(2), 0));
+    contract.topup_with_authorization(sender.sign_topup("channel", NearToken::from_near(2), 0));
+}
+
+#[test]
+#[should_panic(expected = "Invalid signature from sender")]
+fn test_topup_with_authorization_not_from_sender() {
+    let (mut contract, receiver, _) = setup("channel", NearToken::from_near(1));
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(&sponsor.account_id, NearToken::from_near(2), 0);
+    contract.topup_with_authorization(receiver.sign_topup("channel", NearToken::from_near(2), 0));
+}
+
+#[test]
+#[should_panic(expected = "Attached deposit must match the authorized amount")]
+fn test_topup_with_authorization_wrong_deposit() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+    let sponsor = Party::new("sponsor.near");
+
+    set_context(&sponsor.account_id, NearToken::from_near(1), 0);
+    contract.topup_with_authorization(sender.sign_topup("channel", NearToken::from_near(2), 0));
+}
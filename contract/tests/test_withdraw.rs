@@ -0,0 +1,283 @@
+mod common;
+
+use common::{channel_json, listing_cost, set_context, setup, transferred_to};
+use near_sdk::NearToken;
+use serde_json::json;
+
+fn no_deposit() -> NearToken {
+    NearToken::from_yoctonear(0)
+}
+
+#[test]
+fn test_topup_then_withdraw() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, NearToken::from_near(1), 0);
+    contract.topup("channel".to_string());
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(2)));
+
+    let channel = channel_json(&contract, "channel");
+    assert_eq!(channel["added_balance"], json!(NearToken::from_near(2)));
+    assert_eq!(channel["withdrawn_balance"], json!(NearToken::from_near(2)));
+    assert_eq!(
+        transferred_to(&receiver.account_id),
+        NearToken::from_near(2)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Spent exceeds deposit")]
+fn test_withdraw_above_deposit() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    // Signed expecting a topup that hasn't landed yet
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_near(2)));
+}
+
+#[test]
+fn test_withdraw_above_deposit_after_topup() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    let state = sender.sign("channel", NearToken::from_near(2));
+
+    set_context(&sender.account_id, NearToken::from_near(1), 0);
+    contract.topup("channel".to_string());
+
+    // The state signed before the topup is withdrawn in full
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(state);
+    assert_eq!(
+        transferred_to(&receiver.account_id),
+        NearToken::from_near(2)
+    );
+}
+
+// Whatever the order of a topup and a withdraw, the receiver gets what was spent and
+// the sender gets the rest back on close
+#[test]
+fn test_withdraw_topup_interleavings() {
+    for topup_first in [true, false] {
+        for spent in [500, 1000, 1500, 2000] {
+            let spent = NearToken::from_millinear(spent);
+            let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+            let topup = |contract: &mut payment_channel::Contract| {
+                set_context(&sender.account_id, NearToken::from_near(1), 0);
+                contract.topup("channel".to_string());
+            };
+
+            if topup_first {
+                topup(&mut contract);
+            }
+            // A state above the deposit is rejected until the topup lands
+            let mut withdrawn = NearToken::from_yoctonear(0);
+            if topup_first || spent <= NearToken::from_near(1) {
+                set_context(&receiver.account_id, no_deposit(), 0);
+                contract.withdraw(sender.sign("channel", spent));
+                withdrawn = transferred_to(&receiver.account_id);
+            }
+            if !topup_first {
+                topup(&mut contract);
+            }
+
+            let channel = channel_json(&contract, "channel");
+            let added_balance: NearToken =
+                serde_json::from_value(channel["added_balance"].clone()).unwrap();
+            let withdrawn_balance: NearToken =
+                serde_json::from_value(channel["withdrawn_balance"].clone()).unwrap();
+            assert!(withdrawn_balance <= added_balance);
+
+            // A withdraw rejected before the topup is done afterwards
+            if withdrawn_balance < spent {
+                set_context(&receiver.account_id, no_deposit(), 0);
+                contract.withdraw(sender.sign("channel", spent));
+                withdrawn = withdrawn.saturating_add(transferred_to(&receiver.account_id));
+            }
+
+            let added = NearToken::from_near(2);
+            assert_eq!(withdrawn, spent);
+
+            set_context(&sender.account_id, no_deposit(), 0);
+            contract.close(receiver.sign("channel", no_deposit()));
+            assert_eq!(
+                transferred_to(&sender.account_id),
+                added.saturating_sub(spent).saturating_add(listing_cost())
+            );
+        }
+    }
+}
+
+const MINUTE: u64 = 60 * 1_000_000_000;
+
+fn set_withdraw_cooldown(contract: &mut payment_channel::Contract, cooldown: u64) {
+    // Private methods must be called by the contract account itself
+    let contract_account: near_sdk::AccountId = "alice.near".parse().unwrap();
+    set_context(&contract_account, no_deposit(), 0);
+    contract.set_withdraw_cooldown(Some(near_sdk::json_types::U64(cooldown)));
+}
+
+#[test]
+fn test_withdraw_without_cooldown() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(200)));
+
+    assert_eq!(contract.last_withdraw("channel".to_string()), None);
+    assert_eq!(
+        transferred_to(&receiver.account_id),
+        NearToken::from_millinear(200)
+    );
+}
+
+#[test]
+#[should_panic(expected = "Withdraw cooldown has not elapsed yet")]
+fn test_withdraw_within_cooldown() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    set_withdraw_cooldown(&mut contract, 10 * MINUTE);
+
+    set_context(&receiver.account_id, no_deposit(), MINUTE);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    set_context(&receiver.account_id, no_deposit(), 10 * MINUTE);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(200)));
+}
+
+#[test]
+#[should_panic(expected = "Withdraw cooldown has not elapsed yet")]
+fn test_withdraw_and_close_within_cooldown() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    set_withdraw_cooldown(&mut contract, 10 * MINUTE);
+
+    set_context(&receiver.account_id, no_deposit(), MINUTE);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    contract.withdraw_and_close(
+        sender.sign("channel", NearToken::from_millinear(200)),
+        receiver.sign("channel", no_deposit()),
+    );
+}
+
+#[test]
+fn test_withdraw_after_cooldown() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    set_withdraw_cooldown(&mut contract, 10 * MINUTE);
+
+    set_context(&receiver.account_id, no_deposit(), MINUTE);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+    assert_eq!(
+        contract.last_withdraw("channel".to_string()),
+        Some(near_sdk::json_types::U64(MINUTE))
+    );
+
+    set_context(&receiver.account_id, no_deposit(), 11 * MINUTE);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(200)));
+    assert_eq!(
+        contract.last_withdraw("channel".to_string()),
+        Some(near_sdk::json_types::U64(11 * MINUTE))
+    );
+    assert_eq!(
+        channel_json(&contract, "channel")["withdrawn_balance"],
+        json!(NearToken::from_millinear(200))
+    );
+
+    // Closing the channel forgets its last withdraw
+    contract.close(receiver.sign("channel", no_deposit()));
+    assert_eq!(contract.last_withdraw("channel".to_string()), None);
+}
+
+// Borsh layout of `State`, the provider tests check its signing type against it too:
+// the channel id as a u32 little endian length and its bytes, the spent balance
+// as a u128 little endian, then the nonce as a u64 little endian
+fn expected_state_bytes(channel_id: &str, spent_balance: u128, nonce: u64) -> Vec<u8> {
+    let mut bytes = (channel_id.len() as u32).to_le_bytes().to_vec();
+    bytes.extend_from_slice(channel_id.as_bytes());
+    bytes.extend_from_slice(&spent_balance.to_le_bytes());
+    bytes.extend_from_slice(&nonce.to_le_bytes());
+    bytes
+}
+
+#[test]
+fn test_withdraw_state_signed_over_expected_layout() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+    let spent = NearToken::from_millinear(100);
+
+    let signature =
+        sender
+            .secret_key
+            .sign(&expected_state_bytes("channel", spent.as_yoctonear(), 1));
+    let state: payment_channel::SignedState = serde_json::from_value(json!({
+        "state": {
+            "channel_id": "channel",
+            "spent_balance": spent,
+            "nonce": 1,
+        },
+        "signature": signature.to_string(),
+    }))
+    .unwrap();
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(state);
+
+    assert_eq!(transferred_to(&receiver.account_id), spent);
+}
+
+#[test]
+#[should_panic(expected = "Signed state is older than the last accepted one")]
+fn test_withdraw_replayed_state() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    let state = || sender.sign_with_nonce("channel", NearToken::from_millinear(100), 1);
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(state());
+    contract.withdraw(state());
+}
+
+#[test]
+#[should_panic(expected = "Signed state is older than the last accepted one")]
+fn test_withdraw_stale_state() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    // A state signed before the one withdrawn, even with a higher spent balance
+    set_context(&receiver.account_id, no_deposit(), 0);
+    let stale = sender.sign_with_nonce("channel", NearToken::from_millinear(300), 1);
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(200), 2));
+    assert_eq!(contract.state_nonce("channel".to_string()), 2);
+    contract.withdraw(stale);
+}
+
+#[test]
+#[should_panic(expected = "Signed state is older than the last accepted one")]
+fn test_close_with_stale_state() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(200), 2));
+    contract.close(receiver.sign_with_nonce("channel", no_deposit(), 1));
+}
+
+#[test]
+fn test_close_after_withdraw_with_close_nonce() {
+    let (mut contract, receiver, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&receiver.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign_with_nonce("channel", NearToken::from_millinear(200), 2));
+    contract.close(receiver.sign_with_nonce("channel", no_deposit(), ppp_core::CLOSE_NONCE));
+
+    assert_eq!(
+        transferred_to(&sender.account_id),
+        NearToken::from_millinear(800).saturating_add(listing_cost())
+    );
+    // Closing the channel forgets its nonce
+    assert_eq!(contract.state_nonce("channel".to_string()), 0);
+}
+
+#[test]
+#[should_panic(expected = "Only receiver can withdraw")]
+fn test_withdraw_not_from_receiver() {
+    let (mut contract, _, sender) = setup("channel", NearToken::from_near(1));
+
+    set_context(&sender.account_id, no_deposit(), 0);
+    contract.withdraw(sender.sign("channel", NearToken::from_millinear(100)));
+}
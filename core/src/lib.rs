@@ -0,0 +1,68 @@
+//! Types signed by the sender of a payment channel. Shared by the contract, the cli and
+//! the provider, so the bytes signed off-chain are always the bytes verified on-chain.
+use near_sdk::{near, NearToken};
+
+/// Spent balance of a channel authorized by its sender. Signatures are over the borsh
+/// serialization of this struct.
+#[near(serializers = [borsh, json])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub channel_id: String,
+    pub spent_balance: NearToken,
+    /// Increased with every state signed for the channel. The contract only accepts a
+    /// state with a nonce above the last one it accepted, so old states can't be replayed.
+    pub nonce: u64,
+}
+
+/// Layout of `State` before the nonce was added. States signed before the upgrade are
+/// over the borsh serialization of this struct, they carry nonce 0 once decoded.
+#[near(serializers = [borsh])]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LegacyState {
+    pub channel_id: String,
+    pub spent_balance: NearToken,
+}
+
+impl From<&State> for LegacyState {
+    fn from(state: &State) -> Self {
+        Self {
+            channel_id: state.channel_id.clone(),
+            spent_balance: state.spent_balance,
+        }
+    }
+}
+
+/// A `LegacyState` with the signature of the sender, the payloads signed before the nonce
+/// was added. Decoded as a `SignedState` with nonce 0.
+#[near(serializers = [borsh])]
+#[derive(Debug)]
+pub struct LegacySignedState<S> {
+    pub state: LegacyState,
+    pub signature: S,
+}
+
+impl<S> From<LegacySignedState<S>> for SignedState<S> {
+    fn from(legacy: LegacySignedState<S>) -> Self {
+        Self {
+            state: State {
+                channel_id: legacy.state.channel_id,
+                spent_balance: legacy.state.spent_balance,
+                nonce: 0,
+            },
+            signature: legacy.signature,
+        }
+    }
+}
+
+/// Nonce of the close states signed by the receiver. Closing is the last operation on a
+/// channel, so a close state is always newer than the states withdrawn before it.
+pub const CLOSE_NONCE: u64 = u64::MAX;
+
+/// A `State` with the signature of the sender (or of the receiver, to close the channel).
+/// The contract and the clients use different signature types with the same json format.
+#[near(serializers = [borsh, json])]
+#[derive(Debug)]
+pub struct SignedState<S> {
+    pub state: State,
+    pub signature: S,
+}
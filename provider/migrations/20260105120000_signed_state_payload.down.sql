@@ -0,0 +1 @@
+ALTER TABLE signed_state DROP COLUMN payload;
@@ -0,0 +1,3 @@
+-- Keep the exact payload submitted by the client (base64 borsh SignedState)
+-- so historical states can be re-verified independently of the table layout
+ALTER TABLE signed_state ADD COLUMN payload TEXT DEFAULT NULL;
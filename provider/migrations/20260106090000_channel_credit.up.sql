@@ -0,0 +1,2 @@
+-- Prepaid balance not consumed by previous requests (big endian u128), see `cost_per_token`
+ALTER TABLE channel ADD COLUMN credit BLOB NOT NULL DEFAULT x'00000000000000000000000000000000' CHECK (length(credit) = 16);
@@ -0,0 +1,16 @@
+-- Latest (highest spent balance) signed state of each channel, kept small for fast lookups.
+-- `signed_state` remains the append-only history, which can be pruned
+CREATE TABLE IF NOT EXISTS current_state (
+    id INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    channel_id INT PRIMARY KEY NOT NULL,
+    spent_balance BLOB NOT NULL CHECK (length(spent_balance) = 16),
+    signature TEXT NOT NULL,
+    payload TEXT DEFAULT NULL,
+    FOREIGN KEY (channel_id) REFERENCES channel(id)
+);
+
+INSERT INTO current_state (id, created_at, channel_id, spent_balance, signature, payload)
+SELECT id, created_at, channel_id, spent_balance, signature, payload
+FROM signed_state
+WHERE id IN (SELECT MAX(id) FROM signed_state GROUP BY channel_id);
@@ -0,0 +1,2 @@
+-- Channels the operator stopped serving, payments are rejected but funds can still be withdrawn
+ALTER TABLE channel ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Last time the background loop went through the channel, so it resumes where it left off after a restart
+ALTER TABLE channel ADD COLUMN checked_at DATETIME;
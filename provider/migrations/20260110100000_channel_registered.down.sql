@@ -0,0 +1 @@
+ALTER TABLE channel DROP COLUMN registered;
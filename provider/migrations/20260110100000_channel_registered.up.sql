@@ -0,0 +1,2 @@
+-- Channels approved by the operator, only required with `require_registration`
+ALTER TABLE channel ADD COLUMN registered BOOLEAN NOT NULL DEFAULT FALSE;
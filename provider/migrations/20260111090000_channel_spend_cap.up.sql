@@ -0,0 +1,2 @@
+-- Maximum spent balance the provider accepts on the channel, big endian u128, NULL for no cap
+ALTER TABLE channel ADD COLUMN spend_cap BLOB;
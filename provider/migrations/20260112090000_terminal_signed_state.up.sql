@@ -0,0 +1,15 @@
+-- Signed states of closed channels are terminal, they are never accepted or served again,
+-- even if the channel row is reopened
+ALTER TABLE signed_state ADD COLUMN terminal BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE current_state ADD COLUMN terminal BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE signed_state SET terminal = 1
+WHERE channel_id IN (
+    SELECT id FROM channel
+    WHERE soft_closed = 1 OR sender = '0000000000000000000000000000000000000000000000000000000000000000'
+);
+UPDATE current_state SET terminal = 1
+WHERE channel_id IN (
+    SELECT id FROM channel
+    WHERE soft_closed = 1 OR sender = '0000000000000000000000000000000000000000000000000000000000000000'
+);
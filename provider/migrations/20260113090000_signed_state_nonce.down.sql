@@ -0,0 +1,2 @@
+ALTER TABLE current_state DROP COLUMN nonce;
+ALTER TABLE signed_state DROP COLUMN nonce;
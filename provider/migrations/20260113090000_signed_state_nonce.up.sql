@@ -0,0 +1,5 @@
+-- Nonce of each signed state, big endian like the spent balance. States stored before
+-- the nonce existed keep nonce 0, the contract still verifies them with the old layout
+-- until the channel accepts a state with a nonce
+ALTER TABLE signed_state ADD COLUMN nonce BLOB NOT NULL DEFAULT X'0000000000000000';
+ALTER TABLE current_state ADD COLUMN nonce BLOB NOT NULL DEFAULT X'0000000000000000';
@@ -0,0 +1,2 @@
+-- Cost of previous requests not covered by their payment (big endian u128), see `token_rates`
+ALTER TABLE channel ADD COLUMN debt BLOB NOT NULL DEFAULT x'00000000000000000000000000000000' CHECK (length(debt) = 16);
@@ -0,0 +1,10 @@
+-- Append-only record of the admin actions, `error` is NULL for the actions that succeeded
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    channel_name VARCHAR(255) DEFAULT NULL,
+    detail TEXT DEFAULT NULL,
+    error TEXT DEFAULT NULL
+);
@@ -0,0 +1,3 @@
+DROP TABLE IF EXISTS current_state;
+DROP TABLE IF EXISTS signed_state;
+DROP TABLE IF EXISTS channel;
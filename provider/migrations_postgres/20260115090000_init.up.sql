@@ -0,0 +1,44 @@
+-- Schema of the sqlite migrations up to 20260114090000_channel_debt, for the postgres backend.
+-- Balances and nonces are big endian byte strings, like in sqlite, so comparing them compares
+-- the amounts. Timestamps are UTC, the provider runs its sessions in UTC
+CREATE TABLE IF NOT EXISTS channel (
+    id BIGSERIAL PRIMARY KEY,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    name VARCHAR(255) NOT NULL UNIQUE,
+    sender VARCHAR(255) NOT NULL,
+    sender_pk VARCHAR(255) NOT NULL,
+    receiver VARCHAR(255) NOT NULL,
+    receiver_pk VARCHAR(255) NOT NULL,
+    added_balance BYTEA NOT NULL CHECK (length(added_balance) = 16),
+    withdrawn_balance BYTEA NOT NULL CHECK (length(withdrawn_balance) = 16),
+    force_close_started TIMESTAMP DEFAULT NULL,
+    soft_closed BOOLEAN NOT NULL DEFAULT FALSE,
+    credit BYTEA NOT NULL DEFAULT '\x00000000000000000000000000000000' CHECK (length(credit) = 16),
+    disabled BOOLEAN NOT NULL DEFAULT FALSE,
+    checked_at TIMESTAMP,
+    registered BOOLEAN NOT NULL DEFAULT FALSE,
+    spend_cap BYTEA,
+    debt BYTEA NOT NULL DEFAULT '\x00000000000000000000000000000000' CHECK (length(debt) = 16)
+);
+
+CREATE TABLE IF NOT EXISTS signed_state (
+    id BIGSERIAL PRIMARY KEY,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    channel_id BIGINT NOT NULL REFERENCES channel(id),
+    spent_balance BYTEA NOT NULL CHECK (length(spent_balance) = 16),
+    signature TEXT NOT NULL,
+    payload TEXT DEFAULT NULL,
+    terminal BOOLEAN NOT NULL DEFAULT FALSE,
+    nonce BYTEA NOT NULL DEFAULT '\x0000000000000000'
+);
+
+CREATE TABLE IF NOT EXISTS current_state (
+    id BIGINT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    channel_id BIGINT PRIMARY KEY REFERENCES channel(id),
+    spent_balance BYTEA NOT NULL CHECK (length(spent_balance) = 16),
+    signature TEXT NOT NULL,
+    payload TEXT DEFAULT NULL,
+    terminal BOOLEAN NOT NULL DEFAULT FALSE,
+    nonce BYTEA NOT NULL DEFAULT '\x0000000000000000'
+);
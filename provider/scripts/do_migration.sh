@@ -5,4 +5,8 @@ set -e
 echo "Running migrations"
 
 DB_URL=$(yq '.db_url' configs/test_config.yaml)
-sqlx migrate run --database-url $DB_URL
+case $DB_URL in
+  postgres://* | postgresql://*) SOURCE=migrations_postgres ;;
+  *) SOURCE=migrations ;;
+esac
+sqlx migrate run --source $SOURCE --database-url $DB_URL
@@ -1,16 +1,37 @@
 use std::time::Duration;
 
+use chrono::NaiveDateTime;
 use futures::stream::{self, StreamExt};
+use near_sdk::NearToken;
 use tokio::task::JoinHandle;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-use crate::{CloseChannelType, ProviderCtx, ProviderError, STALE_CHANNEL_THRESHOLD};
+use crate::{CloseChannelType, ProviderCtx, ProviderError, SignedStateError};
 
 const POLL_INTERVAL: Duration = Duration::from_secs(5);
 const BATCH_SIZE: u32 = 16;
 const MAX_CONCURRENT_TASKS: u32 = 4;
 const CHANNEL_INACTIVITY_CLOSE_THRESHOLD: Duration = Duration::from_secs(60 * 60 * 24); // 1 day
 
+// A channel is inactive when its last payment is older than the inactivity threshold
+pub fn is_channel_inactive(last_payment_at: NaiveDateTime, now: NaiveDateTime) -> bool {
+    last_payment_at < now - CHANNEL_INACTIVITY_CLOSE_THRESHOLD
+}
+
+// Funds at risk on a force closing channel: the outstanding balance if the force close
+// finishes within the warning window and the balance is above the threshold. The sender is
+// refunded whatever wasn't withdrawn when the force close finishes
+pub fn at_risk_liability(
+    force_close_deadline: NaiveDateTime,
+    outstanding_balance: NearToken,
+    now: NaiveDateTime,
+    warning_window: Duration,
+    threshold: NearToken,
+) -> Option<NearToken> {
+    let finishing_soon = force_close_deadline < now + warning_window;
+    (finishing_soon && outstanding_balance > threshold).then_some(outstanding_balance)
+}
+
 pub struct ProviderBackgroundService {
     ctx: ProviderCtx,
 }
@@ -29,100 +50,200 @@ impl ProviderBackgroundService {
                         info!("Provider Background task shutting down.");
                         break;
                     }
-                    _ = tokio::time::sleep(POLL_INTERVAL) => {
-
-                        // The background task should
-                        // 1. Withdraw+Close any 'inactive' channels
-                        // 2. Withdraw from any force closed channels
-                        match self.ctx.db.get_stale_channels(STALE_CHANNEL_THRESHOLD, Some(BATCH_SIZE)).await {
-                            Ok(channels) => {
-                                if !channels.is_empty() {
-                                    info!("Found {} stale channels", channels.len());
-                                    stream::iter(channels)
-                                        .map(|channel_row| {
-                                            let also_ctx = self.ctx.clone();
-                                            let channel_name = channel_row.name.clone();
-                                            async move {
-                                                let last_signed_state = match also_ctx.db.get_latest_signed_state(&channel_name).await {
-                                                    Ok(Some(last_signed_state)) => last_signed_state,
-
-                                                    // If no signed states are found then nothing to do
-                                                    // Update the channel last active time, and return
-                                                    Ok(None) => {
-                                                        info!("No signed states found for stale channel {}", channel_name);
-                                                        match also_ctx.db.update_channel_last_active(&channel_name).await {
-                                                            Ok(_) => (),
-                                                            Err(e) => error!("Error updating channel last active: {:?}", e),
-                                                        };
-                                                        return;
-                                                    },
-
-                                                    Err(ProviderError::DBError(e)) => {
-                                                        error!("Database error getting latest signed state: {}", e);
-                                                        return;
-                                                    }
-                                                    Err(e) => {
-                                                        error!("Error getting latest signed state: {:?}", e);
-                                                        return;
-                                                    }
-                                                };
-
-                                                // The background service only cares about channels with 'payments' (a.k.a signed states)
-                                                // associated with them. Make sure we have the latest state of the channel
-                                                // before proceeding
-                                                let channel_row = match also_ctx.get_fresh_channel_row(&channel_name).await {
-                                                    Ok(channel_row) => channel_row,
-                                                    Err(e) => {
-                                                        error!("Error getting fresh channel row: {:?}", e);
-                                                        return;
-                                                    }
-                                                };
-
-                                                // To withdraw funds means that the last known signed state
-                                                // has a spend balance greater than the previously withdrawn balance
-                                                let can_withdraw_funds = channel_row.withdrawn_balance() < last_signed_state.spent_balance();
-
-                                                // If the channel is inactive and has a withdrawable balance,
-                                                // try to withdraw funds and close the channel
-                                                let channel_inactive = last_signed_state.created_at < (chrono::Utc::now().naive_utc() - CHANNEL_INACTIVITY_CLOSE_THRESHOLD);
-                                                if channel_inactive && can_withdraw_funds {
-                                                    match also_ctx.try_withdraw_funds(&channel_name, CloseChannelType::HardClose).await {
-                                                        Ok(_) => (),
-                                                        Err(e) => error!("Error withdrawing funds from channel {}: {:?}", channel_name, e),
-                                                    }
-                                                }
-                                                // If the channel has been force closed and has a withdrawable balance,
-                                                // try to withdraw funds. Leave the channel open
-                                                else if channel_row.force_close_started.is_some() && can_withdraw_funds {
-                                                    match also_ctx.try_withdraw_funds(&channel_name, CloseChannelType::SoftClose).await {
-                                                        Ok(_) => (),
-                                                        Err(e) => error!("Error withdrawing funds from channel {}: {:?}", channel_name, e),
-                                                    }
-                                                }
-                                                // if the channel is active update it to the last active time
-                                                else {
-                                                    match also_ctx.db.update_channel_last_active(&channel_name).await {
-                                                        Ok(_) => (),
-                                                        Err(e) => error!("Error updating channel last active: {:?}", e),
-                                                    };
-                                                }
-                                            }
-                                        })
-                                        .buffer_unordered(MAX_CONCURRENT_TASKS as usize)
-                                        .collect::<Vec<_>>()
-                                        .await;
+                    _ = tokio::time::sleep(POLL_INTERVAL) => self.process_stale_channels().await,
+                }
+            }
+        })
+    }
+
+    // One pass of the background task, run every `POLL_INTERVAL`. Processes a batch of
+    // stale channels:
+    // 1. Withdraw+Close any 'inactive' channels
+    // 2. Withdraw from any force closed channels
+    pub async fn process_stale_channels(&self) {
+        match self
+            .ctx
+            .db
+            .get_stale_channels(
+                self.ctx.clock.now(),
+                self.ctx.config.stale_channel_threshold(),
+                Some(BATCH_SIZE),
+            )
+            .await
+        {
+            Ok(channels) => {
+                if !channels.is_empty() {
+                    info!("Found {} stale channels", channels.len());
+                    stream::iter(channels)
+                        .map(|channel_row| {
+                            let also_ctx = self.ctx.clone();
+                            let channel_name = channel_row.name.clone();
+                            async move {
+                                process_stale_channel(&also_ctx, &channel_name).await;
+                                // Persisted, so a restart doesn't rescan the channels we just went through
+                                if let Err(e) = also_ctx
+                                    .db
+                                    .mark_channel_checked(&channel_name, also_ctx.clock.now())
+                                    .await
+                                {
+                                    error!(
+                                        "Error marking channel {} as checked: {:?}",
+                                        channel_name, e
+                                    );
                                 }
                             }
-                            Err(ProviderError::DBError(e)) => {
-                                error!("Database error getting stale channels: {}", e);
-                            }
-                            Err(e) => {
-                                error!("Error getting stale channels: {:?}", e);
-                            }
-                        }
-                    }
+                        })
+                        .buffer_unordered(MAX_CONCURRENT_TASKS as usize)
+                        .collect::<Vec<_>>()
+                        .await;
                 }
             }
-        })
+            Err(ProviderError::DBError(e)) => {
+                error!("Database error getting stale channels: {}", e);
+            }
+            Err(e) => {
+                error!("Error getting stale channels: {:?}", e);
+            }
+        }
+    }
+}
+
+// 1. Withdraw+Close the channel if it's 'inactive'
+// 2. Withdraw from it if it's force closed
+async fn process_stale_channel(ctx: &ProviderCtx, channel_name: &str) {
+    let last_signed_state = match ctx.db.get_latest_signed_state(channel_name).await {
+        Ok(Some(last_signed_state)) => last_signed_state,
+
+        // If no signed states are found then nothing to do
+        // Update the channel last active time, and return
+        Ok(None) => {
+            info!("No signed states found for stale channel {}", channel_name);
+            match ctx.db.update_channel_last_active(channel_name).await {
+                Ok(_) => (),
+                Err(e) => error!("Error updating channel last active: {:?}", e),
+            };
+            return;
+        }
+
+        // Closed, the final signed state was already withdrawn
+        Err(ProviderError::SignedState(SignedStateError::TerminalState(_))) => {
+            info!("Stale channel {} is closed", channel_name);
+            match ctx.db.update_channel_last_active(channel_name).await {
+                Ok(_) => (),
+                Err(e) => error!("Error updating channel last active: {:?}", e),
+            };
+            return;
+        }
+
+        Err(ProviderError::DBError(e)) => {
+            error!("Database error getting latest signed state: {}", e);
+            return;
+        }
+        Err(e) => {
+            error!("Error getting latest signed state: {:?}", e);
+            return;
+        }
+    };
+
+    // The background service only cares about channels with 'payments' (a.k.a signed states)
+    // associated with them. Make sure we have the latest state of the channel
+    // before proceeding
+    let channel_row = match ctx.get_fresh_channel_row(channel_name).await {
+        Ok(channel_row) => channel_row,
+        Err(e) => {
+            error!("Error getting fresh channel row: {:?}", e);
+            return;
+        }
+    };
+
+    // To withdraw funds means that the last known signed state
+    // has a spend balance greater than the previously withdrawn balance
+    let can_withdraw_funds = channel_row.withdrawn_balance() < last_signed_state.spent_balance();
+
+    // Checked before withdrawing, the withdrawal may not land before the force close finishes
+    if let Some(force_close_started) = channel_row.force_close_started {
+        if can_withdraw_funds {
+            let outstanding_balance = last_signed_state
+                .spent_balance()
+                .saturating_sub(channel_row.withdrawn_balance());
+            warn_if_at_risk(ctx, channel_name, force_close_started, outstanding_balance).await;
+        }
+    }
+
+    // If the channel is inactive and has a withdrawable balance,
+    // try to withdraw funds and close the channel
+    let channel_inactive = is_channel_inactive(last_signed_state.created_at, ctx.clock.now());
+    if channel_inactive && can_withdraw_funds {
+        match ctx
+            .try_withdraw_funds(channel_name, CloseChannelType::HardClose)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => error!(
+                "Error withdrawing funds from channel {}: {:?}",
+                channel_name, e
+            ),
+        }
+    }
+    // If the channel has been force closed and has a withdrawable balance,
+    // try to withdraw funds. Leave the channel open
+    else if channel_row.force_close_started.is_some() && can_withdraw_funds {
+        match ctx
+            .try_withdraw_funds(channel_name, CloseChannelType::SoftClose)
+            .await
+        {
+            Ok(_) => (),
+            Err(e) => error!(
+                "Error withdrawing funds from channel {}: {:?}",
+                channel_name, e
+            ),
+        }
+    }
+    // if the channel is active update it to the last active time
+    else {
+        match ctx.db.update_channel_last_active(channel_name).await {
+            Ok(_) => (),
+            Err(e) => error!("Error updating channel last active: {:?}", e),
+        };
+    }
+}
+
+// Logs the at-risk liability of a force closing channel, see `at_risk_liability`
+async fn warn_if_at_risk(
+    ctx: &ProviderCtx,
+    channel_name: &str,
+    force_close_started: NaiveDateTime,
+    outstanding_balance: NearToken,
+) {
+    let Some(warning_window) = ctx
+        .config
+        .at_risk_warning_window_secs
+        .map(Duration::from_secs)
+    else {
+        return;
+    };
+    let threshold = NearToken::from_yoctonear(
+        ctx.config
+            .at_risk_balance_threshold
+            .map(|threshold| threshold.0)
+            .unwrap_or(0),
+    );
+
+    let force_close_deadline = force_close_started + ctx.force_close_timeout(channel_name).await;
+    let now = ctx.clock.now();
+    if let Some(liability) = at_risk_liability(
+        force_close_deadline,
+        outstanding_balance,
+        now,
+        warning_window,
+        threshold,
+    ) {
+        warn!(
+            at_risk_liability = %liability,
+            "Force closing channel {} finishes in {} seconds with {} not withdrawn",
+            channel_name,
+            (force_close_deadline - now).num_seconds().max(0),
+            liability
+        );
     }
 }
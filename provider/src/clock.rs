@@ -0,0 +1,48 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+// Source of the current time for staleness / inactivity decisions.
+// Production uses the system clock, tests can swap in a `MockClock`
+// and advance time deterministically instead of sleeping
+pub trait Clock: Send + Sync {
+    fn now(&self) -> NaiveDateTime;
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> NaiveDateTime {
+        chrono::Utc::now().naive_utc()
+    }
+}
+
+// A clock that only moves when told to
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<NaiveDateTime>>,
+}
+
+impl MockClock {
+    pub fn new(now: NaiveDateTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: NaiveDateTime) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> NaiveDateTime {
+        *self.now.lock().unwrap()
+    }
+}
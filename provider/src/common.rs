@@ -1,15 +1,17 @@
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Error;
+use base64::{prelude::BASE64_STANDARD, Engine};
 use borsh::to_vec;
-use borsh::BorshSerialize;
+use chrono::NaiveDateTime;
 use cli::config::{
     Config as NearPaymentChannelContractClientConfig, SignedState as NearSignedState,
-    State as NearState,
+    State as NearState, CLOSE_NONCE,
 };
-use cli::contract::Contract as NearPaymentChannelContractClient;
-use near_cli_rs::common::KeyPairProperties;
+use cli::contract::{Contract as NearPaymentChannelContractClient, MAX_CHANNELS_PER_VIEW};
 use near_cli_rs::config::Config as NearConfig;
 use near_cli_rs::config::NetworkConfig as NearNetworkConfig;
 use near_crypto::InMemorySigner;
@@ -23,25 +25,577 @@ use near_sdk::json_types::U128;
 use near_sdk::NearToken;
 use serde::Deserialize;
 use serde::Serialize;
+use subtle::ConstantTimeEq;
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tracing::{debug, error, info, instrument, warn};
 
+use crate::AuditEntryRow;
+use crate::ChannelContract;
 use crate::ChannelError;
 use crate::ChannelRow;
+use crate::Clock;
+use crate::KeySource;
 use crate::ProviderError;
 use crate::ProviderResult;
+use crate::SecretKeySource;
+use crate::SharedState;
 use crate::SignedStateError;
-use crate::{ProviderDb, MODEL_DELIMITER};
+use crate::SystemClock;
+use crate::UserFacingError;
+use crate::{
+    is_postgres_url, record_payment_rejected, record_withdrawal, ProviderDb, DEFAULT_MAX_TOKENS,
+    IDEMPOTENCY_KEY_HEADER_NAME, MODEL_DELIMITER, STALE_CHANNEL_THRESHOLD,
+};
+
+// Printed by `generate-config`, all the fields of `ProviderConfig` with the optional ones
+// commented out
+pub const EXAMPLE_CONFIG: &str = include_str!("../configs/example_config.yaml");
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProviderConfig {
     pub providers: Vec<Provider>,
     pub account_id: AccountId,
-    pub network: String,
+    // Additional receiver accounts served by this provider (e.g. to parallelize withdrawals)
+    #[serde(default)]
+    pub extra_account_ids: Vec<AccountId>,
+    pub network: Network,
     pub db_url: String,
     pub cost_per_completion: U128,
     pub min_withdraw_amount: U128,
+    // Upper bound on how much a single signed state can increase the spent balance.
+    // Protects senders from buggy clients signing far more than intended.
+    #[serde(default)]
+    pub max_payment_per_request: Option<U128>,
+    // Bearer token required to access admin endpoints. Admin endpoints are disabled if unset
+    // (and no `operator_api_keys` are configured)
+    #[serde(default)]
+    pub admin_api_key: Option<String>,
+    // Bearer tokens of the admin endpoints keyed by operator name, the audit log records
+    // the actions of each operator under its name. Actions with `admin_api_key` are
+    // recorded as `admin`
+    #[serde(default)]
+    pub operator_api_keys: HashMap<String, String>,
+    // When cooperatively closing a channel with a remaining balance below this threshold,
+    // the provider submits the close (refund) transaction itself. The provider absorbs
+    // the gas cost of the close call (15 TGas, ~0.0015 NEAR at the minimum gas price).
+    // The close request is then answered with a hard closed error instead of a payload
+    #[serde(default)]
+    pub dust_refund_threshold: Option<U128>,
+    // Seconds until a channel is considered stale and is refreshed from the contract
+    #[serde(default)]
+    pub stale_channel_threshold_secs: Option<u64>,
+    // Always read a channel from the contract the first time it's used after a restart,
+    // even if the database copy isn't stale. Guards against serving channels that were
+    // closed on-chain since they were last seen, at the cost of latency on first use
+    #[serde(default)]
+    pub refresh_on_first_use: bool,
+    // Price per completion token, on top of `cost_per_completion`. When set, requests are
+    // pre-authorized for `max_tokens` (or `max_completion_tokens`) and the unused part is kept
+    // as credit for the next request. Requests without a limit are sent with 16 `max_tokens`
+    #[serde(default)]
+    pub cost_per_token: Option<U128>,
+    // Maximum number of completions served concurrently, streams count until they end.
+    // Requests above the limit are rejected with 429, and every response carries the current load
+    #[serde(default)]
+    pub max_concurrent_requests: Option<usize>,
+    // Maximum number of requests handled at once across all routes, above it requests are
+    // rejected with 503. Bounds memory use under a spike of large uploads
+    #[serde(default)]
+    pub max_in_flight_requests: Option<usize>,
+    // Deadline of a whole completion request, payment validation and upstream call included.
+    // Requests past it are aborted with 504 and their payment is credited back, streams are
+    // cut at the deadline. No deadline if unset
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    // Largest upstream completion response read, bigger responses are dropped and answered
+    // with 502 instead of being buffered in memory. No limit if unset
+    #[serde(default)]
+    pub max_response_bytes: Option<usize>,
+    // Only keep the latest signed state of each channel, instead of the full payment history
+    #[serde(default)]
+    pub prune_signed_states: bool,
+    // Serve all the routes under this prefix (e.g. `/api/ppp` behind a reverse proxy)
+    #[serde(default)]
+    pub base_path: Option<String>,
+    // Only accept payments on channels registered by the operator (`/pc/register`),
+    // e.g. to approve senders before serving them
+    #[serde(default)]
+    pub require_registration: bool,
+    // Provider of models requested without the `provider::` prefix. Requests with a bare
+    // model name are rejected if unset
+    #[serde(default)]
+    pub default_provider: Option<String>,
+    // Percent of the price charged for responses cut by the content filter, the rest is
+    // credited to the channel for the next request. Charged in full if unset
+    #[serde(default)]
+    pub content_filter_charge_percent: Option<u8>,
+    // Smallest deposit expected from senders, only used to sanity check the prices
+    // and `min_withdraw_amount` at startup
+    #[serde(default)]
+    pub min_channel_deposit: Option<U128>,
+    // Accept notifications of newly opened channels on `/pc/open` and read them from the
+    // contract in the background, so the first payment on a channel is served from the cache
+    #[serde(default)]
+    pub warm_up_channels: bool,
+    // Warn about force closing channels finishing within this many seconds with earned funds
+    // not withdrawn yet, the sender is refunded whatever wasn't withdrawn once the force close
+    // finishes. No warning if unset
+    #[serde(default)]
+    pub at_risk_warning_window_secs: Option<u64>,
+    // Smallest outstanding balance warned about, see `at_risk_warning_window_secs`.
+    // Any outstanding balance if unset
+    #[serde(default)]
+    pub at_risk_balance_threshold: Option<U128>,
+    // Where the secret keys of the receiver accounts are read from, the near-cli-rs
+    // credentials file by default
+    #[serde(default)]
+    pub key_source: KeySource,
+    // Largest `max_tokens` a completion can request, requests without `max_tokens` count as
+    // the OpenAI default of 16. No limit if unset
+    #[serde(default)]
+    pub max_tokens_limit: Option<u64>,
+    // What happens to requests above the `max_tokens` limit
+    #[serde(default)]
+    pub max_tokens_limit_mode: MaxTokensLimitMode,
+    // What happens to completion requests accepting only `text/event-stream` that don't
+    // set `stream`
+    #[serde(default)]
+    pub event_stream_accept_mode: EventStreamAcceptMode,
+    // Models resold by the provider, served by the models endpoints as is. The other models
+    // of the upstreams are listed and retrieved from their `/models` endpoint
+    #[serde(default)]
+    pub models: Vec<ModelListing>,
+    // Prices per prompt and completion token of some models, keyed by `provider::model`.
+    // Completions of these models are charged their flat price plus their token usage
+    // instead of the provider wide pricing. Requests are pre-authorized for `max_tokens`
+    // completion tokens, the prompt isn't priced until the response comes back and the
+    // part the payment didn't cover is owed on the next request of the channel
+    #[serde(default)]
+    pub token_rates: HashMap<String, TokenRates>,
+    // Endpoints not served (404), e.g. to not expose the cooperative close or the models.
+    // `/health` is always served
+    #[serde(default)]
+    pub disabled_endpoints: Vec<Endpoint>,
+}
+
+// Endpoints that can be disabled, see `ProviderConfig::disabled_endpoints`. Each one
+// covers all the routes of the feature (e.g. `close` is the close and its format)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    Info,
+    Close,
+    Open,
+    State,
+    History,
+    Validate,
+    DebugHeader,
+    Summary,
+    Disable,
+    Register,
+    Drain,
+    Audit,
+    Models,
+    Completions,
+    ChatCompletions,
+    Metrics,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct TokenRates {
+    // Price per prompt token
+    pub price_in: U128,
+    // Price per completion token
+    pub price_out: U128,
+}
+
+impl ProviderConfig {
+    // All the accounts the provider receives payments on, the primary account first
+    pub fn account_ids(&self) -> Vec<AccountId> {
+        let mut account_ids = vec![self.account_id.clone()];
+        account_ids.extend(self.extra_account_ids.iter().cloned());
+        account_ids
+    }
+
+    // Limit of the upstream if it has one, otherwise the provider wide limit
+    pub fn max_tokens_limit(&self, provider: &Provider) -> Option<u64> {
+        provider.max_tokens_limit.or(self.max_tokens_limit)
+    }
+
+    pub fn stale_channel_threshold(&self) -> Duration {
+        self.stale_channel_threshold_secs
+            .map(Duration::from_secs)
+            .unwrap_or(STALE_CHANNEL_THRESHOLD)
+    }
+
+    // Several upstreams can share the same canonical name (e.g. different regions or
+    // quality tiers). Pick the upstream matching the route hint, falling back to the
+    // default upstream (no route configured), and then to the first one listed.
+    pub fn find_provider(&self, canonical_name: &str, route: Option<&str>) -> Option<&Provider> {
+        find_provider(&self.providers, canonical_name, route)
+    }
+
+    // Operator authenticated by a bearer token of the admin endpoints. Every key is
+    // compared in constant time, so the response time doesn't leak how close a token is
+    pub fn admin_operator(&self, token: &str) -> Option<&str> {
+        let admin = self.admin_api_key.iter().map(|key| (ADMIN_OPERATOR, key));
+        let operators = self
+            .operator_api_keys
+            .iter()
+            .map(|(operator, key)| (operator.as_str(), key));
+        admin
+            .chain(operators)
+            .fold(None, |authenticated, (operator, key)| {
+                let matches: bool = key.as_bytes().ct_eq(token.as_bytes()).into();
+                authenticated.or(matches.then_some(operator))
+            })
+    }
+
+    pub fn admin_enabled(&self) -> bool {
+        self.admin_api_key.is_some() || !self.operator_api_keys.is_empty()
+    }
+
+    pub fn is_enabled(&self, endpoint: Endpoint) -> bool {
+        !self.disabled_endpoints.contains(&endpoint)
+    }
+
+    // Flat price of a completion served by `provider`. The price of the upstream takes
+    // precedence over `cost_per_completion`, token prices are added on top of it
+    pub fn completion_price(&self, provider: &Provider) -> u128 {
+        provider.price.unwrap_or(self.cost_per_completion).0
+    }
+
+    // Worst case cost of a completion at `price`, every requested token is generated.
+    // Defaults to 16 tokens if the request doesn't set a limit, it's then sent with that limit
+    pub fn max_completion_cost(&self, price: u128, max_tokens: Option<u64>) -> u128 {
+        self.completion_cost(price, max_tokens.unwrap_or(DEFAULT_MAX_TOKENS))
+    }
+
+    pub fn completion_cost(&self, price: u128, completion_tokens: u64) -> u128 {
+        let cost_per_token = self.cost_per_token.map(|c| c.0).unwrap_or(0);
+        price.saturating_add(cost_per_token.saturating_mul(completion_tokens as u128))
+    }
+
+    pub fn token_rates(&self, model_info: &ModelInfo) -> Option<TokenRates> {
+        self.token_rates
+            .get(&format!(
+                "{}::{}",
+                model_info.provider, model_info.model_name
+            ))
+            .copied()
+    }
+
+    // Worst case cost of a completion priced with `rates`, before its prompt is priced.
+    // Defaults to 16 tokens if the request doesn't set a limit, it's then sent with that limit
+    pub fn max_usage_cost(&self, price: u128, rates: &TokenRates, max_tokens: Option<u64>) -> u128 {
+        self.usage_cost(price, rates, 0, max_tokens.unwrap_or(DEFAULT_MAX_TOKENS))
+    }
+
+    pub fn usage_cost(
+        &self,
+        price: u128,
+        rates: &TokenRates,
+        prompt_tokens: u64,
+        completion_tokens: u64,
+    ) -> u128 {
+        price
+            .saturating_add(rates.price_in.0.saturating_mul(prompt_tokens as u128))
+            .saturating_add(rates.price_out.0.saturating_mul(completion_tokens as u128))
+    }
+
+    // Part of `cost` charged for a response, see `content_filter_charge_percent`
+    pub fn charged_cost(&self, cost: u128, content_filtered: bool) -> u128 {
+        match self.content_filter_charge_percent {
+            Some(percent) if content_filtered => cost / 100 * percent as u128,
+            _ => cost,
+        }
+    }
+
+    // Completions of the default size (see `max_completion_cost`) a balance pays for
+    pub fn estimated_requests_remaining(&self, available_balance: u128) -> Option<u128> {
+        match self.max_completion_cost(self.cost_per_completion.0, None) {
+            0 => None,
+            cost => Some(available_balance / cost),
+        }
+    }
+
+    // Settings that load fine but likely don't do what the operator expects,
+    // e.g. a `min_withdraw_amount` no channel ever reaches. Logged at startup
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        let completion_cost = self.max_completion_cost(self.cost_per_completion.0, None);
+        let min_withdraw_amount = self.min_withdraw_amount.0;
+
+        if completion_cost == 0 {
+            warnings.push(
+                "Completions are free (cost_per_completion and cost_per_token are 0), nothing will be withdrawn"
+                    .to_string(),
+            );
+        } else if min_withdraw_amount / completion_cost > MAX_COMPLETIONS_PER_WITHDRAW {
+            warnings.push(format!(
+                "min_withdraw_amount ({}) is more than {} completions ({} each), channels will rarely be withdrawn from. Lower min_withdraw_amount",
+                min_withdraw_amount, MAX_COMPLETIONS_PER_WITHDRAW, completion_cost
+            ));
+        }
+
+        if let Some(min_channel_deposit) = self.min_channel_deposit.map(|d| d.0) {
+            if min_withdraw_amount > min_channel_deposit {
+                warnings.push(format!(
+                    "min_withdraw_amount ({}) is above min_channel_deposit ({}), small channels are only paid out when they close. Lower min_withdraw_amount",
+                    min_withdraw_amount, min_channel_deposit
+                ));
+            }
+            if completion_cost > min_channel_deposit {
+                warnings.push(format!(
+                    "A completion costs {}, more than min_channel_deposit ({}), small channels can't pay for a single completion",
+                    completion_cost, min_channel_deposit
+                ));
+            }
+        }
+
+        warnings
+    }
+
+    // Checks that can't be expressed by deserialization alone
+    pub fn validate(&self) -> Result<(), String> {
+        validate_providers(&self.providers)?;
+        for (operator, key) in &self.operator_api_keys {
+            if operator.trim().is_empty() || key.trim().is_empty() {
+                return Err("operator_api_keys cannot have empty names or keys".to_string());
+            }
+        }
+        if let Some(default_provider) = &self.default_provider {
+            if !self
+                .providers
+                .iter()
+                .any(|provider| provider.canonical_name == *default_provider)
+            {
+                return Err(format!(
+                    "default_provider {} is not the canonical name of a provider",
+                    default_provider
+                ));
+            }
+        }
+        if self.db_url.trim().is_empty() {
+            return Err("db_url cannot be empty".to_string());
+        }
+        if is_postgres_url(&self.db_url) && !cfg!(feature = "postgres") {
+            return Err(
+                "postgres db_url requires building the provider with the `postgres` feature"
+                    .to_string(),
+            );
+        }
+        if let Network::Custom(name) = &self.network {
+            if name.trim().is_empty() {
+                return Err("custom network name cannot be empty".to_string());
+            }
+        }
+        if self
+            .content_filter_charge_percent
+            .is_some_and(|percent| percent > 100)
+        {
+            return Err("content_filter_charge_percent must be at most 100".to_string());
+        }
+        if self.request_timeout_secs == Some(0) {
+            return Err("request_timeout_secs must be above 0".to_string());
+        }
+        if self.max_response_bytes == Some(0) {
+            return Err("max_response_bytes must be above 0".to_string());
+        }
+        if self.at_risk_warning_window_secs == Some(0) {
+            return Err("at_risk_warning_window_secs must be above 0".to_string());
+        }
+        if let Some(base_path) = &self.base_path {
+            if !base_path.starts_with('/') {
+                return Err(format!("base_path {} must start with '/'", base_path));
+            }
+        }
+        Ok(())
+    }
+}
+
+// NEAR network the provider runs on, must be configured in near-cli-rs. Unknown names
+// are rejected when loading the config, other networks (e.g. a localnet) are used with
+// `network: { custom: <name> }`
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Custom(String),
+}
+
+impl Network {
+    // Name of the connection in the near-cli-rs config
+    pub fn name(&self) -> &str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Custom(name) => name,
+        }
+    }
+
+    // Connection (rpc url, ...) of the network in the near-cli-rs config
+    pub fn network_config(&self, near_config: &NearConfig) -> Result<NearNetworkConfig, String> {
+        near_config
+            .network_connection
+            .get(self.name())
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "Network {} is not configured in near-cli-rs, available: {}",
+                    self.name(),
+                    near_config
+                        .network_connection
+                        .keys()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Requests above the `max_tokens` limit are either rejected with a 400, or served with
+// `max_tokens` lowered to the limit
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaxTokensLimitMode {
+    #[default]
+    Reject,
+    Clamp,
+}
+
+// Completion requests accepting only `text/event-stream` without setting `stream` are
+// either streamed as if they set `stream: true`, or rejected with a 400
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventStreamAcceptMode {
+    #[default]
+    Stream,
+    Reject,
+}
+
+// Operator of the actions taken with `admin_api_key` in the audit log
+pub const ADMIN_OPERATOR: &str = "admin";
+// Operator of the actions triggered by a unix signal (SIGHUP, SIGUSR1) in the audit log
+pub const SIGNAL_OPERATOR: &str = "signal";
+
+// How often draining checks whether the completions in flight are done
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Past this many completions per withdrawal, `min_withdraw_amount` is likely misconfigured
+const MAX_COMPLETIONS_PER_WITHDRAW: u128 = 1_000_000;
+
+fn find_provider<'a>(
+    providers: &'a [Provider],
+    canonical_name: &str,
+    route: Option<&str>,
+) -> Option<&'a Provider> {
+    let mut candidates = providers
+        .iter()
+        .filter(|p| p.canonical_name == canonical_name);
+
+    if let Some(route) = route {
+        if let Some(provider) = candidates
+            .clone()
+            .find(|p| p.route.as_deref() == Some(route))
+        {
+            return Some(provider);
+        }
+    }
+
+    candidates
+        .clone()
+        .find(|p| p.route.is_none())
+        .or_else(|| candidates.next())
+}
+
+// Required fields of `ProviderConfig` and their expected type, checked up front since
+// deserialization errors don't always say which field is wrong
+const REQUIRED_CONFIG_FIELDS: [(&str, &str); 6] = [
+    (
+        "providers",
+        "a list of providers (canonical_name, url, api_key)",
+    ),
+    ("account_id", "a NEAR account id"),
+    (
+        "network",
+        "\"mainnet\", \"testnet\" or { custom: <near-cli-rs network name> }",
+    ),
+    (
+        "db_url",
+        "a sqlite or postgres url, e.g. \"sqlite://db.sqlite?mode=rwc\"",
+    ),
+    ("cost_per_completion", "an amount in yoctoNEAR, as a string"),
+    ("min_withdraw_amount", "an amount in yoctoNEAR, as a string"),
+];
+
+// Read and validate the config file, errors name the file and the offending field
+pub fn load_config(config_filename: &str) -> Result<ProviderConfig, String> {
+    let config = ::config::Config::builder()
+        .add_source(::config::File::with_name(config_filename))
+        .build()
+        .map_err(|e| format!("Error reading config filename {}: {}", config_filename, e))?;
+
+    for (field, expected) in REQUIRED_CONFIG_FIELDS {
+        if config.get::<::config::Value>(field).is_err() {
+            return Err(format!(
+                "Error parsing config {}: missing required field `{}`, expected {}",
+                config_filename, field, expected
+            ));
+        }
+    }
+
+    let provider_config = config.try_deserialize::<ProviderConfig>().map_err(|e| {
+        format!(
+            "Error parsing config {}: {}. Run `generate-config` for an example config",
+            config_filename, e
+        )
+    })?;
+    provider_config
+        .validate()
+        .map_err(|e| format!("Invalid config {}: {}", config_filename, e))?;
+    provider_config
+        .network
+        .network_config(&NearConfig::default())
+        .map_err(|e| format!("Invalid config {}: {}", config_filename, e))?;
+
+    Ok(provider_config)
+}
+
+// Checks the upstream providers are usable before they are (re)loaded
+pub fn validate_providers(providers: &[Provider]) -> Result<(), String> {
+    if providers.is_empty() {
+        return Err("At least one provider must be configured".to_string());
+    }
+    for provider in providers {
+        if provider.canonical_name.trim().is_empty() {
+            return Err("Provider canonical_name cannot be empty".to_string());
+        }
+        if provider.url.trim().is_empty() {
+            return Err(format!(
+                "Provider {} url cannot be empty",
+                provider.canonical_name
+            ));
+        }
+        if let Some(cost_header) = &provider.cost_header {
+            if http::HeaderName::from_bytes(cost_header.name.as_bytes()).is_err() {
+                return Err(format!(
+                    "Provider {} cost_header name {} is not a valid header name",
+                    provider.canonical_name, cost_header.name
+                ));
+            }
+        }
+    }
+    Ok(())
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
@@ -49,6 +603,93 @@ pub struct Provider {
     pub canonical_name: String,
     pub url: String,
     pub api_key: String,
+    // Routing hint clients can send in the `X-PPP-Route` header to select this upstream
+    #[serde(default)]
+    pub route: Option<String>,
+    // Overrides `ProviderConfig::max_tokens_limit` for this upstream
+    #[serde(default)]
+    pub max_tokens_limit: Option<u64>,
+    // Overrides `ProviderConfig::cost_per_completion` for this upstream, e.g. to price a
+    // frontier model above a small one
+    #[serde(default)]
+    pub price: Option<U128>,
+    // Response header of the upstream with the cost of the completion, charged instead of
+    // the token or flat price when present
+    #[serde(default)]
+    pub cost_header: Option<CostHeader>,
+}
+
+// The upstream reports the cost of each completion in a response header, as a decimal
+// amount in its own unit (e.g. `X-Cost: 0.00042` in USD). The amount charged is
+//
+//   ceil(cost * unit_price * (100 + markup_percent) / 100)
+//
+// e.g. 0.00042 USD at 200000000000000000000000 yoctoNEAR per USD with a 20% markup
+// charges 100800000000000000000 yoctoNEAR. The payment of the request is still sized by
+// the token or flat price, a charge above it is only carried over as debt with token rates
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CostHeader {
+    pub name: String,
+    // yoctoNEAR per unit of the header value
+    pub unit_price: U128,
+    // Percent added on top of the upstream cost, 0 charges the cost as is
+    #[serde(default)]
+    pub markup_percent: u32,
+}
+
+// Most fractional digits accepted in a cost header value
+const MAX_COST_DECIMALS: usize = 24;
+
+impl CostHeader {
+    // Amount charged for a header `value`, None if it isn't a non-negative decimal number
+    // or the charge overflows
+    pub fn charge(&self, value: &str) -> Option<u128> {
+        let (integer, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+        if (integer.is_empty() && fraction.is_empty())
+            || fraction.len() > MAX_COST_DECIMALS
+            || !integer
+                .chars()
+                .chain(fraction.chars())
+                .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+        // The cost is `mantissa / 10^decimals`
+        let mantissa: u128 = format!("{}{}", integer, fraction).parse().ok()?;
+        let divisor = 10u128
+            .checked_pow(fraction.len() as u32)?
+            .checked_mul(100)?;
+        let dividend = mantissa
+            .checked_mul(self.unit_price.0)?
+            .checked_mul(100 + self.markup_percent as u128)?;
+        Some(dividend.div_ceil(divisor))
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ModelListing {
+    // Model id as used by clients in completions, e.g. `openai::gpt-4o-mini`
+    pub id: String,
+    pub owned_by: String,
+    // Unix timestamp (in seconds) when the model was created
+    #[serde(default)]
+    pub created: i64,
+    #[serde(default)]
+    pub context_window: Option<u64>,
+    #[serde(default)]
+    pub cost_per_completion: Option<U128>,
+}
+
+impl ModelListing {
+    // OpenAI model object, metadata not part of the OpenAI schema is left out
+    pub fn to_openai_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "object": "model",
+            "created": self.created,
+            "owned_by": self.owned_by,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -102,24 +743,26 @@ pub struct AccountInfoPublic {
     pub public_key: NearPublicKey,
 }
 
+// Served by `/info`. The primary account is kept at the top level, channels are opened
+// with it by default
+#[derive(Clone, Serialize)]
+pub struct ProviderInfo {
+    #[serde(flatten)]
+    pub primary: AccountInfoPublic,
+    // Every account the provider receives payments on, the primary account first
+    pub receivers: Vec<AccountInfoPublic>,
+}
+
 impl AccountInfoPrivate {
     pub fn new(
-        credentials_home_dir: &std::path::Path,
+        key_source: &dyn SecretKeySource,
         account_id: AccountId,
         network_config: NearNetworkConfig,
     ) -> Self {
-        // Get the key pair from the credentials home dir
-        let file_name = format!("{}.json", account_id);
-        let mut path = std::path::PathBuf::from(credentials_home_dir);
-        path.push(network_config.network_name.clone());
-        path.push(file_name);
-        let data = std::fs::read_to_string(&path).expect("Access key file not found!");
-        let key_pair: KeyPairProperties =
-            serde_json::from_str(&data).expect("Error reading data from file");
-        let private_key = NearSecretKey::from_str(&key_pair.secret_keypair_str)
-            .expect("Error reading data from file");
-        let public_key = NearPublicKey::from_str(&key_pair.public_key_str)
-            .expect("Error reading data from file");
+        let private_key = key_source
+            .secret_key(&account_id)
+            .unwrap_or_else(|e| panic!("Unable to load the key of {}: {}", account_id, e));
+        let public_key = private_key.public_key();
 
         Self {
             account_id,
@@ -145,12 +788,8 @@ impl AccountInfoPrivate {
     }
 }
 
-#[derive(Clone, Serialize, BorshSerialize, Deserialize)]
-pub struct State {
-    pub channel_name: String,
-    pub spent_balance: U128,
-}
-
+// Balances sent over HTTP are `U128`, serialized as a string of yoctoNEAR like the
+// `NearToken` balances of the signed states (`cli::config::SignedState`)
 #[derive(Clone, Serialize)]
 pub struct PaymentChannelState {
     pub channel_name: String,
@@ -160,42 +799,107 @@ pub struct PaymentChannelState {
     pub added_balance: U128,
     pub withdraw_balance: U128,
     pub closed: bool,
+    // How many more default sized completions the remaining balance pays for,
+    // None if completions are free
+    pub estimated_requests_remaining: Option<U128>,
+    // Spent balance the channel can't go above, None if it only is limited by its deposit
+    pub spend_cap: Option<U128>,
+    // Nonce of the latest signed state, the next one must be higher
+    pub nonce: u64,
 }
 
-#[derive(Clone)]
-pub struct ProviderCtx {
-    pub config: ProviderConfig,
-    pub cancel_token: CancellationToken,
-    pub db: ProviderDb,
-    pc_client: NearPaymentChannelContractClient,
-    account_info: Arc<RwLock<AccountInfoPrivate>>,
+// How a payment header was read, step by step. The fields of the steps after the first
+// failed one are None, `error` tells why it failed
+#[derive(Clone, Serialize, Default)]
+pub struct PaymentHeaderDiagnosis {
+    pub base64_decoded: bool,
+    pub borsh_parsed: bool,
+    pub channel_id: Option<String>,
+    pub spent_balance: Option<U128>,
+    pub nonce: Option<u64>,
+    // Checked against the sender key of the channel
+    pub signature_valid: Option<bool>,
+    pub error: Option<String>,
 }
 
-pub enum CloseChannelType {
-    HardClose,
-    SoftClose,
-    None,
+#[derive(Clone, Serialize)]
+pub struct SignedStateHistoryEntry {
+    pub created_at: NaiveDateTime,
+    pub spent_balance: U128,
 }
 
-impl ProviderCtx {
-    pub fn new(config: ProviderConfig) -> Self {
-        info!("Loading near config with network: {}", config.network);
-        let near_config = NearConfig::default();
-        let near_network_config = near_config
-            .network_connection
-            .get(&config.network.clone())
-            .expect(&format!("Network not found: {}", config.network))
-            .clone();
-
-        info!("Loading account info: {}", config.account_id);
-        let account_info = AccountInfoPrivate::new(
-            &near_config.credentials_home_dir,
-            config.account_id.clone(),
-            near_network_config.clone(),
-        );
+#[derive(Clone, Serialize)]
+pub struct PaymentChannelHistory {
+    pub channel_name: String,
+    // True if the provider only keeps the latest signed state of each channel
+    pub pruned: bool,
+    pub signed_states: Vec<SignedStateHistoryEntry>,
+}
+
+// Admin action as listed by `/admin/audit`
+#[derive(Clone, Serialize)]
+pub struct AuditEntry {
+    pub created_at: chrono::NaiveDateTime,
+    pub actor: String,
+    pub action: String,
+    pub channel_name: Option<String>,
+    pub detail: Option<String>,
+    // `success` or `failure`
+    pub outcome: &'static str,
+    pub error: Option<String>,
+}
+
+impl From<AuditEntryRow> for AuditEntry {
+    fn from(row: AuditEntryRow) -> Self {
+        Self {
+            created_at: row.created_at,
+            actor: row.actor,
+            action: row.action,
+            channel_name: row.channel_name,
+            detail: row.detail,
+            outcome: if row.error.is_none() {
+                "success"
+            } else {
+                "failure"
+            },
+            error: row.error,
+        }
+    }
+}
+
+// Aggregated view of the funds held in the channels the provider is the receiver of
+#[derive(Clone, Serialize, Default)]
+pub struct ProviderSummary {
+    pub open_channels: u64,
+    pub closing_channels: u64,
+    // Funds deposited in channels that haven't been withdrawn yet (added - withdrawn)
+    pub total_balance: U128,
+    // Funds senders have signed over that haven't been withdrawn yet (spent - withdrawn)
+    pub total_withdrawable: U128,
+    // Withdrawable funds in channels that are being force closed
+    pub total_at_risk: U128,
+}
+
+// A NEAR account the provider receives payments on, with a contract client signing as it
+#[derive(Clone)]
+pub struct ReceiverAccount {
+    pub account_id: AccountId,
+    pc_client: Arc<dyn ChannelContract>,
+    account_info: Arc<RwLock<AccountInfoPrivate>>,
+}
+
+impl ReceiverAccount {
+    fn new(
+        key_source: &dyn SecretKeySource,
+        near_network_config: &NearNetworkConfig,
+        account_id: AccountId,
+    ) -> Self {
+        info!("Loading account info: {}", account_id);
+        let account_info =
+            AccountInfoPrivate::new(key_source, account_id.clone(), near_network_config.clone());
 
         info!("Validating account info");
-        let also_account_id = config.account_id.clone();
+        let also_account_id = account_id.clone();
         let also_near_network_config = near_network_config.clone();
         let result = std::thread::spawn(move || {
             let rpc = JsonRpcClient::connect(also_near_network_config.rpc_url.as_ref());
@@ -218,7 +922,8 @@ impl ProviderCtx {
 
         info!("Creating payment channel client");
         let mut pc_client_config = NearPaymentChannelContractClientConfig::default();
-        pc_client_config.account_id = Some(config.account_id.clone());
+        pc_client_config.account_id = Some(account_id.clone());
+        pc_client_config.near_rpc_url = near_network_config.rpc_url.to_string();
         let pc_client = NearPaymentChannelContractClient::new_with_signer(
             &pc_client_config,
             InMemorySigner::from_secret_key(
@@ -227,18 +932,39 @@ impl ProviderCtx {
             ),
         );
 
-        info!("Creating database");
-        let db = ProviderDb::new(&config.db_url, account_info.account_id.clone());
+        Self {
+            account_id,
+            pc_client: Arc::new(pc_client),
+            account_info: Arc::new(RwLock::new(account_info)),
+        }
+    }
+
+    // Receiver calling the contract through `pc_client` (e.g. a mock contract in tests),
+    // nothing is read from the network
+    pub fn with_contract(
+        account_id: AccountId,
+        private_key: NearSecretKey,
+        near_network_config: NearNetworkConfig,
+        pc_client: Arc<dyn ChannelContract>,
+    ) -> Self {
+        let account_info = AccountInfoPrivate {
+            account_id: account_id.clone(),
+            network_config: near_network_config,
+            public_key: private_key.public_key(),
+            private_key,
+        };
 
         Self {
-            config,
-            db,
+            account_id,
             pc_client,
-            cancel_token: CancellationToken::new(),
             account_info: Arc::new(RwLock::new(account_info)),
         }
     }
 
+    pub async fn public_key(&self) -> NearPublicKey {
+        self.account_info.read().await.public_key.clone()
+    }
+
     // Private function to create a signed state for closing a channel
     // This is used when closing a channel and withdrawing funds
     // The signed state is signed by the provider
@@ -246,6 +972,7 @@ impl ProviderCtx {
         let state = NearState {
             channel_id: channel_name.to_string(),
             spent_balance: NearToken::from_yoctonear(0),
+            nonce: CLOSE_NONCE,
         };
         let message = borsh::to_vec(&state).unwrap();
         let signer = self.account_info.read().await.as_signer();
@@ -253,24 +980,226 @@ impl ProviderCtx {
 
         NearSignedState { state, signature }
     }
+}
+
+#[derive(Clone)]
+pub struct ProviderCtx {
+    pub config: ProviderConfig,
+    pub cancel_token: CancellationToken,
+    pub db: ProviderDb,
+    pub shared: SharedState,
+    pub clock: Arc<dyn Clock>,
+    // Upstream providers, initially `config.providers`. Can be swapped at runtime
+    // (e.g. to rotate api keys) with `reload_providers`
+    providers: Arc<RwLock<Vec<Provider>>>,
+    // The first receiver is the primary account, advertised at the top level of `/info`
+    receivers: Arc<Vec<ReceiverAccount>>,
+}
+
+pub enum CloseChannelType {
+    HardClose,
+    SoftClose,
+    None,
+}
+
+impl ProviderCtx {
+    pub fn new(config: ProviderConfig) -> Self {
+        info!("Loading near config with network: {}", config.network);
+        let near_config = NearConfig::default();
+        let near_network_config = config
+            .network
+            .network_config(&near_config)
+            .unwrap_or_else(|e| panic!("{}", e));
+
+        info!("Loading keys from: {:?}", config.key_source);
+        let key_source = config.key_source.source(&near_config, &near_network_config);
+        let receivers = config
+            .account_ids()
+            .into_iter()
+            .map(|account_id| {
+                ReceiverAccount::new(key_source.as_ref(), &near_network_config, account_id)
+            })
+            .collect::<Vec<_>>();
+
+        Self::with_receivers(config, receivers)
+    }
+
+    // Context receiving payments on `receivers`, which must be the accounts of
+    // `config.account_ids()` in the same order
+    pub fn with_receivers(config: ProviderConfig, receivers: Vec<ReceiverAccount>) -> Self {
+        info!("Creating database");
+        let db = ProviderDb::new(&config.db_url, config.account_ids())
+            .with_signed_state_pruning(config.prune_signed_states);
+
+        Self {
+            providers: Arc::new(RwLock::new(config.providers.clone())),
+            config,
+            db,
+            shared: SharedState::default(),
+            clock: Arc::new(SystemClock),
+            cancel_token: CancellationToken::new(),
+            receivers: Arc::new(receivers),
+        }
+    }
+
+    // Replace the clock used for staleness / inactivity decisions (e.g. a `MockClock` in tests)
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Get the upstream provider serving the request, see `ProviderConfig::find_provider`
+    pub async fn find_provider(
+        &self,
+        canonical_name: &str,
+        route: Option<&str>,
+    ) -> Option<Provider> {
+        find_provider(&self.providers.read().await, canonical_name, route).cloned()
+    }
+
+    // One upstream per canonical name, the one serving requests without a route hint
+    pub async fn canonical_providers(&self) -> Vec<Provider> {
+        let providers = self.providers.read().await;
+        let mut canonical_names: Vec<&str> = vec![];
+        for provider in providers.iter() {
+            if !canonical_names.contains(&provider.canonical_name.as_str()) {
+                canonical_names.push(&provider.canonical_name);
+            }
+        }
+        canonical_names
+            .into_iter()
+            .filter_map(|canonical_name| find_provider(&providers, canonical_name, None).cloned())
+            .collect()
+    }
+
+    // Parse the `provider::model` of a request, qualifying bare model names with the
+    // default provider. The error lists the models (or providers) clients can use
+    pub async fn model_info(&self, model: &str) -> Result<ModelInfo, String> {
+        if let Some(default_provider) = &self.config.default_provider {
+            if !model.contains(MODEL_DELIMITER) && !model.trim().is_empty() {
+                return Ok(ModelInfo::new(
+                    default_provider.clone(),
+                    model.trim().to_string(),
+                ));
+            }
+        }
+
+        let e = match ModelInfo::from_str(model) {
+            Ok(model_info) => return Ok(model_info),
+            Err(e) => e,
+        };
+        let available = if self.config.models.is_empty() {
+            let mut providers = self
+                .providers
+                .read()
+                .await
+                .iter()
+                .map(|p| format!("{}{}<model>", p.canonical_name, MODEL_DELIMITER))
+                .collect::<Vec<_>>();
+            providers.sort();
+            providers.dedup();
+            providers
+        } else {
+            self.config.models.iter().map(|m| m.id.clone()).collect()
+        };
+        Err(format!(
+            "{} Models are requested as `provider{}model`, available: {}",
+            e,
+            MODEL_DELIMITER,
+            available.join(", ")
+        ))
+    }
+
+    // Atomically replace the upstream providers (urls, api keys), leaves the
+    // database and channel state untouched
+    pub async fn reload_providers(&self, providers: Vec<Provider>) -> Result<(), String> {
+        validate_providers(&providers)?;
+        info!(
+            "Reloading providers: {:?}",
+            providers
+                .iter()
+                .map(|p| p.canonical_name.clone())
+                .collect::<Vec<_>>()
+        );
+        *self.providers.write().await = providers;
+        Ok(())
+    }
+
+    fn primary_receiver(&self) -> &ReceiverAccount {
+        &self.receivers[0]
+    }
+
+    // Find the provider account that is the receiver of the channel
+    fn channel_receiver(&self, channel_row: &ChannelRow) -> ProviderResult<&ReceiverAccount> {
+        self.receivers
+            .iter()
+            .find(|receiver| receiver.account_id.as_str() == channel_row.receiver)
+            .ok_or_else(|| {
+                ProviderError::Channel(ChannelError::InvalidOwner(format!(
+                    "Receiver {} of channel {} is not an account of this provider",
+                    channel_row.receiver, channel_row.name
+                )))
+            })
+    }
 
     // Refresh a channel from the contract to the database
     async fn refresh_channel_row(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
         info!("Refreshing channel from contract: {}", channel_name);
-        match self.pc_client.channel(channel_name).await {
-            Some(contract_channel) => Ok(self
-                .db
-                .upsert_channel_row(channel_name, contract_channel)
-                .await?),
+        match self
+            .primary_receiver()
+            .pc_client
+            .channel(channel_name)
+            .await
+        {
+            Some(contract_channel) => {
+                let channel_row = self
+                    .db
+                    .upsert_channel_row(channel_name, contract_channel)
+                    .await?;
+                self.shared.mark_verified_on_chain(channel_name);
+                Ok(channel_row)
+            }
             None => Err(ProviderError::Channel(ChannelError::NotFoundInContract)),
         }
     }
 
+    // Refresh several channels from the contract, one view call per batch
+    async fn refresh_channel_rows(
+        &self,
+        channel_names: &[String],
+    ) -> ProviderResult<Vec<ChannelRow>> {
+        let mut channel_rows = Vec::with_capacity(channel_names.len());
+        for batch in channel_names.chunks(MAX_CHANNELS_PER_VIEW) {
+            info!("Refreshing {} channels from contract", batch.len());
+            let contract_channels = self.primary_receiver().pc_client.channels(batch).await;
+            for (channel_name, contract_channel) in batch.iter().zip(contract_channels) {
+                let contract_channel = contract_channel
+                    .ok_or(ProviderError::Channel(ChannelError::NotFoundInContract))?;
+                let channel_row = self
+                    .db
+                    .upsert_channel_row(channel_name, contract_channel)
+                    .await?;
+                self.shared.mark_verified_on_chain(channel_name);
+                channel_rows.push(channel_row);
+            }
+        }
+        Ok(channel_rows)
+    }
+
     // Reads a channel row from the database, if it's stale
     // refresh the contents from the contract and return
     pub async fn get_fresh_channel_row(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
+        if self.config.refresh_on_first_use && !self.shared.is_verified_on_chain(channel_name) {
+            return self.refresh_channel_row(channel_name).await;
+        }
+
         match self.db.get_channel_row(channel_name).await {
-            Ok(channel_row) if !channel_row.is_stale() => Ok(channel_row),
+            Ok(channel_row)
+                if !channel_row
+                    .is_stale(self.clock.now(), self.config.stale_channel_threshold()) =>
+            {
+                Ok(channel_row)
+            }
             Ok(_) | Err(ProviderError::Channel(ChannelError::NotFoundInDB)) => {
                 self.refresh_channel_row(channel_name).await
             }
@@ -278,26 +1207,142 @@ impl ProviderCtx {
         }
     }
 
+    // How long a force close of the channel takes to finish, read from the contract
+    pub async fn force_close_timeout(&self, channel_name: &str) -> Duration {
+        let timeout = self
+            .primary_receiver()
+            .pc_client
+            .force_close_timeout(channel_name)
+            .await;
+        Duration::from_nanos(timeout)
+    }
+
+    // Cache a channel ahead of its first payment, unless it was already read from the contract
+    pub async fn warm_up_channel(&self, channel_name: &str) {
+        if self.shared.is_verified_on_chain(channel_name) {
+            return;
+        }
+
+        match self.refresh_channel_row(channel_name).await {
+            Ok(_) => info!("Warmed up channel {}", channel_name),
+            Err(e) => warn!("Unable to warm up channel {}: {:?}", channel_name, e),
+        }
+    }
+
     // Return the public account info (pk, account_id, etc.)
     pub async fn public_account_info(&self) -> AccountInfoPublic {
-        self.account_info.read().await.public_view()
+        self.primary_receiver()
+            .account_info
+            .read()
+            .await
+            .public_view()
+    }
+
+    pub async fn provider_info(&self) -> ProviderInfo {
+        let mut receivers = Vec::with_capacity(self.receivers.len());
+        for receiver in &self.receivers {
+            receivers.push(receiver.account_info.read().await.public_view());
+        }
+
+        ProviderInfo {
+            primary: self.public_account_info().await,
+            receivers,
+        }
     }
 
     // Get the state of the payment channel from the database
     // If the channel is stale, refresh it from the contract
+    // Approve a channel, required before serving it with `require_registration`
+    pub async fn register_channel(
+        &self,
+        channel_name: &str,
+        spend_cap: Option<NearToken>,
+    ) -> ProviderResult<ChannelRow> {
+        // Make sure the channel is known locally before flagging it
+        let _ = self.get_fresh_channel_row(channel_name).await?;
+        info!(
+            "Registering channel {} with spend cap {:?}",
+            channel_name, spend_cap
+        );
+        self.db.register_channel(channel_name, spend_cap).await
+    }
+
+    // Stop serving a channel without closing it on chain (which would refund the sender)
+    // Funds already earned on the channel can still be withdrawn
+    pub async fn disable_channel(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
+        // Make sure the channel is known locally before flagging it
+        let _ = self.get_fresh_channel_row(channel_name).await?;
+        info!("Disabling channel {}", channel_name);
+        self.db.disable_channel(channel_name).await
+    }
+
+    // Record an admin action in the audit log, `error` is None if it succeeded. The action
+    // already happened, an entry that can't be stored is only logged
+    pub async fn audit(
+        &self,
+        actor: &str,
+        action: &str,
+        channel_name: Option<&str>,
+        detail: Option<String>,
+        error: Option<String>,
+    ) {
+        info!(
+            actor,
+            action,
+            channel_name = ?channel_name,
+            detail = ?detail,
+            error = ?error,
+            "Admin action"
+        );
+        if let Err(e) = self
+            .db
+            .insert_audit_entry(
+                actor,
+                action,
+                channel_name,
+                detail.as_deref(),
+                error.as_deref(),
+            )
+            .await
+        {
+            error!("Audit entry of {} by {} not stored: {:?}", action, actor, e);
+        }
+    }
+
+    pub async fn audit_entries(&self, limit: u32) -> ProviderResult<Vec<AuditEntry>> {
+        let entries = self.db.get_audit_entries(limit).await?;
+        Ok(entries.into_iter().map(AuditEntry::from).collect())
+    }
+
+    // Spent balance and nonce from the latest signed state, 0 if no signed state is found.
+    // The signed states of closed channels are terminal, they were settled at the withdrawn
+    // balance and take no new nonce
+    async fn latest_state(&self, channel_row: &ChannelRow) -> ProviderResult<(NearToken, u64)> {
+        match self.db.get_latest_signed_state(&channel_row.name).await {
+            Ok(Some(signed_state)) => Ok((signed_state.spent_balance(), signed_state.nonce())),
+            Ok(None) => Ok((NearToken::from_yoctonear(0), 0)),
+            Err(ProviderError::SignedState(SignedStateError::TerminalState(_))) => {
+                Ok((channel_row.withdrawn_balance(), 0))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
     pub async fn get_pc_state(&self, channel_name: &str) -> ProviderResult<PaymentChannelState> {
         let channel_row = self.get_fresh_channel_row(channel_name).await?;
 
-        // Get the spent balance from the latest signed state
-        // If no signed state is found, the spent balance is 0
-        let spent_balance = match self.db.get_latest_signed_state(channel_name).await? {
-            Some(signed_state) => U128::from(signed_state.spent_balance().as_yoctonear()),
-            None => U128::from(0),
-        };
+        let (spent_balance, nonce) = self.latest_state(&channel_row).await?;
+        let spent_balance = U128::from(spent_balance.as_yoctonear());
 
         let added_balance = channel_row.added_balance();
         let withdraw_balance = channel_row.withdrawn_balance();
         let closed = channel_row.is_closed();
+        let estimated_requests_remaining = self
+            .config
+            .estimated_requests_remaining(
+                added_balance.as_yoctonear().saturating_sub(spent_balance.0),
+            )
+            .map(U128);
         Ok(PaymentChannelState {
             channel_name: channel_row.name,
             sender: channel_row.sender,
@@ -306,25 +1351,191 @@ impl ProviderCtx {
             added_balance: U128::from(added_balance.as_yoctonear()),
             withdraw_balance: U128::from(withdraw_balance.as_yoctonear()),
             closed,
+            estimated_requests_remaining,
+            spend_cap: channel_row
+                .spend_cap()
+                .map(|spend_cap| U128::from(spend_cap.as_yoctonear())),
+            nonce,
         })
     }
 
+    // Explain how a payment header is read, for client developers. Nothing is recorded,
+    // the channel is only read from the contract if it isn't cached yet
+    pub async fn diagnose_payment_header(&self, header: &str) -> PaymentHeaderDiagnosis {
+        let mut diagnosis = PaymentHeaderDiagnosis::default();
+
+        let decoded_payload = match BASE64_STANDARD.decode(header.trim()) {
+            Ok(decoded_payload) => decoded_payload,
+            Err(e) => {
+                diagnosis.error = Some(format!("Unable to decode base64: {}", e));
+                return diagnosis;
+            }
+        };
+        diagnosis.base64_decoded = true;
+
+        let signed_state = match borsh::from_slice::<NearSignedState>(&decoded_payload) {
+            Ok(signed_state) => signed_state,
+            Err(e) => {
+                diagnosis.error = Some(format!(
+                    "Unable to deserialize borsh serialized SignedState: {}",
+                    e
+                ));
+                return diagnosis;
+            }
+        };
+        diagnosis.borsh_parsed = true;
+        diagnosis.channel_id = Some(signed_state.state.channel_id.clone());
+        diagnosis.spent_balance = Some(U128::from(signed_state.state.spent_balance.as_yoctonear()));
+        diagnosis.nonce = Some(signed_state.state.nonce);
+
+        let channel_row = match self
+            .get_fresh_channel_row(&signed_state.state.channel_id)
+            .await
+        {
+            Ok(channel_row) => channel_row,
+            Err(e) => {
+                diagnosis.error = Some(UserFacingError::from(&e).to_string());
+                return diagnosis;
+            }
+        };
+        let sender_public_key = match NearPublicKey::from_str(&channel_row.sender_pk) {
+            Ok(sender_public_key) => sender_public_key,
+            Err(e) => {
+                diagnosis.error = Some(format!("Invalid sender public key of the channel: {}", e));
+                return diagnosis;
+            }
+        };
+        let signature_valid = signed_state
+            .signature
+            .verify(&to_vec(&signed_state.state).unwrap(), &sender_public_key);
+        diagnosis.signature_valid = Some(signature_valid);
+        if !signature_valid {
+            diagnosis.error = Some(format!(
+                "Signature doesn't match the sender key {} of the channel",
+                sender_public_key
+            ));
+        }
+
+        diagnosis
+    }
+
+    // Timeline of the signed states the provider accepted for a channel
+    pub async fn get_pc_history(
+        &self,
+        channel_name: &str,
+    ) -> ProviderResult<PaymentChannelHistory> {
+        let channel_row = self.get_fresh_channel_row(channel_name).await?;
+
+        let signed_states = self
+            .db
+            .get_signed_states(channel_name)
+            .await?
+            .into_iter()
+            .map(|signed_state| SignedStateHistoryEntry {
+                created_at: signed_state.created_at,
+                spent_balance: U128::from(signed_state.spent_balance().as_yoctonear()),
+            })
+            .collect();
+
+        Ok(PaymentChannelHistory {
+            channel_name: channel_row.name,
+            pruned: self.config.prune_signed_states,
+            signed_states,
+        })
+    }
+
+    // Aggregate the outstanding liabilities across all the channels the provider is the receiver of
+    // If refresh is set, every channel is first reconciled against the contract
+    pub async fn summary(&self, refresh: bool) -> ProviderResult<ProviderSummary> {
+        let mut summary = ProviderSummary::default();
+
+        let mut channel_rows = self.db.get_receiver_channels().await?;
+        if refresh {
+            let channel_names = channel_rows
+                .iter()
+                .map(|channel_row| channel_row.name.clone())
+                .collect::<Vec<_>>();
+            channel_rows = self.refresh_channel_rows(&channel_names).await?;
+        }
+
+        for channel_row in channel_rows {
+            if channel_row.is_closed() {
+                continue;
+            }
+
+            let (spent_balance, _) = self.latest_state(&channel_row).await?;
+            let balance = channel_row
+                .added_balance()
+                .saturating_sub(channel_row.withdrawn_balance());
+            let withdrawable = spent_balance.saturating_sub(channel_row.withdrawn_balance());
+
+            summary.open_channels += 1;
+            summary.total_balance = U128(summary.total_balance.0 + balance.as_yoctonear());
+            summary.total_withdrawable =
+                U128(summary.total_withdrawable.0 + withdrawable.as_yoctonear());
+            if channel_row.is_closing() {
+                summary.closing_channels += 1;
+                summary.total_at_risk = U128(summary.total_at_risk.0 + withdrawable.as_yoctonear());
+            }
+        }
+
+        Ok(summary)
+    }
+
     // Check that a signed state is valid and can be inserted into the database
     // This is used when a user wants to pay for a service using a payment channel
+    // Returns the accepted payment
+    //
+    // Balances recorded at each check are only emitted at the debug level
+    #[instrument(
+        name = "validate_signed_state",
+        skip_all,
+        fields(channel_name = %signed_state.state.channel_id, insert = insert)
+    )]
     pub async fn validate_signed_state(
         &self,
         min_cost: u128,
         signed_state: &NearSignedState,
         insert: bool,
-    ) -> ProviderResult<()> {
+    ) -> ProviderResult<u128> {
+        let result = self
+            .check_signed_state(min_cost, signed_state, insert)
+            .await;
+        // Dry runs (`/pc/validate`) aren't payments
+        if let (Err(e), true) = (&result, insert) {
+            record_payment_rejected(e);
+        }
+        result
+    }
+
+    async fn check_signed_state(
+        &self,
+        min_cost: u128,
+        signed_state: &NearSignedState,
+        insert: bool,
+    ) -> ProviderResult<u128> {
         let channel_name = signed_state.state.channel_id.clone();
         let channel_row = self.get_fresh_channel_row(&channel_name).await?;
 
-        // If the channel associated with the signed state is closed, return an error
-        channel_row.as_closed_result()?;
+        // If the channel associated with the signed state is closed or disabled, return an error
+        if let Err(e) = channel_row.as_closed_result() {
+            debug!(check = "open", accepted = false, "Channel is closed");
+            return Err(e);
+        }
+
+        if self.config.require_registration && !channel_row.registered {
+            debug!(
+                check = "registered",
+                accepted = false,
+                "Channel is not registered"
+            );
+            return Err(ProviderError::Channel(ChannelError::NotRegistered(
+                channel_name,
+            )));
+        }
 
         // Get the receiver public key registered in the channel,
-        // Check that 'we' are the receiver, otherwise return an error
+        // Check that 'we' are the receiver (any of our accounts), otherwise return an error
         let receiver_public_key =
             NearPublicKey::from_str(&channel_row.receiver_pk).map_err(|e| {
                 ProviderError::Channel(ChannelError::InvalidPublicKey(format!(
@@ -332,12 +1543,13 @@ impl ProviderCtx {
                     e
                 )))
             })?;
-        if receiver_public_key != self.account_info.read().await.public_key {
+        let receiver = self.channel_receiver(&channel_row)?;
+        if receiver_public_key != receiver.public_key().await {
             return Err(ProviderError::Channel(ChannelError::InvalidOwner(format!(
                 "Receiver public key {} of channel {} does not match public key {}",
-                channel_row.name,
                 receiver_public_key,
-                self.account_info.read().await.public_key
+                channel_row.name,
+                receiver.public_key().await
             ))));
         }
 
@@ -355,21 +1567,95 @@ impl ProviderCtx {
             ProviderError::SignedState(SignedStateError::SerializationError(e.to_string()))
         })?;
         if !signed_state.signature.verify(&data, &sender_public_key) {
+            debug!(check = "signature", accepted = false, "Invalid signature");
             return Err(ProviderError::SignedState(
                 SignedStateError::InvalidSignature,
             ));
         }
+        debug!(check = "signature", accepted = true, "Valid signature");
 
-        // Check that the sender is monotonically increasing their spent balance
-        let most_recent_spent_balance = match self
+        // Payments are checked against the latest signed state and inserted in turns,
+        // otherwise concurrent payments could all be checked against the same state
+        let payment_lock = self.shared.payment_lock(&channel_name);
+        let _payment_guard = match insert {
+            true => Some(payment_lock.lock().await),
+            false => None,
+        };
+
+        let most_recent_signed_state = self
             .db
             .get_latest_signed_state(&signed_state.state.channel_id)
-            .await?
-        {
+            .await?;
+
+        // A client retrying after a timeout resends the exact same signed state. Its payment
+        // was already spent on the first request, so the duplicate is rejected here rather
+        // than accepted as a success that would serve a second completion for free. A retry
+        // carrying the same `IDEMPOTENCY_KEY_HEADER_NAME` is answered from the response cache
+        // before reaching this check
+        if let Some(most_recent) = &most_recent_signed_state {
+            let duplicate = most_recent.spent_balance() == signed_state.state.spent_balance
+                && most_recent.nonce() == signed_state.state.nonce
+                && most_recent.signature == signed_state.signature.to_string();
+            debug!(
+                check = "duplicate",
+                accepted = !duplicate,
+                "Checked for an exact duplicate of the latest signed state"
+            );
+            if duplicate {
+                return Err(ProviderError::SignedState(
+                    SignedStateError::DuplicateSignedState(format!(
+                        "Signed state with nonce {} was already spent. Retry with the same {} to get the response it paid for, or sign a new state",
+                        signed_state.state.nonce, IDEMPOTENCY_KEY_HEADER_NAME
+                    )),
+                ));
+            }
+        }
+
+        // Check that the sender is monotonically increasing their nonce, the contract
+        // rejects the withdraw of a state older than the last one withdrawn
+        let most_recent_nonce = match &most_recent_signed_state {
+            Some(signed_state) => signed_state.nonce(),
+            None => 0_u64,
+        };
+        let new_nonce = signed_state.state.nonce;
+        debug!(
+            check = "nonce",
+            accepted = new_nonce > most_recent_nonce,
+            new_nonce,
+            most_recent_nonce,
+            "Checked nonce monotonicity"
+        );
+        if new_nonce <= most_recent_nonce {
+            return Err(ProviderError::SignedState(
+                SignedStateError::NonMonotonicNonce(format!(
+                    "New nonce must monotonically increase. Current nonce: {} <= Previous nonce: {}",
+                    new_nonce, most_recent_nonce
+                )),
+            ));
+        }
+        // The close state of the provider must stay newer than every payment
+        if new_nonce == CLOSE_NONCE {
+            return Err(ProviderError::SignedState(
+                SignedStateError::NonMonotonicNonce(format!(
+                    "Nonce {} is reserved for close states",
+                    CLOSE_NONCE
+                )),
+            ));
+        }
+
+        // Check that the sender is monotonically increasing their spent balance
+        let most_recent_spent_balance = match most_recent_signed_state {
             Some(signed_state) => signed_state.spent_balance().as_yoctonear(),
             None => 0_u128,
         };
         let new_spent_balance = signed_state.state.spent_balance.as_yoctonear();
+        debug!(
+            check = "monotonicity",
+            accepted = new_spent_balance > most_recent_spent_balance,
+            new_spent_balance,
+            most_recent_spent_balance,
+            "Checked spent balance monotonicity"
+        );
         if new_spent_balance <= most_recent_spent_balance {
             return Err(ProviderError::SignedState(
                 SignedStateError::NonMonotonicSpentBalance(format!(
@@ -382,6 +1668,14 @@ impl ProviderCtx {
         // Check that the sender has authorized an amount above the minimum cost
         let new_spent_balance = signed_state.state.spent_balance.as_yoctonear();
         let prev_spend_balance = most_recent_spent_balance;
+        debug!(
+            check = "min_cost",
+            accepted = new_spent_balance >= prev_spend_balance + min_cost,
+            new_spent_balance,
+            prev_spend_balance,
+            min_cost,
+            "Checked payment covers the minimum cost"
+        );
         if new_spent_balance < (prev_spend_balance + min_cost) {
             return Err(ProviderError::SignedState(
                 SignedStateError::PaymentTooSmall(format!(
@@ -392,6 +1686,49 @@ impl ProviderCtx {
             ));
         }
 
+        // Check that the sender has not authorized more than the configured ceiling
+        if let Some(max_payment) = self.config.max_payment_per_request {
+            let payment = new_spent_balance - prev_spend_balance;
+            debug!(
+                check = "max_payment",
+                accepted = payment <= max_payment.0,
+                payment,
+                max_payment = max_payment.0,
+                "Checked payment is under the maximum payment per request"
+            );
+            if payment > max_payment.0 {
+                return Err(ProviderError::SignedState(
+                    SignedStateError::PaymentTooLarge(format!(
+                        "Payment of {} is greater than the maximum payment per request of {}",
+                        NearToken::from_yoctonear(payment).exact_amount_display(),
+                        NearToken::from_yoctonear(max_payment.0).exact_amount_display()
+                    )),
+                ));
+            }
+        }
+
+        // Check that the payment stays within the budget the channel was registered with,
+        // regardless of how much was deposited
+        if let Some(spend_cap) = channel_row.spend_cap() {
+            debug!(
+                check = "spend_cap",
+                accepted = new_spent_balance <= spend_cap.as_yoctonear(),
+                new_spent_balance,
+                spend_cap = spend_cap.as_yoctonear(),
+                "Checked spent balance is under the spend cap"
+            );
+            if new_spent_balance > spend_cap.as_yoctonear() {
+                return Err(ProviderError::SignedState(
+                    SignedStateError::SpendCapExceeded(format!(
+                        "New spent balance {} is greater than the spend cap {} of channel {}",
+                        NearToken::from_yoctonear(new_spent_balance).exact_amount_display(),
+                        spend_cap.exact_amount_display(),
+                        channel_name
+                    )),
+                ));
+            }
+        }
+
         // Check that the user does not have insufficient funds.
         // Insufficient funds means that the user has spent more than the added balance.
         // If insufficient funds, resync the channel and check again (unhappy path)
@@ -403,6 +1740,13 @@ impl ProviderCtx {
             let resynced_channel_row = self.refresh_channel_row(&channel_name).await?;
 
             let resynced_spent_balance = resynced_channel_row.added_balance().as_yoctonear();
+            debug!(
+                check = "funds",
+                accepted = new_spent_balance <= resynced_spent_balance,
+                new_spent_balance,
+                added_balance = resynced_spent_balance,
+                "Checked funds after resyncing channel"
+            );
             if new_spent_balance > resynced_spent_balance {
                 return Err(ProviderError::SignedState(
                     SignedStateError::InsufficientFunds(format!(
@@ -411,12 +1755,118 @@ impl ProviderCtx {
                     )),
                 ));
             }
+        } else {
+            debug!(
+                check = "funds",
+                accepted = true,
+                new_spent_balance,
+                added_balance,
+                "Checked funds"
+            );
         }
 
         if insert {
             self.db.insert_signed_state(signed_state).await?;
+            let requests_served = self.shared.record_request(&channel_name);
+            debug!(requests_served, "Payment accepted");
+        } else {
+            debug!("Payment accepted");
+        }
+        Ok(new_spent_balance - prev_spend_balance)
+    }
+
+    // Balance lock of a channel stored in the database. Credit and debt are taken before
+    // the payment is validated, so channel names sent by anyone must not add locks
+    async fn known_channel_balance_lock(
+        &self,
+        channel_name: &str,
+    ) -> Option<Arc<tokio::sync::Mutex<()>>> {
+        self.db.get_channel_row(channel_name).await.ok()?;
+        Some(self.shared.balance_lock(channel_name))
+    }
+
+    // Take the credit left over from previous pre-authorized requests, zero for unknown
+    // channels. The credit is zero until the request gives back what it didn't use with
+    // `add_channel_credit`, so concurrent requests can't spend it twice
+    pub async fn take_channel_credit(&self, channel_name: &str) -> u128 {
+        let Some(lock) = self.known_channel_balance_lock(channel_name).await else {
+            return 0;
+        };
+        let _guard = lock.lock().await;
+        let credit = match self.db.get_channel_row(channel_name).await {
+            Ok(channel_row) => channel_row.credit().as_yoctonear(),
+            Err(_) => return 0,
+        };
+        if credit == 0 {
+            return 0;
+        }
+        match self
+            .db
+            .update_channel_credit(channel_name, NearToken::from_yoctonear(0))
+            .await
+        {
+            Ok(_) => credit,
+            Err(_) => 0,
+        }
+    }
+
+    pub async fn add_channel_credit(&self, channel_name: &str, credit: u128) -> ProviderResult<()> {
+        if credit == 0 {
+            return Ok(());
+        }
+        let lock = self.shared.balance_lock(channel_name);
+        let _guard = lock.lock().await;
+        let channel_row = self.db.get_channel_row(channel_name).await?;
+        self.db
+            .update_channel_credit(
+                channel_name,
+                channel_row
+                    .credit()
+                    .saturating_add(NearToken::from_yoctonear(credit)),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Take the debt of the channel, like `take_channel_credit`. The request pays it, or
+    // gives back what it couldn't pay with `add_channel_debt`
+    pub async fn take_channel_debt(&self, channel_name: &str) -> u128 {
+        let Some(lock) = self.known_channel_balance_lock(channel_name).await else {
+            return 0;
+        };
+        let _guard = lock.lock().await;
+        let debt = match self.db.get_channel_row(channel_name).await {
+            Ok(channel_row) => channel_row.debt().as_yoctonear(),
+            Err(_) => return 0,
+        };
+        if debt == 0 {
+            return 0;
         }
+        match self
+            .db
+            .update_channel_debt(channel_name, NearToken::from_yoctonear(0))
+            .await
+        {
+            Ok(_) => debt,
+            Err(_) => 0,
+        }
+    }
 
+    pub async fn add_channel_debt(&self, channel_name: &str, debt: u128) -> ProviderResult<()> {
+        if debt == 0 {
+            return Ok(());
+        }
+        let lock = self.shared.balance_lock(channel_name);
+        let _guard = lock.lock().await;
+        let channel_row = self.db.get_channel_row(channel_name).await?;
+        self.db
+            .update_channel_debt(
+                channel_name,
+                channel_row
+                    .debt()
+                    .saturating_add(NearToken::from_yoctonear(debt)),
+            )
+            .await?;
         Ok(())
     }
 
@@ -427,22 +1877,17 @@ impl ProviderCtx {
     ) -> ProviderResult<()> {
         let channel_row = self.get_fresh_channel_row(channel_name).await?;
 
-        // If we have no recorded signed states for the channel,
-        // we can't withdraw funds, nothing to do
-        let signed_state = match self.db.get_latest_signed_state(channel_name).await? {
-            Some(signed_state) => signed_state,
-            None => return Ok(()),
+        // If we have no recorded signed states for the channel, we can't withdraw funds.
+        // Closed channels were withdrawn from when they were closed. Nothing to do
+        let signed_state = match self.db.get_latest_signed_state(channel_name).await {
+            Ok(Some(signed_state)) => signed_state,
+            Ok(None) => return Ok(()),
+            Err(ProviderError::SignedState(SignedStateError::TerminalState(_))) => return Ok(()),
+            Err(e) => return Err(e),
         };
 
         // Check that we are the receiver of the channel
-        if channel_row.receiver != self.account_info.read().await.account_id {
-            return Err(ProviderError::Channel(ChannelError::InvalidOwner(format!(
-                "Receiver public key {} of channel {} does not match public key {}",
-                channel_row.name,
-                channel_row.receiver,
-                self.account_info.read().await.public_key
-            ))));
-        }
+        let receiver = self.channel_receiver(&channel_row)?;
 
         // If we've already withdrawn the full amount, nothing to do
         let already_withdrawn_amount = channel_row.withdrawn_balance().as_yoctonear();
@@ -472,20 +1917,21 @@ impl ProviderCtx {
             )));
         }
 
-        match close_type {
+        // The channel is only soft closed once the withdraw succeeded
+        let withdraw_result = match close_type {
             CloseChannelType::HardClose => {
                 // Close+Withdraw the funds and soft close the channel
                 info!(
                     "Closing and withdrawing funds from channel: {}",
                     channel_name
                 );
-                let close_signed_state = self.create_close_signed_state(&channel_name).await;
+                let close_signed_state = receiver.create_close_signed_state(&channel_name).await;
                 let near_signed_state: NearSignedState =
                     signed_state.as_signed_state(&self.db).await?;
-                self.pc_client
+                receiver
+                    .pc_client
                     .withdraw_and_close(near_signed_state, close_signed_state)
-                    .await;
-                self.db.soft_close_channel(channel_name).await?;
+                    .await
             }
             CloseChannelType::SoftClose => {
                 // Withdraw the funds and soft close the channel
@@ -495,16 +1941,33 @@ impl ProviderCtx {
                 );
                 let near_signed_state: NearSignedState =
                     signed_state.as_signed_state(&self.db).await?;
-                self.pc_client.withdraw(near_signed_state).await;
-                self.db.soft_close_channel(channel_name).await?;
+                receiver.pc_client.withdraw(near_signed_state).await
             }
             CloseChannelType::None => {
                 // Withdraw the funds
                 info!("Withdrawing funds from channel: {}", channel_name);
                 let near_signed_state: NearSignedState =
                     signed_state.as_signed_state(&self.db).await?;
-                self.pc_client.withdraw(near_signed_state).await;
+                receiver.pc_client.withdraw(near_signed_state).await
             }
+        };
+        record_withdrawal(withdraw_result.is_ok());
+        if let Err(failure) = withdraw_result {
+            error!(
+                "Error withdrawing from channel {}: {}",
+                channel_name, failure
+            );
+            return Err(ProviderError::Channel(ChannelError::WithdrawFailed(
+                failure,
+            )));
+        }
+        if matches!(
+            close_type,
+            CloseChannelType::HardClose | CloseChannelType::SoftClose
+        ) {
+            self.db.soft_close_channel(channel_name).await?;
+            // No more requests are served on the channel
+            self.shared.forget_channel(channel_name);
         }
 
         // After withdrawing, update the channel row to latest
@@ -513,12 +1976,48 @@ impl ProviderCtx {
         Ok(())
     }
 
+    // Stop taking new completions, wait for the ones in flight, withdraw what was earned
+    // and shut down, e.g. before a redeploy. Withdrawals that fail are retried by the
+    // background loop of the next instance
+    pub async fn drain(&self) {
+        if !self.shared.start_draining() {
+            return;
+        }
+
+        info!(
+            "Draining, waiting for {} completions in flight",
+            self.shared.in_flight()
+        );
+        while self.shared.in_flight() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        info!("Draining, withdrawing from open channels");
+        match self.db.get_receiver_channels().await {
+            Ok(channel_rows) => {
+                for channel_row in channel_rows {
+                    if let Err(e) = self
+                        .try_withdraw_funds(&channel_row.name, CloseChannelType::None)
+                        .await
+                    {
+                        info!("Not withdrawing from channel {}: {:?}", channel_row.name, e);
+                    }
+                }
+            }
+            Err(e) => error!("Error getting channels to withdraw from: {:?}", e),
+        }
+
+        info!("Drained, shutting down");
+        self.cancel_token.cancel();
+    }
+
     pub async fn close_pc(
         &self,
         channel_name: &str,
         signed_state: &NearSignedState,
     ) -> ProviderResult<NearSignedState> {
         let channel_row = self.get_fresh_channel_row(channel_name).await?;
+        let receiver = self.channel_receiver(&channel_row)?;
 
         // Get the sender public key registered in the channel
         let sender_public_key = NearPublicKey::from_str(&channel_row.sender_pk).map_err(|e| {
@@ -547,22 +2046,69 @@ impl ProviderCtx {
                 )),
             ));
         }
+        if signed_state.state.nonce != CLOSE_NONCE {
+            return Err(ProviderError::SignedState(
+                SignedStateError::InvalidClosedSignedState(format!(
+                    "Signed state is not a valid 'close' signed state: nonce is not {}",
+                    CLOSE_NONCE
+                )),
+            ));
+        }
 
         info!("Closing channel: {}", channel_row.name);
 
-        // Check if there is the sender has spent money that we haven't withdrawn yet
-        if let Some(signed_state) = self.db.get_latest_signed_state(&channel_row.name).await? {
-            info!(
-                "There is a signed state: {:?}",
-                signed_state.spent_balance()
-            );
+        // Check if there is the sender has spent money that we haven't withdrawn yet.
+        // Terminal states were withdrawn when the channel was soft closed, closing again
+        // only hands out the close payload
+        match self.db.get_latest_signed_state(&channel_row.name).await {
+            Ok(Some(signed_state)) => {
+                info!(
+                    "There is a signed state: {:?}",
+                    signed_state.spent_balance()
+                );
+
+                // A failed withdraw aborts the close, the close payload refunds the sender
+                // everything that wasn't withdrawn, including what it spent
+                self.try_withdraw_funds(&channel_name, CloseChannelType::SoftClose)
+                    .await?;
+            }
+            Ok(None) => (),
+            Err(ProviderError::SignedState(SignedStateError::TerminalState(_))) => {
+                info!("Channel {} is already soft closed", channel_name);
+            }
+            Err(e) => return Err(e),
+        }
 
-            self.try_withdraw_funds(&channel_name, CloseChannelType::SoftClose)
-                .await?;
+        // If the remaining balance is dust, closing costs the sender more gas than it's worth,
+        // so refund it on their behalf
+        if let Some(dust_refund_threshold) = self.config.dust_refund_threshold {
+            let channel_row = self.get_fresh_channel_row(channel_name).await?;
+            let remaining_balance = channel_row
+                .added_balance()
+                .saturating_sub(channel_row.withdrawn_balance());
+            if remaining_balance.as_yoctonear() < dust_refund_threshold.0 {
+                info!(
+                    "Refunding dust balance {} and closing channel: {}",
+                    remaining_balance.exact_amount_display(),
+                    channel_name
+                );
+                let close_signed_state = receiver.create_close_signed_state(channel_name).await;
+                receiver
+                    .pc_client
+                    .close(close_signed_state)
+                    .await
+                    .map_err(|e| ProviderError::Channel(ChannelError::CloseFailed(e)))?;
+                self.refresh_channel_row(channel_name).await?;
+
+                // Nothing left for the sender to submit
+                return Err(ProviderError::Channel(ChannelError::HardClosed(
+                    channel_name.to_string(),
+                )));
+            }
         }
 
         // Payload to send to user to close the channel
         // TODO: Update db reflecting that the channel is now closed
-        Ok(self.create_close_signed_state(channel_name).await)
+        Ok(receiver.create_close_signed_state(channel_name).await)
     }
 }
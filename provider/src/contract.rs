@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use cli::config::SignedState;
+use cli::contract::{Contract, ContractChannel};
+
+// Calls of the provider to the payment channel contract, signed as one receiver account.
+// Production uses the contract client of the cli, tests can swap in a mock contract
+// instead of running against a NEAR node
+#[async_trait]
+pub trait ChannelContract: Send + Sync {
+    async fn channel(&self, channel_id: &str) -> Option<ContractChannel>;
+
+    // At most `MAX_CHANNELS_PER_VIEW`, in the order of `channel_ids`
+    async fn channels(&self, channel_ids: &[String]) -> Vec<Option<ContractChannel>>;
+
+    // How long a force close of the channel takes to finish, in nanoseconds
+    async fn force_close_timeout(&self, channel_id: &str) -> u64;
+
+    async fn withdraw(&self, state: SignedState) -> Result<(), String>;
+
+    async fn withdraw_and_close(
+        &self,
+        state: SignedState,
+        close: SignedState,
+    ) -> Result<(), String>;
+
+    async fn close(&self, state: SignedState) -> Result<(), String>;
+}
+
+#[async_trait]
+impl ChannelContract for Contract {
+    async fn channel(&self, channel_id: &str) -> Option<ContractChannel> {
+        Contract::channel(self, channel_id).await
+    }
+
+    async fn channels(&self, channel_ids: &[String]) -> Vec<Option<ContractChannel>> {
+        Contract::channels(self, channel_ids).await
+    }
+
+    async fn force_close_timeout(&self, channel_id: &str) -> u64 {
+        Contract::force_close_timeout(self, channel_id).await.0
+    }
+
+    async fn withdraw(&self, state: SignedState) -> Result<(), String> {
+        Contract::withdraw(self, state).await
+    }
+
+    async fn withdraw_and_close(
+        &self,
+        state: SignedState,
+        close: SignedState,
+    ) -> Result<(), String> {
+        Contract::withdraw_and_close(self, state, close).await
+    }
+
+    async fn close(&self, state: SignedState) -> Result<(), String> {
+        Contract::close(self, state).await
+    }
+}
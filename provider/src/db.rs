@@ -1,17 +1,20 @@
 use std::{str::FromStr, time::Duration};
 
-use chrono::Utc;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::NaiveDateTime;
 use cli::{
     config::{SignedState, State},
     contract::ContractChannel,
 };
-use near_crypto::Signature;
+use near_crypto::{PublicKey, Signature};
 use near_sdk::{AccountId, NearToken};
+#[cfg(feature = "postgres")]
+use sqlx::postgres::{PgConnectOptions, PgPool};
 use sqlx::sqlite::SqlitePool;
 use tracing::{error, info, warn};
 
 use crate::{
-    ChannelError, ProviderError, ProviderResult, CLOSED_CHANNEL_ACCOUNT_ID, STALE_CHANNEL_THRESHOLD,
+    ChannelError, ProviderError, ProviderResult, SignedStateError, CLOSED_CHANNEL_ACCOUNT_ID,
 };
 
 #[derive(Default, Debug, sqlx::FromRow)]
@@ -24,12 +27,23 @@ pub struct ChannelRow {
     pub sender: String,
     pub sender_pk: String,
     // Near Tokens in contracts are represented as u128's
-    // this isn't supported by sqlite, so we store them as bytes big endian
+    // this isn't supported by sqlite (nor postgres), so we store them as bytes big endian
     pub added_balance: Vec<u8>,
     pub withdrawn_balance: Vec<u8>,
 
     pub force_close_started: Option<chrono::NaiveDateTime>,
     pub soft_closed: bool,
+    pub credit: Vec<u8>,
+    // Set by the operator to stop serving the channel without closing it
+    pub disabled: bool,
+    // Last time the background loop went through the channel
+    pub checked_at: Option<chrono::NaiveDateTime>,
+    // Approved by the operator, see `ProviderConfig::require_registration`
+    pub registered: bool,
+    // Budget set at registration, payments can't bring the spent balance above it
+    pub spend_cap: Option<Vec<u8>>,
+    // Cost of previous requests their payment didn't cover, see `ProviderConfig::token_rates`
+    pub debt: Vec<u8>,
 }
 
 impl ChannelRow {
@@ -45,31 +59,58 @@ impl ChannelRow {
         ))
     }
 
+    pub fn credit(&self) -> NearToken {
+        NearToken::from_yoctonear(u128::from_be_bytes(
+            self.credit[..].try_into().unwrap_or([0; 16]),
+        ))
+    }
+
+    pub fn debt(&self) -> NearToken {
+        NearToken::from_yoctonear(u128::from_be_bytes(
+            self.debt[..].try_into().unwrap_or([0; 16]),
+        ))
+    }
+
+    pub fn spend_cap(&self) -> Option<NearToken> {
+        self.spend_cap.as_ref().map(|spend_cap| {
+            NearToken::from_yoctonear(u128::from_be_bytes(
+                spend_cap[..].try_into().unwrap_or([0; 16]),
+            ))
+        })
+    }
+
+    // Same definition as the contract, see `ContractChannel::is_closed`
     pub fn is_closed(&self) -> bool {
-        self.receiver == CLOSED_CHANNEL_ACCOUNT_ID && self.sender == CLOSED_CHANNEL_ACCOUNT_ID
+        self.sender == CLOSED_CHANNEL_ACCOUNT_ID
     }
 
     pub fn is_closing(&self) -> bool {
         self.force_close_started.is_some()
     }
 
-    pub fn is_stale(&self) -> bool {
-        let now = Utc::now().naive_utc();
-        let inactive_threshold = now - STALE_CHANNEL_THRESHOLD;
+    pub fn is_stale(&self, now: NaiveDateTime, stale_threshold: Duration) -> bool {
+        let inactive_threshold = now - stale_threshold;
         self.updated_at < inactive_threshold
     }
 
     pub fn as_closed_result(&self) -> ProviderResult<()> {
         let also_name = self.name.to_owned();
-        if self.force_close_started.is_some() {
-            return Err(ProviderError::Channel(ChannelError::Closing(also_name)));
-        }
+        // Checked first, a tombstone may still have the force close start of the old channel
         if self.is_closed() {
             return Err(ProviderError::Channel(ChannelError::HardClosed(also_name)));
         }
+        if self.force_close_started.is_some() {
+            return Err(ProviderError::Channel(ChannelError::Closing(also_name)));
+        }
         if self.soft_closed {
             return Err(ProviderError::Channel(ChannelError::SoftClosed(also_name)));
         }
+        // Not closed, but the operator stopped serving it
+        if self.disabled {
+            return Err(ProviderError::Channel(ChannelError::ChannelDisabled(
+                also_name,
+            )));
+        }
         Ok(())
     }
 }
@@ -81,6 +122,13 @@ pub struct SignedStateRow {
     pub channel_id: i64,
     pub spent_balance: Vec<u8>,
     pub signature: String,
+    // Base64 borsh serialized SignedState as submitted by the client,
+    // None for states stored before payloads were recorded
+    pub payload: Option<String>,
+    // Set when the channel is soft closed, terminal states are never accepted or served again
+    pub terminal: bool,
+    // Big endian, like the spent balance
+    pub nonce: Vec<u8>,
 }
 
 impl SignedStateRow {
@@ -90,52 +138,139 @@ impl SignedStateRow {
         ))
     }
 
+    pub fn nonce(&self) -> u64 {
+        u64::from_be_bytes(self.nonce[..].try_into().unwrap_or([0; 8]))
+    }
+
+    // Re-verify the stored payload against the sender public key, without relying
+    // on the other columns. None if the payload is missing or can't be decoded
+    pub fn verify_payload(&self, sender_public_key: &PublicKey) -> Option<bool> {
+        let raw = BASE64_STANDARD.decode(self.payload.as_ref()?).ok()?;
+        let signed_state = borsh::from_slice::<SignedState>(&raw).ok()?;
+        let message = borsh::to_vec(&signed_state.state).ok()?;
+        Some(signed_state.signature.verify(&message, sender_public_key))
+    }
+
     pub async fn as_signed_state(&self, db: &ProviderDb) -> ProviderResult<SignedState> {
         let channel = db.get_channel_from_signed_state(self).await?;
+        // Rows stored before the nonce existed have nonce 0, their signature is over
+        // `ppp_core::LegacyState` and the contract accepts them as such
         Ok(SignedState {
             state: State {
                 channel_id: channel.name,
                 spent_balance: self.spent_balance(),
+                nonce: self.nonce(),
             },
             signature: Signature::from_str(&self.signature).unwrap(),
         })
     }
 }
 
+#[derive(Default, Debug, sqlx::FromRow)]
+pub struct AuditEntryRow {
+    pub id: i64,
+    pub created_at: chrono::NaiveDateTime,
+    // Operator who took the action, see `ProviderConfig::operator_api_keys`
+    pub actor: String,
+    pub action: String,
+    pub channel_name: Option<String>,
+    // Parameters of the action, e.g. the spend cap of a registration
+    pub detail: Option<String>,
+    // None if the action succeeded
+    pub error: Option<String>,
+}
+
+// Schemes of the database urls served by the postgres backend, any other url is sqlite
+const POSTGRES_URL_SCHEMES: [&str; 2] = ["postgres://", "postgresql://"];
+
+pub fn is_postgres_url(database_url: &str) -> bool {
+    POSTGRES_URL_SCHEMES
+        .iter()
+        .any(|scheme| database_url.starts_with(scheme))
+}
+
+// Backend picked from the scheme of `db_url`. The queries are checked at runtime and
+// shared by both backends, so they stick to what both understand: `$N` placeholders,
+// `TRUE`/`FALSE` literals and byte strings compared as big endian numbers
+#[derive(Clone)]
+enum Pool {
+    Sqlite(SqlitePool),
+    #[cfg(feature = "postgres")]
+    Postgres(PgPool),
+}
+
+// Evaluates `$query` with `$pool` bound to the pool of the backend in use
+macro_rules! with_pool {
+    ($db:expr, |$pool:ident| $query:expr) => {
+        match &$db.pool {
+            Pool::Sqlite($pool) => $query,
+            #[cfg(feature = "postgres")]
+            Pool::Postgres($pool) => $query,
+        }
+    };
+}
+
+// Sessions run in UTC, so `CURRENT_TIMESTAMP` matches the UTC times sqlite stores
+#[cfg(feature = "postgres")]
+async fn connect_postgres(database_url: &str) -> Pool {
+    let options = PgConnectOptions::from_str(database_url)
+        .unwrap()
+        .options([("timezone", "UTC")]);
+    Pool::Postgres(PgPool::connect_with(options).await.unwrap())
+}
+
+// Rejected by `ProviderConfig::validate` already
+#[cfg(not(feature = "postgres"))]
+async fn connect_postgres(_database_url: &str) -> Pool {
+    panic!("Postgres database urls require the provider to be built with the `postgres` feature")
+}
+
 #[derive(Clone)]
 pub struct ProviderDb {
-    connection: SqlitePool,
-    account_id: AccountId,
+    pool: Pool,
+    // Receiver accounts of the provider
+    account_ids: Vec<AccountId>,
+    // Drop superseded signed states instead of keeping the full history
+    prune_signed_states: bool,
 }
 
 impl ProviderDb {
-    pub fn new(database_url: &str, account_id: AccountId) -> Self {
+    pub fn new(database_url: &str, account_ids: Vec<AccountId>) -> Self {
         info!("Initializing database");
         let also_database_url = database_url.to_string();
         let result = std::thread::spawn(move || {
             let rt = tokio::runtime::Runtime::new().unwrap();
             rt.block_on(async {
-                let connection = SqlitePool::connect(&also_database_url).await.unwrap();
-                connection
+                if is_postgres_url(&also_database_url) {
+                    connect_postgres(&also_database_url).await
+                } else {
+                    Pool::Sqlite(SqlitePool::connect(&also_database_url).await.unwrap())
+                }
             })
         });
-        let connection = result.join().expect("Thread panicked");
+        let pool = result.join().expect("Thread panicked");
 
         Self {
-            connection,
-            account_id,
+            pool,
+            account_ids,
+            prune_signed_states: false,
         }
     }
 
+    pub fn with_signed_state_pruning(mut self, prune_signed_states: bool) -> Self {
+        self.prune_signed_states = prune_signed_states;
+        self
+    }
+
     pub async fn get_channel_row(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
-        match sqlx::query_as!(
-            ChannelRow,
-            "SELECT * FROM channel WHERE name = ? LIMIT 1",
-            channel_name
-        )
-        .fetch_optional(&self.connection)
-        .await
-        {
+        let channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>("SELECT * FROM channel WHERE name = $1 LIMIT 1")
+                .bind(channel_name)
+                .fetch_optional(pool)
+                .await
+        });
+
+        match channel_row {
             Ok(Some(channel)) => {
                 info!("Querying channel retrieved from database: {}", channel_name);
                 Ok(channel)
@@ -170,35 +305,40 @@ impl ProviderDb {
             .as_yoctonear()
             .to_be_bytes()
             .to_vec();
+        let force_close_started = contract_channel
+            .force_close_started
+            .map(|started| chrono::DateTime::from_timestamp_nanos(started as i64).naive_utc());
 
         info!("Upserting channel into database: {}", channel_name);
-        let contract_channel_row = sqlx::query_as!(
-            ChannelRow,
-            r#"
-            INSERT INTO channel
-            (name, sender, sender_pk, receiver, receiver_pk, added_balance, withdrawn_balance)
-            VALUES (?, ?, ?, ?, ?, ?, ?)
-            ON CONFLICT(name) DO UPDATE SET
-                updated_at = CURRENT_TIMESTAMP,
-                sender = excluded.sender,
-                sender_pk = excluded.sender_pk,
-                receiver = excluded.receiver,
-                receiver_pk = excluded.receiver_pk,
-                added_balance = excluded.added_balance,
-                withdrawn_balance = excluded.withdrawn_balance,
-                updated_at = CURRENT_TIMESTAMP
-            RETURNING *
-            "#,
-            channel_name,
-            sender_account,
-            sender_pk,
-            receiver_account,
-            receiver_pk,
-            added_balance,
-            withdrawn_balance
-        )
-        .fetch_one(&self.connection)
-        .await;
+        let contract_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                INSERT INTO channel
+                (name, sender, sender_pk, receiver, receiver_pk, added_balance, withdrawn_balance, force_close_started)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                ON CONFLICT(name) DO UPDATE SET
+                    sender = excluded.sender,
+                    sender_pk = excluded.sender_pk,
+                    receiver = excluded.receiver,
+                    receiver_pk = excluded.receiver_pk,
+                    added_balance = excluded.added_balance,
+                    withdrawn_balance = excluded.withdrawn_balance,
+                    force_close_started = excluded.force_close_started,
+                    updated_at = CURRENT_TIMESTAMP
+                RETURNING *
+                "#,
+            )
+            .bind(channel_name)
+            .bind(&sender_account)
+            .bind(&sender_pk)
+            .bind(&receiver_account)
+            .bind(&receiver_pk)
+            .bind(&added_balance)
+            .bind(&withdrawn_balance)
+            .bind(force_close_started)
+            .fetch_one(pool)
+            .await
+        });
 
         contract_channel_row.map_err(|e| {
             error!("Error upserting channel into database: {}", e);
@@ -210,18 +350,19 @@ impl ProviderDb {
         &self,
         channel_name: &str,
     ) -> ProviderResult<ChannelRow> {
-        let updated_channel_row = sqlx::query_as!(
-            ChannelRow,
-            r#"
-            UPDATE channel
-            SET updated_at = CURRENT_TIMESTAMP
-            WHERE name = ?
-            RETURNING *
-            "#,
-            channel_name
-        )
-        .fetch_optional(&self.connection)
-        .await;
+        let updated_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET updated_at = CURRENT_TIMESTAMP
+                WHERE name = $1
+                RETURNING *
+                "#,
+            )
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
 
         updated_channel_row
             .map_err(|e| {
@@ -231,6 +372,64 @@ impl ProviderDb {
             .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))
     }
 
+    pub async fn update_channel_credit(
+        &self,
+        channel_name: &str,
+        credit: NearToken,
+    ) -> ProviderResult<ChannelRow> {
+        let credit = credit.as_yoctonear().to_be_bytes().to_vec();
+        let updated_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET credit = $1
+                WHERE name = $2
+                RETURNING *
+                "#,
+            )
+            .bind(&credit)
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
+
+        updated_channel_row
+            .map_err(|e| {
+                error!("Error updating channel credit in database: {}", e);
+                ProviderError::DBError(e)
+            })?
+            .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))
+    }
+
+    pub async fn update_channel_debt(
+        &self,
+        channel_name: &str,
+        debt: NearToken,
+    ) -> ProviderResult<ChannelRow> {
+        let debt = debt.as_yoctonear().to_be_bytes().to_vec();
+        let updated_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET debt = $1
+                WHERE name = $2
+                RETURNING *
+                "#,
+            )
+            .bind(&debt)
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
+
+        updated_channel_row
+            .map_err(|e| {
+                error!("Error updating channel debt in database: {}", e);
+                ProviderError::DBError(e)
+            })?
+            .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))
+    }
+
     pub async fn insert_signed_state(
         &self,
         signed_state: &SignedState,
@@ -244,28 +443,27 @@ impl ProviderDb {
             .as_yoctonear()
             .to_be_bytes()
             .to_vec();
+        let nonce = signed_state.state.nonce.to_be_bytes().to_vec();
         let signature = signed_state.signature.to_string();
+        // Borsh is canonical, so this matches the bytes the client submitted
+        let payload = BASE64_STANDARD.encode(borsh::to_vec(signed_state).unwrap());
         info!(
             "Inserting new latest signed state for channel {} into database",
             channel_row.name
         );
-        let signed_state_row = sqlx::query_as!(
-            SignedStateRow,
-            r#"
-            INSERT INTO signed_state
-            (channel_id, spent_balance, signature)
-            VALUES (?, ?, ?)
-            RETURNING *
-            "#,
-            channel_row.id,
-            spent_balance,
-            signature
-        )
-        .fetch_one(&self.connection)
-        .await;
+        let signed_state_row = self
+            .insert_signed_state_row(channel_row.id, spent_balance, nonce, signature, payload)
+            .await;
 
         match signed_state_row {
-            Ok(signed_state) => Ok(signed_state),
+            Ok(Some(signed_state)) => Ok(signed_state),
+            Ok(None) => Err(ProviderError::SignedState(
+                SignedStateError::NonMonotonicSpentBalance(format!(
+                    "A signed state with a spent balance of at least {} was already recorded for channel {}",
+                    signed_state.state.spent_balance.exact_amount_display(),
+                    channel_row.name
+                )),
+            )),
             Err(e) => {
                 error!("Error inserting signed state into database: {}", e);
                 Err(ProviderError::DBError(e))
@@ -273,51 +471,239 @@ impl ProviderDb {
         }
     }
 
-    // Soft close a channel by setting the receiver to the closed channel account id
-    pub async fn soft_close_channel(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
-        let _ = self.get_channel_row(channel_name).await?;
-        let updated_channel_row = sqlx::query_as!(
-            ChannelRow,
-            r#"
-            UPDATE channel
-            SET soft_closed = 1
-            WHERE name = ?
-            RETURNING *
-            "#,
-            channel_name
-        )
-        .fetch_optional(&self.connection)
-        .await;
+    // Append to the history and update the current state in one transaction.
+    // Balances and nonces are big endian, so comparing the blobs compares the amounts.
+    // None, and nothing is stored, if the current state already has a spent balance as
+    // high (e.g. it was inserted by a concurrent payment)
+    async fn insert_signed_state_row(
+        &self,
+        channel_id: i64,
+        spent_balance: Vec<u8>,
+        nonce: Vec<u8>,
+        signature: String,
+        payload: String,
+    ) -> Result<Option<SignedStateRow>, sqlx::Error> {
+        with_pool!(self, |pool| {
+            let mut tx = pool.begin().await?;
+
+            let signed_state_row = sqlx::query_as::<_, SignedStateRow>(
+                r#"
+                INSERT INTO signed_state
+                (channel_id, spent_balance, nonce, signature, payload)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+                "#,
+            )
+            .bind(channel_id)
+            .bind(&spent_balance)
+            .bind(&nonce)
+            .bind(&signature)
+            .bind(&payload)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            let current_state = sqlx::query(
+                r#"
+                INSERT INTO current_state
+                (id, created_at, channel_id, spent_balance, nonce, signature, payload)
+                VALUES ($1, $2, $3, $4, $5, $6, $7)
+                ON CONFLICT(channel_id) DO UPDATE SET
+                    id = excluded.id,
+                    created_at = excluded.created_at,
+                    spent_balance = excluded.spent_balance,
+                    nonce = excluded.nonce,
+                    signature = excluded.signature,
+                    payload = excluded.payload
+                WHERE excluded.spent_balance > current_state.spent_balance AND
+                      current_state.terminal = FALSE
+                "#,
+            )
+            .bind(signed_state_row.id)
+            .bind(signed_state_row.created_at)
+            .bind(signed_state_row.channel_id)
+            .bind(&signed_state_row.spent_balance)
+            .bind(&signed_state_row.nonce)
+            .bind(&signed_state_row.signature)
+            .bind(&signed_state_row.payload)
+            .execute(&mut *tx)
+            .await?;
+            // Dropping the transaction rolls back the insert into the history
+            if current_state.rows_affected() == 0 {
+                return Ok(None);
+            }
+
+            if self.prune_signed_states {
+                sqlx::query(
+                    r#"
+                    DELETE FROM signed_state
+                    WHERE channel_id = $1 AND
+                          id NOT IN (SELECT id FROM current_state WHERE channel_id = $1)
+                    "#,
+                )
+                .bind(channel_id)
+                .execute(&mut *tx)
+                .await?;
+            }
+
+            tx.commit().await?;
+            Ok(Some(signed_state_row))
+        })
+    }
+
+    pub async fn mark_channel_checked(
+        &self,
+        channel_name: &str,
+        checked_at: NaiveDateTime,
+    ) -> ProviderResult<()> {
+        with_pool!(self, |pool| {
+            sqlx::query(
+                r#"
+                UPDATE channel
+                SET checked_at = $1
+                WHERE name = $2
+                "#,
+            )
+            .bind(checked_at)
+            .bind(channel_name)
+            .execute(pool)
+            .await
+            .map(|_| ())
+        })
+        .map_err(|e| {
+            error!("Error marking channel as checked in database: {}", e);
+            ProviderError::DBError(e)
+        })
+    }
+
+    // Registering again replaces the spend cap, None removes it
+    pub async fn register_channel(
+        &self,
+        channel_name: &str,
+        spend_cap: Option<NearToken>,
+    ) -> ProviderResult<ChannelRow> {
+        let spend_cap = spend_cap.map(|spend_cap| spend_cap.as_yoctonear().to_be_bytes().to_vec());
+        let updated_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET registered = TRUE, spend_cap = $1
+                WHERE name = $2
+                RETURNING *
+                "#,
+            )
+            .bind(&spend_cap)
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
 
         updated_channel_row
             .map_err(|e| {
-                error!("Error soft closing channel in database: {}", e);
+                error!("Error registering channel in database: {}", e);
+                ProviderError::DBError(e)
+            })?
+            .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))
+    }
+
+    // Stop accepting payments on a channel, withdrawals are still allowed
+    pub async fn disable_channel(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
+        let updated_channel_row = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET disabled = TRUE
+                WHERE name = $1
+                RETURNING *
+                "#,
+            )
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
+
+        updated_channel_row
+            .map_err(|e| {
+                error!("Error disabling channel in database: {}", e);
                 ProviderError::DBError(e)
             })?
             .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))
     }
 
+    // Soft close a channel and mark its signed states as terminal, so they can't be used
+    // again even if the channel row is reopened
+    pub async fn soft_close_channel(&self, channel_name: &str) -> ProviderResult<ChannelRow> {
+        let _ = self.get_channel_row(channel_name).await?;
+        with_pool!(self, |pool| {
+            let mut tx = pool.begin().await.map_err(ProviderError::DBError)?;
+            let updated_channel_row = sqlx::query_as::<_, ChannelRow>(
+                r#"
+                UPDATE channel
+                SET soft_closed = TRUE
+                WHERE name = $1
+                RETURNING *
+                "#,
+            )
+            .bind(channel_name)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| {
+                error!("Error soft closing channel in database: {}", e);
+                ProviderError::DBError(e)
+            })?
+            .ok_or(ProviderError::Channel(ChannelError::NotFoundInDB))?;
+
+            sqlx::query(
+                r#"
+                UPDATE signed_state
+                SET terminal = TRUE
+                WHERE channel_id = $1
+                "#,
+            )
+            .bind(updated_channel_row.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(ProviderError::DBError)?;
+            sqlx::query(
+                r#"
+                UPDATE current_state
+                SET terminal = TRUE
+                WHERE channel_id = $1
+                "#,
+            )
+            .bind(updated_channel_row.id)
+            .execute(&mut *tx)
+            .await
+            .map_err(ProviderError::DBError)?;
+
+            tx.commit().await.map_err(ProviderError::DBError)?;
+            Ok(updated_channel_row)
+        })
+    }
+
+    // Fails with `SignedStateError::TerminalState` once the channel was closed
     pub async fn get_latest_signed_state(
         &self,
         channel_name: &str,
     ) -> ProviderResult<Option<SignedStateRow>> {
         info!("Getting latest signed state for channel {}", channel_name);
-        let signed_state = sqlx::query_as!(
-            SignedStateRow,
-            r#"
-                SELECT signed_state.*
-                FROM signed_state
-                LEFT JOIN channel ON signed_state.channel_id = channel.id
-                WHERE channel.name = ?
-                ORDER BY signed_state.created_at DESC
-                LIMIT 1
-            "#,
-            channel_name,
-        )
-        .fetch_optional(&self.connection)
-        .await;
+        let signed_state = with_pool!(self, |pool| {
+            sqlx::query_as::<_, SignedStateRow>(
+                r#"
+                SELECT current_state.*
+                FROM current_state
+                LEFT JOIN channel ON current_state.channel_id = channel.id
+                WHERE channel.name = $1
+                "#,
+            )
+            .bind(channel_name)
+            .fetch_optional(pool)
+            .await
+        });
 
         match signed_state {
+            Ok(Some(signed_state)) if signed_state.terminal => Err(ProviderError::SignedState(
+                SignedStateError::TerminalState(channel_name.to_string()),
+            )),
             Ok(Some(signed_state)) => Ok(Some(signed_state)),
             Ok(None) => Ok(None),
             Err(e) => {
@@ -327,21 +713,50 @@ impl ProviderDb {
         }
     }
 
+    // All the signed states stored for a channel, oldest first
+    // Only the latest state is kept when prune_signed_states is set
+    pub async fn get_signed_states(
+        &self,
+        channel_name: &str,
+    ) -> ProviderResult<Vec<SignedStateRow>> {
+        let signed_states = with_pool!(self, |pool| {
+            sqlx::query_as::<_, SignedStateRow>(
+                r#"
+                SELECT signed_state.*
+                FROM signed_state
+                LEFT JOIN channel ON signed_state.channel_id = channel.id
+                WHERE channel.name = $1
+                ORDER BY signed_state.id ASC
+                "#,
+            )
+            .bind(channel_name)
+            .fetch_all(pool)
+            .await
+        })
+        .map_err(|e| {
+            error!("Error querying signed states from database: {}", e);
+            ProviderError::DBError(e)
+        })?;
+
+        Ok(signed_states)
+    }
+
     pub async fn get_channel_from_signed_state(
         &self,
         signed_state: &SignedStateRow,
     ) -> ProviderResult<ChannelRow> {
-        let channel = sqlx::query_as!(
-            ChannelRow,
-            r#"
-            SELECT *
-            FROM channel
-            WHERE id = ?
-            "#,
-            signed_state.channel_id
-        )
-        .fetch_one(&self.connection)
-        .await;
+        let channel = with_pool!(self, |pool| {
+            sqlx::query_as::<_, ChannelRow>(
+                r#"
+                SELECT *
+                FROM channel
+                WHERE id = $1
+                "#,
+            )
+            .bind(signed_state.channel_id)
+            .fetch_one(pool)
+            .await
+        });
 
         channel.map_err(|e| {
             error!("Error querying channel from database: {}", e);
@@ -351,35 +766,127 @@ impl ProviderDb {
 
     pub async fn get_stale_channels(
         &self,
+        now: NaiveDateTime,
         stale_threshold: Duration,
         limit: Option<u32>,
     ) -> ProviderResult<Vec<ChannelRow>> {
         // Get all the channels that:
         // 1. are owned by the provider + are open
         // 2. haven't been updated in a while
-        let updated_at_threshold = chrono::Utc::now().naive_utc() - stale_threshold;
-        let limit = limit.unwrap_or(16);
-        let account_id = self.account_id.to_string();
-        let channels = sqlx::query_as!(
-            ChannelRow,
+        // 3. haven't been checked by the background loop in a while (e.g. before a restart)
+        let updated_at_threshold = now - stale_threshold;
+        // Postgres has no unsigned integers
+        let limit = i64::from(limit.unwrap_or(16));
+        // One query for all the accounts, the limit is shared by all of them
+        let receivers = (0..self.account_ids.len())
+            .map(|i| format!("${}", i + 2))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!(
             r#"
             SELECT *
             FROM channel
-            WHERE updated_at < ? AND
-                  receiver = ?
+            WHERE updated_at < $1 AND
+                  (checked_at IS NULL OR checked_at < $1) AND
+                  receiver IN ({})
             ORDER BY updated_at DESC
-            LIMIT ?
+            LIMIT ${}
             "#,
-            updated_at_threshold,
-            account_id,
-            limit
-        )
-        .fetch_all(&self.connection)
-        .await;
-
-        channels.map_err(|e| {
+            receivers,
+            self.account_ids.len() + 2
+        );
+        let channels = with_pool!(self, |pool| {
+            let mut query = sqlx::query_as::<_, ChannelRow>(&query).bind(updated_at_threshold);
+            for account_id in &self.account_ids {
+                query = query.bind(account_id.to_string());
+            }
+            query.bind(limit).fetch_all(pool).await
+        })
+        .map_err(|e| {
             error!("Error querying stale channels from database: {}", e);
             ProviderError::DBError(e)
+        })?;
+
+        Ok(channels)
+    }
+
+    // Get all the channels the provider is the receiver of (closed channels excluded)
+    pub async fn get_receiver_channels(&self) -> ProviderResult<Vec<ChannelRow>> {
+        let mut channels = Vec::new();
+        for account_id in &self.account_ids {
+            let account_id = account_id.to_string();
+            let account_channels = with_pool!(self, |pool| {
+                sqlx::query_as::<_, ChannelRow>(
+                    r#"
+                    SELECT *
+                    FROM channel
+                    WHERE receiver = $1
+                    ORDER BY updated_at DESC
+                    "#,
+                )
+                .bind(&account_id)
+                .fetch_all(pool)
+                .await
+            })
+            .map_err(|e| {
+                error!("Error querying receiver channels from database: {}", e);
+                ProviderError::DBError(e)
+            })?;
+            channels.extend(account_channels);
+        }
+
+        Ok(channels)
+    }
+
+    pub async fn insert_audit_entry(
+        &self,
+        actor: &str,
+        action: &str,
+        channel_name: Option<&str>,
+        detail: Option<&str>,
+        error: Option<&str>,
+    ) -> ProviderResult<AuditEntryRow> {
+        with_pool!(self, |pool| {
+            sqlx::query_as::<_, AuditEntryRow>(
+                r#"
+                INSERT INTO audit_log
+                (actor, action, channel_name, detail, error)
+                VALUES ($1, $2, $3, $4, $5)
+                RETURNING *
+                "#,
+            )
+            .bind(actor)
+            .bind(action)
+            .bind(channel_name)
+            .bind(detail)
+            .bind(error)
+            .fetch_one(pool)
+            .await
+        })
+        .map_err(|e| {
+            error!("Error inserting audit entry into database: {}", e);
+            ProviderError::DBError(e)
+        })
+    }
+
+    // Latest audit entries, newest first
+    pub async fn get_audit_entries(&self, limit: u32) -> ProviderResult<Vec<AuditEntryRow>> {
+        with_pool!(self, |pool| {
+            sqlx::query_as::<_, AuditEntryRow>(
+                r#"
+                SELECT *
+                FROM audit_log
+                ORDER BY id DESC
+                LIMIT $1
+                "#,
+            )
+            .bind(i64::from(limit))
+            .fetch_all(pool)
+            .await
+        })
+        .map_err(|e| {
+            error!("Error querying audit entries from database: {}", e);
+            ProviderError::DBError(e)
         })
     }
 }
@@ -19,9 +19,17 @@ pub enum ChannelError {
     SoftClosed(String),
     Closing(String),
 
+    // Administrative errors
+    ChannelDisabled(String),
+    NotRegistered(String),
+
     // Withdraw errors
     WithdrawTooSmall(String),
     WithdrawNonMonotonic,
+    // The withdraw transaction failed, the channel is left as it was
+    WithdrawFailed(String),
+    // The close transaction failed, the channel is still open
+    CloseFailed(String),
 
     // Invalid errors
     InvalidOwner(String),
@@ -34,11 +42,55 @@ pub enum SignedStateError {
     SerializationError(String),
     InvalidSignature,
     InvalidClosedSignedState(String),
+    // The channel was closed, its signed states can't be used anymore
+    TerminalState(String),
 
     // Spend errors
+    // The latest signed state of the channel was sent again, its payment is already spent
+    DuplicateSignedState(String),
     NonMonotonicSpentBalance(String),
+    NonMonotonicNonce(String),
     PaymentTooSmall(String),
+    PaymentTooLarge(String),
     InsufficientFunds(String),
+    SpendCapExceeded(String),
+}
+
+impl ProviderError {
+    // Name of the variant, the `reason` label of the rejected payments metric
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProviderError::Channel(e) => match e {
+                ChannelError::NotFoundInDB => "not_found_in_db",
+                ChannelError::NotFoundInContract => "not_found_in_contract",
+                ChannelError::HardClosed(_) => "hard_closed",
+                ChannelError::SoftClosed(_) => "soft_closed",
+                ChannelError::Closing(_) => "closing",
+                ChannelError::ChannelDisabled(_) => "channel_disabled",
+                ChannelError::NotRegistered(_) => "not_registered",
+                ChannelError::WithdrawTooSmall(_) => "withdraw_too_small",
+                ChannelError::WithdrawNonMonotonic => "withdraw_non_monotonic",
+                ChannelError::WithdrawFailed(_) => "withdraw_failed",
+                ChannelError::CloseFailed(_) => "close_failed",
+                ChannelError::InvalidOwner(_) => "invalid_owner",
+                ChannelError::InvalidPublicKey(_) => "invalid_public_key",
+            },
+            ProviderError::SignedState(e) => match e {
+                SignedStateError::SerializationError(_) => "serialization_error",
+                SignedStateError::InvalidSignature => "invalid_signature",
+                SignedStateError::InvalidClosedSignedState(_) => "invalid_closed_signed_state",
+                SignedStateError::TerminalState(_) => "terminal_state",
+                SignedStateError::DuplicateSignedState(_) => "duplicate_signed_state",
+                SignedStateError::NonMonotonicSpentBalance(_) => "non_monotonic_spent_balance",
+                SignedStateError::NonMonotonicNonce(_) => "non_monotonic_nonce",
+                SignedStateError::PaymentTooSmall(_) => "payment_too_small",
+                SignedStateError::PaymentTooLarge(_) => "payment_too_large",
+                SignedStateError::InsufficientFunds(_) => "insufficient_funds",
+                SignedStateError::SpendCapExceeded(_) => "spend_cap_exceeded",
+            },
+            ProviderError::DBError(_) => "db_error",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -71,6 +123,13 @@ impl From<&ProviderError> for UserFacingError {
             ProviderError::Channel(ChannelError::Closing(e)) => {
                 UserFacingError(format!("Payment channel closing: {}", e))
             }
+            ProviderError::Channel(ChannelError::ChannelDisabled(e)) => {
+                UserFacingError(format!("Channel temporarily unavailable: {}", e))
+            }
+            ProviderError::Channel(ChannelError::NotRegistered(e)) => UserFacingError(format!(
+                "Payment channel not registered with the provider: {}",
+                e
+            )),
             ProviderError::Channel(ChannelError::InvalidOwner(e)) => {
                 UserFacingError(format!("Invalid owner: {}", e))
             }
@@ -83,6 +142,12 @@ impl From<&ProviderError> for UserFacingError {
             ProviderError::Channel(ChannelError::WithdrawNonMonotonic) => {
                 UserFacingError("Non-monotonic withdraw".to_string())
             }
+            ProviderError::Channel(ChannelError::WithdrawFailed(e)) => {
+                UserFacingError(format!("Withdraw failed, try again later: {}", e))
+            }
+            ProviderError::Channel(ChannelError::CloseFailed(e)) => {
+                UserFacingError(format!("Close failed, try again later: {}", e))
+            }
 
             //
             // SignedState errors
@@ -93,15 +158,30 @@ impl From<&ProviderError> for UserFacingError {
             ProviderError::SignedState(SignedStateError::InvalidSignature) => {
                 UserFacingError("Invalid signature".to_string())
             }
+            ProviderError::SignedState(SignedStateError::DuplicateSignedState(e)) => {
+                UserFacingError(format!("Duplicate signed state: {}", e))
+            }
             ProviderError::SignedState(SignedStateError::NonMonotonicSpentBalance(e)) => {
                 UserFacingError(format!("Non-monotonic spent balance: {}", e))
             }
+            ProviderError::SignedState(SignedStateError::NonMonotonicNonce(e)) => {
+                UserFacingError(format!("Non-monotonic nonce: {}", e))
+            }
             ProviderError::SignedState(SignedStateError::PaymentTooSmall(e)) => {
                 UserFacingError(format!("Payment too small: {}", e))
             }
+            ProviderError::SignedState(SignedStateError::PaymentTooLarge(e)) => {
+                UserFacingError(format!("Payment too large: {}", e))
+            }
             ProviderError::SignedState(SignedStateError::InsufficientFunds(e)) => {
                 UserFacingError(format!("Insufficient funds: {}", e))
             }
+            ProviderError::SignedState(SignedStateError::SpendCapExceeded(e)) => {
+                UserFacingError(format!("Spend cap exceeded: {}", e))
+            }
+            ProviderError::SignedState(SignedStateError::TerminalState(e)) => UserFacingError(
+                format!("Payment channel closed, signed states are final: {}", e),
+            ),
             ProviderError::SignedState(SignedStateError::InvalidClosedSignedState(e)) => {
                 UserFacingError(format!("Invalid signed state: {}", e))
             }
@@ -120,22 +200,45 @@ impl From<&ProviderError> for StatusCode {
             ProviderError::Channel(ChannelError::Closing(_)) => StatusCode::BAD_REQUEST,
             ProviderError::Channel(ChannelError::HardClosed(_)) => StatusCode::BAD_REQUEST,
             ProviderError::Channel(ChannelError::SoftClosed(_)) => StatusCode::BAD_REQUEST,
+            ProviderError::Channel(ChannelError::ChannelDisabled(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ProviderError::Channel(ChannelError::NotRegistered(_)) => StatusCode::FORBIDDEN,
             ProviderError::Channel(ChannelError::InvalidOwner(_)) => StatusCode::BAD_REQUEST,
             ProviderError::Channel(ChannelError::InvalidPublicKey(_)) => StatusCode::BAD_REQUEST,
             ProviderError::Channel(ChannelError::WithdrawTooSmall(_)) => StatusCode::BAD_REQUEST,
             ProviderError::Channel(ChannelError::WithdrawNonMonotonic) => StatusCode::BAD_REQUEST,
+            ProviderError::Channel(ChannelError::WithdrawFailed(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ProviderError::Channel(ChannelError::CloseFailed(_)) => StatusCode::SERVICE_UNAVAILABLE,
             ProviderError::SignedState(SignedStateError::InvalidSignature) => {
                 StatusCode::BAD_REQUEST
             }
+            ProviderError::SignedState(SignedStateError::DuplicateSignedState(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             ProviderError::SignedState(SignedStateError::NonMonotonicSpentBalance(_)) => {
                 StatusCode::BAD_REQUEST
             }
+            ProviderError::SignedState(SignedStateError::NonMonotonicNonce(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             ProviderError::SignedState(SignedStateError::PaymentTooSmall(_)) => {
                 StatusCode::BAD_REQUEST
             }
+            ProviderError::SignedState(SignedStateError::PaymentTooLarge(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             ProviderError::SignedState(SignedStateError::InsufficientFunds(_)) => {
                 StatusCode::BAD_REQUEST
             }
+            ProviderError::SignedState(SignedStateError::SpendCapExceeded(_)) => {
+                StatusCode::BAD_REQUEST
+            }
+            ProviderError::SignedState(SignedStateError::TerminalState(_)) => {
+                StatusCode::BAD_REQUEST
+            }
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
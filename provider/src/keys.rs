@@ -0,0 +1,140 @@
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use near_cli_rs::common::KeyPairProperties;
+use near_cli_rs::config::Config as NearConfig;
+use near_cli_rs::config::NetworkConfig as NearNetworkConfig;
+use near_crypto::SecretKey as NearSecretKey;
+use near_primitives::types::AccountId;
+use serde::Deserialize;
+
+// Replaced by the account id in the env var name and command arguments of a key source
+pub const ACCOUNT_ID_PLACEHOLDER: &str = "{account_id}";
+
+// Loads the secret key a receiver account signs with.
+// Errors must never contain the key (or anything it was parsed from), they are logged
+pub trait SecretKeySource {
+    fn secret_key(&self, account_id: &AccountId) -> Result<NearSecretKey, String>;
+}
+
+// Where the provider reads the secret keys of its receiver accounts from
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    // Key file written by near-cli-rs, `<credentials_home_dir>/<network>/<account_id>.json`
+    #[default]
+    File,
+    // Environment variable holding the secret key (`ed25519:...`)
+    Env(String),
+    // Command printing the secret key on stdout, e.g. to read it from Vault or SOPS
+    Command(Vec<String>),
+}
+
+impl KeySource {
+    pub fn source(
+        &self,
+        near_config: &NearConfig,
+        network_config: &NearNetworkConfig,
+    ) -> Box<dyn SecretKeySource> {
+        match self {
+            KeySource::File => Box::new(FileKeySource {
+                credentials_dir: near_config
+                    .credentials_home_dir
+                    .join(&network_config.network_name),
+            }),
+            KeySource::Env(var) => Box::new(EnvKeySource { var: var.clone() }),
+            KeySource::Command(command) => Box::new(CommandKeySource {
+                command: command.clone(),
+            }),
+        }
+    }
+}
+
+pub struct FileKeySource {
+    pub credentials_dir: PathBuf,
+}
+
+impl SecretKeySource for FileKeySource {
+    fn secret_key(&self, account_id: &AccountId) -> Result<NearSecretKey, String> {
+        let path = self.credentials_dir.join(format!("{}.json", account_id));
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Access key file {} not found: {}", path.display(), e))?;
+        let key_pair: KeyPairProperties = serde_json::from_str(&data)
+            .map_err(|_| format!("Invalid access key file {}", path.display()))?;
+        parse_secret_key(&key_pair.secret_keypair_str)
+    }
+}
+
+// One variable per account, the account id in the name is upper cased and any character
+// that isn't valid in a variable name is replaced by `_` (`PPP_KEY_{account_id}` reads
+// `PPP_KEY_PROVIDER_TESTNET` for `provider.testnet`)
+pub struct EnvKeySource {
+    pub var: String,
+}
+
+impl EnvKeySource {
+    pub fn var_name(&self, account_id: &AccountId) -> String {
+        let account_id = account_id
+            .as_str()
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        self.var.replace(ACCOUNT_ID_PLACEHOLDER, &account_id)
+    }
+}
+
+impl SecretKeySource for EnvKeySource {
+    fn secret_key(&self, account_id: &AccountId) -> Result<NearSecretKey, String> {
+        let var = self.var_name(account_id);
+        let secret_key =
+            std::env::var(&var).map_err(|_| format!("Environment variable {} is not set", var))?;
+        parse_secret_key(&secret_key)
+    }
+}
+
+// The command is run without a shell, stderr is left to the terminal so the secrets
+// backend can report errors (e.g. an expired token)
+pub struct CommandKeySource {
+    pub command: Vec<String>,
+}
+
+impl SecretKeySource for CommandKeySource {
+    fn secret_key(&self, account_id: &AccountId) -> Result<NearSecretKey, String> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| "Key command is empty".to_string())?;
+        let args = args
+            .iter()
+            .map(|arg| arg.replace(ACCOUNT_ID_PLACEHOLDER, account_id.as_str()));
+
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("Unable to run key command {}: {}", program, e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "Key command {} failed with {}",
+                program, output.status
+            ));
+        }
+
+        let secret_key = String::from_utf8(output.stdout)
+            .map_err(|_| format!("Key command {} printed invalid utf-8", program))?;
+        parse_secret_key(secret_key.trim())
+    }
+}
+
+// The parse error is dropped, it may echo part of the key
+fn parse_secret_key(secret_key: &str) -> Result<NearSecretKey, String> {
+    NearSecretKey::from_str(secret_key).map_err(|_| "Invalid secret key".to_string())
+}
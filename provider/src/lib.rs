@@ -1,15 +1,25 @@
 pub mod background;
+pub mod clock;
 pub mod common;
+pub mod contract;
 pub mod db;
 pub mod errors;
+pub mod keys;
 pub mod service;
+pub mod state;
+pub mod telemetry;
 
 use std::time::Duration;
 
 pub use crate::background::*;
+pub use crate::clock::*;
 pub use crate::common::*;
+pub use crate::contract::*;
 pub use crate::db::*;
+pub use crate::keys::*;
 pub use crate::service::*;
+pub use crate::state::*;
+pub use crate::telemetry::*;
 
 use crate::errors::*;
 
@@ -19,16 +29,28 @@ pub const MODEL_DELIMITER: &str = "::";
 pub const BAD_REQUEST: &str = "Bad Request";
 pub const FOUR_HUNDRED: &str = "400";
 
-pub const PAYMENTS_HEADER_NAME: &str = "X-Payments-Signature";
+pub const PAYMENTS_HEADER_NAME: &str = cli::provider::PAYMENTS_HEADER_NAME;
+// Occupancy of the provider in percent, sent when `max_concurrent_requests` is configured
+pub const LOAD_HEADER_NAME: &str = "X-PPP-Load";
+// Seconds clients are asked to wait before retrying when the provider is at capacity
+pub const RETRY_AFTER_SECS: u64 = 1;
+// Optional hint used to pick among several upstreams serving the same provider
+pub const ROUTE_HEADER_NAME: &str = "X-PPP-Route";
+// Optional key of a completion request. A request retried with the same key and payment
+// (e.g. after its response was lost) is answered with the response already paid for
+pub const IDEMPOTENCY_KEY_HEADER_NAME: &str = "Idempotency-Key";
 
 // When a channel is closed, the receiver / sender account id is set to this value
-pub const CLOSED_CHANNEL_ACCOUNT_ID: &str =
-    "0000000000000000000000000000000000000000000000000000000000000000";
+pub const CLOSED_CHANNEL_ACCOUNT_ID: &str = cli::contract::CLOSED_CHANNEL_ACCOUNT_ID;
 
-// Amount of time until a channel is considered stale and the state should be
-// refreshed from the contract
+// Default amount of time until a channel is considered stale and the state should be
+// refreshed from the contract. See `ProviderConfig::stale_channel_threshold_secs`
 pub const STALE_CHANNEL_THRESHOLD: Duration = Duration::from_secs(30);
 
+// Default `max_tokens` of OpenAI completions, also set on the token priced requests
+// without a limit (chat completions have none)
+pub const DEFAULT_MAX_TOKENS: u64 = 16;
+
 // Copied from the contract code
 pub const SECOND: u64 = 1_000_000_000;
 pub const DAY: u64 = 24 * 60 * 60 * SECOND;
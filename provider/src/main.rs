@@ -1,38 +1,74 @@
-use axum::extract::{DefaultBodyLimit, Request};
+use axum::extract::{DefaultBodyLimit, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use clap::{command, Parser, Subcommand};
-use config::Config;
-use http::HeaderValue;
+use http::{header, HeaderValue, StatusCode};
 use openaiapi::server;
-use std::{net::Ipv4Addr, time::Duration};
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Semaphore;
 use tower_http::{
     limit::RequestBodyLimitLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
     LatencyUnit,
 };
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 
 use provider::{
-    ProviderBackgroundService, ProviderBaseService, ProviderConfig, ProviderCtx,
-    ProviderOaiService, PAYMENTS_HEADER_NAME,
+    disabled_endpoints_middleware, install_metrics_recorder, load_config, load_shedding_middleware,
+    request_timeout_middleware, stream_completions_middleware, ProviderBackgroundService,
+    ProviderBaseService, ProviderCtx, ProviderOaiService, EXAMPLE_CONFIG,
+    IDEMPOTENCY_KEY_HEADER_NAME, PAYMENTS_HEADER_NAME, RETRY_AFTER_SECS, ROUTE_HEADER_NAME,
+    SIGNAL_OPERATOR,
 };
 
 // Since we are using generated server stubs that don't support extracting headers, we
 // have this shim middleware to convert our needed headers into 'cookies' which are
 // supported by the generated server stubs
 async fn payments_headers_to_cookie_middleware<B>(mut req: Request<B>) -> Request<B> {
-    let desired_header = PAYMENTS_HEADER_NAME;
-    let desired_header_opt = req.headers().get(desired_header);
-    match desired_header_opt {
-        None => req,
-        Some(header) => {
-            let cookie_header = format!("{}={}", PAYMENTS_HEADER_NAME, header.to_str().unwrap());
-            req.headers_mut()
-                .insert("Cookie", HeaderValue::from_str(&cookie_header).unwrap());
-            req
-        }
+    let desired_headers = [
+        PAYMENTS_HEADER_NAME,
+        ROUTE_HEADER_NAME,
+        IDEMPOTENCY_KEY_HEADER_NAME,
+    ];
+    let cookies = desired_headers
+        .iter()
+        .filter_map(|desired_header| {
+            req.headers()
+                .get(*desired_header)
+                .map(|header| format!("{}={}", desired_header, header.to_str().unwrap()))
+        })
+        .collect::<Vec<_>>();
+    if cookies.is_empty() {
+        return req;
     }
+    req.headers_mut().insert(
+        "Cookie",
+        HeaderValue::from_str(&cookies.join("; ")).unwrap(),
+    );
+    req
+}
+
+// Coarse safety valve on every route, bounds the requests (and their bodies) held in
+// memory at once. Independent of `load_shedding_middleware`, which only sees completions
+async fn in_flight_limit_middleware(
+    State(permits): State<Arc<Semaphore>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let _permit = match permits.try_acquire() {
+        Ok(permit) => permit,
+        Err(_) => {
+            let mut response = StatusCode::SERVICE_UNAVAILABLE.into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+            return response;
+        }
+    };
+    next.run(req).await
 }
 
 #[derive(Parser, Debug)]
@@ -45,6 +81,8 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Run(RunCli),
+    /// Print an example config with all the fields documented
+    GenerateConfig,
 }
 
 #[derive(Debug, Parser)]
@@ -59,27 +97,69 @@ pub struct RunCli {
     config: Option<String>,
 }
 
-pub async fn start_server(addr: &str, args: RunCli) {
-    tracing_subscriber::fmt().init();
-    let provider_model_config = match args.config {
-        Some(config_filename) => {
-            match Config::builder()
-                .add_source(config::File::with_name(&config_filename))
-                .build()
-            {
-                Ok(config) => match config.try_deserialize::<ProviderConfig>() {
-                    Ok(config) => config,
-                    Err(e) => {
-                        panic!("Error parsing config: {}", e);
-                    }
-                },
-                Err(e) => {
-                    panic!("Error reading config filename {}: {}", config_filename, e);
+// Reload the upstream providers from the config file on SIGHUP, e.g. to rotate
+// an api key without restarting the server
+fn reload_providers_on_sighup(ctx: ProviderCtx, config_filename: String) {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen for SIGHUP");
+        loop {
+            tokio::select! {
+                _ = ctx.cancel_token.cancelled() => break,
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading providers from {}", config_filename);
+                    let result = match load_config(&config_filename) {
+                        Ok(config) => ctx.reload_providers(config.providers).await.map_err(|e| {
+                            error!("Invalid providers, keeping the current ones: {}", e);
+                            e
+                        }),
+                        Err(e) => {
+                            error!("{}, keeping the current providers", e);
+                            Err(e)
+                        }
+                    };
+                    ctx.audit(
+                        SIGNAL_OPERATOR,
+                        "reload_providers",
+                        None,
+                        Some(config_filename.clone()),
+                        result.err(),
+                    )
+                    .await;
                 }
             }
         }
+    });
+}
+
+// Drain on SIGUSR1, see `ProviderCtx::drain`
+fn drain_on_sigusr1(ctx: ProviderCtx) {
+    tokio::spawn(async move {
+        let mut user_defined =
+            signal(SignalKind::user_defined1()).expect("Failed to listen for SIGUSR1");
+        tokio::select! {
+            _ = ctx.cancel_token.cancelled() => (),
+            _ = user_defined.recv() => {
+                info!("Received SIGUSR1, draining");
+                ctx.audit(SIGNAL_OPERATOR, "drain", None, None, None).await;
+                ctx.drain().await;
+            }
+        }
+    });
+}
+
+pub async fn start_server(addr: &str, args: RunCli) {
+    tracing_subscriber::fmt().init();
+    let config_filename = match args.config {
+        Some(config_filename) => config_filename,
         None => panic!("No config file provided"),
     };
+    let provider_model_config = match load_config(&config_filename) {
+        Ok(config) => config,
+        Err(e) => panic!("{}", e),
+    };
+    for warning in provider_model_config.warnings() {
+        warn!("Config {}: {}", config_filename, warning);
+    }
 
     info!("Creating common provider context");
     let ctx = ProviderCtx::new(provider_model_config.clone());
@@ -87,11 +167,36 @@ pub async fn start_server(addr: &str, args: RunCli) {
     info!("Starting provider background service");
     let background_service_handle = ProviderBackgroundService::new(ctx.clone()).run();
 
+    reload_providers_on_sighup(ctx.clone(), config_filename);
+    drain_on_sigusr1(ctx.clone());
+
     info!("Starting Provider API");
-    let provider_base = ProviderBaseService::new(ctx.clone());
+    let provider_base =
+        ProviderBaseService::new(ctx.clone()).with_metrics(install_metrics_recorder());
     let provider_base_service = ProviderBaseService::router(provider_base);
     let provider_oai = ProviderOaiService::new(ctx.clone());
-    let provider_oai_service = server::new(provider_oai);
+    let provider_oai_service = server::new(provider_oai.clone())
+        .layer(axum::middleware::from_fn_with_state(
+            provider_oai,
+            stream_completions_middleware,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            ctx.clone(),
+            disabled_endpoints_middleware,
+        ));
+    let provider_oai_service = match provider_model_config.request_timeout_secs {
+        Some(request_timeout_secs) => {
+            info!(
+                "Aborting completions after {} seconds",
+                request_timeout_secs
+            );
+            provider_oai_service.layer(axum::middleware::from_fn_with_state(
+                Duration::from_secs(request_timeout_secs),
+                request_timeout_middleware,
+            ))
+        }
+        None => provider_oai_service,
+    };
     let app = axum::Router::new()
         .layer(DefaultBodyLimit::disable())
         .layer(
@@ -106,17 +211,41 @@ pub async fn start_server(addr: &str, args: RunCli) {
         .layer(RequestBodyLimitLayer::new(500 * 1000 * 1000)) // 500MB
         .nest(
             "/",
-            provider_oai_service.layer(axum::middleware::map_request(payments_headers_to_cookie_middleware)),
+            provider_oai_service
+                .layer(axum::middleware::map_request(payments_headers_to_cookie_middleware))
+                .layer(axum::middleware::from_fn_with_state(ctx.clone(), load_shedding_middleware)),
         )
         .nest("/", provider_base_service);
 
+    // Mount everything (health check included) under the base path if configured
+    let app = match provider_model_config.base_path.as_deref() {
+        Some(base_path) if base_path.trim_end_matches('/') != "" => {
+            info!("Serving under base path: {}", base_path);
+            axum::Router::new().nest(base_path.trim_end_matches('/'), app)
+        }
+        _ => app,
+    };
+
+    let app = match provider_model_config.max_in_flight_requests {
+        Some(max_in_flight_requests) => {
+            info!("Limiting in flight requests to {}", max_in_flight_requests);
+            app.layer(axum::middleware::from_fn_with_state(
+                Arc::new(Semaphore::new(max_in_flight_requests)),
+                in_flight_limit_middleware,
+            ))
+        }
+        None => app,
+    };
+
     let listener = TcpListener::bind(addr).await.unwrap();
     info!("Listening on: {}", addr);
     if let Err(e) = axum::serve(listener, app)
         .with_graceful_shutdown(async move {
-            tokio::signal::ctrl_c()
-                .await
-                .expect("Failed to listen for Ctrl+C");
+            // Either Ctrl+C or the end of a drain
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => result.expect("Failed to listen for Ctrl+C"),
+                _ = ctx.cancel_token.cancelled() => (),
+            }
             ctx.cancel_token.cancel();
         })
         .await
@@ -134,5 +263,6 @@ async fn main() {
             let addr = format!("{}:{}", cli_args.host, cli_args.port);
             start_server(&addr, cli_args).await;
         }
+        Commands::GenerateConfig => print!("{}", EXAMPLE_CONFIG),
     }
 }
@@ -1,26 +1,63 @@
 use async_trait::async_trait;
+use axum::body::Body;
 use axum::extract::*;
-use axum::response::IntoResponse;
+use axum::middleware::Next;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::routing::post;
+use axum::routing::MethodRouter;
 use axum::Json;
 use axum::Router;
 use axum_extra::extract::CookieJar;
 use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use cli::config::SignedState;
 use http::header;
+use http::HeaderMap;
+use http::HeaderValue;
 use http::Method;
 use http::StatusCode;
+use metrics_exporter_prometheus::PrometheusHandle;
+use near_sdk::json_types::U128;
+use near_sdk::NearToken;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde_json::json;
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::AccountInfoPublic;
+use crate::record_completion;
+use crate::AuditEntry;
+use crate::CachedResponse;
+use crate::InFlightGuard;
+use crate::PaymentChannelState;
+use crate::PaymentHeaderDiagnosis;
 use crate::ProviderCtx;
+use crate::ProviderError;
+use crate::ProviderInfo;
+use crate::ProviderResult;
+use crate::ProviderSummary;
 use crate::UserFacingError;
+use crate::IDEMPOTENCY_KEY_HEADER_NAME;
+use crate::LOAD_HEADER_NAME;
 use crate::PAYMENTS_HEADER_NAME;
-use crate::{ModelInfo, Provider, BAD_REQUEST, FOUR_HUNDRED};
-use cli::config::SignedState as NearSignedState;
+use crate::RETRY_AFTER_SECS;
+use crate::ROUTE_HEADER_NAME;
+use crate::{
+    CostHeader, Endpoint, EventStreamAcceptMode, MaxTokensLimitMode, ModelInfo, Provider,
+    TokenRates, BAD_REQUEST, DEFAULT_MAX_TOKENS, FOUR_HUNDRED, MODEL_DELIMITER,
+};
+use cli::config::{SignedState as NearSignedState, CLOSE_NONCE};
+use cli::provider::{CLOSE_PAYLOAD_VERSION, CLOSE_VERSION_HEADER_NAME};
+use openaiapi::apis::chat::{
+    Chat, CreateChatCompletionResponse as CreateChatCompletionResponseAPI,
+};
 use openaiapi::apis::completions::{
     Completions, CreateCompletionResponse as CreateCompletionResponseAPI,
 };
@@ -28,14 +65,234 @@ use openaiapi::apis::models::{
     DeleteModelResponse, ListModelsResponse, Models, RetrieveModelResponse,
 };
 use openaiapi::models::{
-    self, CreateCompletionRequest as CreateCompletionRequestAPI, DeleteModelPathParams, Error,
+    self, CreateChatCompletionRequest as CreateChatCompletionRequestAPI,
+    CreateCompletionRequest as CreateCompletionRequestAPI, DeleteModelPathParams, Error,
     RetrieveModelPathParams,
 };
 
+use openaiclient::apis::chat_api::create_chat_completion;
 use openaiclient::apis::completions_api::create_completion;
 use openaiclient::apis::configuration::Configuration;
+use openaiclient::apis::models_api::list_models as list_upstream_models;
+use openaiclient::apis::ResponseContent;
+use openaiclient::models::CreateChatCompletionRequest as CreateChatCompletionRequestClient;
 use openaiclient::models::CreateCompletionRequest as CreateCompletionRequestClient;
 
+// Payment of a completion that hasn't been charged yet. Unless `charge` is called, the
+// payment (and the credit it was added to) is credited back to the channel when dropped,
+// so a request aborted midway costs nothing
+struct UnchargedPayment {
+    ctx: ProviderCtx,
+    channel_name: String,
+    // Debt taken from the channel with the payment, paid out of `available` when settling
+    debt: u128,
+    // Credit taken from the channel plus the payment
+    available: u128,
+    // Flat price of the completion, see `ProviderConfig::completion_price`
+    price: u128,
+    // Token prices of the model, see `ProviderConfig::token_rates`
+    rates: Option<TokenRates>,
+    // Cost header of the upstream, see `Provider::cost_header`
+    cost_header: Option<CostHeader>,
+    // Charge read from the cost header of the upstream response
+    upstream_charge: Option<u128>,
+    charged: bool,
+}
+
+impl UnchargedPayment {
+    fn charge(mut self) {
+        self.charged = true;
+    }
+
+    // Read the charge of the completion from the upstream response headers. Without a
+    // valid cost header the completion is priced as usual
+    fn read_upstream_cost(&mut self, headers: &HeaderMap) {
+        let Some(cost_header) = &self.cost_header else {
+            return;
+        };
+        self.upstream_charge = headers.get(cost_header.name.as_str()).and_then(|value| {
+            let charge = value
+                .to_str()
+                .ok()
+                .and_then(|value| cost_header.charge(value));
+            if charge.is_none() {
+                warn!(
+                    "Invalid {} header {:?} from the upstream, pricing the completion as usual",
+                    cost_header.name, value
+                );
+            }
+            charge
+        });
+    }
+
+    // Record the unused part of the pre-authorized amount as credit and charge the rest.
+    // `response` is the upstream response, None if the upstream call failed. Failed
+    // requests consume nothing, responses without usage consume the whole pre-authorization.
+    // With flat pricing the payment is consumed, minus the refunded part of the price
+    // of content filtered responses. A charge read from the upstream cost header replaces
+    // all of these. The debt of the channel is paid first, with token rates a cost above
+    // what's available is recorded as the new debt
+    async fn settle(self, response: Option<&serde_json::Value>) {
+        let config = &self.ctx.config;
+        let available = self.available;
+        let consumed = match response {
+            Some(response_json) => {
+                let finish_reasons = finish_reasons(response_json);
+                info!(channel_name = %self.channel_name, ?finish_reasons, "Completion finished");
+                let content_filtered = finish_reasons
+                    .iter()
+                    .any(|reason| reason == CONTENT_FILTER_FINISH_REASON);
+
+                let usage = &response_json["usage"];
+                match (self.upstream_charge, self.rates, config.cost_per_token) {
+                    (Some(charge), rates, _) => {
+                        let cost = config.charged_cost(charge, content_filtered);
+                        // Only token rates pricing carries a cost above the payment over
+                        if rates.is_some() {
+                            cost
+                        } else {
+                            cost.min(available)
+                        }
+                    }
+                    (None, Some(rates), _) => usage["prompt_tokens"]
+                        .as_u64()
+                        .zip(usage["completion_tokens"].as_u64())
+                        .map(|(prompt_tokens, completion_tokens)| {
+                            let cost = config.usage_cost(
+                                self.price,
+                                &rates,
+                                prompt_tokens,
+                                completion_tokens,
+                            );
+                            config.charged_cost(cost, content_filtered)
+                        })
+                        .unwrap_or(available.saturating_sub(self.debt)),
+                    (None, None, Some(_)) => usage["completion_tokens"]
+                        .as_u64()
+                        .map(|tokens| {
+                            let cost = config.completion_cost(self.price, tokens);
+                            config.charged_cost(cost, content_filtered)
+                        })
+                        .unwrap_or(available),
+                    (None, None, None) => {
+                        let cost = self.price;
+                        let refund = cost - config.charged_cost(cost, content_filtered);
+                        available.saturating_sub(refund)
+                    }
+                }
+            }
+            None if self.rates.is_some() || config.cost_per_token.is_some() => 0,
+            None => available,
+        };
+        let due = self.debt.saturating_add(consumed);
+        let remaining_credit = available.saturating_sub(due);
+        let remaining_debt = due.saturating_sub(available);
+        // Added to the balances of the channel, concurrent requests settle their own share
+        if remaining_debt > 0 {
+            warn!(
+                "Completion cost more than the payment of channel {}, {} is owed on the next request",
+                self.channel_name, remaining_debt
+            );
+            if let Err(e) = self
+                .ctx
+                .add_channel_debt(&self.channel_name, remaining_debt)
+                .await
+            {
+                error!(
+                    "Error updating debt of channel {}: {:?}",
+                    self.channel_name, e
+                );
+            }
+        }
+        if let Err(e) = self
+            .ctx
+            .add_channel_credit(&self.channel_name, remaining_credit)
+            .await
+        {
+            error!(
+                "Error updating credit of channel {}: {:?}",
+                self.channel_name, e
+            );
+        }
+        self.charge();
+    }
+}
+
+// Field limiting the completion tokens of a request. Chat completions may set the newer
+// `max_completion_tokens` instead of `max_tokens`
+fn max_tokens_field(body: &serde_json::Value) -> &'static str {
+    if body["max_completion_tokens"].is_u64() {
+        "max_completion_tokens"
+    } else {
+        "max_tokens"
+    }
+}
+
+// A completion request (legacy or chat) that passed the checks shared by the completion
+// endpoints and whose payment is validated, ready to be sent upstream
+struct PaidCompletion {
+    configuration: Configuration,
+    // Request sent upstream, with the bare model name
+    body: serde_json::Value,
+    // Fully qualified `provider::model` requested by the client
+    requested_model: String,
+    payment: UnchargedPayment,
+    // Set if the client sent an idempotency key, the response is kept for its retries
+    replay_key: Option<ReplayKey>,
+}
+
+// A paid request as identified by its retries, see `IDEMPOTENCY_KEY_HEADER_NAME`
+struct ReplayKey {
+    channel_name: String,
+    idempotency_key: String,
+    // Signature of the signed state in the payment header
+    signature: String,
+}
+
+impl ReplayKey {
+    // None if the request has no idempotency key or no valid payment header
+    fn from_cookies(cookies: &CookieJar) -> Option<Self> {
+        let idempotency_key = cookies.get(IDEMPOTENCY_KEY_HEADER_NAME)?;
+        let payment_header = cookies.get(PAYMENTS_HEADER_NAME)?;
+        let decoded_payload = BASE64_STANDARD.decode(payment_header.value()).ok()?;
+        let signed_state: SignedState = borsh::from_slice(&decoded_payload).ok()?;
+        Some(Self {
+            channel_name: signed_state.state.channel_id,
+            idempotency_key: idempotency_key.value().to_string(),
+            signature: signed_state.signature.to_string(),
+        })
+    }
+}
+
+impl Drop for UnchargedPayment {
+    fn drop(&mut self) {
+        if self.charged {
+            return;
+        }
+
+        let ctx = self.ctx.clone();
+        let channel_name = std::mem::take(&mut self.channel_name);
+        let credit = self.available;
+        let debt = self.debt;
+        warn!(channel_name = %channel_name, "Request aborted, crediting back its payment");
+        tokio::spawn(async move { give_back_balances(&ctx, &channel_name, credit, debt).await });
+    }
+}
+
+// Give back the credit and debt a request took from its channel, see
+// `ProviderCtx::take_channel_credit`
+async fn give_back_balances(ctx: &ProviderCtx, channel_name: &str, credit: u128, debt: u128) {
+    if let Err(e) = ctx.add_channel_credit(channel_name, credit).await {
+        error!("Error crediting back channel {}: {:?}", channel_name, e);
+    }
+    if let Err(e) = ctx.add_channel_debt(channel_name, debt).await {
+        error!(
+            "Error giving back the debt of channel {}: {:?}",
+            channel_name, e
+        );
+    }
+}
+
 #[derive(Debug)]
 pub struct ProviderBaseServiceError {
     pub message: String,
@@ -65,6 +322,8 @@ impl IntoResponse for ProviderBaseServiceError {
 #[derive(Clone)]
 pub struct ProviderBaseService {
     ctx: ProviderCtx,
+    // Renders `/metrics`, unset if no metrics recorder was installed
+    metrics: Option<PrometheusHandle>,
 }
 
 impl AsRef<ProviderBaseService> for ProviderBaseService {
@@ -76,44 +335,353 @@ impl AsRef<ProviderBaseService> for ProviderBaseService {
 impl ProviderBaseService {
     pub fn new(ctx: ProviderCtx) -> Self {
         info!("Creating ProviderBaseService");
-        Self { ctx }
+        Self { ctx, metrics: None }
+    }
+
+    pub fn with_metrics(mut self, metrics: PrometheusHandle) -> Self {
+        self.metrics = Some(metrics);
+        self
     }
 
+    // Routes of endpoints disabled in the config are not mounted (404)
     pub fn router(self) -> axum::Router {
-        Router::new()
-            .route("/health", get(|| async { "OK" }))
-            .route("/info", get(info_handler))
-            .route("/pc/close/:channel_name", post(close_handler))
-            .route("/pc/state/:channel_name", get(get_pc_state))
-            .route("/pc/validate", post(validate_pc_signed_state))
-            .with_state(self)
+        let routes: [(Endpoint, &str, MethodRouter<ProviderBaseService>); 14] = [
+            (Endpoint::Info, "/info", get(info_handler)),
+            (
+                Endpoint::Close,
+                "/pc/close/:channel_name",
+                post(close_handler),
+            ),
+            (
+                Endpoint::Close,
+                "/pc/close/:channel_name/format",
+                get(close_format_handler),
+            ),
+            (Endpoint::Open, "/pc/open/:channel_name", post(open_handler)),
+            (
+                Endpoint::State,
+                "/pc/state/:channel_name",
+                get(get_pc_state),
+            ),
+            (
+                Endpoint::History,
+                "/pc/history/:channel_name",
+                get(get_pc_history),
+            ),
+            (
+                Endpoint::Validate,
+                "/pc/validate",
+                post(validate_pc_signed_state),
+            ),
+            (
+                Endpoint::DebugHeader,
+                "/pc/debug/header",
+                post(debug_header_handler),
+            ),
+            (Endpoint::Summary, "/pc/summary", get(summary_handler)),
+            (
+                Endpoint::Disable,
+                "/pc/disable/:channel_name",
+                post(disable_handler),
+            ),
+            (
+                Endpoint::Register,
+                "/pc/register/:channel_name",
+                post(register_handler),
+            ),
+            (Endpoint::Drain, "/admin/drain", post(drain_handler)),
+            (Endpoint::Audit, "/admin/audit", get(audit_handler)),
+            (Endpoint::Metrics, "/metrics", get(metrics_handler)),
+        ];
+
+        let mut router = Router::new().route("/health", get(health_handler));
+        for (endpoint, path, handler) in routes {
+            if self.ctx.config.is_enabled(endpoint) {
+                router = router.route(path, handler);
+            }
+        }
+        router.with_state(self)
     }
 }
 
-async fn info_handler(State(state): State<ProviderBaseService>) -> Json<AccountInfoPublic> {
-    Json(state.ctx.public_account_info().await)
+// Admin endpoints require the admin api key or an operator api key as a bearer token,
+// returns the authenticated operator
+fn authorize_admin(
+    state: &ProviderBaseService,
+    headers: &HeaderMap,
+) -> Result<String, ProviderBaseServiceError> {
+    if !state.ctx.config.admin_enabled() {
+        return Err(ProviderBaseServiceError::new(
+            "Admin endpoints are disabled".to_string(),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|token| state.ctx.config.admin_operator(token))
+        .map(|operator| operator.to_string())
+        .ok_or_else(|| {
+            ProviderBaseServiceError::new(
+                "Invalid admin api key".to_string(),
+                StatusCode::UNAUTHORIZED,
+            )
+        })
+}
+
+// Error of a failed admin action as recorded in the audit log
+fn audit_error<T>(result: &ProviderResult<T>) -> Option<String> {
+    result
+        .as_ref()
+        .err()
+        .map(|e| UserFacingError::from(e).to_string())
+}
+
+#[derive(Deserialize)]
+struct SummaryParams {
+    #[serde(default)]
+    refresh: bool,
+}
+
+async fn summary_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+    Query(params): Query<SummaryParams>,
+) -> Result<Json<ProviderSummary>, ProviderBaseServiceError> {
+    let operator = authorize_admin(&state, &headers)?;
+
+    let result = state.ctx.summary(params.refresh).await;
+    state
+        .ctx
+        .audit(
+            &operator,
+            "summary",
+            None,
+            Some(format!("refresh={}", params.refresh)),
+            audit_error(&result),
+        )
+        .await;
+    let result = result.map_err(|e| {
+        ProviderBaseServiceError::new(UserFacingError::from(&e).to_string(), StatusCode::from(&e))
+    })?;
+    Ok(Json(result))
+}
+
+async fn disable_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+    Path(channel_name): Path<String>,
+) -> Result<Json<PaymentChannelState>, ProviderBaseServiceError> {
+    let operator = authorize_admin(&state, &headers)?;
+
+    let to_service_error = |e: ProviderError| {
+        ProviderBaseServiceError::new(UserFacingError::from(&e).to_string(), StatusCode::from(&e))
+    };
+    let result = state.ctx.disable_channel(&channel_name).await;
+    state
+        .ctx
+        .audit(
+            &operator,
+            "disable_channel",
+            Some(&channel_name),
+            None,
+            audit_error(&result),
+        )
+        .await;
+    result.map_err(to_service_error)?;
+    let result = state
+        .ctx
+        .get_pc_state(&channel_name)
+        .await
+        .map_err(to_service_error)?;
+    Ok(Json(result))
+}
+
+#[derive(Deserialize)]
+struct RegisterParams {
+    // Maximum spent balance accepted on the channel, in yoctoNEAR
+    spend_cap: Option<U128>,
+}
+
+async fn register_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+    Path(channel_name): Path<String>,
+    Query(params): Query<RegisterParams>,
+) -> Result<Json<PaymentChannelState>, ProviderBaseServiceError> {
+    let operator = authorize_admin(&state, &headers)?;
+
+    let to_service_error = |e: ProviderError| {
+        ProviderBaseServiceError::new(UserFacingError::from(&e).to_string(), StatusCode::from(&e))
+    };
+    let spend_cap = params
+        .spend_cap
+        .map(|spend_cap| NearToken::from_yoctonear(spend_cap.0));
+    let result = state.ctx.register_channel(&channel_name, spend_cap).await;
+    state
+        .ctx
+        .audit(
+            &operator,
+            "register_channel",
+            Some(&channel_name),
+            spend_cap.map(|spend_cap| format!("spend_cap={}", spend_cap.as_yoctonear())),
+            audit_error(&result),
+        )
+        .await;
+    result.map_err(to_service_error)?;
+    let result = state
+        .ctx
+        .get_pc_state(&channel_name)
+        .await
+        .map_err(to_service_error)?;
+    Ok(Json(result))
+}
+
+// Break down how a payment header value (the body) is parsed, without recording it
+async fn debug_header_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<PaymentHeaderDiagnosis>, ProviderBaseServiceError> {
+    let operator = authorize_admin(&state, &headers)?;
+
+    // The header itself isn't recorded, it is a spendable payment
+    state
+        .ctx
+        .audit(&operator, "debug_header", None, None, None)
+        .await;
+    Ok(Json(state.ctx.diagnose_payment_header(&body).await))
+}
+
+// Load balancers should stop sending traffic to a draining provider
+async fn health_handler(State(state): State<ProviderBaseService>) -> impl IntoResponse {
+    if state.ctx.shared.is_draining() {
+        (StatusCode::SERVICE_UNAVAILABLE, "DRAINING")
+    } else {
+        (StatusCode::OK, "OK")
+    }
+}
+
+// Prometheus text format
+async fn metrics_handler(State(state): State<ProviderBaseService>) -> Response {
+    match &state.metrics {
+        Some(metrics) => (
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            metrics.render(),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Start draining, the provider shuts down once the completions in flight are done
+async fn drain_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ProviderBaseServiceError> {
+    let operator = authorize_admin(&state, &headers)?;
+
+    state.ctx.audit(&operator, "drain", None, None, None).await;
+    let ctx = state.ctx.clone();
+    tokio::spawn(async move { ctx.drain().await });
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "draining": true,
+            "in_flight": state.ctx.shared.in_flight(),
+        })),
+    ))
+}
+
+// Entries returned by `/admin/audit` without a `limit`
+const DEFAULT_AUDIT_LIMIT: u32 = 100;
+const MAX_AUDIT_LIMIT: u32 = 1000;
+
+#[derive(Deserialize)]
+struct AuditParams {
+    limit: Option<u32>,
+}
+
+// Latest admin actions first. Reading the audit log isn't recorded in it
+async fn audit_handler(
+    State(state): State<ProviderBaseService>,
+    headers: HeaderMap,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<Vec<AuditEntry>>, ProviderBaseServiceError> {
+    authorize_admin(&state, &headers)?;
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .min(MAX_AUDIT_LIMIT);
+    let result = state.ctx.audit_entries(limit).await.map_err(|e| {
+        ProviderBaseServiceError::new(UserFacingError::from(&e).to_string(), StatusCode::from(&e))
+    })?;
+    Ok(Json(result))
+}
+
+async fn info_handler(State(state): State<ProviderBaseService>) -> Json<ProviderInfo> {
+    Json(state.ctx.provider_info().await)
+}
+
+// Describes the payload expected by `close_handler`, so clients can check they speak the same version
+async fn close_format_handler(Path(channel_name): Path<String>) -> Json<serde_json::Value> {
+    Json(json!({
+        "version": CLOSE_PAYLOAD_VERSION,
+        "version_header": CLOSE_VERSION_HEADER_NAME,
+        "method": "POST",
+        "path": format!("/pc/close/{}", channel_name),
+        "body": "base64 encoded borsh serialized SignedState",
+        "signed_state": {
+            "state": {
+                "channel_id": channel_name,
+                "spent_balance": "u128 in yoctoNEAR, must be 0",
+                "nonce": CLOSE_NONCE,
+            },
+            "signature": "signature of the borsh serialized state by the channel sender key",
+        },
+    }))
 }
 
 async fn close_handler(
     State(state): State<ProviderBaseService>,
     Path(channel_name): Path<String>,
+    headers: HeaderMap,
     body: String,
 ) -> Result<Json<NearSignedState>, ProviderBaseServiceError> {
-    let decoded_payload = BASE64_STANDARD.decode(&body).map_err(|e| {
-        ProviderBaseServiceError::new(
-            format!("Unable to decode base64: {}", e),
+    // Clients that don't send the version predate it, and use the first version
+    let version = match headers.get(CLOSE_VERSION_HEADER_NAME) {
+        Some(value) => value.to_str().ok().and_then(|v| v.parse::<u32>().ok()),
+        None => Some(CLOSE_PAYLOAD_VERSION),
+    };
+    if version != Some(CLOSE_PAYLOAD_VERSION) {
+        return Err(ProviderBaseServiceError::new(
+            format!(
+                "Unsupported close payload version {:?}, expected version {}. See GET /pc/close/{}/format",
+                headers.get(CLOSE_VERSION_HEADER_NAME).unwrap(),
+                CLOSE_PAYLOAD_VERSION,
+                channel_name
+            ),
             StatusCode::BAD_REQUEST,
-        )
-    })?;
-    let signed_state = borsh::from_slice::<NearSignedState>(&decoded_payload).map_err(|e| {
+        ));
+    }
+
+    let invalid_payload = |reason: String| {
         ProviderBaseServiceError::new(
             format!(
-                "Unable to deserialize borsh serialized SignedState from body: {}",
-                e
+                "Invalid close payload (version {}): {}. See GET /pc/close/{}/format",
+                CLOSE_PAYLOAD_VERSION, reason, channel_name
             ),
             StatusCode::BAD_REQUEST,
         )
-    })?;
+    };
+    let decoded_payload = BASE64_STANDARD
+        .decode(&body)
+        .map_err(|e| invalid_payload(format!("body is not base64: {}", e)))?;
+    let signed_state = borsh::from_slice::<NearSignedState>(&decoded_payload)
+        .map_err(|e| invalid_payload(format!("body is not a borsh SignedState: {}", e)))?;
 
     let result = state
         .ctx
@@ -138,6 +706,40 @@ async fn get_pc_state(
     Ok((StatusCode::OK, Json(result)))
 }
 
+// Read a newly opened channel from the contract in the background, so its first
+// payment doesn't wait on a contract view call
+async fn open_handler(
+    State(state): State<ProviderBaseService>,
+    Path(channel_name): Path<String>,
+) -> Result<impl IntoResponse, ProviderBaseServiceError> {
+    if !state.ctx.config.warm_up_channels {
+        return Err(ProviderBaseServiceError::new(
+            "Channel warm up is disabled".to_string(),
+            StatusCode::NOT_FOUND,
+        ));
+    }
+
+    let ctx = state.ctx.clone();
+    let also_channel_name = channel_name.clone();
+    tokio::spawn(async move { ctx.warm_up_channel(&also_channel_name).await });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({ "channel_name": channel_name })),
+    ))
+}
+
+async fn get_pc_history(
+    State(state): State<ProviderBaseService>,
+    Path(channel_name): Path<String>,
+) -> Result<impl IntoResponse, ProviderBaseServiceError> {
+    let result = state.ctx.get_pc_history(&channel_name).await.map_err(|e| {
+        ProviderBaseServiceError::new(UserFacingError::from(&e).to_string(), StatusCode::from(&e))
+    })?;
+
+    Ok((StatusCode::OK, Json(result)))
+}
+
 async fn validate_pc_signed_state(
     State(state): State<ProviderBaseService>,
     body: String,
@@ -175,6 +777,190 @@ async fn validate_pc_signed_state(
     Ok((StatusCode::OK, Json(signed_state)))
 }
 
+enum UpstreamCompletionError<E> {
+    Client(openaiclient::apis::Error<E>),
+    // The response was bigger than `max_response_bytes`
+    ResponseTooLarge(usize),
+}
+
+// Same call as the generated client functions (`create_completion`, ...), except the
+// response headers are returned and the response is read chunk by chunk and dropped once
+// it is bigger than `max_bytes`, instead of buffered whole
+async fn post_upstream<
+    Request: serde::Serialize,
+    Response: DeserializeOwned,
+    E: DeserializeOwned,
+>(
+    configuration: &Configuration,
+    path: &str,
+    request: Request,
+    max_bytes: Option<usize>,
+) -> Result<(Response, HeaderMap), UpstreamCompletionError<E>> {
+    let mut request_builder = configuration
+        .client
+        .post(format!("{}{}", configuration.base_path, path))
+        .json(&request);
+    if let Some(token) = &configuration.bearer_access_token {
+        request_builder = request_builder.bearer_auth(token);
+    }
+    let mut response = request_builder
+        .send()
+        .await
+        .map_err(|e| UpstreamCompletionError::Client(e.into()))?;
+
+    if let Some(max_bytes) = max_bytes.filter(|max_bytes| {
+        response
+            .content_length()
+            .is_some_and(|length| length > *max_bytes as u64)
+    }) {
+        return Err(UpstreamCompletionError::ResponseTooLarge(max_bytes));
+    }
+    let status = response.status();
+    let headers = response.headers().clone();
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| UpstreamCompletionError::Client(e.into()))?
+    {
+        if let Some(max_bytes) = max_bytes.filter(|max_bytes| body.len() + chunk.len() > *max_bytes)
+        {
+            return Err(UpstreamCompletionError::ResponseTooLarge(max_bytes));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    if status.is_client_error() || status.is_server_error() {
+        let content = String::from_utf8_lossy(&body).into_owned();
+        return Err(UpstreamCompletionError::Client(
+            openaiclient::apis::Error::ResponseError(ResponseContent {
+                status,
+                entity: serde_json::from_str(&content).ok(),
+                content,
+            }),
+        ));
+    }
+    let response =
+        serde_json::from_slice(&body).map_err(|e| UpstreamCompletionError::Client(e.into()))?;
+    Ok((response, headers))
+}
+
+// Error answered to the client when the upstream call failed
+enum UpstreamFailure {
+    BadGateway(Error),
+    InternalServerError(Error),
+}
+
+impl<E: std::fmt::Debug> From<&UpstreamCompletionError<E>> for UpstreamFailure {
+    fn from(error: &UpstreamCompletionError<E>) -> Self {
+        match error {
+            UpstreamCompletionError::ResponseTooLarge(max_bytes) => {
+                error!(
+                    "Upstream completion response is bigger than {} bytes",
+                    max_bytes
+                );
+                UpstreamFailure::BadGateway(Error::new(
+                    "Bad Gateway".to_string(),
+                    "Bad Gateway".to_string(),
+                    format!("Upstream response is bigger than {} bytes", max_bytes),
+                    "invalid_request_error".to_string(),
+                ))
+            }
+            UpstreamCompletionError::Client(e) => {
+                let message = upstream_error_message(e);
+                error!("Upstream completion failed: {}", message);
+                UpstreamFailure::InternalServerError(Error::new(
+                    "Internal Server Error".to_string(),
+                    "Internal Server Error".to_string(),
+                    message,
+                    "invalid_request_error".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+// Longest upstream error detail passed on to clients
+const MAX_UPSTREAM_ERROR_LENGTH: usize = 300;
+
+// Concise description of a failed upstream call. Gateways in front of the upstreams
+// often answer with HTML pages or plain text, only their text is kept, truncated
+fn upstream_error_message<T: std::fmt::Debug>(error: &openaiclient::apis::Error<T>) -> String {
+    let openaiclient::apis::Error::ResponseError(response) = error else {
+        return truncate(&error.to_string(), MAX_UPSTREAM_ERROR_LENGTH);
+    };
+
+    let detail = match serde_json::from_str::<serde_json::Value>(&response.content) {
+        // OpenAI style `{"error": {"message": ...}}`, or any other JSON as is
+        Ok(body) => body["error"]["message"]
+            .as_str()
+            .or(body["message"].as_str())
+            .map(|message| message.to_string())
+            .unwrap_or_else(|| body.to_string()),
+        Err(_) => strip_html(&response.content),
+    };
+    format!(
+        "Upstream returned status {}: {}",
+        response.status.as_u16(),
+        truncate(&detail, MAX_UPSTREAM_ERROR_LENGTH)
+    )
+}
+
+// Text of an HTML (or plain text) body with the whitespace collapsed
+fn strip_html(body: &str) -> String {
+    let mut text = String::with_capacity(body.len());
+    let mut in_tag = false;
+    for c in body.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            _ if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn truncate(text: &str, max_length: usize) -> String {
+    match text.char_indices().nth(max_length) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+// Finish reason of choices cut by the upstream content filter
+const CONTENT_FILTER_FINISH_REASON: &str = "content_filter";
+
+// Finish reason of every choice of a completion response ("stop", "length", ...)
+fn finish_reasons(response: &serde_json::Value) -> Vec<String> {
+    response["choices"]
+        .as_array()
+        .map(|choices| {
+            choices
+                .iter()
+                .filter_map(|choice| choice["finish_reason"].as_str())
+                .map(|reason| reason.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Upstreams only know the bare model name they were sent, give clients back the
+// fully qualified `provider::model` they requested. Works on any response with
+// a top level `model` field (completions, chat completions)
+fn echo_requested_model(
+    mut response: serde_json::Value,
+    requested_model: &str,
+) -> serde_json::Value {
+    if let Some(model) = response.get_mut("model") {
+        *model = json!(requested_model);
+    }
+    response
+}
+
 #[derive(Clone)]
 pub struct ProviderOaiService {
     ctx: ProviderCtx,
@@ -201,6 +987,73 @@ impl AsRef<ProviderOaiService> for ProviderOaiService {
     }
 }
 
+// Create the client configuration of an upstream from the provider configuration
+fn upstream_configuration(provider: &Provider) -> Configuration {
+    let mut configuration: Configuration = Configuration::new();
+    configuration.user_agent = None;
+    configuration.base_path = provider.url.clone();
+    configuration.bearer_access_token = Some(provider.api_key.clone());
+    configuration
+}
+
+// Longest wait for the `/models` of an upstream, slow upstreams are left out of the list
+const UPSTREAM_MODELS_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl ProviderOaiService {
+    // Models listed by the upstreams exposing `/models`, with `provider::model` ids as
+    // used in completions. Upstreams without a listing are skipped
+    async fn upstream_models(&self) -> Vec<serde_json::Value> {
+        let providers = self.ctx.canonical_providers().await;
+        futures::future::join_all(providers.iter().map(provider_models))
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+// Models listed by the `/models` endpoint of one upstream, empty if it has no listing
+async fn provider_models(provider: &Provider) -> Vec<serde_json::Value> {
+    let configuration = upstream_configuration(provider);
+    let listing = match tokio::time::timeout(
+        UPSTREAM_MODELS_TIMEOUT,
+        list_upstream_models(&configuration),
+    )
+    .await
+    {
+        Ok(Ok(listing)) => serde_json::to_value(listing).unwrap(),
+        Ok(Err(e)) => {
+            warn!(
+                "Unable to list the models of {}: {}",
+                provider.canonical_name,
+                upstream_error_message(&e)
+            );
+            return vec![];
+        }
+        Err(_) => {
+            warn!(
+                "Listing the models of {} timed out",
+                provider.canonical_name
+            );
+            return vec![];
+        }
+    };
+    listing["data"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|model| model["id"].as_str().map(|id| (id, model)))
+        .map(|(id, model)| {
+            json!({
+                "id": format!("{}{}{}", provider.canonical_name, MODEL_DELIMITER, id),
+                "object": "model",
+                "created": model["created"].as_i64().unwrap_or(0),
+                "owned_by": provider.canonical_name,
+            })
+        })
+        .collect()
+}
+
 #[async_trait]
 impl Models for ProviderOaiService {
     /// Delete a fine-tuned model. You must have the Owner role in your organization to delete a model..
@@ -232,14 +1085,23 @@ impl Models for ProviderOaiService {
         _host: Host,
         _cookies: CookieJar,
     ) -> Result<ListModelsResponse, ()> {
-        Ok(ListModelsResponse::Status500_InternalServerError(
-            Error::new(
-                "not_implemented".to_string(),
-                "Not implemented".to_string(),
-                "None".to_string(),
-                "invalid_request_error".to_string(),
-            ),
-        ))
+        // Configured models first, they take precedence over the upstream listings
+        let mut data = self
+            .ctx
+            .config
+            .models
+            .iter()
+            .map(|model| model.to_openai_json())
+            .collect::<Vec<_>>();
+        for model in self.upstream_models().await {
+            if !data.iter().any(|listed| listed["id"] == model["id"]) {
+                data.push(model);
+            }
+        }
+        let models_list: models::ListModelsResponse =
+            serde_json::from_value(json!({ "object": "list", "data": data })).unwrap();
+
+        Ok(ListModelsResponse::Status200_OK(models_list))
     }
 
     /// Retrieves a model instance, providing basic information about the model such as the owner and permissioning..
@@ -250,16 +1112,269 @@ impl Models for ProviderOaiService {
         _method: Method,
         _host: Host,
         _cookies: CookieJar,
-        _path_params: RetrieveModelPathParams,
+        path_params: RetrieveModelPathParams,
     ) -> Result<RetrieveModelResponse, ()> {
-        Ok(RetrieveModelResponse::Status500_InternalServerError(
-            Error::new(
-                "not_implemented".to_string(),
-                "Not implemented".to_string(),
-                "None".to_string(),
+        if let Some(model) = self
+            .ctx
+            .config
+            .models
+            .iter()
+            .find(|model| model.id == path_params.model)
+        {
+            let model: models::Model = serde_json::from_value(model.to_openai_json()).unwrap();
+            return Ok(RetrieveModelResponse::Status200_OK(model));
+        }
+
+        let not_found = |message: String| {
+            Ok(RetrieveModelResponse::Status404_NotFound(Error::new(
+                "model_not_found".to_string(),
+                message,
+                "model".to_string(),
                 "invalid_request_error".to_string(),
-            ),
-        ))
+            )))
+        };
+
+        // Bare model names are models of the default provider, like in completions
+        let model_info = match self.ctx.model_info(&path_params.model).await {
+            Ok(model_info) => model_info,
+            Err(e) => return not_found(e),
+        };
+        let Some(provider) = self.ctx.find_provider(&model_info.provider, None).await else {
+            return not_found(format!(
+                "The model '{}' does not exist, provider {} is not configured",
+                path_params.model, model_info.provider
+            ));
+        };
+
+        // Metadata listed by the upstream if it has any for the model, the upstream may
+        // still serve models it doesn't list. The id is echoed as requested
+        let qualified_id = format!(
+            "{}{}{}",
+            model_info.provider, MODEL_DELIMITER, model_info.model_name
+        );
+        let mut model = provider_models(&provider)
+            .await
+            .into_iter()
+            .find(|model| model["id"] == qualified_id)
+            .unwrap_or_else(|| {
+                json!({
+                    "object": "model",
+                    "created": 0,
+                    "owned_by": provider.canonical_name,
+                })
+            });
+        model["id"] = json!(path_params.model);
+        let model: models::Model = serde_json::from_value(model).unwrap();
+        Ok(RetrieveModelResponse::Status200_OK(model))
+    }
+}
+
+impl ProviderOaiService {
+    // Checks shared by the completion endpoints, then validation of the payment in the
+    // payment header. Rejected requests get the returned error with a 400
+    async fn prepare_completion(
+        &self,
+        cookies: &CookieJar,
+        mut body: serde_json::Value,
+    ) -> Result<PaidCompletion, Error> {
+        let bad_request = |message: String, param: &str| {
+            Error::new(
+                FOUR_HUNDRED.to_string(),
+                BAD_REQUEST.to_string(),
+                message,
+                param.to_string(),
+            )
+        };
+
+        // Parse the model info from the request
+        let requested_model = body["model"].as_str().unwrap_or_default().to_string();
+        let model_info: ModelInfo = self
+            .ctx
+            .model_info(&requested_model)
+            .await
+            .map_err(|e| bad_request(e.to_string(), ""))?;
+
+        // Get the provider from the config, using the route hint if the client sent one
+        let route = cookies
+            .get(ROUTE_HEADER_NAME)
+            .map(|c| c.value().to_string());
+        let provider: Provider = self
+            .ctx
+            .find_provider(&model_info.provider, route.as_deref())
+            .await
+            .ok_or_else(|| {
+                bad_request(format!("Provider {} not found", model_info.provider), "")
+            })?;
+
+        // Enforce the `max_tokens` limit of the upstream before pricing the request
+        let max_tokens_field = max_tokens_field(&body);
+        if let Some(limit) = self.ctx.config.max_tokens_limit(&provider) {
+            let max_tokens = body[max_tokens_field]
+                .as_u64()
+                .unwrap_or(DEFAULT_MAX_TOKENS);
+            if max_tokens > limit {
+                match self.ctx.config.max_tokens_limit_mode {
+                    MaxTokensLimitMode::Reject => {
+                        return Err(bad_request(
+                            format!(
+                                "{} of {} is above the limit of {}",
+                                max_tokens_field, max_tokens, limit
+                            ),
+                            max_tokens_field,
+                        ));
+                    }
+                    MaxTokensLimitMode::Clamp => {
+                        body[max_tokens_field] = json!(limit);
+                    }
+                }
+            }
+        }
+
+        // Parse the payment header from the request
+        let payment_header = cookies
+            .get(PAYMENTS_HEADER_NAME)
+            .map(|payment_header| payment_header.value().to_string())
+            .ok_or_else(|| {
+                bad_request(
+                    format!(
+                        "Payment header not found. Please ensure you've added the correct header under {} to your request.",
+                        PAYMENTS_HEADER_NAME
+                    ),
+                    "",
+                )
+            })?;
+
+        // Validate the payment header. If it's not valid, return a 400
+        let decoded_payload = BASE64_STANDARD.decode(&payment_header).map_err(|e| {
+            bad_request(format!("Unable to decode base64 payment header: {}", e), "")
+        })?;
+        let signed_state: SignedState = borsh::from_slice(&decoded_payload).map_err(|e| {
+            bad_request(
+                format!(
+                    "Unable to deserialize borsh serialized SignedState from payment header: {}",
+                    e
+                ),
+                "",
+            )
+        })?;
+        let replay_key = cookies
+            .get(IDEMPOTENCY_KEY_HEADER_NAME)
+            .map(|idempotency_key| ReplayKey {
+                channel_name: signed_state.state.channel_id.clone(),
+                idempotency_key: idempotency_key.value().to_string(),
+                signature: signed_state.signature.to_string(),
+            });
+        // With per token pricing the payment plus the channel credit must cover the
+        // worst case cost of the request, the surplus is credited after the completion
+        let channel_name = signed_state.state.channel_id.clone();
+        let price = self.ctx.config.completion_price(&provider);
+        let rates = self.ctx.config.token_rates(&model_info);
+        let max_tokens = body[max_tokens_field].as_u64();
+        let token_priced = rates.is_some() || self.ctx.config.cost_per_token.is_some();
+        // The upstream must not generate more than what's pre-authorized, chat completions
+        // have no default limit
+        if token_priced && max_tokens.is_none() {
+            body["max_tokens"] = json!(DEFAULT_MAX_TOKENS);
+        }
+        let (max_cost, takes_credit, takes_debt) = match (rates, self.ctx.config.cost_per_token) {
+            // Only token pricing leaves debt (the prompt isn't pre-authorized, an upstream
+            // may ignore the limit), the payment must also cover it
+            (Some(rates), _) => {
+                let max_cost = self.ctx.config.max_usage_cost(price, &rates, max_tokens);
+                (max_cost, true, true)
+            }
+            (None, Some(_)) => {
+                let max_cost = self.ctx.config.max_completion_cost(price, max_tokens);
+                (max_cost, true, true)
+            }
+            // Flat pricing only accumulates credit from content filtered responses, payments
+            // of aborted requests and upstream charges below the price
+            (None, None)
+                if self.ctx.config.content_filter_charge_percent.is_some()
+                    || self.ctx.config.request_timeout_secs.is_some()
+                    || provider.cost_header.is_some() =>
+            {
+                (price, true, false)
+            }
+            (None, None) => (price, false, false),
+        };
+        let cost_header = provider.cost_header.clone();
+        // Run apart from the request, so a request dropped while the signed state is being
+        // stored (e.g. past `request_timeout_secs`) still gets its payment credited back.
+        // The balances taken from the channel are given back if the payment is rejected
+        let payment = tokio::spawn({
+            let ctx = self.ctx.clone();
+            async move {
+                let credit = match takes_credit {
+                    true => ctx.take_channel_credit(&channel_name).await,
+                    false => 0,
+                };
+                let debt = match takes_debt {
+                    true => ctx.take_channel_debt(&channel_name).await,
+                    false => 0,
+                };
+                let min_cost = max_cost.saturating_add(debt).saturating_sub(credit);
+                let payment = match ctx
+                    .validate_signed_state(min_cost, &signed_state, true) // user is paying for the service
+                    .await
+                {
+                    Ok(payment) => payment,
+                    Err(e) => {
+                        give_back_balances(&ctx, &channel_name, credit, debt).await;
+                        return Err(e);
+                    }
+                };
+                Ok::<_, ProviderError>(UnchargedPayment {
+                    ctx,
+                    channel_name,
+                    debt,
+                    available: credit.saturating_add(payment),
+                    price,
+                    rates,
+                    cost_header,
+                    upstream_charge: None,
+                    charged: false,
+                })
+            }
+        })
+        .await
+        .unwrap()
+        .map_err(|e| bad_request(UserFacingError::from(&e).to_string(), ""))?;
+
+        let configuration = upstream_configuration(&provider);
+        body["model"] = json!(model_info.model_name);
+        Ok(PaidCompletion {
+            configuration,
+            body,
+            requested_model,
+            payment,
+            replay_key,
+        })
+    }
+
+    // Response already paid by the payment of a retried request
+    fn cached_completion(&self, cookies: &CookieJar) -> Option<serde_json::Value> {
+        let replay_key = ReplayKey::from_cookies(cookies)?;
+        let response = self.ctx.shared.cached_response(
+            &replay_key.channel_name,
+            &replay_key.idempotency_key,
+            &replay_key.signature,
+        )?;
+        info!(channel_name = %replay_key.channel_name, "Replaying the response of a retried payment");
+        Some(response)
+    }
+
+    fn cache_completion(&self, replay_key: Option<ReplayKey>, response: &serde_json::Value) {
+        if let Some(replay_key) = replay_key {
+            self.ctx.shared.cache_response(
+                &replay_key.channel_name,
+                CachedResponse {
+                    idempotency_key: replay_key.idempotency_key,
+                    signature: replay_key.signature,
+                    response: response.clone(),
+                },
+            );
+        }
     }
 }
 
@@ -270,141 +1385,579 @@ impl Completions for ProviderOaiService {
         _method: Method,
         _host: Host,
         cookies: CookieJar,
-        mut body: CreateCompletionRequestAPI,
+        body: CreateCompletionRequestAPI,
     ) -> Result<CreateCompletionResponseAPI, ()> {
-        // Parse the model info from the request
-        let model_info: ModelInfo = match ModelInfo::from_str(&body.model) {
-            Ok(m) => m,
-            Err(e) => {
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
-                        FOUR_HUNDRED.to_string(),
-                        BAD_REQUEST.to_string(),
-                        e.to_string(),
-                        "".to_string(),
-                    ),
-                ));
-            }
-        };
+        if let Some(response) = self
+            .cached_completion(&cookies)
+            .and_then(|response| serde_json::from_value(response).ok())
+        {
+            return Ok(CreateCompletionResponseAPI::Status200_OK(response));
+        }
 
-        // Get the provider from the config
-        let provider: &Provider = match self
-            .ctx
-            .config
-            .providers
-            .iter()
-            .find(|p| p.canonical_name == model_info.provider)
+        let mut completion = match self
+            .prepare_completion(&cookies, serde_json::to_value(&body).unwrap())
+            .await
         {
-            Some(p) => p,
-            None => {
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
-                        FOUR_HUNDRED.to_string(),
-                        BAD_REQUEST.to_string(),
-                        format!("Provider {} not found", model_info.provider),
-                        "".to_string(),
-                    ),
-                ))
-            }
+            Ok(completion) => completion,
+            Err(e) => return Ok(CreateCompletionResponseAPI::Status400_BadRequest(e)),
         };
 
-        // Parse the payment header from the request
-        let payment_header = match cookies.get(PAYMENTS_HEADER_NAME) {
-            Some(payment_header) => payment_header.value().to_string(),
-            None => {
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
-                        FOUR_HUNDRED.to_string(),
-                        BAD_REQUEST.to_string(),
-                        format!(
-                            "Payment header not found. Please ensure you've added the correct header under {} to your request.",
-                            PAYMENTS_HEADER_NAME
-                        ),
-                        "".to_string(),
-                    ),
-                ));
+        // Convert the user request to a client request
+        let client_request: CreateCompletionRequestClient =
+            serde_json::from_value(completion.body).unwrap();
+        let started = Instant::now();
+        // The generated client doesn't return the response headers
+        let max_bytes = self.ctx.config.max_response_bytes;
+        let response = if max_bytes.is_some() || completion.payment.cost_header.is_some() {
+            post_upstream(
+                &completion.configuration,
+                "/completions",
+                client_request,
+                max_bytes,
+            )
+            .await
+            .map(|(response, headers)| {
+                completion.payment.read_upstream_cost(&headers);
+                response
+            })
+        } else {
+            create_completion(&completion.configuration, client_request)
+                .await
+                .map_err(UpstreamCompletionError::Client)
+        };
+        record_completion("/completions", started.elapsed(), response.is_ok());
+
+        let response_json = response
+            .as_ref()
+            .ok()
+            .map(|response| serde_json::to_value(response).unwrap());
+        completion.payment.settle(response_json.as_ref()).await;
+
+        match response {
+            Ok(_) => {
+                let response_json =
+                    echo_requested_model(response_json.unwrap(), &completion.requested_model);
+                self.cache_completion(completion.replay_key, &response_json);
+                let api_response: models::CreateCompletionResponse =
+                    serde_json::from_value(response_json).unwrap();
+                Ok(CreateCompletionResponseAPI::Status200_OK(api_response))
             }
+            Err(e) => match UpstreamFailure::from(&e) {
+                UpstreamFailure::BadGateway(e) => {
+                    Ok(CreateCompletionResponseAPI::Status502_BadGateway(e))
+                }
+                UpstreamFailure::InternalServerError(e) => Ok(
+                    CreateCompletionResponseAPI::Status500_InternalServerError(e),
+                ),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl Chat for ProviderOaiService {
+    /// Creates a model response for the given chat conversation, paid like `create_completion`.
+    ///
+    /// CreateChatCompletion - POST /oai/chat/completions
+    async fn create_chat_completion(
+        &self,
+        _method: Method,
+        _host: Host,
+        cookies: CookieJar,
+        body: CreateChatCompletionRequestAPI,
+    ) -> Result<CreateChatCompletionResponseAPI, ()> {
+        if let Some(response) = self
+            .cached_completion(&cookies)
+            .and_then(|response| serde_json::from_value(response).ok())
+        {
+            return Ok(CreateChatCompletionResponseAPI::Status200_OK(response));
+        }
+
+        let mut completion = match self
+            .prepare_completion(&cookies, serde_json::to_value(&body).unwrap())
+            .await
+        {
+            Ok(completion) => completion,
+            Err(e) => return Ok(CreateChatCompletionResponseAPI::Status400_BadRequest(e)),
         };
 
-        // Validate the payment header. If it's not valid, return a 400
-        let decoded_payload = match BASE64_STANDARD.decode(&payment_header) {
-            Ok(d) => d,
-            Err(e) => {
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
+        let client_request: CreateChatCompletionRequestClient =
+            serde_json::from_value(completion.body).unwrap();
+        let started = Instant::now();
+        // The generated client doesn't return the response headers
+        let max_bytes = self.ctx.config.max_response_bytes;
+        let response = if max_bytes.is_some() || completion.payment.cost_header.is_some() {
+            post_upstream(
+                &completion.configuration,
+                "/chat/completions",
+                client_request,
+                max_bytes,
+            )
+            .await
+            .map(|(response, headers)| {
+                completion.payment.read_upstream_cost(&headers);
+                response
+            })
+        } else {
+            create_chat_completion(&completion.configuration, client_request)
+                .await
+                .map_err(UpstreamCompletionError::Client)
+        };
+        record_completion("/chat/completions", started.elapsed(), response.is_ok());
+
+        let response_json = response
+            .as_ref()
+            .ok()
+            .map(|response| serde_json::to_value(response).unwrap());
+        completion.payment.settle(response_json.as_ref()).await;
+
+        match response {
+            Ok(_) => {
+                let response_json =
+                    echo_requested_model(response_json.unwrap(), &completion.requested_model);
+                self.cache_completion(completion.replay_key, &response_json);
+                let api_response: models::CreateChatCompletionResponse =
+                    serde_json::from_value(response_json).unwrap();
+                Ok(CreateChatCompletionResponseAPI::Status200_OK(api_response))
+            }
+            Err(e) => match UpstreamFailure::from(&e) {
+                UpstreamFailure::BadGateway(e) => {
+                    Ok(CreateChatCompletionResponseAPI::Status502_BadGateway(e))
+                }
+                UpstreamFailure::InternalServerError(e) => {
+                    Ok(CreateChatCompletionResponseAPI::Status500_InternalServerError(e))
+                }
+            },
+        }
+    }
+}
+
+// The generated server stubs mount every endpoint of the spec, the endpoints disabled in
+// the config are answered with a 404 here instead
+pub async fn disabled_endpoints_middleware(
+    State(ctx): State<ProviderCtx>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    let endpoint = match path {
+        "/oai/completions" => Some(Endpoint::Completions),
+        "/oai/chat/completions" => Some(Endpoint::ChatCompletions),
+        _ if path == "/oai/models" || path.starts_with("/oai/models/") => Some(Endpoint::Models),
+        _ => None,
+    };
+    match endpoint {
+        Some(endpoint) if !ctx.config.is_enabled(endpoint) => StatusCode::NOT_FOUND.into_response(),
+        _ => next.run(req).await,
+    }
+}
+
+// Aborts completions past `request_timeout_secs`. Dropping the handler credits back the
+// payment it took, see `UnchargedPayment`
+pub async fn request_timeout_middleware(
+    State(timeout): State<Duration>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    // Streams are relayed after the response is returned, they stop at the same deadline
+    req.extensions_mut()
+        .insert(RequestDeadline(tokio::time::Instant::now() + timeout));
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Request exceeded the timeout of {:?}, aborting it", timeout);
+            StatusCode::GATEWAY_TIMEOUT.into_response()
+        }
+    }
+}
+
+// Limits the completions served concurrently, and tells clients how loaded the provider
+// is so they can slow down before being rejected. Rejects all completions while draining
+pub async fn load_shedding_middleware(
+    State(ctx): State<ProviderCtx>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    if ctx.shared.is_draining() {
+        let mut response = StatusCode::SERVICE_UNAVAILABLE.into_response();
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+        return response;
+    }
+
+    // Completions in flight are always counted, draining waits for them
+    let max_in_flight = match ctx.config.max_concurrent_requests {
+        Some(max_in_flight) => max_in_flight,
+        None => {
+            let guard = ctx.shared.try_start_request(usize::MAX).map(Arc::new);
+            if let Some(guard) = &guard {
+                req.extensions_mut().insert(guard.clone());
+            }
+            return next.run(req).await;
+        }
+    };
+    let load = |in_flight: usize| (in_flight * 100 / max_in_flight.max(1)).to_string();
+
+    let guard = match ctx.shared.try_start_request(max_in_flight) {
+        Some(guard) => Arc::new(guard),
+        None => {
+            let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+            let headers = response.headers_mut();
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+            headers.insert(
+                LOAD_HEADER_NAME,
+                HeaderValue::from_str(&load(max_in_flight)).unwrap(),
+            );
+            return response;
+        }
+    };
+
+    let current_load = load(ctx.shared.in_flight());
+    // Streams hold a clone until they end, see `StreamLimits`
+    req.extensions_mut().insert(guard.clone());
+    let mut response = next.run(req).await;
+    response.headers_mut().insert(
+        LOAD_HEADER_NAME,
+        HeaderValue::from_str(&current_load).unwrap(),
+    );
+    response
+}
+
+// Upstream paths of the completion endpoints, keyed by the path they are served on
+const COMPLETION_PATHS: [(&str, &str); 2] = [
+    ("/oai/completions", "/completions"),
+    ("/oai/chat/completions", "/chat/completions"),
+];
+
+// The generated server stubs only answer with complete JSON responses. Streamed completion
+// requests (`stream: true`) are answered here instead, with server-sent events, and
+// everything else is passed on to the stubs
+pub async fn stream_completions_middleware(
+    State(service): State<ProviderOaiService>,
+    cookies: CookieJar,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some((_, upstream_path)) = COMPLETION_PATHS
+        .iter()
+        .find(|(path, _)| req.method() == Method::POST && req.uri().path() == *path)
+    else {
+        return next.run(req).await;
+    };
+
+    let accepts_only_event_stream = accepts_only_event_stream(req.headers());
+    let limits = StreamLimits::of(&req);
+    let (parts, body) = req.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(body) if body["stream"].as_bool() == Some(true) => {
+            service
+                .stream_completion(&cookies, upstream_path, body, limits)
+                .await
+        }
+        // The client can only read a stream but didn't ask for one, answering with JSON
+        // would break its parser. An explicit `stream: false` is served as asked
+        Ok(mut body) if accepts_only_event_stream && body["stream"].is_null() => {
+            match service.ctx.config.event_stream_accept_mode {
+                EventStreamAcceptMode::Stream => {
+                    body["stream"] = json!(true);
+                    service
+                        .stream_completion(&cookies, upstream_path, body, limits)
+                        .await
+                }
+                EventStreamAcceptMode::Reject => (
+                    StatusCode::BAD_REQUEST,
+                    Json(Error::new(
                         FOUR_HUNDRED.to_string(),
                         BAD_REQUEST.to_string(),
-                        format!("Unable to decode base64 payment header: {}", e),
-                        "".to_string(),
-                    ),
-                ))
+                        "Accept is text/event-stream but the request doesn't set `stream` to true. Set `stream` to true, or accept application/json".to_string(),
+                        "stream".to_string(),
+                    )),
+                )
+                    .into_response(),
             }
+        }
+        // Left to the stubs, which also reject invalid bodies
+        _ => {
+            next.run(Request::from_parts(parts, Body::from(bytes)))
+                .await
+        }
+    }
+}
+
+// Whether the Accept header lists text/event-stream and no type a JSON response matches
+fn accepts_only_event_stream(headers: &HeaderMap) -> bool {
+    let media_types = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .collect::<Vec<_>>();
+    media_types
+        .iter()
+        .any(|media_type| media_type == "text/event-stream")
+        && !media_types.iter().any(|media_type| {
+            matches!(
+                media_type.as_str(),
+                "application/json" | "application/*" | "*/*"
+            )
+        })
+}
+
+impl ProviderOaiService {
+    // The payment is validated before the upstream call, and settled once the stream ends
+    // or the client disconnects, see `StreamSettlement`
+    async fn stream_completion(
+        &self,
+        cookies: &CookieJar,
+        upstream_path: &str,
+        body: serde_json::Value,
+        limits: StreamLimits,
+    ) -> Response {
+        let include_usage = body["stream_options"]["include_usage"].as_bool() == Some(true);
+        let mut completion = match self.prepare_completion(cookies, body).await {
+            Ok(completion) => completion,
+            Err(e) => return (StatusCode::BAD_REQUEST, Json(e)).into_response(),
         };
-        let signed_state: SignedState = match borsh::from_slice(&decoded_payload) {
-            Ok(s) => s,
-            Err(e) => {
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
-                        FOUR_HUNDRED.to_string(),
-                        BAD_REQUEST.to_string(),
-                        format!(
-                            "Unable to deserialize borsh serialized SignedState from payment header: {}",
-                            e
-                        ),
-                        "".to_string(),
-                    ),
-                ));
+        // Usage is needed to price the completion, it's only sent on request. The other
+        // stream options of the client are passed on
+        if !completion.body["stream_options"].is_object() {
+            completion.body["stream_options"] = json!({});
+        }
+        completion.body["stream_options"]["include_usage"] = json!(true);
+
+        let configuration = &completion.configuration;
+        let mut request_builder = configuration
+            .client
+            .post(format!("{}{}", configuration.base_path, upstream_path))
+            .json(&completion.body);
+        if let Some(token) = &configuration.bearer_access_token {
+            request_builder = request_builder.bearer_auth(token);
+        }
+        let started = Instant::now();
+        let upstream_response = request_builder.send().await;
+        record_completion(
+            upstream_path,
+            started.elapsed(),
+            matches!(&upstream_response, Ok(response) if response.status().is_success()),
+        );
+        let upstream_error = match upstream_response {
+            Ok(response) if response.status().is_success() => {
+                completion.payment.read_upstream_cost(response.headers());
+                let events = CompletionEvents {
+                    upstream: response.bytes_stream().boxed(),
+                    buffer: Vec::new(),
+                    received: 0,
+                    max_bytes: self.ctx.config.max_response_bytes,
+                    pending: VecDeque::new(),
+                    done: false,
+                    requested_model: completion.requested_model,
+                    include_usage,
+                    limits,
+                    settlement: StreamSettlement {
+                        payment: Some(completion.payment),
+                        summary: json!({ "choices": [] }),
+                    },
+                };
+                return Sse::new(events.into_stream())
+                    .keep_alive(KeepAlive::default())
+                    .into_response();
+            }
+            Ok(response) => {
+                let status = response.status();
+                let content = response.text().await.unwrap_or_default();
+                openaiclient::apis::Error::<serde_json::Value>::ResponseError(ResponseContent {
+                    status,
+                    entity: serde_json::from_str(&content).ok(),
+                    content,
+                })
             }
+            Err(e) => e.into(),
         };
-        let min_cost = self.ctx.config.cost_per_completion.0;
-        let validate_signed_state_result = self
-            .ctx
-            .validate_signed_state(min_cost, &signed_state, true) // user is paying for the service
-            .await;
-        match validate_signed_state_result {
-            Ok(_) => (),
-            Err(e) => {
-                let user_error = UserFacingError::from(&e);
-                return Ok(CreateCompletionResponseAPI::Status400_BadRequest(
-                    Error::new(
-                        FOUR_HUNDRED.to_string(),
-                        BAD_REQUEST.to_string(),
-                        user_error.to_string(),
-                        "".to_string(),
-                    ),
-                ));
+
+        completion.payment.settle(None).await;
+        match UpstreamFailure::from(&UpstreamCompletionError::Client(upstream_error)) {
+            UpstreamFailure::BadGateway(e) => (StatusCode::BAD_GATEWAY, Json(e)).into_response(),
+            UpstreamFailure::InternalServerError(e) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(e)).into_response()
             }
         }
+    }
+}
 
-        // Create the configuration from the provider configuration
-        let mut configuration: Configuration = Configuration::new();
-        configuration.user_agent = None;
-        configuration.base_path = provider.url.clone();
-        configuration.bearer_access_token = Some(provider.api_key.clone());
+// Deadline of a request with a timeout, see `request_timeout_middleware`
+#[derive(Clone, Copy)]
+struct RequestDeadline(tokio::time::Instant);
 
-        // Convert the user request to a client request
-        // by serialize -> deserialize chain
-        body.model = model_info.model_name;
-        let serialized_body = serde_json::to_string(&body).unwrap();
-        let client_request: CreateCompletionRequestClient =
-            serde_json::from_str(&serialized_body).unwrap();
+// What the middlewares limiting a request leave in its extensions. The response of a
+// stream is returned before its events are relayed, the stream keeps them until it ends
+struct StreamLimits {
+    // Counts the stream as in flight, see `load_shedding_middleware`
+    _in_flight: Option<Arc<InFlightGuard>>,
+    deadline: Option<tokio::time::Instant>,
+}
 
-        let response = create_completion(&configuration, client_request).await;
-        match response {
-            Ok(response) => {
-                let serialized_response = serde_json::to_string(&response).unwrap();
-                let api_response: models::CreateCompletionResponse =
-                    serde_json::from_str(&serialized_response).unwrap();
-                return Ok(CreateCompletionResponseAPI::Status200_OK(api_response));
+impl StreamLimits {
+    fn of(req: &Request) -> Self {
+        Self {
+            _in_flight: req.extensions().get::<Arc<InFlightGuard>>().cloned(),
+            deadline: req
+                .extensions()
+                .get::<RequestDeadline>()
+                .map(|deadline| deadline.0),
+        }
+    }
+}
+
+// Settles the payment of a streamed completion when dropped, i.e. when the stream ends
+// or the client disconnects. `summary` gathers the finish reasons and the usage of the
+// chunks relayed so far, in the shape of a complete response
+struct StreamSettlement {
+    payment: Option<UnchargedPayment>,
+    summary: serde_json::Value,
+}
+
+impl StreamSettlement {
+    fn record(&mut self, chunk: &serde_json::Value) {
+        for reason in finish_reasons(chunk) {
+            self.summary["choices"]
+                .as_array_mut()
+                .unwrap()
+                .push(json!({ "finish_reason": reason }));
+        }
+        if chunk["usage"].is_object() {
+            self.summary["usage"] = chunk["usage"].clone();
+        }
+    }
+}
+
+impl Drop for StreamSettlement {
+    fn drop(&mut self) {
+        let Some(payment) = self.payment.take() else {
+            return;
+        };
+        let summary = std::mem::take(&mut self.summary);
+        tokio::spawn(async move { payment.settle(Some(&summary)).await });
+    }
+}
+
+// Server-sent events relayed from an upstream completion stream. Dropping it (e.g. when
+// the client disconnects) drops the upstream response, which aborts the upstream request
+struct CompletionEvents {
+    upstream: BoxStream<'static, reqwest::Result<Bytes>>,
+    // Received data not forming a complete event yet
+    buffer: Vec<u8>,
+    received: usize,
+    max_bytes: Option<usize>,
+    // Events parsed but not relayed yet
+    pending: VecDeque<Event>,
+    done: bool,
+    requested_model: String,
+    // Whether the client asked for the usage chunk, it's always requested upstream
+    include_usage: bool,
+    limits: StreamLimits,
+    settlement: StreamSettlement,
+}
+
+impl CompletionEvents {
+    fn into_stream(self) -> impl Stream<Item = Result<Event, Infallible>> {
+        stream::unfold(self, |mut events| async move {
+            loop {
+                if let Some(event) = events.pending.pop_front() {
+                    return Some((Ok(event), events));
+                }
+                if events.done {
+                    return None;
+                }
+                let next = match events.limits.deadline {
+                    Some(deadline) => {
+                        match tokio::time::timeout_at(deadline, events.upstream.next()).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                warn!(
+                                    "Completion stream exceeded the request timeout, aborting it"
+                                );
+                                events.done = true;
+                                continue;
+                            }
+                        }
+                    }
+                    None => events.upstream.next().await,
+                };
+                match next {
+                    Some(Ok(chunk)) => events.receive(&chunk),
+                    Some(Err(e)) => {
+                        error!("Upstream completion stream failed: {}", e);
+                        events.done = true;
+                    }
+                    None => events.done = true,
+                }
             }
-            Err(e) => Ok(CreateCompletionResponseAPI::Status500_InternalServerError(
-                Error::new(
-                    "Internal Server Error".to_string(),
-                    "Internal Server Error".to_string(),
-                    e.to_string(),
-                    "invalid_request_error".to_string(),
-                ),
-            )),
+        })
+    }
+
+    fn receive(&mut self, chunk: &[u8]) {
+        self.received += chunk.len();
+        if let Some(max_bytes) = self
+            .max_bytes
+            .filter(|max_bytes| self.received > *max_bytes)
+        {
+            error!(
+                "Upstream completion stream is bigger than {} bytes",
+                max_bytes
+            );
+            self.done = true;
+            return;
+        }
+
+        // Events are separated by a blank line, see the SSE format
+        self.buffer
+            .extend(chunk.iter().filter(|byte| **byte != b'\r'));
+        while let Some(end) = self.buffer.windows(2).position(|window| window == b"\n\n") {
+            let event = self.buffer.drain(..end + 2).collect::<Vec<_>>();
+            for line in String::from_utf8_lossy(&event).lines() {
+                if let Some(data) = line.strip_prefix("data:") {
+                    self.relay(data.trim());
+                }
+            }
+        }
+    }
+
+    fn relay(&mut self, data: &str) {
+        if data == "[DONE]" {
+            self.pending.push_back(Event::default().data(data));
+            return;
+        }
+        let chunk: serde_json::Value = match serde_json::from_str(data) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("Skipping invalid upstream completion chunk: {}", e);
+                return;
+            }
+        };
+        self.settlement.record(&chunk);
+
+        // The usage chunk has no choices
+        let usage_only = chunk["choices"]
+            .as_array()
+            .is_some_and(|choices| choices.is_empty());
+        if usage_only && !self.include_usage {
+            return;
         }
+        let chunk = echo_requested_model(chunk, &self.requested_model);
+        self.pending
+            .push_back(Event::default().data(chunk.to_string()));
     }
 }
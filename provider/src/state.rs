@@ -0,0 +1,228 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+const SHARDS: usize = 16;
+
+// A map split into independently locked shards, so handlers touching
+// different keys (e.g. different channels) don't contend on a single lock.
+// Locks are never held across an `.await`.
+pub struct ShardedMap<K, V> {
+    shards: Vec<Mutex<HashMap<K, V>>>,
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, V>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % SHARDS]
+    }
+
+    // Run `f` with exclusive access to the entry of `key`, inserting `default` if missing
+    pub fn update<R>(&self, key: K, default: impl FnOnce() -> V, f: impl FnOnce(&mut V) -> R) -> R {
+        let mut shard = self.shard(&key).lock().unwrap();
+        f(shard.entry(key).or_insert_with(default))
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.shard(key).lock().unwrap().contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> ShardedMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).lock().unwrap().get(key).cloned()
+    }
+}
+
+// In-process state of a channel, lost on restart
+#[derive(Clone, Debug, Default)]
+pub struct ChannelLocalState {
+    // Payments accepted on the channel during this process lifetime
+    pub requests_served: u64,
+    // Whether the channel was read from the contract during this process lifetime
+    pub verified_on_chain: bool,
+}
+
+// Response of the last completion paid on a channel, see `IDEMPOTENCY_KEY_HEADER_NAME`
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub idempotency_key: String,
+    // Signature of the signed state that paid for the response
+    pub signature: String,
+    pub response: serde_json::Value,
+}
+
+#[derive(Default)]
+struct SharedStateInner {
+    channels: ShardedMap<String, ChannelLocalState>,
+    // Only the last response of each channel is kept, a payment can only be retried
+    // while it's the latest signed state of the channel
+    responses: ShardedMap<String, CachedResponse>,
+    // Held while the credit or debt of a channel is read and written back
+    balance_locks: ShardedMap<String, Arc<tokio::sync::Mutex<()>>>,
+    // Held while a payment of a channel is checked against its latest signed state and
+    // inserted
+    payment_locks: ShardedMap<String, Arc<tokio::sync::Mutex<()>>>,
+    // Requests currently being served by the upstreams
+    in_flight: AtomicUsize,
+    // Set once the provider stops taking new completions before shutting down
+    draining: AtomicBool,
+}
+
+// Held while a request is served, releases its slot when dropped
+pub struct InFlightGuard {
+    state: SharedState,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.state.inner.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Mutable state shared by all the handlers and the background service.
+// Cloning is cheap, all the clones refer to the same state.
+#[derive(Clone, Default)]
+pub struct SharedState {
+    inner: Arc<SharedStateInner>,
+}
+
+impl SharedState {
+    pub fn channel(&self, channel_name: &str) -> Option<ChannelLocalState> {
+        self.inner.channels.get(&channel_name.to_string())
+    }
+
+    // Record a served request for the channel, returning the number served so far
+    pub fn record_request(&self, channel_name: &str) -> u64 {
+        self.inner.channels.update(
+            channel_name.to_string(),
+            ChannelLocalState::default,
+            |channel| {
+                channel.requests_served += 1;
+                channel.requests_served
+            },
+        )
+    }
+
+    pub fn is_verified_on_chain(&self, channel_name: &str) -> bool {
+        self.channel(channel_name)
+            .map(|channel| channel.verified_on_chain)
+            .unwrap_or(false)
+    }
+
+    pub fn mark_verified_on_chain(&self, channel_name: &str) {
+        self.inner.channels.update(
+            channel_name.to_string(),
+            ChannelLocalState::default,
+            |channel| channel.verified_on_chain = true,
+        )
+    }
+
+    // Take a request slot if less than `max_in_flight` requests are being served
+    pub fn try_start_request(&self, max_in_flight: usize) -> Option<InFlightGuard> {
+        self.inner
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (in_flight < max_in_flight).then_some(in_flight + 1)
+            })
+            .ok()?;
+        Some(InFlightGuard {
+            state: self.clone(),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.inner.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Returns false if the provider was already draining
+    pub fn start_draining(&self) -> bool {
+        !self.inner.draining.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    // Lock of the credit and debt of the channel, concurrent requests of a channel update
+    // them in turns
+    pub fn balance_lock(&self, channel_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.inner
+            .balance_locks
+            .update(channel_name.to_string(), Default::default, |lock| {
+                lock.clone()
+            })
+    }
+
+    // Lock of the signed states of the channel, concurrent payments of a channel are
+    // checked and inserted in turns
+    pub fn payment_lock(&self, channel_name: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.inner
+            .payment_locks
+            .update(channel_name.to_string(), Default::default, |lock| {
+                lock.clone()
+            })
+    }
+
+    pub fn has_balance_lock(&self, channel_name: &str) -> bool {
+        self.inner
+            .balance_locks
+            .contains_key(&channel_name.to_string())
+    }
+
+    pub fn cache_response(&self, channel_name: &str, response: CachedResponse) {
+        self.inner
+            .responses
+            .insert(channel_name.to_string(), response);
+    }
+
+    // Response paid by the signed state with `signature` and requested with `idempotency_key`
+    pub fn cached_response(
+        &self,
+        channel_name: &str,
+        idempotency_key: &str,
+        signature: &str,
+    ) -> Option<serde_json::Value> {
+        self.inner
+            .responses
+            .get(&channel_name.to_string())
+            .filter(|cached| {
+                cached.idempotency_key == idempotency_key && cached.signature == signature
+            })
+            .map(|cached| cached.response)
+    }
+
+    pub fn forget_channel(&self, channel_name: &str) -> Option<ChannelLocalState> {
+        self.inner.responses.remove(&channel_name.to_string());
+        self.inner.balance_locks.remove(&channel_name.to_string());
+        self.inner.payment_locks.remove(&channel_name.to_string());
+        self.inner.channels.remove(&channel_name.to_string())
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::ProviderError;
+
+pub const COMPLETIONS_TOTAL: &str = "pay_per_prompt_completions_total";
+pub const PAYMENT_REJECTED_TOTAL: &str = "pay_per_prompt_payment_rejected_total";
+pub const WITHDRAWALS_TOTAL: &str = "pay_per_prompt_withdrawals_total";
+pub const UPSTREAM_LATENCY_SECONDS: &str = "pay_per_prompt_upstream_completion_latency_seconds";
+
+// Completions take from a fraction of a second to minutes
+const UPSTREAM_LATENCY_BUCKETS: [f64; 10] =
+    [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0];
+// Histograms are folded into the rendered buckets on upkeep
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Installs the global recorder, the handle renders the `/metrics` page.
+// Must be called from a tokio runtime, the upkeep runs in a background task
+pub fn install_metrics_recorder() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(UPSTREAM_LATENCY_SECONDS.to_string()),
+            &UPSTREAM_LATENCY_BUCKETS,
+        )
+        .unwrap()
+        .install_recorder()
+        .expect("Failed to install the metrics recorder");
+
+    describe_counter!(
+        COMPLETIONS_TOTAL,
+        "Paid completions forwarded upstream, by endpoint and outcome"
+    );
+    describe_counter!(
+        PAYMENT_REJECTED_TOTAL,
+        "Signed states rejected by the payment validation, by reason"
+    );
+    describe_counter!(
+        WITHDRAWALS_TOTAL,
+        "Withdraw transactions submitted to the contract, by outcome"
+    );
+    describe_histogram!(
+        UPSTREAM_LATENCY_SECONDS,
+        Unit::Seconds,
+        "Time until the upstream answered a completion, by endpoint"
+    );
+
+    let upkeep_handle = handle.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(UPKEEP_INTERVAL).await;
+            upkeep_handle.run_upkeep();
+        }
+    });
+
+    handle
+}
+
+fn outcome(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+// A completion sent upstream on `endpoint` (e.g. `/chat/completions`), `success` if the
+// upstream answered with a completion
+pub fn record_completion(endpoint: &str, upstream_latency: Duration, success: bool) {
+    counter!(
+        COMPLETIONS_TOTAL,
+        "endpoint" => endpoint.to_string(),
+        "outcome" => outcome(success)
+    )
+    .increment(1);
+    histogram!(UPSTREAM_LATENCY_SECONDS, "endpoint" => endpoint.to_string())
+        .record(upstream_latency.as_secs_f64());
+}
+
+pub fn record_payment_rejected(error: &ProviderError) {
+    counter!(PAYMENT_REJECTED_TOTAL, "reason" => error.reason()).increment(1);
+}
+
+pub fn record_withdrawal(success: bool) {
+    counter!(WITHDRAWALS_TOTAL, "outcome" => outcome(success)).increment(1);
+}
@@ -0,0 +1,378 @@
+// Shared by the integration test binaries, not every binary uses every helper
+#![allow(dead_code)]
+
+pub mod upstream;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use axum::middleware::from_fn_with_state;
+use axum::Router;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cli::config::{SignedState, State, CLOSE_NONCE};
+use cli::contract::{ContractAccount, ContractChannel, CLOSED_CHANNEL_ACCOUNT_ID};
+use near_cli_rs::config::Config as NearConfig;
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::{AccountId, NearToken};
+use openaiapi::server;
+use provider::{
+    disabled_endpoints_middleware, load_shedding_middleware, request_timeout_middleware,
+    stream_completions_middleware, ChannelContract, MockClock, ProviderBaseService, ProviderConfig,
+    ProviderCtx, ProviderOaiService, ProviderResult, ReceiverAccount, HARD_CLOSE_TIMEOUT,
+    PAYMENTS_HEADER_NAME,
+};
+use serde_json::{json, Value};
+use sqlx::sqlite::SqlitePool;
+
+pub const PROVIDER: &str = "provider.testnet";
+pub const SENDER: &str = "sender.testnet";
+
+// Every database is a new file, tests run in parallel
+static NEXT_DB: AtomicU64 = AtomicU64::new(0);
+
+pub struct Party {
+    pub account_id: AccountId,
+    pub secret_key: SecretKey,
+}
+
+impl Party {
+    pub fn new(account_id: &str) -> Self {
+        Self {
+            account_id: account_id.parse().unwrap(),
+            secret_key: SecretKey::from_seed(KeyType::ED25519, account_id),
+        }
+    }
+
+    pub fn account(&self) -> ContractAccount {
+        ContractAccount {
+            account_id: self.account_id.clone(),
+            public_key: self.secret_key.public_key(),
+        }
+    }
+
+    pub fn sign(&self, channel_id: &str, spent_balance: u128, nonce: u64) -> SignedState {
+        let state = State {
+            channel_id: channel_id.to_string(),
+            spent_balance: NearToken::from_yoctonear(spent_balance),
+            nonce,
+        };
+        let signature = self.secret_key.sign(&borsh::to_vec(&state).unwrap());
+        SignedState { state, signature }
+    }
+}
+
+// Value of the payment header carrying `signed_state`
+pub fn payment_header(signed_state: &SignedState) -> String {
+    BASE64_STANDARD.encode(borsh::to_vec(signed_state).unwrap())
+}
+
+// Cookie carrying the payment, the binary turns the payment header into this cookie
+pub fn payment_cookie(signed_state: &SignedState) -> String {
+    format!("{}={}", PAYMENTS_HEADER_NAME, payment_header(signed_state))
+}
+
+// POST `body` to the oai endpoint `path` (e.g. `/completions`) of the provider at `url`,
+// paid with `signed_state`
+pub async fn post_completion(
+    url: &str,
+    path: &str,
+    signed_state: &SignedState,
+    body: Value,
+) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/oai{}", url, path))
+        .header(reqwest::header::COOKIE, payment_cookie(signed_state))
+        .json(&body)
+        .send()
+        .await
+        .unwrap()
+}
+
+fn merge(config: &mut Value, overrides: Value) {
+    match overrides {
+        Value::Object(overrides) => {
+            for (key, value) in overrides {
+                config[key] = value;
+            }
+        }
+        Value::Null => (),
+        _ => panic!("Config overrides must be an object"),
+    }
+}
+
+// A valid config with one upstream, a completion costs 100 yoctoNEAR
+pub fn config(overrides: Value) -> ProviderConfig {
+    let db_path = std::env::temp_dir().join(format!(
+        "ppp-provider-test-{}-{}.sqlite",
+        std::process::id(),
+        NEXT_DB.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut config = json!({
+        "providers": [{
+            "canonical_name": "openai",
+            "url": "http://127.0.0.1:9",
+            "api_key": "upstream-key",
+        }],
+        "account_id": PROVIDER,
+        "network": "testnet",
+        "db_url": format!("sqlite://{}?mode=rwc", db_path.display()),
+        "cost_per_completion": "100",
+        "min_withdraw_amount": "1",
+    });
+    merge(&mut config, overrides);
+    serde_json::from_value(config).unwrap()
+}
+
+// Contract keeping the channels in memory, and recording the calls it gets
+#[derive(Default)]
+pub struct MockContract {
+    channels: Mutex<HashMap<String, ContractChannel>>,
+    // Views of a single channel, batched views aren't counted
+    pub channel_reads: AtomicUsize,
+    // Spent balance of every signed state withdrawn
+    pub withdrawals: Mutex<Vec<u128>>,
+    pub closes: AtomicUsize,
+    // Error the withdraws fail with, if set
+    pub withdraw_error: Mutex<Option<String>>,
+    // Error the closes fail with, if set
+    pub close_error: Mutex<Option<String>>,
+}
+
+impl MockContract {
+    pub fn insert(&self, channel_id: &str, channel: ContractChannel) {
+        self.channels
+            .lock()
+            .unwrap()
+            .insert(channel_id.to_string(), channel);
+    }
+
+    pub fn get(&self, channel_id: &str) -> Option<ContractChannel> {
+        self.channels.lock().unwrap().get(channel_id).cloned()
+    }
+
+    pub fn fail_withdrawals(&self, error: &str) {
+        *self.withdraw_error.lock().unwrap() = Some(error.to_string());
+    }
+
+    pub fn fail_closes(&self, error: &str) {
+        *self.close_error.lock().unwrap() = Some(error.to_string());
+    }
+
+    fn record_withdrawal(&self, state: &SignedState) -> Result<(), String> {
+        if let Some(error) = self.withdraw_error.lock().unwrap().clone() {
+            return Err(error);
+        }
+        let mut channels = self.channels.lock().unwrap();
+        let channel = channels
+            .get_mut(&state.state.channel_id)
+            .ok_or_else(|| "Channel not found".to_string())?;
+        channel.withdrawn_balance = state.state.spent_balance;
+        self.withdrawals
+            .lock()
+            .unwrap()
+            .push(state.state.spent_balance.as_yoctonear());
+        Ok(())
+    }
+
+    // Closed channels are replaced by a tombstone, see `ContractChannel::is_closed`
+    fn tombstone(&self, channel_id: &str) {
+        let closed: AccountId = CLOSED_CHANNEL_ACCOUNT_ID.parse().unwrap();
+        if let Some(channel) = self.channels.lock().unwrap().get_mut(channel_id) {
+            channel.sender.account_id = closed.clone();
+            channel.receiver.account_id = closed;
+        }
+        self.closes.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[async_trait]
+impl ChannelContract for MockContract {
+    async fn channel(&self, channel_id: &str) -> Option<ContractChannel> {
+        self.channel_reads.fetch_add(1, Ordering::SeqCst);
+        self.get(channel_id)
+    }
+
+    async fn channels(&self, channel_ids: &[String]) -> Vec<Option<ContractChannel>> {
+        channel_ids
+            .iter()
+            .map(|channel_id| self.get(channel_id))
+            .collect()
+    }
+
+    async fn force_close_timeout(&self, _channel_id: &str) -> u64 {
+        HARD_CLOSE_TIMEOUT
+    }
+
+    async fn withdraw(&self, state: SignedState) -> Result<(), String> {
+        self.record_withdrawal(&state)
+    }
+
+    async fn withdraw_and_close(
+        &self,
+        state: SignedState,
+        _close: SignedState,
+    ) -> Result<(), String> {
+        self.record_withdrawal(&state)?;
+        self.tombstone(&state.state.channel_id);
+        Ok(())
+    }
+
+    async fn close(&self, state: SignedState) -> Result<(), String> {
+        if let Some(error) = self.close_error.lock().unwrap().clone() {
+            return Err(error);
+        }
+        self.tombstone(&state.state.channel_id);
+        Ok(())
+    }
+}
+
+pub struct TestProvider {
+    pub ctx: ProviderCtx,
+    pub contract: Arc<MockContract>,
+    // One per account of `ProviderConfig::account_ids`, in the same order
+    pub receivers: Vec<Party>,
+    pub sender: Party,
+    // Starts at the current time, staleness is decided against the database timestamps
+    pub clock: MockClock,
+}
+
+// Provider with a migrated database, calling a mock contract. The accounts of the config
+// are the receivers, `SENDER` is the sender of the channels
+pub async fn setup(config: ProviderConfig) -> TestProvider {
+    let pool = SqlitePool::connect(&config.db_url).await.unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool.close().await;
+
+    let contract = Arc::new(MockContract::default());
+    let network_config = config
+        .network
+        .network_config(&NearConfig::default())
+        .unwrap();
+    let receivers = config
+        .account_ids()
+        .iter()
+        .map(|account_id| Party::new(account_id.as_str()))
+        .collect::<Vec<_>>();
+    let receiver_accounts = receivers
+        .iter()
+        .map(|receiver| {
+            ReceiverAccount::with_contract(
+                receiver.account_id.clone(),
+                receiver.secret_key.clone(),
+                network_config.clone(),
+                contract.clone(),
+            )
+        })
+        .collect();
+
+    let clock = MockClock::new(chrono::Utc::now().naive_utc());
+
+    TestProvider {
+        ctx: ProviderCtx::with_receivers(config, receiver_accounts)
+            .with_clock(Arc::new(clock.clone())),
+        contract,
+        receivers,
+        sender: Party::new(SENDER),
+        clock,
+    }
+}
+
+impl TestProvider {
+    // Open a channel with the primary receiver of the provider
+    pub async fn open_channel(&self, channel_id: &str, added_balance: u128) {
+        self.open_channel_with(channel_id, 0, added_balance).await
+    }
+
+    // Open a channel on chain and cache it in the database, like a channel the provider
+    // already served. `receiver` indexes `TestProvider::receivers`
+    pub async fn open_channel_with(&self, channel_id: &str, receiver: usize, added_balance: u128) {
+        let channel = ContractChannel {
+            receiver: self.receivers[receiver].account(),
+            sender: self.sender.account(),
+            added_balance: NearToken::from_yoctonear(added_balance),
+            withdrawn_balance: NearToken::from_yoctonear(0),
+            force_close_started: None,
+        };
+        self.contract.insert(channel_id, channel.clone());
+        self.ctx
+            .db
+            .upsert_channel_row(channel_id, channel)
+            .await
+            .unwrap();
+    }
+
+    // Change the channel on chain, and in the database as if it was refreshed
+    pub async fn update_channel(
+        &self,
+        channel_id: &str,
+        update: impl FnOnce(&mut ContractChannel),
+    ) {
+        let mut channel = self.contract.get(channel_id).unwrap();
+        update(&mut channel);
+        self.contract.insert(channel_id, channel.clone());
+        self.ctx
+            .db
+            .upsert_channel_row(channel_id, channel)
+            .await
+            .unwrap();
+    }
+
+    // Pay `spent_balance` on the channel, as a completion does
+    pub async fn pay(&self, channel_id: &str, spent_balance: u128, nonce: u64) -> u128 {
+        self.try_pay(channel_id, spent_balance, nonce)
+            .await
+            .unwrap()
+    }
+
+    // Close state the sender sends to `/pc/close`
+    pub fn close_request(&self, channel_id: &str) -> SignedState {
+        self.sender.sign(channel_id, 0, CLOSE_NONCE)
+    }
+
+    pub async fn try_pay(
+        &self,
+        channel_id: &str,
+        spent_balance: u128,
+        nonce: u64,
+    ) -> ProviderResult<u128> {
+        let signed_state = self.sender.sign(channel_id, spent_balance, nonce);
+        self.ctx.validate_signed_state(0, &signed_state, true).await
+    }
+
+    // Routes of the provider as mounted by the binary, without the optional middlewares
+    // other than the request timeout and the load shedding. The headers of the oai
+    // endpoints must be sent as cookies
+    pub fn app(&self) -> Router {
+        let provider_oai = ProviderOaiService::new(self.ctx.clone());
+        let mut provider_oai_service = server::new(provider_oai.clone())
+            .layer(from_fn_with_state(
+                provider_oai,
+                stream_completions_middleware,
+            ))
+            .layer(from_fn_with_state(
+                self.ctx.clone(),
+                disabled_endpoints_middleware,
+            ));
+        if let Some(request_timeout_secs) = self.ctx.config.request_timeout_secs {
+            provider_oai_service = provider_oai_service.layer(from_fn_with_state(
+                Duration::from_secs(request_timeout_secs),
+                request_timeout_middleware,
+            ));
+        }
+        let provider_oai_service = provider_oai_service.layer(from_fn_with_state(
+            self.ctx.clone(),
+            load_shedding_middleware,
+        ));
+        Router::new()
+            .nest("/", provider_oai_service)
+            .nest("/", ProviderBaseService::new(self.ctx.clone()).router())
+    }
+
+    // Serve `app`, returns its base url
+    pub async fn serve(&self) -> String {
+        upstream::serve(self.app()).await
+    }
+}
@@ -0,0 +1,258 @@
+// OpenAI compatible upstream answering every completion with "Hello", and recording the
+// requests it gets
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream::{self, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+pub const COMPLETION_TEXT: &str = "Hello";
+
+pub struct UpstreamState {
+    // Bodies of the completion requests, legacy and chat
+    pub requests: Mutex<Vec<Value>>,
+    // Bearer tokens of the completion requests
+    pub api_keys: Mutex<Vec<String>>,
+    // Prompt and completion tokens reported in the usage, no usage if unset
+    pub usage: Mutex<Option<(u64, u64)>>,
+    pub finish_reason: Mutex<String>,
+    // Sent with every completion response, e.g. a cost header
+    pub headers: Mutex<Vec<(String, String)>>,
+    // Bytes of whitespace added to the completion responses
+    pub padding: AtomicUsize,
+    // Ids listed by `/models`
+    pub models: Mutex<Vec<String>>,
+    // How long the completions take to answer
+    pub delay: Mutex<Duration>,
+    // How long each event of a streamed completion takes, after the response started
+    pub event_delay: Mutex<Duration>,
+}
+
+impl Default for UpstreamState {
+    fn default() -> Self {
+        Self {
+            requests: Mutex::default(),
+            api_keys: Mutex::default(),
+            usage: Mutex::new(Some((10, 5))),
+            finish_reason: Mutex::new("stop".to_string()),
+            headers: Mutex::default(),
+            padding: AtomicUsize::new(0),
+            models: Mutex::default(),
+            delay: Mutex::default(),
+            event_delay: Mutex::default(),
+        }
+    }
+}
+
+impl UpstreamState {
+    async fn record(&self, headers: &HeaderMap, request: &Value) {
+        self.requests.lock().unwrap().push(request.clone());
+        let api_key = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        self.api_keys.lock().unwrap().push(api_key.to_string());
+
+        let delay = *self.delay.lock().unwrap();
+        tokio::time::sleep(delay).await;
+    }
+
+    fn usage(&self) -> Option<Value> {
+        self.usage
+            .lock()
+            .unwrap()
+            .map(|(prompt_tokens, completion_tokens)| {
+                json!({
+                    "prompt_tokens": prompt_tokens,
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt_tokens + completion_tokens,
+                })
+            })
+    }
+
+    fn respond(&self, mut response: Response) -> Response {
+        for (name, value) in self.headers.lock().unwrap().iter() {
+            response.headers_mut().insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        response
+    }
+
+    fn json(&self, mut body: Value) -> Response {
+        if let Some(usage) = self.usage() {
+            body["usage"] = usage;
+        }
+        let mut body = serde_json::to_string(&body).unwrap();
+        body.push_str(&" ".repeat(self.padding.load(Ordering::SeqCst)));
+        self.respond(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+    }
+
+    // Server-sent events: the text, the finish reason, the usage if the request asked for
+    // it with `stream_options.include_usage`, then `[DONE]`
+    fn stream(&self, request: &Value, text_chunk: Value, finish_chunk: Value) -> Response {
+        let mut events = vec![text_chunk, finish_chunk];
+        if request["stream_options"]["include_usage"].as_bool() == Some(true) {
+            if let Some(usage) = self.usage() {
+                let mut usage_chunk = events[0].clone();
+                usage_chunk["choices"] = json!([]);
+                usage_chunk["usage"] = usage;
+                events.push(usage_chunk);
+            }
+        }
+        let mut events = events
+            .iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .collect::<Vec<_>>();
+        events.push("data: [DONE]\n\n".to_string());
+        let event_delay = *self.event_delay.lock().unwrap();
+        let body = Body::from_stream(stream::iter(events).then(move |event| async move {
+            tokio::time::sleep(event_delay).await;
+            Ok::<_, Infallible>(event)
+        }));
+        self.respond(([(header::CONTENT_TYPE, "text/event-stream")], body).into_response())
+    }
+}
+
+async fn completions(
+    State(state): State<Arc<UpstreamState>>,
+    headers: HeaderMap,
+    Json(request): Json<Value>,
+) -> Response {
+    state.record(&headers, &request).await;
+    let finish_reason = state.finish_reason.lock().unwrap().clone();
+    let chunk = |text: &str, finish_reason: Value| {
+        json!({
+            "id": "cmpl-1",
+            "object": "text_completion",
+            "created": 0,
+            "model": request["model"],
+            "choices": [{
+                "text": text,
+                "index": 0,
+                "logprobs": null,
+                "finish_reason": finish_reason,
+            }],
+        })
+    };
+    if request["stream"].as_bool() == Some(true) {
+        return state.stream(
+            &request,
+            chunk(COMPLETION_TEXT, Value::Null),
+            chunk("", json!(finish_reason)),
+        );
+    }
+    state.json(chunk(COMPLETION_TEXT, json!(finish_reason)))
+}
+
+async fn chat_completions(
+    State(state): State<Arc<UpstreamState>>,
+    headers: HeaderMap,
+    Json(request): Json<Value>,
+) -> Response {
+    state.record(&headers, &request).await;
+    let finish_reason = state.finish_reason.lock().unwrap().clone();
+    if request["stream"].as_bool() == Some(true) {
+        let chunk = |delta: Value, finish_reason: Value| {
+            json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion.chunk",
+                "created": 0,
+                "model": request["model"],
+                "choices": [{
+                    "index": 0,
+                    "delta": delta,
+                    "logprobs": null,
+                    "finish_reason": finish_reason,
+                }],
+            })
+        };
+        return state.stream(
+            &request,
+            chunk(
+                json!({ "role": "assistant", "content": COMPLETION_TEXT }),
+                Value::Null,
+            ),
+            chunk(json!({}), json!(finish_reason)),
+        );
+    }
+    state.json(json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": request["model"],
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": COMPLETION_TEXT },
+            "logprobs": null,
+            "finish_reason": finish_reason,
+        }],
+    }))
+}
+
+async fn models(State(state): State<Arc<UpstreamState>>) -> Json<Value> {
+    let data = state
+        .models
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|id| json!({ "id": id, "object": "model", "created": 0, "owned_by": "upstream" }))
+        .collect::<Vec<_>>();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+// Serve `router` on a free local port, returns its base url
+pub async fn serve(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    url
+}
+
+pub struct MockUpstream {
+    pub url: String,
+    pub state: Arc<UpstreamState>,
+}
+
+impl MockUpstream {
+    pub async fn start() -> Self {
+        let state = Arc::new(UpstreamState::default());
+        let router = Router::new()
+            .route("/completions", post(completions))
+            .route("/chat/completions", post(chat_completions))
+            .route("/models", get(models))
+            .with_state(state.clone());
+        Self {
+            url: serve(router).await,
+            state,
+        }
+    }
+
+    // `providers` of a config with this upstream as the only one, serving `openai`
+    pub fn providers(&self) -> Value {
+        json!([{
+            "canonical_name": "openai",
+            "url": self.url,
+            "api_key": "upstream-key",
+        }])
+    }
+
+    pub fn requests(&self) -> Vec<Value> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    pub fn api_keys(&self) -> Vec<String> {
+        self.state.api_keys.lock().unwrap().clone()
+    }
+}
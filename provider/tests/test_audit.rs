@@ -0,0 +1,103 @@
+mod common;
+
+use common::{config, setup};
+use provider::ADMIN_OPERATOR;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[test]
+fn test_admin_operators() {
+    let operators = config(json!({
+        "admin_api_key": "admin-key",
+        "operator_api_keys": { "alice": "alice-key", "bob": "bob-key" },
+    }));
+
+    assert!(operators.admin_enabled());
+    assert_eq!(operators.admin_operator("admin-key"), Some(ADMIN_OPERATOR));
+    assert_eq!(operators.admin_operator("alice-key"), Some("alice"));
+    assert_eq!(operators.admin_operator("bob-key"), Some("bob"));
+    assert_eq!(operators.admin_operator("unknown"), None);
+
+    // Operators alone enable the admin endpoints
+    let operators = config(json!({ "operator_api_keys": { "alice": "alice-key" } }));
+    assert!(operators.admin_enabled());
+    assert!(!config(json!({})).admin_enabled());
+}
+
+#[test]
+fn test_operator_api_keys_cannot_be_empty() {
+    let error = config(json!({ "operator_api_keys": { "alice": " " } }))
+        .validate()
+        .unwrap_err();
+    assert_eq!(error, "operator_api_keys cannot have empty names or keys");
+}
+
+#[tokio::test]
+async fn test_admin_actions_are_audited() {
+    let provider = setup(config(json!({
+        "operator_api_keys": { "alice": "alice-key" },
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{}/pc/register/channel?spend_cap=500", url))
+        .bearer_auth("alice-key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // Failed actions are recorded too
+    let response = client
+        .post(format!("{}/pc/disable/missing", url))
+        .bearer_auth("alice-key")
+        .send()
+        .await
+        .unwrap();
+    assert!(!response.status().is_success());
+    // Not an operator, nothing happened
+    let response = client
+        .post(format!("{}/pc/disable/channel", url))
+        .bearer_auth("mallory-key")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let entries: Vec<Value> = client
+        .get(format!("{}/admin/audit", url))
+        .bearer_auth("alice-key")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    // Newest first
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["actor"], "alice");
+    assert_eq!(entries[0]["action"], "disable_channel");
+    assert_eq!(entries[0]["channel_name"], "missing");
+    assert_eq!(entries[0]["outcome"], "failure");
+    assert!(entries[0]["error"].is_string());
+    assert_eq!(entries[1]["actor"], "alice");
+    assert_eq!(entries[1]["action"], "register_channel");
+    assert_eq!(entries[1]["channel_name"], "channel");
+    assert_eq!(entries[1]["detail"], "spend_cap=500");
+    assert_eq!(entries[1]["outcome"], "success");
+    assert_eq!(entries[1]["error"], Value::Null);
+
+    let entries: Vec<Value> = client
+        .get(format!("{}/admin/audit?limit=1", url))
+        .bearer_auth("alice-key")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["action"], "disable_channel");
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use common::{config, setup};
+use provider::{is_channel_inactive, ProviderBackgroundService};
+use serde_json::json;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[test]
+fn test_channel_inactivity_threshold() {
+    let last_payment_at = chrono::Utc::now().naive_utc();
+
+    assert!(!is_channel_inactive(last_payment_at, last_payment_at));
+    assert!(!is_channel_inactive(
+        last_payment_at,
+        last_payment_at + HOUR
+    ));
+    assert!(is_channel_inactive(
+        last_payment_at,
+        last_payment_at + DAY + HOUR
+    ));
+}
+
+#[tokio::test]
+async fn test_active_channel_is_kept_open() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1000).await;
+    provider.pay("channel", 100, 1).await;
+
+    // Stale, but the last payment is an hour old
+    provider.clock.advance(HOUR);
+    ProviderBackgroundService::new(provider.ctx.clone())
+        .process_stale_channels()
+        .await;
+
+    assert!(provider.contract.withdrawals.lock().unwrap().is_empty());
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 0);
+    assert!(!provider.contract.get("channel").unwrap().is_closed());
+}
+
+#[tokio::test]
+async fn test_inactive_channel_is_withdrawn_and_closed() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1000).await;
+    provider.pay("channel", 100, 1).await;
+    let background = ProviderBackgroundService::new(provider.ctx.clone());
+
+    provider.clock.advance(HOUR);
+    background.process_stale_channels().await;
+    assert!(provider.contract.withdrawals.lock().unwrap().is_empty());
+
+    // A day without payments
+    provider.clock.advance(DAY);
+    background.process_stale_channels().await;
+
+    assert_eq!(*provider.contract.withdrawals.lock().unwrap(), vec![100]);
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 1);
+    assert!(provider.contract.get("channel").unwrap().is_closed());
+    assert!(provider
+        .ctx
+        .db
+        .get_channel_row("channel")
+        .await
+        .unwrap()
+        .is_closed());
+}
+
+#[tokio::test]
+async fn test_inactive_channel_without_payments_is_left_alone() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1000).await;
+
+    provider.clock.advance(DAY + HOUR);
+    ProviderBackgroundService::new(provider.ctx.clone())
+        .process_stale_channels()
+        .await;
+
+    assert!(provider.contract.withdrawals.lock().unwrap().is_empty());
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_channel_is_refreshed_after_the_stale_threshold() {
+    let provider = setup(config(json!({ "stale_channel_threshold_secs": 60 }))).await;
+    provider.open_channel("channel", 1000).await;
+
+    provider.clock.advance(Duration::from_secs(30));
+    provider.ctx.get_fresh_channel_row("channel").await.unwrap();
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 0);
+
+    provider.clock.advance(Duration::from_secs(40));
+    provider.ctx.get_fresh_channel_row("channel").await.unwrap();
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_channel_is_refreshed_on_first_use() {
+    let provider = setup(config(json!({ "refresh_on_first_use": true }))).await;
+    provider.open_channel("channel", 1000).await;
+    // Topped up while the provider wasn't running
+    provider.contract.insert("channel", {
+        let mut channel = provider.contract.get("channel").unwrap();
+        channel.added_balance = near_sdk::NearToken::from_yoctonear(5000);
+        channel
+    });
+
+    let channel_row = provider.ctx.get_fresh_channel_row("channel").await.unwrap();
+    assert_eq!(channel_row.added_balance().as_yoctonear(), 5000);
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+
+    // Read from the database from then on, until it's stale
+    provider.ctx.get_fresh_channel_row("channel").await.unwrap();
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_cached_channel_is_trusted_without_refresh_on_first_use() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1000).await;
+
+    provider.ctx.get_fresh_channel_row("channel").await.unwrap();
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_warmed_up_channel_is_paid_without_contract_read() {
+    let provider = setup(config(json!({
+        "refresh_on_first_use": true,
+        "warm_up_channels": true,
+    })))
+    .await;
+    provider.open_channel("channel", 1000).await;
+
+    provider.ctx.warm_up_channel("channel").await;
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+
+    // The first payment is served from the cache
+    provider.pay("channel", 100, 1).await;
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+
+    // Already read from the contract, not read again
+    provider.ctx.warm_up_channel("channel").await;
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_open_notification_warms_up_channel() {
+    let provider = setup(config(json!({
+        "refresh_on_first_use": true,
+        "warm_up_channels": true,
+    })))
+    .await;
+    provider.open_channel("channel", 1000).await;
+    let url = provider.serve().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/open/channel", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+
+    // Read in the background
+    for _ in 0..100 {
+        if provider.contract.channel_reads.load(Ordering::SeqCst) == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_open_notification_without_warm_up() {
+    let provider = setup(config(json!({}))).await;
+    let url = provider.serve().await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/open/channel", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+    assert_eq!(provider.contract.channel_reads.load(Ordering::SeqCst), 0);
+}
@@ -0,0 +1,222 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cli::provider::{
+    Provider, ProviderError as CliProviderError, CLOSE_PAYLOAD_VERSION, CLOSE_VERSION_HEADER_NAME,
+};
+use common::{config, setup};
+use provider::errors::{ChannelError, ProviderError, SignedStateError};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use sqlx::sqlite::SqlitePool;
+
+#[tokio::test]
+async fn test_close_withdraws_and_returns_the_payload() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 600, 1).await;
+
+    let close = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await
+        .unwrap();
+
+    assert_eq!(close.state.channel_id, "channel");
+    assert_eq!(close.state.spent_balance.as_yoctonear(), 0);
+    assert_eq!(*provider.contract.withdrawals.lock().unwrap(), vec![600]);
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_close_is_aborted_when_the_withdraw_fails() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 600, 1).await;
+    provider
+        .contract
+        .fail_withdrawals("Exceeded the prepaid gas");
+
+    let result = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await;
+
+    // No payload refunding the sender what it spent
+    assert!(matches!(
+        result,
+        Err(ProviderError::Channel(ChannelError::WithdrawFailed(_)))
+    ));
+    assert!(provider.contract.withdrawals.lock().unwrap().is_empty());
+    let channel_row = provider.ctx.db.get_channel_row("channel").await.unwrap();
+    assert!(!channel_row.soft_closed);
+
+    // The close can be retried
+    *provider.contract.withdraw_error.lock().unwrap() = None;
+    let close = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await
+        .unwrap();
+    assert_eq!(close.state.channel_id, "channel");
+    assert_eq!(*provider.contract.withdrawals.lock().unwrap(), vec![600]);
+}
+
+#[tokio::test]
+async fn test_close_refunds_dust_balance() {
+    let provider = setup(config(json!({ "dust_refund_threshold": "500" }))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 600, 1).await;
+
+    let result = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await;
+
+    // Closed by the provider, there is no payload to hand out
+    assert!(matches!(
+        result,
+        Err(ProviderError::Channel(ChannelError::HardClosed(_)))
+    ));
+    assert_eq!(*provider.contract.withdrawals.lock().unwrap(), vec![600]);
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 1);
+    assert!(provider
+        .ctx
+        .db
+        .get_channel_row("channel")
+        .await
+        .unwrap()
+        .is_closed());
+}
+
+#[tokio::test]
+async fn test_close_reports_failed_dust_refund() {
+    let provider = setup(config(json!({ "dust_refund_threshold": "500" }))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 600, 1).await;
+    provider.contract.fail_closes("Exceeded the prepaid gas");
+
+    let result = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await;
+
+    assert!(matches!(
+        result,
+        Err(ProviderError::Channel(ChannelError::CloseFailed(e))) if e == "Exceeded the prepaid gas"
+    ));
+    assert!(!provider.contract.get("channel").unwrap().is_closed());
+}
+
+#[tokio::test]
+async fn test_close_above_dust_threshold_returns_the_payload() {
+    let provider = setup(config(json!({ "dust_refund_threshold": "500" }))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 100, 1).await;
+
+    let close = provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await
+        .unwrap();
+
+    assert_eq!(close.state.channel_id, "channel");
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_closed_channel_states_are_terminal() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1_000).await;
+    provider.pay("channel", 600, 1).await;
+    provider
+        .ctx
+        .close_pc("channel", &provider.close_request("channel"))
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        provider.ctx.db.get_latest_signed_state("channel").await,
+        Err(ProviderError::SignedState(SignedStateError::TerminalState(
+            _
+        )))
+    ));
+
+    // The channel row is reopened, e.g. restored from a backup
+    let pool = SqlitePool::connect(&provider.ctx.config.db_url)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE channel SET soft_closed = FALSE WHERE name = 'channel'")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    let result = provider.try_pay("channel", 700, 2).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(SignedStateError::TerminalState(
+            _
+        )))
+    ));
+    assert!(matches!(
+        provider.ctx.db.get_latest_signed_state("channel").await,
+        Err(ProviderError::SignedState(SignedStateError::TerminalState(
+            _
+        )))
+    ));
+}
+
+#[tokio::test]
+async fn test_close_rejects_unsupported_versions() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1_000).await;
+    let url = provider.serve().await;
+    let payload =
+        BASE64_STANDARD.encode(borsh::to_vec(&provider.close_request("channel")).unwrap());
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/close/channel", url))
+        .header(CLOSE_VERSION_HEADER_NAME, CLOSE_PAYLOAD_VERSION + 1)
+        .body(payload)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error = response.json::<Value>().await.unwrap();
+    let message = error["message"].as_str().unwrap();
+    assert!(message.contains("Unsupported close payload version"));
+    assert!(message.contains("/pc/close/channel/format"));
+    assert_eq!(provider.contract.closes.load(Ordering::SeqCst), 0);
+}
+
+#[tokio::test]
+async fn test_close_rejects_malformed_payloads() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 1_000).await;
+    let client = Provider::new(provider.serve().await);
+
+    // Read with the client of the cli, it sends the current version
+    for (payload, reason) in [
+        ("not base64!".to_string(), "body is not base64"),
+        (
+            BASE64_STANDARD.encode(b"not a signed state"),
+            "body is not a borsh SignedState",
+        ),
+    ] {
+        match client.close_payload("channel", &payload).await {
+            Err(CliProviderError::Rejected { status, message }) => {
+                assert_eq!(status, 400);
+                assert!(message.contains(&format!(
+                    "Invalid close payload (version {})",
+                    CLOSE_PAYLOAD_VERSION
+                )));
+                assert!(message.contains(reason));
+            }
+            result => panic!("Expected a rejection, got {:?}", result.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,227 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use common::upstream::{MockUpstream, COMPLETION_TEXT};
+use common::{config, payment_cookie, post_completion, setup};
+use provider::IDEMPOTENCY_KEY_HEADER_NAME;
+use reqwest::header::COOKIE;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn test_retry_with_idempotency_key_is_served_from_cache() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({ "providers": upstream.providers() }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 100, 1);
+    let completion = |idempotency_key: Option<&str>| {
+        let mut cookie = payment_cookie(&signed_state);
+        if let Some(idempotency_key) = idempotency_key {
+            cookie.push_str(&format!(
+                "; {}={}",
+                IDEMPOTENCY_KEY_HEADER_NAME, idempotency_key
+            ));
+        }
+        reqwest::Client::new()
+            .post(format!("{}/oai/completions", url))
+            .header(COOKIE, cookie)
+            .json(&json!({ "model": "openai::gpt", "prompt": "Hi" }))
+            .send()
+    };
+
+    let response = completion(Some("key")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let first: Value = response.json().await.unwrap();
+    assert_eq!(first["choices"][0]["text"], COMPLETION_TEXT);
+
+    // The idempotent retry of an exact duplicate succeeds, and isn't stored twice
+    let response = completion(Some("key")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<Value>().await.unwrap(), first);
+    assert_eq!(upstream.requests().len(), 1);
+    let signed_states = provider.ctx.db.get_signed_states("channel").await.unwrap();
+    assert_eq!(signed_states.len(), 1);
+
+    // Without the key the payment can't be spent twice
+    let response = completion(None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let error = response.text().await.unwrap();
+    assert!(error.contains(IDEMPOTENCY_KEY_HEADER_NAME));
+    assert_eq!(upstream.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_only_the_latest_payment_is_replayed() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({ "providers": upstream.providers() }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let completion = |spent_balance, nonce, idempotency_key: &str| {
+        let cookie = format!(
+            "{}; {}={}",
+            payment_cookie(&provider.sender.sign("channel", spent_balance, nonce)),
+            IDEMPOTENCY_KEY_HEADER_NAME,
+            idempotency_key
+        );
+        reqwest::Client::new()
+            .post(format!("{}/oai/chat/completions", url))
+            .header(COOKIE, cookie)
+            .json(&json!({
+                "model": "openai::gpt",
+                "messages": [{ "role": "user", "content": "Hi" }],
+            }))
+            .send()
+    };
+
+    assert_eq!(
+        completion(100, 1, "first").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        completion(100, 1, "first").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Another key doesn't replay the response of the payment
+    let response = completion(100, 1, "other").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    assert_eq!(
+        completion(200, 2, "second").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(upstream.requests().len(), 2);
+    // The first payment is no longer the latest one
+    let response = completion(100, 1, "first").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+// Status of a completion asking for `max_tokens`, and the `max_tokens` forwarded upstream
+async fn completion_with_limit(config_overrides: Value, max_tokens: u64) -> (StatusCode, Value) {
+    let upstream = MockUpstream::start().await;
+    let mut overrides = json!({ "providers": upstream.providers() });
+    for (key, value) in config_overrides.as_object().unwrap() {
+        overrides[key] = value.clone();
+    }
+    let provider = setup(config(overrides)).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({ "model": "openai::gpt", "prompt": "Hi", "max_tokens": max_tokens }),
+    )
+    .await;
+    let forwarded = upstream
+        .requests()
+        .first()
+        .map(|request| request["max_tokens"].clone())
+        .unwrap_or(Value::Null);
+    (response.status(), forwarded)
+}
+
+#[tokio::test]
+async fn test_max_tokens_limit_reject() {
+    let limit = json!({ "max_tokens_limit": 100 });
+
+    assert_eq!(
+        completion_with_limit(limit.clone(), 50).await,
+        (StatusCode::OK, json!(50))
+    );
+    assert_eq!(
+        completion_with_limit(limit.clone(), 100).await,
+        (StatusCode::OK, json!(100))
+    );
+    // Rejected before it's paid or sent upstream
+    assert_eq!(
+        completion_with_limit(limit, 101).await,
+        (StatusCode::BAD_REQUEST, Value::Null)
+    );
+}
+
+#[tokio::test]
+async fn test_max_tokens_limit_clamp() {
+    let limit = json!({ "max_tokens_limit": 100, "max_tokens_limit_mode": "clamp" });
+
+    assert_eq!(
+        completion_with_limit(limit.clone(), 50).await,
+        (StatusCode::OK, json!(50))
+    );
+    assert_eq!(
+        completion_with_limit(limit.clone(), 100).await,
+        (StatusCode::OK, json!(100))
+    );
+    assert_eq!(
+        completion_with_limit(limit, 101).await,
+        (StatusCode::OK, json!(100))
+    );
+}
+
+#[tokio::test]
+async fn test_upstream_max_tokens_limit_overrides_provider_limit() {
+    let config = config(json!({
+        "max_tokens_limit": 100,
+        "providers": [
+            { "canonical_name": "openai", "url": "http://default", "api_key": "key" },
+            {
+                "canonical_name": "openai",
+                "url": "http://us",
+                "api_key": "key",
+                "route": "us",
+                "max_tokens_limit": 1000,
+            },
+        ],
+    }));
+
+    let limit = |route| config.max_tokens_limit(config.find_provider("openai", route).unwrap());
+    assert_eq!(limit(None), Some(100));
+    assert_eq!(limit(Some("us")), Some(1000));
+}
+
+#[tokio::test]
+async fn test_oversized_upstream_response_is_dropped() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "max_response_bytes": 2_000,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let request = json!({ "model": "openai::gpt", "prompt": "Hi" });
+
+    // Under the cap, whitespace included
+    upstream.state.padding.store(1_000, Ordering::SeqCst);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        request.clone(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!(completion["choices"][0]["text"], COMPLETION_TEXT);
+
+    upstream.state.padding.store(10_000, Ordering::SeqCst);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 200, 2),
+        request,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Upstream response is bigger than 2000 bytes"));
+}
@@ -0,0 +1,220 @@
+mod common;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use ::config::{Config, File, FileFormat};
+use common::config;
+use provider::{
+    is_postgres_url, load_config, ProviderConfig, EXAMPLE_CONFIG, STALE_CHANNEL_THRESHOLD,
+};
+use serde_json::json;
+
+static NEXT_CONFIG: AtomicU64 = AtomicU64::new(0);
+
+// Write `contents` to a new yaml config file, returns its path
+fn config_file(contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!(
+        "ppp-provider-config-{}-{}.yaml",
+        std::process::id(),
+        NEXT_CONFIG.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path.display().to_string()
+}
+
+const VALID_CONFIG: &str = r#"
+providers:
+  - canonical_name: "openai"
+    url: "https://api.openai.com/v1"
+    api_key: "key"
+network: "testnet"
+account_id: "provider.testnet"
+db_url: "sqlite://db.sqlite?mode=rwc"
+cost_per_completion: "100"
+min_withdraw_amount: "1"
+"#;
+
+#[test]
+fn test_find_provider_by_route() {
+    let config = config(json!({
+        "providers": [
+            { "canonical_name": "openai", "url": "http://eu", "api_key": "key", "route": "eu" },
+            { "canonical_name": "openai", "url": "http://default", "api_key": "key" },
+            { "canonical_name": "openai", "url": "http://us", "api_key": "key", "route": "us" },
+            { "canonical_name": "other", "url": "http://other", "api_key": "key" },
+        ],
+    }));
+    let url = |route| {
+        config
+            .find_provider("openai", route)
+            .map(|provider| provider.url.as_str())
+    };
+
+    assert_eq!(url(Some("us")), Some("http://us"));
+    assert_eq!(url(Some("eu")), Some("http://eu"));
+    // Unknown routes and requests without a route go to the default upstream
+    assert_eq!(url(Some("asia")), Some("http://default"));
+    assert_eq!(url(None), Some("http://default"));
+    assert!(config.find_provider("missing", None).is_none());
+}
+
+#[test]
+fn test_find_provider_without_default_upstream() {
+    let config = config(json!({
+        "providers": [
+            { "canonical_name": "openai", "url": "http://eu", "api_key": "key", "route": "eu" },
+            { "canonical_name": "openai", "url": "http://us", "api_key": "key", "route": "us" },
+        ],
+    }));
+
+    // The first one listed
+    let provider = config.find_provider("openai", None).unwrap();
+    assert_eq!(provider.url, "http://eu");
+    let provider = config.find_provider("openai", Some("asia")).unwrap();
+    assert_eq!(provider.url, "http://eu");
+}
+
+#[test]
+fn test_stale_channel_threshold() {
+    assert_eq!(
+        config(json!({})).stale_channel_threshold(),
+        STALE_CHANNEL_THRESHOLD
+    );
+    assert_eq!(
+        config(json!({ "stale_channel_threshold_secs": 300 })).stale_channel_threshold(),
+        Duration::from_secs(300)
+    );
+}
+
+#[test]
+fn test_estimated_requests_remaining() {
+    // A default sized completion costs its flat price and 16 tokens
+    assert_eq!(
+        config(json!({})).estimated_requests_remaining(1_050),
+        Some(10)
+    );
+    assert_eq!(
+        config(json!({ "cost_per_token": "10" })).estimated_requests_remaining(1_050),
+        Some(4)
+    );
+    assert_eq!(config(json!({})).estimated_requests_remaining(99), Some(0));
+    assert_eq!(
+        config(json!({ "cost_per_completion": "0" })).estimated_requests_remaining(1_050),
+        None
+    );
+}
+
+#[test]
+fn test_example_config_round_trips() {
+    let config: ProviderConfig = Config::builder()
+        .add_source(File::from_str(EXAMPLE_CONFIG, FileFormat::Yaml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap();
+
+    config.validate().unwrap();
+    assert_eq!(config.account_id.as_str(), "provider.near");
+    assert_eq!(config.providers.len(), 1);
+    assert_eq!(config.providers[0].canonical_name, "fireworks");
+}
+
+#[test]
+fn test_load_config() {
+    let config = load_config(&config_file(VALID_CONFIG)).unwrap();
+    assert_eq!(config.account_id.as_str(), "provider.testnet");
+    assert_eq!(config.cost_per_completion.0, 100);
+}
+
+#[test]
+fn test_load_missing_config_file() {
+    let error = load_config("/nonexistent/provider.yaml").unwrap_err();
+    assert!(error.contains("Error reading config filename /nonexistent/provider.yaml"));
+}
+
+#[test]
+fn test_load_config_without_required_field() {
+    let path = config_file(&VALID_CONFIG.replace("account_id: \"provider.testnet\"\n", ""));
+
+    let error = load_config(&path).unwrap_err();
+    assert!(error.contains(&path));
+    assert!(error.contains("missing required field `account_id`, expected a NEAR account id"));
+}
+
+#[test]
+fn test_load_config_with_mistyped_field() {
+    let path = config_file(
+        &VALID_CONFIG.replace("cost_per_completion: \"100\"", "cost_per_completion: [100]"),
+    );
+
+    let error = load_config(&path).unwrap_err();
+    assert!(error.starts_with(&format!("Error parsing config {}", path)));
+    assert!(error.contains("cost_per_completion"));
+}
+
+#[test]
+fn test_load_config_without_providers() {
+    let path = config_file(&VALID_CONFIG.replace(
+        "providers:\n  - canonical_name: \"openai\"\n    url: \"https://api.openai.com/v1\"\n    api_key: \"key\"",
+        "providers: []",
+    ));
+
+    let error = load_config(&path).unwrap_err();
+    assert_eq!(
+        error,
+        format!(
+            "Invalid config {}: At least one provider must be configured",
+            path
+        )
+    );
+}
+
+#[test]
+fn test_load_config_with_unknown_network() {
+    let path = config_file(&VALID_CONFIG.replace("network: \"testnet\"", "network: \"moonnet\""));
+
+    let error = load_config(&path).unwrap_err();
+    assert!(error.contains(&path));
+    assert!(error.contains("moonnet"));
+}
+
+#[test]
+fn test_is_postgres_url() {
+    assert!(is_postgres_url("postgres://provider@localhost/ppp"));
+    assert!(is_postgres_url("postgresql://provider@localhost/ppp"));
+    assert!(!is_postgres_url("sqlite://db.sqlite?mode=rwc"));
+    assert!(!is_postgres_url("db.sqlite"));
+}
+
+#[test]
+fn test_postgres_db_url_requires_the_postgres_feature() {
+    let config = config(json!({ "db_url": "postgres://provider@localhost/ppp" }));
+
+    let result = config.validate();
+    if cfg!(feature = "postgres") {
+        result.unwrap();
+    } else {
+        assert_eq!(
+            result.unwrap_err(),
+            "postgres db_url requires building the provider with the `postgres` feature"
+        );
+    }
+}
+
+#[test]
+fn test_default_provider_must_be_configured() {
+    let providers =
+        json!([{ "canonical_name": "openai", "url": "http://openai", "api_key": "key" }]);
+    config(json!({ "providers": providers, "default_provider": "openai" }))
+        .validate()
+        .unwrap();
+
+    let error = config(json!({ "providers": providers, "default_provider": "other" }))
+        .validate()
+        .unwrap_err();
+    assert_eq!(
+        error,
+        "default_provider other is not the canonical name of a provider"
+    );
+}
@@ -0,0 +1,374 @@
+mod common;
+
+use common::upstream::MockUpstream;
+use common::{config, post_completion, setup, TestProvider};
+use near_sdk::json_types::U128;
+use provider::{CostHeader, ModelInfo, TokenRates};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+// Answered with 10 prompt tokens and 5 completion tokens, see `MockUpstream`
+fn completion(max_tokens: u64) -> Value {
+    json!({ "model": "openai::gpt", "prompt": "Hi", "max_tokens": max_tokens })
+}
+
+async fn credit(provider: &TestProvider) -> u128 {
+    let channel_row = provider.ctx.db.get_channel_row("channel").await.unwrap();
+    channel_row.credit().as_yoctonear()
+}
+
+async fn debt(provider: &TestProvider) -> u128 {
+    let channel_row = provider.ctx.db.get_channel_row("channel").await.unwrap();
+    channel_row.debt().as_yoctonear()
+}
+
+#[test]
+fn test_pre_authorized_cost() {
+    let config = config(json!({ "cost_per_token": "10" }));
+    assert_eq!(config.max_completion_cost(100, Some(50)), 600);
+    // The OpenAI default of 16 tokens
+    assert_eq!(config.max_completion_cost(100, None), 260);
+    assert_eq!(config.completion_cost(100, 5), 150);
+
+    let rates: TokenRates =
+        serde_json::from_value(json!({ "price_in": "2", "price_out": "10" })).unwrap();
+    // The prompt isn't priced up front
+    assert_eq!(config.max_usage_cost(100, &rates, Some(50)), 600);
+    assert_eq!(config.usage_cost(100, &rates, 10, 5), 170);
+}
+
+#[test]
+fn test_token_rates_are_per_model() {
+    let config = config(json!({
+        "token_rates": { "openai::gpt": { "price_in": "2", "price_out": "10" } },
+    }));
+    let rates = |provider: &str, model_name: &str| {
+        config.token_rates(&ModelInfo::new(
+            provider.to_string(),
+            model_name.to_string(),
+        ))
+    };
+
+    let gpt = rates("openai", "gpt").unwrap();
+    assert_eq!((gpt.price_in.0, gpt.price_out.0), (2, 10));
+    assert_eq!(rates("openai", "other"), None);
+    assert_eq!(rates("other", "gpt"), None);
+}
+
+#[tokio::test]
+async fn test_models_without_token_rates_are_priced_flat() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "token_rates": { "openai::other": { "price_in": "2", "price_out": "10" } },
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 100, 1);
+    let response = post_completion(&url, "/completions", &signed_state, completion(50)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    // cost_per_completion, whatever the usage
+    assert_eq!(credit(&provider).await, 0);
+    assert_eq!(debt(&provider).await, 0);
+}
+
+#[tokio::test]
+async fn test_surplus_is_carried_over_as_credit() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let pay = |spent_balance, nonce| {
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        async move { post_completion(&url, "/completions", &signed_state, completion(50)).await }
+    };
+
+    // 50 completion tokens at most
+    assert_eq!(pay(599, 1).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(pay(600, 1).await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 600 - 150);
+
+    // The credit covers most of the next pre-authorization
+    assert_eq!(pay(749, 2).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(credit(&provider).await, 450);
+    assert_eq!(pay(750, 2).await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 450 + 150 - 150);
+    assert_eq!(upstream.requests().len(), 2);
+}
+
+#[test]
+fn test_upstream_price_overrides_cost_per_completion() {
+    let config = config(json!({
+        "providers": [
+            { "canonical_name": "openai", "url": "http://openai", "api_key": "key", "price": "300" },
+            { "canonical_name": "other", "url": "http://other", "api_key": "key" },
+        ],
+    }));
+    let price = |canonical_name| {
+        config.completion_price(config.find_provider(canonical_name, None).unwrap())
+    };
+
+    assert_eq!(price("openai"), 300);
+    assert_eq!(price("other"), 100);
+}
+
+#[tokio::test]
+async fn test_upstream_price_is_charged() {
+    let upstream = MockUpstream::start().await;
+    let mut providers = upstream.providers();
+    providers[0]["price"] = json!("300");
+    let provider = setup(config(json!({
+        "providers": providers,
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let pay = |spent_balance, nonce| {
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        async move { post_completion(&url, "/completions", &signed_state, completion(50)).await }
+    };
+
+    // The per token cost is added on top of the price of the upstream
+    assert_eq!(pay(799, 1).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(pay(800, 1).await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 800 - 350);
+}
+
+#[tokio::test]
+async fn test_cost_above_the_payment_is_owed_on_the_next_request() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "token_rates": { "openai::gpt": { "price_in": "2", "price_out": "10" } },
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let pay = |spent_balance, nonce| {
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        async move { post_completion(&url, "/completions", &signed_state, completion(10)).await }
+    };
+
+    // A long prompt, 100 + 2 * 100 + 10 * 5 for a pre-authorization of 100 + 10 * 10
+    *upstream.state.usage.lock().unwrap() = Some((100, 5));
+    assert_eq!(pay(200, 1).await.status(), StatusCode::OK);
+    assert_eq!(debt(&provider).await, 350 - 200);
+    assert_eq!(credit(&provider).await, 0);
+
+    *upstream.state.usage.lock().unwrap() = Some((10, 5));
+    assert_eq!(pay(200 + 349, 2).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(debt(&provider).await, 150);
+    assert_eq!(pay(200 + 350, 2).await.status(), StatusCode::OK);
+    assert_eq!(debt(&provider).await, 0);
+    assert_eq!(credit(&provider).await, 350 - 150 - 170);
+}
+
+#[tokio::test]
+async fn test_concurrent_requests_share_the_credit() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    // Settled by concurrent requests, none overwrites the others
+    let settles = (0..20).map(|_| provider.ctx.add_channel_credit("channel", 50));
+    for result in futures::future::join_all(settles).await {
+        result.unwrap();
+    }
+    assert_eq!(credit(&provider).await, 1_000);
+
+    // Only one request gets the credit
+    let (first, second) = tokio::join!(
+        provider.ctx.take_channel_credit("channel"),
+        provider.ctx.take_channel_credit("channel")
+    );
+    assert_eq!(first + second, 1_000);
+    assert_eq!(first.min(second), 0);
+    assert_eq!(credit(&provider).await, 0);
+
+    provider.ctx.add_channel_debt("channel", 300).await.unwrap();
+    assert_eq!(provider.ctx.take_channel_debt("channel").await, 300);
+    assert_eq!(provider.ctx.take_channel_debt("channel").await, 0);
+}
+
+#[tokio::test]
+async fn test_unknown_channels_take_no_balance_lock() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    // Taken before the payment is validated, for any channel name a client sends
+    assert_eq!(provider.ctx.take_channel_credit("unknown").await, 0);
+    assert_eq!(provider.ctx.take_channel_debt("unknown").await, 0);
+    assert!(!provider.ctx.shared.has_balance_lock("unknown"));
+
+    provider.ctx.take_channel_credit("channel").await;
+    assert!(provider.ctx.shared.has_balance_lock("channel"));
+    provider.ctx.shared.forget_channel("channel");
+    assert!(!provider.ctx.shared.has_balance_lock("channel"));
+}
+
+fn cost_header(unit_price: u128, markup_percent: u32) -> CostHeader {
+    CostHeader {
+        name: "X-Cost".to_string(),
+        unit_price: U128(unit_price),
+        markup_percent,
+    }
+}
+
+#[test]
+fn test_cost_header_charge() {
+    // The example of the config, 0.00042 USD with a 20% markup
+    assert_eq!(
+        cost_header(200_000_000_000_000_000_000_000, 20).charge("0.00042"),
+        Some(100_800_000_000_000_000_000)
+    );
+    assert_eq!(cost_header(100, 0).charge("1"), Some(100));
+    assert_eq!(cost_header(100, 0).charge(" 1. "), Some(100));
+    assert_eq!(cost_header(100, 0).charge(".5"), Some(50));
+    // Rounded up to the next yoctoNEAR
+    assert_eq!(cost_header(3, 0).charge("0.5"), Some(2));
+    assert_eq!(cost_header(3, 10).charge("0"), Some(0));
+
+    for invalid in ["", ".", "-1", "1e3", "0x10", "1.2.3", "one"] {
+        assert_eq!(cost_header(100, 0).charge(invalid), None, "{}", invalid);
+    }
+    assert_eq!(
+        cost_header(100, 0).charge(&format!("0.{}", "1".repeat(25))),
+        None
+    );
+    assert_eq!(cost_header(u128::MAX, 0).charge("2"), None);
+}
+
+#[tokio::test]
+async fn test_upstream_cost_header_is_charged() {
+    let upstream = MockUpstream::start().await;
+    let mut providers = upstream.providers();
+    providers[0]["cost_header"] = json!({ "name": "X-Cost", "unit_price": "100" });
+    let provider = setup(config(json!({ "providers": providers }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let pay = |spent_balance, nonce, cost: &str| {
+        *upstream.state.headers.lock().unwrap() = vec![("X-Cost".to_string(), cost.to_string())];
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        async move { post_completion(&url, "/completions", &signed_state, completion(50)).await }
+    };
+
+    // Below the flat price, the rest is credited
+    assert_eq!(pay(100, 1, "0.4").await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 60);
+
+    // Above the payment and the credit, capped without token rates
+    assert_eq!(pay(200, 2, "2").await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 0);
+    assert_eq!(debt(&provider).await, 0);
+
+    // Priced as usual
+    assert_eq!(pay(300, 3, "abc").await.status(), StatusCode::OK);
+    assert_eq!(credit(&provider).await, 0);
+    assert_eq!(upstream.requests().len(), 3);
+}
+
+#[tokio::test]
+async fn test_upstream_cost_above_the_payment_is_owed_with_token_rates() {
+    let upstream = MockUpstream::start().await;
+    let mut providers = upstream.providers();
+    providers[0]["cost_header"] = json!({ "name": "X-Cost", "unit_price": "100" });
+    let provider = setup(config(json!({
+        "providers": providers,
+        "token_rates": { "openai::gpt": { "price_in": "2", "price_out": "10" } },
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    *upstream.state.headers.lock().unwrap() = vec![("X-Cost".to_string(), "5".to_string())];
+
+    // 100 + 10 * 10 pre-authorized, the upstream reports 500
+    let signed_state = provider.sender.sign("channel", 200, 1);
+    let response = post_completion(&url, "/completions", &signed_state, completion(10)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(debt(&provider).await, 300);
+    assert_eq!(credit(&provider).await, 0);
+}
+
+#[tokio::test]
+async fn test_chat_completions_are_sent_with_the_pre_authorized_limit() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let chat = |spent_balance, nonce, limit: Value| {
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        let mut request = json!({
+            "model": "openai::gpt",
+            "messages": [{ "role": "user", "content": "Hi" }],
+        });
+        if let Value::Object(limit) = limit {
+            request.as_object_mut().unwrap().extend(limit);
+        }
+        async move { post_completion(&url, "/chat/completions", &signed_state, request).await }
+    };
+
+    // Without a limit, 16 tokens are pre-authorized and asked for
+    assert_eq!(
+        chat(259, 1, Value::Null).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(chat(260, 1, Value::Null).await.status(), StatusCode::OK);
+    assert_eq!(upstream.requests()[0]["max_tokens"], json!(16));
+    assert_eq!(credit(&provider).await, 260 - 150);
+
+    // The newer field is priced and passed on as is
+    let limit = json!({ "max_completion_tokens": 50 });
+    assert_eq!(
+        chat(260 + 489, 2, limit.clone()).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(chat(260 + 490, 2, limit).await.status(), StatusCode::OK);
+    let request = &upstream.requests()[1];
+    assert_eq!(request["max_completion_tokens"], json!(50));
+    assert_eq!(request["max_tokens"], Value::Null);
+}
+
+#[tokio::test]
+async fn test_cost_per_token_overrun_is_collected_as_debt() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let pay = |spent_balance, nonce| {
+        let signed_state = provider.sender.sign("channel", spent_balance, nonce);
+        let url = url.clone();
+        async move { post_completion(&url, "/completions", &signed_state, completion(16)).await }
+    };
+
+    // An upstream ignoring the limit, 20 tokens cost 300 out of the 260 pre-authorized
+    *upstream.state.usage.lock().unwrap() = Some((10, 20));
+    assert_eq!(pay(260, 1).await.status(), StatusCode::OK);
+    assert_eq!(debt(&provider).await, 40);
+
+    // The next payment covers the debt on top of its own pre-authorization
+    *upstream.state.usage.lock().unwrap() = Some((10, 5));
+    assert_eq!(pay(260 + 299, 2).await.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(debt(&provider).await, 40);
+    assert_eq!(pay(260 + 300, 2).await.status(), StatusCode::OK);
+    assert_eq!(debt(&provider).await, 0);
+    assert_eq!(credit(&provider).await, 300 - 40 - 150);
+}
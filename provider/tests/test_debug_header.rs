@@ -0,0 +1,94 @@
+mod common;
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use common::{config, payment_header, setup, Party};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn test_diagnose_well_formed_header() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    let header = payment_header(&provider.sender.sign("channel", 100, 1));
+    let diagnosis = provider.ctx.diagnose_payment_header(&header).await;
+
+    assert!(diagnosis.base64_decoded);
+    assert!(diagnosis.borsh_parsed);
+    assert_eq!(diagnosis.channel_id.as_deref(), Some("channel"));
+    assert_eq!(diagnosis.spent_balance.map(|balance| balance.0), Some(100));
+    assert_eq!(diagnosis.nonce, Some(1));
+    assert_eq!(diagnosis.signature_valid, Some(true));
+    assert_eq!(diagnosis.error, None);
+
+    // Nothing was recorded, the same payment is still accepted
+    assert_eq!(provider.pay("channel", 100, 1).await, 100);
+}
+
+#[tokio::test]
+async fn test_diagnose_header_signed_by_another_key() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    let header = payment_header(&Party::new("mallory.testnet").sign("channel", 100, 1));
+    let diagnosis = provider.ctx.diagnose_payment_header(&header).await;
+
+    assert!(diagnosis.borsh_parsed);
+    assert_eq!(diagnosis.signature_valid, Some(false));
+    assert!(diagnosis
+        .error
+        .unwrap()
+        .starts_with("Signature doesn't match the sender key"));
+}
+
+#[tokio::test]
+async fn test_diagnose_malformed_headers() {
+    let provider = setup(config(json!({}))).await;
+
+    let diagnosis = provider.ctx.diagnose_payment_header("not base64!").await;
+    assert!(!diagnosis.base64_decoded);
+    assert!(!diagnosis.borsh_parsed);
+    assert!(diagnosis
+        .error
+        .unwrap()
+        .starts_with("Unable to decode base64"));
+
+    let header = BASE64_STANDARD.encode(b"not a signed state");
+    let diagnosis = provider.ctx.diagnose_payment_header(&header).await;
+    assert!(diagnosis.base64_decoded);
+    assert!(!diagnosis.borsh_parsed);
+    assert_eq!(diagnosis.channel_id, None);
+    assert_eq!(diagnosis.signature_valid, None);
+    assert!(diagnosis
+        .error
+        .unwrap()
+        .starts_with("Unable to deserialize borsh serialized SignedState"));
+}
+
+#[tokio::test]
+async fn test_debug_header_endpoint_requires_admin_key() {
+    let provider = setup(config(json!({ "admin_api_key": "admin" }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let header = payment_header(&provider.sender.sign("channel", 100, 1));
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/debug/header", url))
+        .body(header.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/debug/header", url))
+        .bearer_auth("admin")
+        .body(header)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let diagnosis: Value = response.json().await.unwrap();
+    assert_eq!(diagnosis["channel_id"], "channel");
+    assert_eq!(diagnosis["signature_valid"], true);
+}
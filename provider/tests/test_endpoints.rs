@@ -0,0 +1,86 @@
+mod common;
+
+use common::upstream::MockUpstream;
+use common::{config, post_completion, setup};
+use provider::Endpoint;
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[test]
+fn test_endpoints_are_enabled_by_default() {
+    let config = config(json!({ "disabled_endpoints": ["close", "debug_header"] }));
+
+    assert!(!config.is_enabled(Endpoint::Close));
+    assert!(!config.is_enabled(Endpoint::DebugHeader));
+    assert!(config.is_enabled(Endpoint::Info));
+    assert!(config.is_enabled(Endpoint::Completions));
+}
+
+#[tokio::test]
+async fn test_disabled_base_routes_are_not_served() {
+    let provider = setup(config(json!({ "disabled_endpoints": ["close", "info"] }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let client = reqwest::Client::new();
+    let status =
+        |request: reqwest::RequestBuilder| async move { request.send().await.unwrap().status() };
+
+    // Every route of the endpoint
+    assert_eq!(
+        status(client.post(format!("{}/pc/close/channel", url))).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        status(client.get(format!("{}/pc/close/channel/format", url))).await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        status(client.get(format!("{}/info", url))).await,
+        StatusCode::NOT_FOUND
+    );
+
+    assert_eq!(
+        status(client.get(format!("{}/health", url))).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        status(client.get(format!("{}/pc/state/channel", url))).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn test_disabled_oai_routes_are_not_served() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "disabled_endpoints": ["completions"],
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({ "model": "openai::gpt", "prompt": "Hi" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(upstream.requests().is_empty());
+
+    // Nothing was paid, the same payment buys a chat completion
+    let response = post_completion(
+        &url,
+        "/chat/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({
+            "model": "openai::gpt",
+            "messages": [{ "role": "user", "content": "Hi" }],
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(upstream.requests().len(), 1);
+}
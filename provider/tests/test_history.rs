@@ -0,0 +1,45 @@
+mod common;
+
+use cli::provider::Provider;
+use common::{config, setup};
+use near_sdk::json_types::U128;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_history_lists_the_signed_states() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    provider.pay("channel", 100, 1).await;
+    provider.pay("channel", 250, 2).await;
+    let url = provider.serve().await;
+
+    // Read with the client of the cli
+    let history = Provider::new(url).history("channel").await.unwrap();
+    assert_eq!(history.channel_name, "channel");
+    assert!(!history.pruned);
+    assert_eq!(history.charges(), vec![100, 150]);
+}
+
+#[tokio::test]
+async fn test_pruned_history_only_has_the_latest_signed_state() {
+    let provider = setup(config(json!({ "prune_signed_states": true }))).await;
+    provider.open_channel("channel", 10_000).await;
+    provider.pay("channel", 100, 1).await;
+    provider.pay("channel", 250, 2).await;
+    let url = provider.serve().await;
+
+    let history = Provider::new(url).history("channel").await.unwrap();
+    assert!(history.pruned);
+    assert_eq!(history.charges(), vec![250]);
+}
+
+#[tokio::test]
+async fn test_state_reports_estimated_requests_remaining() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    provider.pay("channel", 250, 1).await;
+
+    // 9_750 left at 100 per completion
+    let state = provider.ctx.get_pc_state("channel").await.unwrap();
+    assert_eq!(state.estimated_requests_remaining, Some(U128(97)));
+}
@@ -0,0 +1,124 @@
+use near_crypto::{KeyType, SecretKey};
+use near_primitives::types::AccountId;
+use provider::{CommandKeySource, EnvKeySource, FileKeySource, SecretKeySource};
+
+fn account_id() -> AccountId {
+    "provider.testnet".parse().unwrap()
+}
+
+// Not the key of any real account
+fn fake_secret() -> SecretKey {
+    SecretKey::from_seed(KeyType::ED25519, "fake secret")
+}
+
+fn command(args: &[&str]) -> CommandKeySource {
+    CommandKeySource {
+        command: args.iter().map(|arg| arg.to_string()).collect(),
+    }
+}
+
+#[test]
+fn test_env_var_name() {
+    let source = EnvKeySource {
+        var: "PPP_KEY_{account_id}".to_string(),
+    };
+    assert_eq!(source.var_name(&account_id()), "PPP_KEY_PROVIDER_TESTNET");
+
+    let source = EnvKeySource {
+        var: "PPP_KEY".to_string(),
+    };
+    assert_eq!(source.var_name(&account_id()), "PPP_KEY");
+}
+
+#[test]
+fn test_env_key_source() {
+    std::env::set_var("PPP_TEST_KEY_PROVIDER_TESTNET", fake_secret().to_string());
+    let source = EnvKeySource {
+        var: "PPP_TEST_KEY_{account_id}".to_string(),
+    };
+
+    assert_eq!(source.secret_key(&account_id()).unwrap(), fake_secret());
+}
+
+#[test]
+fn test_env_key_source_errors() {
+    let source = EnvKeySource {
+        var: "PPP_TEST_UNSET_KEY".to_string(),
+    };
+    assert_eq!(
+        source.secret_key(&account_id()).unwrap_err(),
+        "Environment variable PPP_TEST_UNSET_KEY is not set"
+    );
+
+    std::env::set_var("PPP_TEST_INVALID_KEY", "ed25519:not-a-key");
+    let source = EnvKeySource {
+        var: "PPP_TEST_INVALID_KEY".to_string(),
+    };
+    let error = source.secret_key(&account_id()).unwrap_err();
+    assert_eq!(error, "Invalid secret key");
+}
+
+#[test]
+fn test_command_key_source() {
+    // The trailing newline of `echo` is trimmed
+    let source = command(&["echo", &fake_secret().to_string()]);
+    assert_eq!(source.secret_key(&account_id()).unwrap(), fake_secret());
+}
+
+#[test]
+fn test_command_key_source_account_id_placeholder() {
+    let secret = fake_secret().to_string();
+    let script = format!(
+        "test \"$0\" = provider.testnet && echo {} || exit 1",
+        secret
+    );
+    let source = command(&["sh", "-c", &script, "{account_id}"]);
+
+    assert_eq!(source.secret_key(&account_id()).unwrap(), fake_secret());
+}
+
+#[test]
+fn test_command_key_source_errors() {
+    let error = command(&[]).secret_key(&account_id()).unwrap_err();
+    assert_eq!(error, "Key command is empty");
+
+    let error = command(&["false"]).secret_key(&account_id()).unwrap_err();
+    assert!(error.starts_with("Key command false failed with"));
+
+    let error = command(&["/nonexistent/key-command"])
+        .secret_key(&account_id())
+        .unwrap_err();
+    assert!(error.starts_with("Unable to run key command /nonexistent/key-command"));
+
+    // Whatever was printed stays out of the error
+    let error = command(&["echo", "ed25519:leaked"])
+        .secret_key(&account_id())
+        .unwrap_err();
+    assert_eq!(error, "Invalid secret key");
+}
+
+#[test]
+fn test_file_key_source() {
+    let credentials_dir = std::env::temp_dir().join(format!("ppp-keys-{}", std::process::id()));
+    std::fs::create_dir_all(&credentials_dir).unwrap();
+    std::fs::write(
+        credentials_dir.join("provider.testnet.json"),
+        // As written by near-cli-rs
+        serde_json::json!({
+            "seed_phrase_hd_path": "m/44'/397'/0'",
+            "master_seed_phrase": "",
+            "implicit_account_id": "0".repeat(64),
+            "public_key": fake_secret().public_key().to_string(),
+            "private_key": fake_secret().to_string(),
+        })
+        .to_string(),
+    )
+    .unwrap();
+    let source = FileKeySource { credentials_dir };
+
+    assert_eq!(source.secret_key(&account_id()).unwrap(), fake_secret());
+    let error = source
+        .secret_key(&"missing.testnet".parse().unwrap())
+        .unwrap_err();
+    assert!(error.starts_with("Access key file"));
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use std::time::Duration;
+
+use common::upstream::MockUpstream;
+use common::{config, post_completion, setup};
+use provider::LOAD_HEADER_NAME;
+use reqwest::header::RETRY_AFTER;
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_load_header_under_load() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.delay.lock().unwrap() = Duration::from_millis(500);
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "max_concurrent_requests": 1,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let request = json!({ "model": "openai::gpt", "prompt": "Hi" });
+
+    let first = tokio::spawn({
+        let url = url.clone();
+        let signed_state = provider.sender.sign("channel", 100, 1);
+        let request = request.clone();
+        async move { post_completion(&url, "/completions", &signed_state, request).await }
+    });
+    // The only slot is taken once the first completion reaches the upstream
+    while upstream.requests().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 200, 2),
+        request.clone(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()[RETRY_AFTER], "1");
+    assert_eq!(response.headers()[LOAD_HEADER_NAME], "100");
+    assert_eq!(upstream.requests().len(), 1);
+
+    // Served responses carry the load too
+    let response = first.await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[LOAD_HEADER_NAME], "100");
+
+    // The slot is free again, the rejected payment can be sent again
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 200, 2),
+        request,
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_stream_is_in_flight_until_it_ends() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.event_delay.lock().unwrap() = Duration::from_millis(200);
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "max_concurrent_requests": 1,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let request = json!({ "model": "openai::gpt", "prompt": "Hi", "stream": true });
+
+    // The response starts before the events are relayed
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        request.clone(),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(provider.ctx.shared.in_flight(), 1);
+
+    let rejected = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 200, 2),
+        request.clone(),
+    )
+    .await;
+    assert_eq!(rejected.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    assert!(response.text().await.unwrap().contains("data: [DONE]"));
+    for _ in 0..100 {
+        if provider.ctx.shared.in_flight() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(provider.ctx.shared.in_flight(), 0);
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{config, setup, upstream, Party};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use provider::errors::{ChannelError, ProviderError, SignedStateError};
+use provider::{
+    record_completion, record_payment_rejected, record_withdrawal, ProviderBaseService,
+    COMPLETIONS_TOTAL, PAYMENT_REJECTED_TOTAL, WITHDRAWALS_TOTAL,
+};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[test]
+fn test_rejection_reasons() {
+    assert_eq!(
+        ProviderError::SignedState(SignedStateError::InvalidSignature).reason(),
+        "invalid_signature"
+    );
+    assert_eq!(
+        ProviderError::Channel(ChannelError::WithdrawFailed(String::new())).reason(),
+        "withdraw_failed"
+    );
+}
+
+#[test]
+fn test_metrics_are_recorded() {
+    // Local to the test, the global recorder is only installed by the binary
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+
+    metrics::with_local_recorder(&recorder, || {
+        record_completion("/completions", Duration::from_millis(300), true);
+        record_completion("/completions", Duration::from_millis(300), true);
+        record_completion("/chat/completions", Duration::from_secs(1), false);
+        record_payment_rejected(&ProviderError::SignedState(
+            SignedStateError::InvalidSignature,
+        ));
+        record_withdrawal(true);
+    });
+
+    let rendered = handle.render();
+    assert!(rendered.contains(&format!(
+        "{}{{endpoint=\"/completions\",outcome=\"success\"}} 2",
+        COMPLETIONS_TOTAL
+    )));
+    assert!(rendered.contains(&format!(
+        "{}{{endpoint=\"/chat/completions\",outcome=\"failure\"}} 1",
+        COMPLETIONS_TOTAL
+    )));
+    assert!(rendered.contains(&format!(
+        "{}{{reason=\"invalid_signature\"}} 1",
+        PAYMENT_REJECTED_TOTAL
+    )));
+    assert!(rendered.contains(&format!("{}{{outcome=\"success\"}} 1", WITHDRAWALS_TOTAL)));
+}
+
+#[tokio::test]
+async fn test_rejected_payments_are_counted() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    // The test runs on a single thread
+    let _guard = metrics::set_default_local_recorder(&recorder);
+
+    // Not signed by the sender of the channel
+    let signed_state = Party::new("mallory.testnet").sign("channel", 100, 1);
+    assert!(provider
+        .ctx
+        .validate_signed_state(0, &signed_state, true)
+        .await
+        .is_err());
+    // Dry runs aren't payments
+    assert!(provider
+        .ctx
+        .validate_signed_state(0, &signed_state, false)
+        .await
+        .is_err());
+
+    assert!(handle.render().contains(&format!(
+        "{}{{reason=\"invalid_signature\"}} 1",
+        PAYMENT_REJECTED_TOTAL
+    )));
+}
+
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let provider = setup(config(json!({}))).await;
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = recorder.handle();
+    metrics::with_local_recorder(&recorder, || record_withdrawal(false));
+
+    let url = upstream::serve(
+        ProviderBaseService::new(provider.ctx.clone())
+            .with_metrics(handle)
+            .router(),
+    )
+    .await;
+    let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains(&format!("{}{{outcome=\"failure\"}} 1", WITHDRAWALS_TOTAL)));
+
+    // Without a recorder there is nothing to render
+    let response = reqwest::get(format!("{}/metrics", provider.serve().await))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use common::upstream::MockUpstream;
+use common::{config, post_completion, setup};
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn test_curated_models_are_listed_first() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.models.lock().unwrap() = vec!["gpt-4o".to_string(), "curated".to_string()];
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "models": [{ "id": "openai::curated", "owned_by": "operator", "created": 42 }],
+    })))
+    .await;
+    let url = provider.serve().await;
+
+    let listing: Value = reqwest::get(format!("{}/oai/models", url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let models = listing["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|model| {
+            (
+                model["id"].as_str().unwrap(),
+                model["owned_by"].as_str().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    // The upstream listing of the curated model is left out
+    assert_eq!(
+        models,
+        vec![
+            ("openai::curated", "operator"),
+            ("openai::gpt-4o", "openai")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_curated_model_is_retrieved_from_the_config() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "models": [{ "id": "openai::curated", "owned_by": "operator", "created": 42 }],
+    })))
+    .await;
+    let url = provider.serve().await;
+
+    let model: Value = reqwest::get(format!("{}/oai/models/openai::curated", url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(model["id"], "openai::curated");
+    assert_eq!(model["owned_by"], "operator");
+    assert_eq!(model["created"], 42);
+}
+
+#[tokio::test]
+async fn test_upstream_model_is_retrieved() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.models.lock().unwrap() = vec!["gpt-4o".to_string()];
+    let provider = setup(config(json!({ "providers": upstream.providers() }))).await;
+    let url = provider.serve().await;
+    let retrieve = |model: &str| reqwest::get(format!("{}/oai/models/{}", url, model));
+
+    let response = retrieve("openai::gpt-4o").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let model: Value = response.json().await.unwrap();
+    assert_eq!(model["id"], "openai::gpt-4o");
+    assert_eq!(model["owned_by"], "openai");
+
+    // Not listed by the upstream, which may still serve it
+    let response = retrieve("openai::unlisted").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let model: Value = response.json().await.unwrap();
+    assert_eq!(model["id"], "openai::unlisted");
+    assert_eq!(model["owned_by"], "openai");
+}
+
+#[tokio::test]
+async fn test_model_of_unknown_provider_is_not_found() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({ "providers": upstream.providers() }))).await;
+    let url = provider.serve().await;
+    let retrieve = |model: &str| reqwest::get(format!("{}/oai/models/{}", url, model));
+
+    let response = retrieve("other::gpt").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("provider other is not configured"));
+
+    // No default provider to resolve a bare model name against
+    let response = retrieve("gpt").await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_bare_model_names_resolve_to_the_default_provider() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.models.lock().unwrap() = vec!["gpt-4o".to_string()];
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "default_provider": "openai",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let model_info = provider.ctx.model_info("gpt-4o").await.unwrap();
+    assert_eq!(
+        (model_info.provider.as_str(), model_info.model_name.as_str()),
+        ("openai", "gpt-4o")
+    );
+
+    // Echoed as requested
+    let model: Value = reqwest::get(format!("{}/oai/models/gpt-4o", url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(model["id"], "gpt-4o");
+    assert_eq!(model["owned_by"], "openai");
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({ "model": "gpt-4o", "prompt": "Hi" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let completion: Value = response.json().await.unwrap();
+    assert_eq!(completion["model"], "gpt-4o");
+    assert_eq!(upstream.requests()[0]["model"], "gpt-4o");
+
+    // Qualified names keep working
+    let model: Value = reqwest::get(format!("{}/oai/models/openai::gpt-4o", url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(model["id"], "openai::gpt-4o");
+}
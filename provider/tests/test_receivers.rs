@@ -0,0 +1,70 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{config, setup, PROVIDER};
+use provider::Clock;
+use serde_json::json;
+
+const SECOND_RECEIVER: &str = "second.testnet";
+
+#[tokio::test]
+async fn test_info_lists_every_receiver() {
+    let provider = setup(config(json!({ "extra_account_ids": [SECOND_RECEIVER] }))).await;
+
+    let info = provider.ctx.provider_info().await;
+
+    assert_eq!(info.primary.account_id.as_str(), PROVIDER);
+    let receivers = info
+        .receivers
+        .iter()
+        .map(|receiver| receiver.account_id.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(receivers, vec![PROVIDER, SECOND_RECEIVER]);
+    assert_eq!(
+        info.receivers[1].public_key,
+        provider.receivers[1].secret_key.public_key()
+    );
+}
+
+#[tokio::test]
+async fn test_payments_to_any_receiver() {
+    let provider = setup(config(json!({ "extra_account_ids": [SECOND_RECEIVER] }))).await;
+    provider.open_channel_with("primary", 0, 1_000).await;
+    provider.open_channel_with("secondary", 1, 1_000).await;
+
+    assert_eq!(provider.pay("primary", 100, 1).await, 100);
+    assert_eq!(provider.pay("secondary", 200, 1).await, 200);
+}
+
+#[tokio::test]
+async fn test_stale_channel_limit_is_shared_by_the_receivers() {
+    let provider = setup(config(json!({ "extra_account_ids": [SECOND_RECEIVER] }))).await;
+    for i in 0..3 {
+        provider
+            .open_channel_with(&format!("primary-{}", i), 0, 1_000)
+            .await;
+        provider
+            .open_channel_with(&format!("secondary-{}", i), 1, 1_000)
+            .await;
+    }
+    provider.clock.advance(Duration::from_secs(60 * 60));
+
+    let now = provider.clock.now();
+    let threshold = provider.ctx.config.stale_channel_threshold();
+    let stale = provider
+        .ctx
+        .db
+        .get_stale_channels(now, threshold, Some(4))
+        .await
+        .unwrap();
+    assert_eq!(stale.len(), 4);
+
+    let stale = provider
+        .ctx
+        .db
+        .get_stale_channels(now, threshold, None)
+        .await
+        .unwrap();
+    assert_eq!(stale.len(), 6);
+}
@@ -0,0 +1,55 @@
+mod common;
+
+use common::upstream::MockUpstream;
+use common::{config, post_completion, setup};
+use provider::Provider;
+use reqwest::StatusCode;
+use serde_json::json;
+
+fn upstream_provider(url: &str, api_key: &str) -> Provider {
+    serde_json::from_value(json!({
+        "canonical_name": "openai",
+        "url": url,
+        "api_key": api_key,
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_reload_picks_up_a_changed_key() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({ "providers": upstream.providers() }))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let body = json!({ "model": "openai::gpt", "prompt": "Hi" });
+
+    let signed_state = provider.sender.sign("channel", 100, 1);
+    let response = post_completion(&url, "/completions", &signed_state, body.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    provider
+        .ctx
+        .reload_providers(vec![upstream_provider(&upstream.url, "rotated-key")])
+        .await
+        .unwrap();
+    let signed_state = provider.sender.sign("channel", 200, 2);
+    let response = post_completion(&url, "/completions", &signed_state, body).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    assert_eq!(upstream.api_keys(), vec!["upstream-key", "rotated-key"]);
+}
+
+#[tokio::test]
+async fn test_invalid_reload_keeps_the_providers() {
+    let provider = setup(config(json!({}))).await;
+
+    assert!(provider.ctx.reload_providers(vec![]).await.is_err());
+    assert!(provider
+        .ctx
+        .reload_providers(vec![upstream_provider("", "rotated-key")])
+        .await
+        .is_err());
+
+    let openai = provider.ctx.find_provider("openai", None).await.unwrap();
+    assert_eq!(openai.api_key, "upstream-key");
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use cli::config::{Channel, SignedState, State};
+use cli::provider::{Details, Provider};
+use common::{config, setup, PROVIDER, SENDER};
+use near_crypto::{KeyType, SecretKey};
+use near_sdk::json_types::U128;
+use near_sdk::NearToken;
+use serde_json::{json, Value};
+
+// Borsh layout of `State` the contract verifies withdrawals with (its tests sign these
+// same bytes): the channel id as a u32 little endian length and its bytes, the spent
+// balance as a u128 little endian, then the nonce as a u64 little endian. Payments
+// signed over anything else are accepted but can't be withdrawn
+#[test]
+fn test_state_layout_matches_contract() {
+    let channel_id = "layout-check";
+    let spent_balance: u128 = 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10;
+    let nonce: u64 = 0x1112_1314_1516_1718;
+    let state = State {
+        channel_id: channel_id.to_string(),
+        spent_balance: NearToken::from_yoctonear(spent_balance),
+        nonce,
+    };
+
+    let mut expected = (channel_id.len() as u32).to_le_bytes().to_vec();
+    expected.extend_from_slice(channel_id.as_bytes());
+    expected.extend_from_slice(&spent_balance.to_le_bytes());
+    expected.extend_from_slice(&nonce.to_le_bytes());
+
+    assert_eq!(borsh::to_vec(&state).unwrap(), expected);
+}
+
+// A channel as the cli keeps it, with the keys of the test parties
+fn cli_channel(channel_id: &str, spent_balance: u128, nonce: u64) -> Channel {
+    let details = |account_id: &str| Details {
+        account_id: account_id.parse().unwrap(),
+        public_key: SecretKey::from_seed(KeyType::ED25519, account_id).public_key(),
+    };
+    Channel {
+        channel_id: channel_id.to_string(),
+        receiver: details(PROVIDER),
+        sender: details(SENDER),
+        sender_secret_key: SecretKey::from_seed(KeyType::ED25519, SENDER),
+        spent_balance: NearToken::from_yoctonear(spent_balance),
+        added_balance: NearToken::from_yoctonear(10_000),
+        withdrawn_balance: NearToken::from_yoctonear(0),
+        force_close_started: None,
+        nonce,
+        label: None,
+    }
+}
+
+#[tokio::test]
+async fn test_cli_payload_verifies_on_the_provider() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+    let channel = cli_channel("channel", 250, 3);
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/pc/validate", url))
+        .body(channel.payload_b64())
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), reqwest::StatusCode::OK);
+    let signed_state = response.json::<SignedState>().await.unwrap();
+    assert_eq!(signed_state.state, channel.info());
+    assert_eq!(signed_state.signature, channel.payload().signature);
+}
+
+#[tokio::test]
+async fn test_balances_are_yocto_strings_in_json() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    provider.pay("channel", 250, 1).await;
+    let url = provider.serve().await;
+
+    let state = reqwest::get(format!("{}/pc/state/channel", url))
+        .await
+        .unwrap()
+        .json::<Value>()
+        .await
+        .unwrap();
+    assert_eq!(state["spent_balance"], json!("250"));
+    assert_eq!(state["added_balance"], json!("10000"));
+    assert_eq!(state["withdraw_balance"], json!("0"));
+    assert_eq!(state["nonce"], json!(1));
+
+    // Read the same way by the cli
+    let spent_balance = Provider::new(url).spent_balance("channel").await;
+    assert_eq!(spent_balance.spent_balance, U128(250));
+    assert_eq!(spent_balance.nonce, 1);
+
+    // The signed states use `NearToken`, with the same representation
+    let signed_state = serde_json::to_value(cli_channel("channel", 250, 1).payload()).unwrap();
+    assert_eq!(signed_state["state"]["spent_balance"], json!("250"));
+}
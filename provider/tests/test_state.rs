@@ -0,0 +1,127 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+
+use provider::{CachedResponse, ShardedMap, SharedState};
+use serde_json::json;
+
+const THREADS: usize = 8;
+const REQUESTS: u64 = 1_000;
+
+#[test]
+fn test_concurrent_requests_are_all_counted() {
+    let state = SharedState::default();
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let handles = (0..THREADS)
+        .map(|thread| {
+            let state = state.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for request in 0..REQUESTS {
+                    // Every thread hits the shared channel, and a channel of its own
+                    state.record_request("shared");
+                    state.record_request(&format!("channel-{}", thread));
+                    if request % 100 == 0 {
+                        state.mark_verified_on_chain("shared");
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let shared = state.channel("shared").unwrap();
+    assert_eq!(shared.requests_served, THREADS as u64 * REQUESTS);
+    assert!(shared.verified_on_chain);
+    for thread in 0..THREADS {
+        let channel = state.channel(&format!("channel-{}", thread)).unwrap();
+        assert_eq!(channel.requests_served, REQUESTS);
+        assert!(!channel.verified_on_chain);
+    }
+
+    assert!(state.forget_channel("shared").is_some());
+    assert!(state.channel("shared").is_none());
+}
+
+#[test]
+fn test_in_flight_cap_under_contention() {
+    let state = SharedState::default();
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    // Every thread grabs as many slots as it can, only `THREADS` are handed out in total
+    let handles = (0..THREADS)
+        .map(|_| {
+            let state = state.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                (0..THREADS)
+                    .filter_map(|_| state.try_start_request(THREADS))
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect::<Vec<_>>();
+    let guards = handles
+        .into_iter()
+        .flat_map(|handle| handle.join().unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(guards.len(), THREADS);
+    assert_eq!(state.in_flight(), THREADS);
+    assert!(state.try_start_request(THREADS).is_none());
+
+    drop(guards);
+    assert_eq!(state.in_flight(), 0);
+}
+
+#[test]
+fn test_sharded_map_keys_are_independent() {
+    let map = ShardedMap::<u64, u64>::default();
+    assert!(map.is_empty());
+
+    for key in 0..100 {
+        map.update(key, || 0, |value| *value += key);
+        map.update(key, || 0, |value| *value += 1);
+    }
+
+    assert_eq!(map.len(), 100);
+    for key in 0..100 {
+        assert_eq!(map.get(&key), Some(key + 1));
+    }
+    assert_eq!(map.remove(&7), Some(8));
+    assert!(!map.contains_key(&7));
+    assert_eq!(map.len(), 99);
+}
+
+#[test]
+fn test_cached_response_matches_key_and_payment() {
+    let state = SharedState::default();
+    let cached = |idempotency_key: &str, signature: &str, response| CachedResponse {
+        idempotency_key: idempotency_key.to_string(),
+        signature: signature.to_string(),
+        response,
+    };
+
+    state.cache_response("channel", cached("key", "first", json!(1)));
+    assert_eq!(
+        state.cached_response("channel", "key", "first"),
+        Some(json!(1))
+    );
+    assert_eq!(state.cached_response("channel", "other", "first"), None);
+    assert_eq!(state.cached_response("channel", "key", "second"), None);
+    assert_eq!(state.cached_response("other", "key", "first"), None);
+
+    // Only the response of the latest payment of a channel is kept
+    state.cache_response("channel", cached("key", "second", json!(2)));
+    assert_eq!(state.cached_response("channel", "key", "first"), None);
+    assert_eq!(
+        state.cached_response("channel", "key", "second"),
+        Some(json!(2))
+    );
+
+    state.forget_channel("channel");
+    assert_eq!(state.cached_response("channel", "key", "second"), None);
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use std::time::Duration;
+
+use common::upstream::{MockUpstream, COMPLETION_TEXT};
+use common::{config, post_completion, setup, TestProvider};
+use reqwest::StatusCode;
+use serde_json::json;
+
+// Streamed payments are settled once the stream is dropped, apart from the response
+async fn settled_credit(provider: &TestProvider) -> u128 {
+    for _ in 0..100 {
+        let channel_row = provider.ctx.db.get_channel_row("channel").await.unwrap();
+        if channel_row.credit().as_yoctonear() > 0 {
+            return channel_row.credit().as_yoctonear();
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    0
+}
+
+#[tokio::test]
+async fn test_streamed_completion_is_priced_from_its_usage() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 600, 1);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &signed_state,
+        json!({
+            "model": "openai::gpt",
+            "prompt": "Hi",
+            "max_tokens": 50,
+            "stream": true,
+            "stream_options": { "continuous_usage_stats": true },
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let events = response.text().await.unwrap();
+    assert!(events.contains(COMPLETION_TEXT));
+    assert!(events.contains("data: [DONE]"));
+    // Requested upstream to price the completion, the client didn't ask for it
+    assert!(!events.contains("usage"));
+
+    let requests = upstream.requests();
+    assert_eq!(
+        requests[0]["stream_options"],
+        json!({ "continuous_usage_stats": true, "include_usage": true })
+    );
+    // 5 completion tokens out of the 50 pre-authorized
+    assert_eq!(settled_credit(&provider).await, 600 - 150);
+}
+
+#[tokio::test]
+async fn test_streamed_completion_is_priced_with_token_rates() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "token_rates": { "openai::gpt": { "price_in": "2", "price_out": "10" } },
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    // 100 + 10 * 50 pre-authorized
+    let signed_state = provider.sender.sign("channel", 600, 1);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &signed_state,
+        json!({ "model": "openai::gpt", "prompt": "Hi", "max_tokens": 50, "stream": true }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("data: [DONE]"));
+
+    // 10 prompt tokens and 5 completion tokens
+    assert_eq!(settled_credit(&provider).await, 600 - 170);
+}
+
+#[tokio::test]
+async fn test_streamed_usage_is_relayed_on_request() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 600, 1);
+    let response = post_completion(
+        &url,
+        "/chat/completions",
+        &signed_state,
+        json!({
+            "model": "openai::gpt",
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "max_tokens": 50,
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let events = response.text().await.unwrap();
+    assert!(events.contains("\"completion_tokens\":5"));
+    assert_eq!(settled_credit(&provider).await, 450);
+}
+
+#[tokio::test]
+async fn test_streamed_usage_is_requested_upstream() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    // Without stream options, then opting out of the usage
+    for (nonce, stream_options) in [(1, None), (2, Some(json!({ "include_usage": false })))] {
+        let mut request = json!({
+            "model": "openai::gpt",
+            "messages": [{ "role": "user", "content": "Hi" }],
+            "max_tokens": 50,
+            "stream": true,
+        });
+        if let Some(stream_options) = stream_options {
+            request["stream_options"] = stream_options;
+        }
+        let signed_state = provider.sender.sign("channel", 600 * nonce as u128, nonce);
+        let response = post_completion(&url, "/chat/completions", &signed_state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = response.text().await.unwrap();
+        assert!(events.contains(COMPLETION_TEXT));
+        assert!(!events.contains("usage"));
+        if nonce == 1 {
+            // The usage captured upstream prices it at 5 completion tokens
+            assert_eq!(settled_credit(&provider).await, 600 - 150);
+        }
+    }
+
+    for request in upstream.requests() {
+        assert_eq!(request["stream_options"], json!({ "include_usage": true }));
+    }
+}
+
+#[tokio::test]
+async fn test_streamed_completion_without_usage_consumes_the_payment() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.usage.lock().unwrap() = None;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "cost_per_token": "10",
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 600, 1);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &signed_state,
+        json!({ "model": "openai::gpt", "prompt": "Hi", "max_tokens": 50, "stream": true }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("data: [DONE]"));
+
+    // Priced at the whole pre-authorization, nothing is credited back
+    assert_eq!(settled_credit(&provider).await, 0);
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use common::{config, setup};
+use near_sdk::json_types::U128;
+use near_sdk::NearToken;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_summary_aggregates_open_channels() {
+    let provider = setup(config(json!({}))).await;
+
+    provider.open_channel("paid", 1_000).await;
+    provider.pay("paid", 300, 1).await;
+
+    provider.open_channel("withdrawn", 2_000).await;
+    provider.pay("withdrawn", 500, 1).await;
+    provider
+        .update_channel("withdrawn", |channel| {
+            channel.withdrawn_balance = NearToken::from_yoctonear(200)
+        })
+        .await;
+
+    provider.open_channel("closing", 4_000).await;
+    provider.pay("closing", 1_000, 1).await;
+    provider
+        .update_channel("closing", |channel| {
+            channel.force_close_started = Some(1_700_000_000 * provider::SECOND)
+        })
+        .await;
+
+    let summary = provider.ctx.summary(false).await.unwrap();
+
+    assert_eq!(summary.open_channels, 3);
+    assert_eq!(summary.closing_channels, 1);
+    // (1000 - 0) + (2000 - 200) + (4000 - 0)
+    assert_eq!(summary.total_balance, U128(6_800));
+    // (300 - 0) + (500 - 200) + (1000 - 0)
+    assert_eq!(summary.total_withdrawable, U128(1_600));
+    assert_eq!(summary.total_at_risk, U128(1_000));
+}
+
+#[tokio::test]
+async fn test_summary_skips_closed_channels() {
+    let provider = setup(config(json!({}))).await;
+
+    provider.open_channel("open", 1_000).await;
+    provider.open_channel("closed", 1_000).await;
+    provider.pay("closed", 300, 1).await;
+    provider
+        .update_channel("closed", |channel| {
+            channel.sender.account_id = provider::CLOSED_CHANNEL_ACCOUNT_ID.parse().unwrap()
+        })
+        .await;
+
+    let summary = provider.ctx.summary(false).await.unwrap();
+
+    assert_eq!(summary.open_channels, 1);
+    assert_eq!(summary.total_balance, U128(1_000));
+    assert_eq!(summary.total_withdrawable, U128(0));
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use std::time::Duration;
+
+use common::upstream::{MockUpstream, COMPLETION_TEXT};
+use common::{config, post_completion, setup};
+use reqwest::StatusCode;
+use serde_json::json;
+
+#[tokio::test]
+async fn test_request_past_the_timeout_is_not_charged() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.delay.lock().unwrap() = Duration::from_secs(2);
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "request_timeout_secs": 1,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let signed_state = provider.sender.sign("channel", 100, 1);
+    let response = post_completion(
+        &url,
+        "/completions",
+        &signed_state,
+        json!({ "model": "openai::gpt", "prompt": "Hi" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    // Aborted while waiting on the upstream, after the payment was validated
+    assert_eq!(upstream.requests().len(), 1);
+
+    // The payment is credited back in the background, and pays for the next completion
+    let mut credit = 0;
+    for _ in 0..100 {
+        let channel_row = provider.ctx.db.get_channel_row("channel").await.unwrap();
+        credit = channel_row.credit().as_yoctonear();
+        if credit > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(credit, 100);
+
+    // The spent balance must still increase, the credit pays for the rest
+    *upstream.state.delay.lock().unwrap() = Duration::ZERO;
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 101, 2),
+        json!({ "model": "openai::gpt", "prompt": "Hi" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_request_within_the_timeout_is_served() {
+    let upstream = MockUpstream::start().await;
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "request_timeout_secs": 5,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({ "model": "openai::gpt", "prompt": "Hi" }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        provider
+            .ctx
+            .db
+            .get_channel_row("channel")
+            .await
+            .unwrap()
+            .credit()
+            .as_yoctonear(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_stream_past_the_timeout_is_cut() {
+    let upstream = MockUpstream::start().await;
+    *upstream.state.event_delay.lock().unwrap() = Duration::from_millis(600);
+    let provider = setup(config(json!({
+        "providers": upstream.providers(),
+        "request_timeout_secs": 1,
+    })))
+    .await;
+    provider.open_channel("channel", 10_000).await;
+    let url = provider.serve().await;
+
+    let response = post_completion(
+        &url,
+        "/completions",
+        &provider.sender.sign("channel", 100, 1),
+        json!({ "model": "openai::gpt", "prompt": "Hi", "stream": true }),
+    )
+    .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Only the first event arrives before the deadline, the stream ends there
+    let started = std::time::Instant::now();
+    let events = response.text().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert!(events.contains(COMPLETION_TEXT));
+    assert!(!events.contains("data: [DONE]"));
+}
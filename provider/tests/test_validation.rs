@@ -0,0 +1,244 @@
+mod common;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use common::{config, setup, Party};
+use near_sdk::NearToken;
+use provider::errors::{ProviderError, SignedStateError};
+use serde_json::json;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+// Fields of a span when it was created, or of an event with the span it was emitted in
+#[derive(Debug)]
+struct Captured {
+    span: String,
+    fields: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Fields(HashMap<String, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+#[derive(Clone, Default)]
+struct Capture(Arc<Mutex<Vec<Captured>>>);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Capture {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        self.0.lock().unwrap().push(Captured {
+            span: attrs.metadata().name().to_string(),
+            fields: fields.0,
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.0.lock().unwrap().push(Captured {
+            span: ctx
+                .event_span(event)
+                .map(|span| span.name().to_string())
+                .unwrap_or_default(),
+            fields: fields.0,
+        });
+    }
+}
+
+#[tokio::test]
+async fn test_payment_without_ceiling() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    assert_eq!(provider.pay("channel", 9_000, 1).await, 9_000);
+}
+
+#[tokio::test]
+async fn test_payment_over_ceiling_is_rejected() {
+    let provider = setup(config(json!({ "max_payment_per_request": "500" }))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    let result = provider.try_pay("channel", 501, 1).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::PaymentTooLarge(_)
+        ))
+    ));
+    // Nothing was stored, the same nonce can still be used
+    assert_eq!(provider.pay("channel", 500, 1).await, 500);
+}
+
+#[tokio::test]
+async fn test_payment_ceiling_is_per_request() {
+    let provider = setup(config(json!({ "max_payment_per_request": "500" }))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    assert_eq!(provider.pay("channel", 400, 1).await, 400);
+    // The spent balance is over the ceiling, the payment isn't
+    assert_eq!(provider.pay("channel", 900, 2).await, 500);
+
+    let result = provider.try_pay("channel", 1_401, 3).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::PaymentTooLarge(_)
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn test_rejected_payment_span_fields() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    let capture = Capture::default();
+    let _guard =
+        tracing::subscriber::set_default(tracing_subscriber::registry().with(capture.clone()));
+
+    // Not signed by the sender of the channel
+    let signed_state = Party::new("mallory.testnet").sign("channel", 100, 1);
+    let result = provider
+        .ctx
+        .validate_signed_state(0, &signed_state, true)
+        .await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::InvalidSignature
+        ))
+    ));
+
+    let captured = capture.0.lock().unwrap();
+    let span = captured
+        .iter()
+        .find(|captured| {
+            captured.span == "validate_signed_state" && captured.fields.contains_key("channel_name")
+        })
+        .unwrap();
+    assert_eq!(span.fields["channel_name"], "channel");
+    assert_eq!(span.fields["insert"], "true");
+
+    let check = captured
+        .iter()
+        .find(|captured| captured.fields.get("check").map(String::as_str) == Some("signature"))
+        .unwrap();
+    assert_eq!(check.span, "validate_signed_state");
+    assert_eq!(check.fields["accepted"], "false");
+    // Rejected before any balance was checked
+    assert!(!captured
+        .iter()
+        .any(|captured| captured.fields.get("check").map(String::as_str) == Some("monotonicity")));
+}
+
+#[tokio::test]
+async fn test_duplicate_signed_state_is_rejected() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    assert_eq!(provider.pay("channel", 100, 1).await, 100);
+    // Resending the same payment doesn't buy a second completion, and isn't stored twice
+    let result = provider.try_pay("channel", 100, 1).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::DuplicateSignedState(_)
+        ))
+    ));
+    let signed_states = provider.ctx.db.get_signed_states("channel").await.unwrap();
+    assert_eq!(signed_states.len(), 1);
+
+    // A state with the same balance but signed again isn't a duplicate
+    let result = provider.try_pay("channel", 100, 2).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::NonMonotonicSpentBalance(_)
+        ))
+    ));
+    assert_eq!(provider.pay("channel", 200, 2).await, 100);
+}
+
+#[tokio::test]
+async fn test_payment_over_spend_cap_is_rejected() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    provider
+        .ctx
+        .register_channel("channel", Some(NearToken::from_yoctonear(500)))
+        .await
+        .unwrap();
+
+    assert_eq!(provider.pay("channel", 400, 1).await, 400);
+    // Under the deposit, above the cap
+    let result = provider.try_pay("channel", 501, 2).await;
+    assert!(matches!(
+        result,
+        Err(ProviderError::SignedState(
+            SignedStateError::SpendCapExceeded(_)
+        ))
+    ));
+    assert_eq!(provider.pay("channel", 500, 2).await, 100);
+
+    // Registering again without a cap lifts it
+    provider
+        .ctx
+        .register_channel("channel", None)
+        .await
+        .unwrap();
+    assert_eq!(provider.pay("channel", 1_000, 3).await, 500);
+}
+
+#[tokio::test]
+async fn test_concurrent_payments_are_checked_in_turns() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+
+    // Checked against the same latest state, both would be accepted in full
+    let (first, second) = tokio::join!(
+        provider.try_pay("channel", 100, 1),
+        provider.try_pay("channel", 200, 2)
+    );
+    let accepted: u128 = [first, second].into_iter().filter_map(Result::ok).sum();
+    assert_eq!(accepted, 200);
+
+    let (first, second) = tokio::join!(
+        provider.try_pay("channel", 300, 3),
+        provider.try_pay("channel", 300, 3)
+    );
+    assert_eq!(first.is_ok() as u8 + second.is_ok() as u8, 1);
+}
+
+#[tokio::test]
+async fn test_stale_signed_state_is_not_recorded() {
+    let provider = setup(config(json!({}))).await;
+    provider.open_channel("channel", 10_000).await;
+    assert_eq!(provider.pay("channel", 200, 2).await, 200);
+
+    // E.g. inserted by another provider instance sharing the database
+    let stale = provider.sender.sign("channel", 100, 1);
+    assert!(matches!(
+        provider.ctx.db.insert_signed_state(&stale).await,
+        Err(ProviderError::SignedState(
+            SignedStateError::NonMonotonicSpentBalance(_)
+        ))
+    ));
+    let signed_states = provider.ctx.db.get_signed_states("channel").await.unwrap();
+    assert_eq!(signed_states.len(), 1);
+    assert_eq!(signed_states[0].spent_balance().as_yoctonear(), 200);
+}